@@ -0,0 +1,524 @@
+//! Managing login accounts.
+//!
+//! Accounts are created and changed by editing `/etc/passwd`, `/etc/group` and `/etc/shadow`
+//! under the root, the way `useradd` and `usermod` do, so that a system mounted somewhere else
+//! can be managed without running anything inside it. `/etc/gshadow` is kept in step when it
+//! exists.
+//!
+//! Each database is rewritten as a whole into `<file>+` and then renamed over the original, so
+//! an interrupted write never leaves a truncated `/etc/passwd` behind. The databases are only
+//! read and written while holding `/etc/.pwd.lock`, as `lckpwdf(3)` does.
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::probe::{GROUP_LOCATION, PASSWD_LOCATION, SHADOW_LOCATION, UID_MAX, UID_MIN};
+use crate::system::{path_in_root, Account, Password};
+
+pub const GSHADOW_LOCATION: &str = "/etc/gshadow";
+/// The login shell of new accounts that don't ask for one, as in Void's `/etc/default/useradd`.
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+/// The permission bits of home directories that don't ask for others.
+pub const HOME_MODE: u32 = 0o700;
+/// The permission bits of the directories created inside home directories.
+pub const SUBDIR_MODE: u32 = 0o755;
+/// The files new home directories may be seeded with.
+pub const SKEL_LOCATION: &str = "/etc/skel";
+/// The file `lckpwdf(3)` locks while the account databases are changed.
+pub const PWD_LOCK_LOCATION: &str = "/etc/.pwd.lock";
+
+#[derive(Debug)]
+pub enum AccountError {
+    Io(io::Error),
+    /// The account to create is already in `/etc/passwd`.
+    UserExists(String),
+    MissingUser(String),
+    MissingGroup(String),
+    /// The uid asked for belongs to another account.
+    UidTaken(u32),
+    /// Every id in the range given to regular users is in use.
+    NoFreeId,
+    /// The password for the user isn't a crypt(3) hash, and may be in plaintext.
+    UnhashedPassword(String),
+}
+
+impl Display for AccountError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountError::Io(err) => write!(f, "{}", err),
+            AccountError::UserExists(name) => write!(f, "The user {} already exists", name),
+            AccountError::MissingUser(name) => write!(f, "There is no user named {}", name),
+            AccountError::MissingGroup(name) => write!(f, "There is no group named {}", name),
+            AccountError::UidTaken(uid) => write!(f, "The uid {} is already in use", uid),
+            AccountError::NoFreeId => write!(f, "There are no free ids between {} and {}", UID_MIN, UID_MAX),
+            AccountError::UnhashedPassword(name) => write!(f, "The password for {} is not a crypt(3) hash", name),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+impl From<io::Error> for AccountError {
+    fn from(err: io::Error) -> Self {
+        AccountError::Io(err)
+    }
+}
+
+/// The crypt(3) methods accepted for passwords: MD5, the bcrypt variants, SHA-256, SHA-512,
+/// scrypt, yescrypt and gost-yescrypt.
+const HASH_METHODS: [&str; 9] = ["1", "2a", "2b", "2y", "5", "6", "7", "y", "gy"];
+
+/// Whether `password` looks like a hash one of [`HASH_METHODS`] produced, as
+/// `$<method>$[<parameters>$]<salt>$<hash>`. DES hashes aren't accepted, as they can't be told
+/// apart from a short plaintext password.
+pub(crate) fn is_hashed(password: &str) -> bool {
+    let fields = match password.strip_prefix('$') {
+        Some(rest) => rest.split('$').collect::<Vec<&str>>(),
+        None => return false,
+    };
+    let is_encoded = |field: &&str| !field.is_empty() && field.chars().all(|c| c.is_ascii_alphanumeric() || "./=,".contains(c));
+    HASH_METHODS.contains(&fields[0]) && (3..=4).contains(&fields.len()) && fields[1..].iter().all(is_encoded)
+}
+
+/// Whether a password field from `/etc/shadow` has been locked with `usermod -L`.
+///
+/// A lone `!` is an account without a password rather than a locked one, as unlocking it
+/// would leave an empty password.
+pub(crate) fn is_locked(password: &str) -> bool {
+    password.len() > 1 && password.starts_with('!')
+}
+
+fn set_locked(password: &str, locked: bool) -> String {
+    match (locked, is_locked(password)) {
+        (true, false) => format!("!{}", password),
+        (false, true) => password[1..].to_string(),
+        _ => password.to_string(),
+    }
+}
+
+/// One of the colon separated databases, as the fields of each of its lines.
+struct Table {
+    location: PathBuf,
+    entries: Vec<Vec<String>>,
+    /// The permissions to give the file when it doesn't exist yet.
+    mode: u32,
+}
+
+impl Table {
+    fn read(location: PathBuf, mode: u32) -> io::Result<Table> {
+        let contents = match fs::read_to_string(&location) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            contents => contents?,
+        };
+        let entries = contents
+            .lines()
+            .map(|line| line.split(':').map(String::from).collect())
+            .collect();
+        Ok(Table { location, entries, mode })
+    }
+
+    fn find(&self, name: &str) -> Option<&Vec<String>> {
+        self.entries.iter().find(|entry| entry[0] == name)
+    }
+
+    fn find_mut(&mut self, name: &str) -> Option<&mut Vec<String>> {
+        self.entries.iter_mut().find(|entry| entry[0] == name)
+    }
+
+    /// Whether any entry has `id` in the field at `index`.
+    fn has_id(&self, index: usize, id: u32) -> bool {
+        self.entries.iter().any(|entry| entry.get(index).is_some_and(|field| field == &id.to_string()))
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let contents = self.entries
+            .iter()
+            .map(|entry| format!("{}\n", entry.join(":")))
+            .collect::<String>();
+        let mode = match fs::metadata(&self.location) {
+            Ok(metadata) => metadata.permissions().mode(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => self.mode,
+            Err(err) => return Err(err),
+        };
+
+        let mut temporary = self.location.clone().into_os_string();
+        temporary.push("+");
+        // A file left by an interrupted write is stale, as nothing else writes it while we hold the lock.
+        match fs::remove_file(&temporary) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+        // The shadow files must never be readable by others, not even before their mode is set.
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(mode).open(&temporary)?;
+        file.write_all(contents.as_bytes())?;
+        // The umask may have taken bits away.
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        file.sync_all()?;
+        fs::rename(temporary, &self.location)
+    }
+}
+
+/// The lock on the account databases, held until it is dropped.
+struct PwdLock {
+    _file: fs::File,
+}
+
+impl PwdLock {
+    /// Waits for a write lock on the whole of `/etc/.pwd.lock`, the lock `lckpwdf(3)` takes, so
+    /// that `useradd`, `passwd` and the like don't change the databases at the same time.
+    fn take(root: &Path) -> io::Result<PwdLock> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path_in_root(root, Path::new(PWD_LOCK_LOCATION)))?;
+        // SAFETY: an all zero flock is valid, and locks from the start of the file to its end.
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = libc::F_WRLCK as libc::c_short;
+        lock.l_whence = libc::SEEK_SET as libc::c_short;
+        // SAFETY: the descriptor is open for as long as `file` is, and `lock` outlives the call.
+        match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLKW, &lock) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(PwdLock { _file: file }),
+        }
+    }
+}
+
+/// The account databases, read together so changes to them can be checked before any is written.
+struct Databases {
+    passwd: Table,
+    group: Table,
+    shadow: Table,
+    gshadow: Option<Table>,
+}
+
+impl Databases {
+    fn read(root: &Path) -> io::Result<Databases> {
+        let resolve = |location: &str| path_in_root(root, Path::new(location));
+        let gshadow = resolve(GSHADOW_LOCATION);
+        Ok(Databases {
+            passwd: Table::read(resolve(PASSWD_LOCATION), 0o644)?,
+            group: Table::read(resolve(GROUP_LOCATION), 0o644)?,
+            shadow: Table::read(resolve(SHADOW_LOCATION), 0o600)?,
+            gshadow: match gshadow.exists() {
+                true => Some(Table::read(gshadow, 0o600)?),
+                false => None,
+            },
+        })
+    }
+
+    fn write(&self) -> io::Result<()> {
+        self.group.write()?;
+        if let Some(gshadow) = &self.gshadow {
+            gshadow.write()?;
+        }
+        self.passwd.write()?;
+        self.shadow.write()
+    }
+
+    /// The lowest id in the regular range that `table` doesn't use, preferring `preferred`.
+    fn free_id(table: &Table, preferred: Option<u32>) -> Result<u32, AccountError> {
+        preferred
+            .into_iter()
+            .chain(UID_MIN..=UID_MAX)
+            .find(|id| !table.has_id(2, *id))
+            .ok_or(AccountError::NoFreeId)
+    }
+
+    fn gid(&self, group: &str) -> Result<String, AccountError> {
+        self.group
+            .find(group)
+            .and_then(|entry| entry.get(2).cloned())
+            .ok_or_else(|| AccountError::MissingGroup(group.to_string()))
+    }
+
+    /// The gid of the group named after `username`, which is created if it doesn't exist.
+    fn private_group(&mut self, username: &str, uid: u32) -> Result<String, AccountError> {
+        if let Some(gid) = self.group.find(username).and_then(|entry| entry.get(2)) {
+            return Ok(gid.clone());
+        }
+
+        let gid = Databases::free_id(&self.group, Some(uid))?.to_string();
+        self.group.entries.push(vec![username.to_string(), String::from("x"), gid.clone(), String::new()]);
+        if let Some(gshadow) = &mut self.gshadow {
+            gshadow.entries.push(vec![username.to_string(), String::from("!"), String::new(), String::new()]);
+        }
+        Ok(gid)
+    }
+
+    /// Makes `username` a member of exactly `groups`, among the supplementary groups.
+    fn set_groups(&mut self, username: &str, groups: &[impl AsRef<str>]) -> Result<(), AccountError> {
+        if let Some(missing) = groups.iter().find(|group| self.group.find(group.as_ref()).is_none()) {
+            return Err(AccountError::MissingGroup(missing.as_ref().to_string()));
+        }
+
+        let tables = std::iter::once(&mut self.group).chain(self.gshadow.as_mut());
+        for table in tables {
+            for entry in table.entries.iter_mut().filter(|entry| entry.len() >= 4) {
+                let wanted = groups.iter().any(|group| group.as_ref() == entry[0]);
+                let mut members = entry[3].split(',').filter(|member| !member.is_empty()).map(String::from).collect::<Vec<String>>();
+                match (wanted, members.iter().any(|member| member == username)) {
+                    (true, false) => members.push(username.to_string()),
+                    (false, true) => members.retain(|member| member != username),
+                    _ => continue,
+                }
+                entry[3] = members.join(",");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `/etc/shadow` entry of a new account, with the ageing `useradd` gives. Without a hash
+/// the account has no password.
+fn shadow_entry(username: &str, hash: Option<&str>, locked: bool) -> Vec<String> {
+    [username, &set_locked(hash.unwrap_or("!"), locked), &days_since_epoch(), "0", "99999", "7", "", "", ""]
+        .map(String::from)
+        .to_vec()
+}
+
+/// The date the password last changed is kept in days since the epoch.
+fn days_since_epoch() -> String {
+    (SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400).to_string()
+}
+
+/// The hash to give `username`, reading it from its file if need be. The hash is checked again
+/// here, as neither a plan file nor a password file is checked when the configuration is read.
+fn password_hash(username: &str, password: &Password) -> Result<String, AccountError> {
+    let hash = match password {
+        Password::Hashed(hash) => hash.to_string(),
+        Password::File(path) => fs::read_to_string(path)?.trim().to_string(),
+    };
+    match is_hashed(&hash) {
+        true => Ok(hash),
+        false => Err(AccountError::UnhashedPassword(username.to_string())),
+    }
+}
+
+pub(crate) struct AccountManager {
+    root: PathBuf,
+}
+
+impl AccountManager {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        AccountManager { root }
+    }
+
+    /// Adds the account `username`, with its home directory at `home`.
+    ///
+    /// Without a primary group, the account gets a group of its own with the same id as its
+    /// uid where that is free. New accounts have no password unless `account` gives one.
+    pub(crate) fn create(&self, username: &str, home: &Path, account: &Account) -> Result<(), AccountError> {
+        let hash = account.password.as_ref().map(|password| password_hash(username, password)).transpose()?;
+        let _lock = PwdLock::take(&self.root)?;
+        let mut databases = Databases::read(&self.root)?;
+        if databases.passwd.find(username).is_some() {
+            return Err(AccountError::UserExists(username.to_string()));
+        }
+
+        let uid = match account.uid {
+            Some(uid) if databases.passwd.has_id(2, uid) => return Err(AccountError::UidTaken(uid)),
+            Some(uid) => uid,
+            None => Databases::free_id(&databases.passwd, None)?,
+        };
+        let gid = match &account.group {
+            Some(group) => databases.gid(group)?,
+            None => databases.private_group(username, uid)?,
+        };
+        databases.set_groups(username, &account.groups)?;
+
+        let shell = account.shell.as_deref().unwrap_or(Path::new(DEFAULT_SHELL));
+        databases.passwd.entries.push(vec![
+            username.to_string(),
+            String::from("x"),
+            uid.to_string(),
+            gid,
+            String::new(),
+            home.to_string_lossy().to_string(),
+            shell.to_string_lossy().to_string(),
+        ]);
+        databases.shadow.entries.retain(|entry| entry[0] != username);
+        databases.shadow.entries.push(shadow_entry(username, hash.as_deref(), account.locked));
+        Ok(databases.write()?)
+    }
+
+    /// Brings the existing account `username` in line with `account`.
+    ///
+    /// The uid, shell and password are only changed when `account` sets them. Files already
+    /// owned by the account keep their old uid.
+    pub(crate) fn modify(&self, username: &str, account: &Account) -> Result<(), AccountError> {
+        let hash = account.password.as_ref().map(|password| password_hash(username, password)).transpose()?;
+        let _lock = PwdLock::take(&self.root)?;
+        let mut databases = Databases::read(&self.root)?;
+        let mut entry = databases.passwd
+            .find(username)
+            .filter(|entry| entry.len() >= 7)
+            .cloned()
+            .ok_or_else(|| AccountError::MissingUser(username.to_string()))?;
+
+        if let Some(uid) = account.uid {
+            let taken = databases.passwd.entries.iter().any(|other| other[0] != username && other.get(2) == Some(&uid.to_string()));
+            if taken {
+                return Err(AccountError::UidTaken(uid));
+            }
+            entry[2] = uid.to_string();
+        }
+        entry[3] = match &account.group {
+            Some(group) => databases.gid(group)?,
+            None => {
+                let uid = entry[2].parse().unwrap_or(UID_MIN);
+                databases.private_group(username, uid)?
+            }
+        };
+        if let Some(shell) = &account.shell {
+            entry[6] = shell.to_string_lossy().to_string();
+        }
+        databases.set_groups(username, &account.groups)?;
+        *databases.passwd.find_mut(username).expect("the account was found above") = entry;
+
+        match databases.shadow.find_mut(username) {
+            Some(shadow) if shadow.len() >= 3 => match hash {
+                Some(hash) if shadow[1].trim_start_matches('!') != hash => {
+                    shadow[1] = set_locked(&hash, account.locked);
+                    shadow[2] = days_since_epoch();
+                }
+                _ => shadow[1] = set_locked(&shadow[1], account.locked),
+            },
+            _ => databases.shadow.entries.push(shadow_entry(username, hash.as_deref(), account.locked)),
+        }
+        Ok(databases.write()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        let root = scratch_dir(name);
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/passwd"), "root:x:0:0:root:/root:/bin/bash\nsapeint:x:1000:1000::/home/sapeint:/bin/bash\n").unwrap();
+        fs::write(root.join("etc/group"), "root:x:0:\nwheel:x:4:sapeint\naudio:x:12:\nsapeint:x:1000:\n").unwrap();
+        fs::write(root.join("etc/shadow"), "root:$6$salt$hash:19000:0:99999:7:::\nsapeint:$6$salt$hash:19000:0:99999:7:::\n").unwrap();
+        fs::set_permissions(root.join("etc/shadow"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::write(root.join("etc/gshadow"), "root:::\nwheel:::sapeint\naudio:::\nsapeint:!::\n").unwrap();
+        root
+    }
+
+    #[test]
+    fn test_create() {
+        let root = fixture("accounts-create");
+        // Left behind by an interrupted write.
+        fs::write(root.join("etc/shadow+"), "guest::0::::::\n").unwrap();
+        let manager = AccountManager::new(root.clone());
+        manager.create("guest", Path::new("/home/guest"), &Account {
+            groups: vec![Rc::from("audio")],
+            ..Account::default()
+        }).unwrap();
+
+        let passwd = fs::read_to_string(root.join("etc/passwd")).unwrap();
+        assert!(passwd.ends_with("guest:x:1001:1001::/home/guest:/bin/sh\n"));
+        let group = fs::read_to_string(root.join("etc/group")).unwrap();
+        assert!(group.contains("audio:x:12:guest\n"));
+        assert!(group.ends_with("guest:x:1001:\n"));
+        assert!(fs::read_to_string(root.join("etc/gshadow")).unwrap().contains("audio:::guest\n"));
+
+        let shadow = fs::read_to_string(root.join("etc/shadow")).unwrap();
+        assert!(shadow.lines().last().unwrap().starts_with("guest:!:"));
+        assert_eq!(fs::metadata(root.join("etc/shadow")).unwrap().permissions().mode() & 0o777, 0o640);
+        assert!(!root.join("etc/shadow+").exists());
+        assert_eq!(fs::metadata(root.join("etc/.pwd.lock")).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let existing = manager.create("sapeint", Path::new("/home/sapeint"), &Account::default());
+        assert!(matches!(existing, Err(AccountError::UserExists(_))));
+        let taken = manager.create("other", Path::new("/home/other"), &Account { uid: Some(1000), ..Account::default() });
+        assert!(matches!(taken, Err(AccountError::UidTaken(1000))));
+        let missing = manager.create("other", Path::new("/home/other"), &Account { group: Some(Rc::from("video")), ..Account::default() });
+        assert!(matches!(missing, Err(AccountError::MissingGroup(group)) if group == "video"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_modify() {
+        let root = fixture("accounts-modify");
+        let manager = AccountManager::new(root.clone());
+        manager.modify("sapeint", &Account {
+            uid: None,
+            group: Some(Rc::from("audio")),
+            groups: vec![Rc::from("audio")],
+            shell: Some(PathBuf::from("/bin/zsh")),
+            locked: true,
+            password: None,
+        }).unwrap();
+
+        assert!(fs::read_to_string(root.join("etc/passwd")).unwrap().contains("sapeint:x:1000:12::/home/sapeint:/bin/zsh\n"));
+        let group = fs::read_to_string(root.join("etc/group")).unwrap();
+        assert!(group.contains("wheel:x:4:\n") && group.contains("audio:x:12:sapeint\n"));
+        assert!(fs::read_to_string(root.join("etc/shadow")).unwrap().contains("sapeint:!$6$salt$hash:"));
+
+        manager.modify("sapeint", &Account::default()).unwrap();
+        assert!(fs::read_to_string(root.join("etc/passwd")).unwrap().contains("sapeint:x:1000:1000::/home/sapeint:/bin/zsh\n"));
+        assert!(fs::read_to_string(root.join("etc/shadow")).unwrap().contains("sapeint:$6$salt$hash:"));
+
+        let missing = manager.modify("guest", &Account::default());
+        assert!(matches!(missing, Err(AccountError::MissingUser(_))));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_passwords() {
+        let root = fixture("accounts-passwords");
+        let manager = AccountManager::new(root.clone());
+        let hash = "$y$j9T$salt$hash";
+        manager.create("guest", Path::new("/home/guest"), &Account {
+            password: Some(Password::Hashed(Rc::from(hash))),
+            locked: true,
+            ..Account::default()
+        }).unwrap();
+        assert!(fs::read_to_string(root.join("etc/shadow")).unwrap().contains("guest:!$y$j9T$salt$hash:"));
+
+        fs::write(root.join("secret"), "$6$other$hash\n").unwrap();
+        manager.modify("sapeint", &Account { password: Some(Password::File(root.join("secret"))), ..Account::default() }).unwrap();
+        let shadow = fs::read_to_string(root.join("etc/shadow")).unwrap();
+        assert!(shadow.contains(&format!("sapeint:$6$other$hash:{}:", days_since_epoch())));
+
+        // Nothing is written when the password isn't hashed.
+        fs::write(root.join("secret"), "hunter2\n").unwrap();
+        let err = manager.modify("sapeint", &Account { password: Some(Password::File(root.join("secret"))), ..Account::default() }).unwrap_err();
+        assert!(matches!(err, AccountError::UnhashedPassword(name) if name == "sapeint"));
+        assert_eq!(fs::read_to_string(root.join("etc/shadow")).unwrap(), shadow);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_is_hashed() {
+        assert!(is_hashed("$6$salt$hash"));
+        assert!(is_hashed("$y$j9T$salt$hash"));
+        assert!(is_hashed("$2b$12$saltandhash"));
+        assert!(!is_hashed("PASS"));
+        assert!(!is_hashed("abcdefghijklm"));
+        assert!(!is_hashed("$6$salt$"));
+        assert!(!is_hashed("$9$salt$hash"));
+        assert!(!is_hashed("$6$salt$hash with spaces"));
+    }
+
+    #[test]
+    fn test_locking() {
+        assert!(is_locked("!$6$salt$hash"));
+        assert!(!is_locked("!"));
+        assert_eq!(set_locked("!", true), "!!");
+        assert_eq!(set_locked("!!", false), "!");
+        assert_eq!(set_locked("$6$salt$hash", false), "$6$salt$hash");
+    }
+}
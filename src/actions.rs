@@ -1,72 +1,216 @@
-use std::rc::Rc;
-use crate::parser::Expr;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
 use crate::system::*;
 
-#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Clone)]
+#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Action {
     File(FileSystemAction),
     System(SystemAction),
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Clone)]
+#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 /// Represents Actions we perform on the system as a whole.
 pub enum SystemAction {
     AddPackage {
         package_name: String,
+        /// Installs exactly this version and revision, rather than the newest available.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<String>,
         package_repository: PackageRepository
     },
-    
+
+    /// Builds a package from a void-packages repository with `xbps-src` and installs the result.
+    BuildPackage {
+        package_name: String,
+        /// The version and revision the build must produce, which is checked when installing it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<String>,
+        package_repository: PackageRepository,
+    },
+
     RemovePackage {
         package_name: String,
     },
-    
+
+    /// Holds an installed package at its version, or releases the hold.
+    HoldPackage {
+        package_name: String,
+        hold: bool,
+    },
+
     AddRepository {
         package_repository: PackageRepository
     },
-    
+
     RemoveRepository {
         package_repository: PackageRepository
     },
-    
+
+    /// Links a runit service into the supervised service directory.
+    EnableService {
+        service_name: String,
+        /// Set for a user's services, which live in their home directory rather than `/etc/sv`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<ServiceUser>,
+    },
+
+    DisableService {
+        service_name: String,
+        /// Set for a user's services, which live in their home directory rather than `/etc/sv`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<ServiceUser>,
+    },
+
+    /// Adds or removes the service's `down` file, which keeps runit from starting it.
+    DownService {
+        service_name: String,
+        down: bool,
+        /// Set for a user's services, which live in their home directory rather than `/etc/sv`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<ServiceUser>,
+    },
+
+    /// Restarts a running service with `sv restart`, so it picks up changes to its files.
+    RestartService {
+        service_name: String,
+        /// Set for a user's services, which live in their home directory rather than `/etc/sv`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<ServiceUser>,
+    },
+
+    /// Sends a running service SIGHUP with `sv hup`, so it rereads its configuration.
+    ReloadService {
+        service_name: String,
+        /// Set for a user's services, which live in their home directory rather than `/etc/sv`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<ServiceUser>,
+    },
+
     ConfigurePackage {
         package_name: String,
         configuration_actions: Vec<Action>,
     },
+
+    /// Adds a login account, along with its primary group when that is the user's own.
+    CreateUser {
+        username: String,
+        home: PathBuf,
+        account: Account,
+    },
+
+    /// Changes an existing account's uid, groups, shell or lock to match `account`.
+    ModifyUser {
+        username: String,
+        account: Account,
+    },
+
+    /// Installs the sudo or doas rules generated for the configured users once the tool has
+    /// accepted them, or removes them when no user has any left.
+    InstallPrivileges {
+        tool: PrivilegeTool,
+        contents: Option<String>,
+    },
+
+    /// Copies a user's dotfiles into their home directory, and removes those that have left
+    /// the repository.
+    DeployDotfiles {
+        username: String,
+        dotfile_actions: Vec<Action>,
+    },
+
+    /// Writes the files of a service defined in the configuration.
+    ConfigureService {
+        service_name: String,
+        configuration_actions: Vec<Action>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<ServiceUser>,
+    },
+}
+
+/// The user whose runit services a service action manages.
+#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct ServiceUser {
+    pub username: String,
+    pub home: PathBuf,
 }
 
 /// Represents an action we can perform on the File System
-#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Clone)]
+///
+/// Locations are always absolute paths on the managed system, they are resolved
+/// against the root being operated on when the action is applied.
+#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum FileSystemAction {
     MoveFile {
-        original_location: Rc<Expr>,
-        final_location: Rc<Expr>,
+        original_location: PathBuf,
+        final_location: PathBuf,
         is_dir: bool,
     },
 
     CopyFile {
-        original_location: Rc<Expr>,
-        final_location: Rc<Expr>,
+        original_location: PathBuf,
+        final_location: PathBuf,
         is_recursive: bool,
+        /// Permission bits to give the copy, rather than those of the original.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<u32>,
+        /// The user to give the copy, and any directories created for it, to.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<String>,
     },
 
     RenameFile {
-        original_name: Rc<Expr>,
-        final_name: Rc<Expr>,
+        original_name: PathBuf,
+        final_name: PathBuf,
     },
 
     AddToFile {
-        original_file: Rc<Expr>,
-        content_to_add: Rc<Expr>,
+        original_file: PathBuf,
+        content_to_add: String,
     },
 
     RemoveFile {
-        file_location: Rc<Expr>,
+        file_location: PathBuf,
         is_dir: bool,
     },
 
     CreateFile {
-        file_location: Rc<Expr>,
-        contents: Option<Rc<Expr>>,
+        file_location: PathBuf,
+        contents: Option<String>,
         is_dir: bool,
+        /// Permission bits to give the file, such as `0o755` for scripts.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<u32>,
+        /// The user to give the file, and any directories created for it, to.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<String>,
+        /// The group to give the file to, which is the owner's primary group when not set.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
-}
\ No newline at end of file
+
+    /// Creates a symbolic link at `link_name` pointing to `target`.
+    Symlink {
+        target: PathBuf,
+        link_name: PathBuf,
+        /// Whether a file or link already at `link_name` is replaced, rather than failing.
+        force: bool,
+    },
+}
+
+impl FileSystemAction {
+    /// Returns every location on the system this action reads or modifies.
+    pub(crate) fn affected_paths(&self) -> Vec<&PathBuf> {
+        match self {
+            FileSystemAction::MoveFile { original_location, final_location, .. } => vec![original_location, final_location],
+            FileSystemAction::CopyFile { original_location, final_location, .. } => vec![original_location, final_location],
+            FileSystemAction::RenameFile { original_name, final_name } => vec![original_name, final_name],
+            FileSystemAction::AddToFile { original_file, .. } => vec![original_file],
+            FileSystemAction::RemoveFile { file_location, .. } => vec![file_location],
+            FileSystemAction::CreateFile { file_location, .. } => vec![file_location],
+            FileSystemAction::Symlink { link_name, .. } => vec![link_name],
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Building packages from void-packages repositories with `xbps-src`.
+//!
+//! A checkout is bootstrapped once with `./xbps-src binary-bootstrap`, after which each
+//! package is built with `./xbps-src pkg <name>`. Built packages end up in `hostdir/binpkgs`,
+//! which is then installed from as a local repository.
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::command::{CommandError, CommandRunner};
+use crate::system::{LocalSource, PackageRepository, Source};
+
+/// `xbps-src` creates this in the masterdir once it has been bootstrapped.
+const BOOTSTRAP_MARKER: &str = ".xbps_chroot_init";
+const ALLOW_RESTRICTED: &str = "XBPS_ALLOW_RESTRICTED=yes";
+
+#[derive(Debug)]
+pub enum BuildError {
+    Io(io::Error),
+    Command(CommandError),
+    /// The directory doesn't contain an `xbps-src` to build with.
+    NotACheckout(PathBuf),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::Io(err) => write!(f, "{}", err),
+            BuildError::Command(err) => write!(f, "{}", err),
+            BuildError::NotACheckout(path) => write!(f, "{} is not a void-packages checkout", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> Self {
+        BuildError::Io(err)
+    }
+}
+
+impl From<CommandError> for BuildError {
+    fn from(err: CommandError) -> Self {
+        BuildError::Command(err)
+    }
+}
+
+/// Builds packages in one checkout of a void-packages repository.
+pub(crate) struct XbpsSrc<R: CommandRunner> {
+    runner: R,
+    checkout: PathBuf,
+    bootstrapped: bool,
+}
+
+impl<R: CommandRunner> XbpsSrc<R> {
+    pub(crate) fn new(runner: R, checkout: PathBuf) -> Self {
+        XbpsSrc { runner, checkout, bootstrapped: false }
+    }
+
+    /// Lets `xbps-src` build restricted packages, by adding `XBPS_ALLOW_RESTRICTED` to the
+    /// checkout's `etc/conf`.
+    pub(crate) fn allow_restricted(&self) -> Result<(), BuildError> {
+        let conf = self.checkout.join("etc/conf");
+        let contents = match fs::read_to_string(&conf) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        if contents.lines().any(|line| line.trim() == ALLOW_RESTRICTED) {
+            return Ok(());
+        }
+
+        fs::create_dir_all(self.checkout.join("etc"))?;
+        let separator = match contents.is_empty() || contents.ends_with('\n') {
+            true => "",
+            false => "\n",
+        };
+        fs::write(conf, format!("{}{}{}\n", contents, separator, ALLOW_RESTRICTED))?;
+        Ok(())
+    }
+
+    /// Builds `package`, bootstrapping the checkout first if it hasn't been yet.
+    pub(crate) fn build(&mut self, package: &str) -> Result<(), BuildError> {
+        self.bootstrap()?;
+        self.xbps_src(&["pkg", package])
+    }
+
+    /// The local repository built packages are written to.
+    pub(crate) fn repository(&self) -> PackageRepository {
+        PackageRepository {
+            name: None,
+            location: Source::Local(LocalSource::Directory(self.checkout.join("hostdir/binpkgs"))),
+            allow_restricted: false,
+        }
+    }
+
+    fn bootstrap(&mut self) -> Result<(), BuildError> {
+        if self.bootstrapped || self.has_masterdir()? {
+            self.bootstrapped = true;
+            return Ok(());
+        }
+        self.xbps_src(&["binary-bootstrap"])?;
+        self.bootstrapped = true;
+        Ok(())
+    }
+
+    /// Whether a previous run already bootstrapped a masterdir, which is named `masterdir`
+    /// or, by newer versions of `xbps-src`, `masterdir-<arch>`.
+    fn has_masterdir(&self) -> Result<bool, BuildError> {
+        for entry in fs::read_dir(&self.checkout)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with("masterdir") && entry.path().join(BOOTSTRAP_MARKER).exists() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn xbps_src(&self, args: &[&str]) -> Result<(), BuildError> {
+        let xbps_src = self.checkout.join("xbps-src");
+        if !xbps_src.is_file() {
+            return Err(BuildError::NotACheckout(self.checkout.clone()));
+        }
+        self.runner.run_checked(&xbps_src.to_string_lossy(), args)?;
+        Ok(())
+    }
+}
+
+/// The version and revision, such as `0.9_1`, that the template for `package` in `checkout`
+/// builds, or `None` if the checkout has no such template.
+pub(crate) fn template_version(checkout: &Path, package: &str) -> Result<Option<String>, BuildError> {
+    let template = match fs::read_to_string(checkout.join("srcpkgs").join(package).join("template")) {
+        Ok(template) => template,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let value = |key: &str| template
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix('='))
+        .map(|value| value.trim().trim_matches(|c| c == '"' || c == '\''));
+
+    match (value("version"), value("revision")) {
+        (Some(version), Some(revision)) => Ok(Some(format!("{}_{}", version, revision))),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use crate::command::fake::FakeCommandRunner;
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let checkout = scratch_dir("builder-build");
+        fs::write(checkout.join("xbps-src"), "#!/bin/sh\n").unwrap();
+        let xbps_src = checkout.join("xbps-src").to_string_lossy().to_string();
+
+        let runner = Rc::new(FakeCommandRunner::default());
+        let mut builder = XbpsSrc::new(runner.clone(), checkout.clone());
+        builder.build("st").unwrap();
+        builder.build("discord").unwrap();
+        assert_eq!(runner.calls(), vec![
+            format!("{} binary-bootstrap", xbps_src),
+            format!("{} pkg st", xbps_src),
+            format!("{} pkg discord", xbps_src),
+        ]);
+        assert_eq!(builder.repository().location, Source::Local(LocalSource::Directory(checkout.join("hostdir/binpkgs"))));
+
+        // A checkout bootstrapped by an earlier run isn't bootstrapped again.
+        fs::create_dir_all(checkout.join("masterdir-x86_64")).unwrap();
+        fs::write(checkout.join("masterdir-x86_64").join(BOOTSTRAP_MARKER), "").unwrap();
+        let runner = Rc::new(FakeCommandRunner::default());
+        XbpsSrc::new(runner.clone(), checkout.clone()).build("st").unwrap();
+        assert_eq!(runner.calls(), vec![format!("{} pkg st", xbps_src)]);
+
+        fs::remove_dir_all(checkout).unwrap();
+    }
+
+    #[test]
+    fn test_build_failure() {
+        let checkout = scratch_dir("builder-failure");
+        let runner = Rc::new(FakeCommandRunner::default());
+        assert!(matches!(XbpsSrc::new(runner.clone(), checkout.clone()).build("st"), Err(BuildError::NotACheckout(_))));
+
+        fs::write(checkout.join("xbps-src"), "#!/bin/sh\n").unwrap();
+        runner.respond(0, "");
+        runner.respond(2, "");
+        assert!(matches!(XbpsSrc::new(runner, checkout.clone()).build("st"), Err(BuildError::Command(CommandError::Failed { status: 2, .. }))));
+
+        fs::remove_dir_all(checkout).unwrap();
+    }
+
+    #[test]
+    fn test_allow_restricted() {
+        let checkout = scratch_dir("builder-restricted");
+        let builder = XbpsSrc::new(FakeCommandRunner::default(), checkout.clone());
+
+        builder.allow_restricted().unwrap();
+        builder.allow_restricted().unwrap();
+        assert_eq!(fs::read_to_string(checkout.join("etc/conf")).unwrap(), "XBPS_ALLOW_RESTRICTED=yes\n");
+
+        fs::write(checkout.join("etc/conf"), "XBPS_CCACHE=yes").unwrap();
+        builder.allow_restricted().unwrap();
+        assert_eq!(fs::read_to_string(checkout.join("etc/conf")).unwrap(), "XBPS_CCACHE=yes\nXBPS_ALLOW_RESTRICTED=yes\n");
+
+        fs::remove_dir_all(checkout).unwrap();
+    }
+}
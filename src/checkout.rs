@@ -0,0 +1,199 @@
+//! Local checkouts of git sources, such as dotfiles and void-packages repositories.
+//!
+//! Each source is cloned once into a cache directory and fetched on later uses. A checkout
+//! is always left at a detached commit: the one the lockfile pins, or the tip of the
+//! source's branch. Checkouts with uncommitted changes are reported rather than overwritten.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::command::{CommandError, CommandRunner};
+use crate::system::Source;
+
+/// Where checkouts are kept, relative to the root being managed.
+pub(crate) const CHECKOUT_CACHE_LOCATION: &str = "/var/cache/svsm/sources";
+
+#[derive(Debug)]
+pub enum CheckoutError {
+    Io(io::Error),
+    Command(CommandError),
+    /// The checkout has changes that updating it would lose.
+    Dirty(PathBuf),
+    NotGit(String),
+}
+
+impl Display for CheckoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckoutError::Io(err) => write!(f, "{}", err),
+            CheckoutError::Command(err) => write!(f, "{}", err),
+            CheckoutError::Dirty(path) => write!(f, "{} has uncommitted changes, commit or discard them first", path.display()),
+            CheckoutError::NotGit(source) => write!(f, "{} is not a git repository", source),
+        }
+    }
+}
+
+impl std::error::Error for CheckoutError {}
+
+impl From<io::Error> for CheckoutError {
+    fn from(err: io::Error) -> Self {
+        CheckoutError::Io(err)
+    }
+}
+
+impl From<CommandError> for CheckoutError {
+    fn from(err: CommandError) -> Self {
+        CheckoutError::Command(err)
+    }
+}
+
+pub(crate) struct Checkouts<R: CommandRunner> {
+    runner: R,
+    cache: PathBuf,
+}
+
+impl<R: CommandRunner> Checkouts<R> {
+    pub(crate) fn new(runner: R, cache: PathBuf) -> Self {
+        Checkouts { runner, cache }
+    }
+
+    /// Where `source` is checked out, named after its URL and branch.
+    pub(crate) fn directory_for(&self, source: &Source) -> Option<PathBuf> {
+        let (url, branch) = source.git_remote()?;
+        let mut name = url
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+            .collect::<String>();
+        if let Some(branch) = branch {
+            name.push('@');
+            name.extend(branch.chars().map(|c| if c == '/' { '_' } else { c }));
+        }
+        Some(self.cache.join(name))
+    }
+
+    /// Clones or fetches `source`, and checks out `commit` or, without one, the tip of the
+    /// source's branch. Returns the directory it is checked out in.
+    pub(crate) fn fetch(&self, source: &Source, commit: Option<&str>) -> Result<PathBuf, CheckoutError> {
+        let (url, branch) = source.git_remote().ok_or_else(|| CheckoutError::NotGit(source.to_string()))?;
+        let directory = self.directory_for(source).expect("Git sources always have a directory");
+        let path = directory.to_string_lossy();
+
+        if !directory.exists() {
+            std::fs::create_dir_all(&self.cache)?;
+            let mut args = vec!["clone", "--quiet"];
+            if let Some(branch) = branch {
+                args.extend_from_slice(&["--branch", branch]);
+            }
+            args.extend_from_slice(&["--", &url, &path]);
+            self.runner.run_checked("git", &args)?;
+
+            if let Some(commit) = commit {
+                self.git(&directory, &["checkout", "--quiet", "--detach", commit])?;
+            }
+            return Ok(directory);
+        }
+
+        if self.is_dirty(&directory)? {
+            return Err(CheckoutError::Dirty(directory));
+        }
+        self.git(&directory, &["fetch", "--quiet", "origin", branch.unwrap_or("HEAD")])?;
+        self.git(&directory, &["checkout", "--quiet", "--detach", commit.unwrap_or("FETCH_HEAD")])?;
+        Ok(directory)
+    }
+
+    /// The commit the checkout in `directory` is at.
+    pub(crate) fn head(&self, directory: &Path) -> Result<String, CheckoutError> {
+        Ok(self.git(directory, &["rev-parse", "HEAD"])?.trim().to_string())
+    }
+
+    /// Whether the checkout has modified or untracked files.
+    pub(crate) fn is_dirty(&self, directory: &Path) -> Result<bool, CheckoutError> {
+        let status = self.git(directory, &["status", "--porcelain"])?;
+        Ok(!status.trim().is_empty())
+    }
+
+    fn git(&self, directory: &Path, args: &[&str]) -> Result<String, CheckoutError> {
+        let path = directory.to_string_lossy();
+        let args = ["-C", &path].iter().chain(args).copied().collect::<Vec<&str>>();
+        Ok(self.runner.run_checked("git", &args)?.stdout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process::Command;
+    use std::rc::Rc;
+    use crate::command::SystemCommandRunner;
+    use crate::system::RemoteSource;
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    fn git(directory: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(["-C", &directory.to_string_lossy(), "-c", "user.name=svsm", "-c", "user.email=svsm@localhost"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    fn commit(repository: &Path, file: &str, contents: &str) -> String {
+        fs::write(repository.join(file), contents).unwrap();
+        git(repository, &["add", file]);
+        git(repository, &["commit", "--quiet", "-m", file]);
+        git(repository, &["rev-parse", "HEAD"])
+    }
+
+    #[test]
+    fn test_fetch() {
+        let root = scratch_dir("checkout-fetch");
+        let upstream = root.join("dotfiles");
+        fs::create_dir_all(&upstream).unwrap();
+        git(&upstream, &["init", "--quiet", "--initial-branch", "main"]);
+        let first = commit(&upstream, "bashrc", "alias ll='ls -l'\n");
+
+        let source = Source::Remote(RemoteSource::GitRemote {
+            url: Rc::from(format!("file://{}", upstream.display())),
+            branch_name: Some(Rc::from("main")),
+        });
+        let checkouts = Checkouts::new(SystemCommandRunner, root.join("cache"));
+
+        let directory = checkouts.fetch(&source, None).unwrap();
+        assert_eq!(directory, checkouts.directory_for(&source).unwrap());
+        assert_eq!(fs::read_to_string(directory.join("bashrc")).unwrap(), "alias ll='ls -l'\n");
+
+        // Later fetches follow the branch, unless a commit is pinned.
+        let second = commit(&upstream, "bashrc", "alias la='ls -a'\n");
+        checkouts.fetch(&source, None).unwrap();
+        assert_eq!(git(&directory, &["rev-parse", "HEAD"]), second);
+        checkouts.fetch(&source, Some(&first)).unwrap();
+        assert_eq!(fs::read_to_string(directory.join("bashrc")).unwrap(), "alias ll='ls -l'\n");
+
+        fs::write(directory.join("bashrc"), "# local changes\n").unwrap();
+        assert!(matches!(checkouts.fetch(&source, None), Err(CheckoutError::Dirty(_))));
+        assert_eq!(fs::read_to_string(directory.join("bashrc")).unwrap(), "# local changes\n");
+
+        // A fresh clone goes straight to the pinned commit.
+        let checkouts = Checkouts::new(SystemCommandRunner, root.join("other-cache"));
+        let directory = checkouts.fetch(&source, Some(&first)).unwrap();
+        assert_eq!(git(&directory, &["rev-parse", "HEAD"]), first);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_directory_for() {
+        let checkouts = Checkouts::new(SystemCommandRunner, PathBuf::from("/var/cache/svsm/sources"));
+        let source = Source::Remote(RemoteSource::GithubRemote {
+            user: Rc::from("sapein"),
+            repository_name: Rc::from("void-packages"),
+            branch_name: Some(Rc::from("personal")),
+        });
+
+        assert_eq!(checkouts.directory_for(&source), Some(PathBuf::from("/var/cache/svsm/sources/https___github.com_sapein_void-packages.git@personal")));
+        assert_eq!(checkouts.directory_for(&Source::Remote(RemoteSource::VoidRepo)), None);
+        assert!(matches!(checkouts.fetch(&Source::Remote(RemoteSource::VoidRepo), None), Err(CheckoutError::NotGit(_))));
+    }
+}
@@ -0,0 +1,288 @@
+//! The SVSM command line.
+//!
+//! ```text
+//! svsm (opts) plan (-o [plan file])
+//! svsm (opts) show [plan file]
+//! svsm (opts) apply [plan file]
+//! svsm (opts) freeze-pkgs
+//! svsm (opts) status
+//!
+//! opts:
+//!   --config_location | -c [location]
+//!   --root | -r [location]
+//!   --packages_location | -p [location] (may be repeated)
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::checkout::{Checkouts, CHECKOUT_CACHE_LOCATION};
+use crate::command::{CommandRunner, SystemCommandRunner};
+use crate::executor::Executor;
+use crate::interpreter::Interpreter;
+use crate::interpreter::package_definitions::{PackageDefinitions, PACKAGE_DEFINITION_LOCATIONS};
+use crate::lockfile::Lockfile;
+use crate::lex::Lexer;
+use crate::package_manager::XbpsPackageManager;
+use crate::parser::{Expr, Parser};
+use crate::plan::{Observed, Plan};
+use crate::planner::Planner;
+use crate::probe::{Probe, SERVICE_LOCATION};
+use crate::render::PlanRenderer;
+use crate::repodata::{RepodataCache, REPODATA_CACHE_LOCATION};
+use crate::service::USER_SERVICE_LOCATION;
+use crate::supervise;
+use crate::system::{path_in_root, HomeDirectory, Source, System};
+
+const USAGE: &str = "Usage: svsm [--config_location <file>] [--root <dir>] [--packages_location <dir>]... <plan [-o <plan file>] | show <plan file> | apply <plan file> | freeze-pkgs | status>";
+
+struct Options {
+    config_location: PathBuf,
+    root: PathBuf,
+    /// Directories to read package files from, later ones overriding earlier ones.
+    packages_locations: Vec<PathBuf>,
+    command: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: Vec<String>) -> Result<Options, String> {
+        let mut options = Options {
+            config_location: PathBuf::from("config.vsm"),
+            root: PathBuf::from("/"),
+            packages_locations: PACKAGE_DEFINITION_LOCATIONS.iter().map(PathBuf::from).collect(),
+            command: vec![],
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config_location" | "-c" => options.config_location = PathBuf::from(args.next().ok_or("Missing value for --config_location")?),
+                "--root" | "-r" => options.root = PathBuf::from(args.next().ok_or("Missing value for --root")?),
+                "--packages_location" | "-p" => options.packages_locations.push(PathBuf::from(args.next().ok_or("Missing value for --packages_location")?)),
+                _ => {
+                    options.command.push(arg);
+                    options.command.extend(args.by_ref());
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Runs SVSM with the given arguments (excluding the program name), returning the exit code.
+pub fn run(args: Vec<String>) -> i32 {
+    let result = Options::parse(args).and_then(|options| {
+        match options.command.iter().map(String::as_str).collect::<Vec<&str>>().as_slice() {
+            ["plan"] => plan(&options, None),
+            ["plan", "-o", output] => plan(&options, Some(Path::new(output))),
+            ["show", plan_file] => show(&options, Path::new(plan_file)),
+            ["apply", plan_file] => apply(&options, Path::new(plan_file)),
+            ["freeze-pkgs"] => freeze_packages(&options),
+            ["status"] => status(&options),
+            _ => Err(String::from(USAGE)),
+        }
+    });
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/// Evaluates the configuration file and converts `system.config` into a [`System`].
+///
+/// The live system is available to the configuration as `system.current`, and every package
+/// definition as a variable named after the package.
+pub(crate) fn load_config(location: &Path, current: &System, definitions: &PackageDefinitions) -> Result<System, String> {
+    let input = fs::read_to_string(location)
+        .map_err(|err| format!("Unable to read {}: {}", location.display(), err))?;
+
+    let mut lexer = Lexer::from_string(&input);
+    let mut parser = Parser::from_token_list_smart(lexer.tokenize_input_smart());
+    let mut interpreter = Interpreter::new(parser.parse_input()).create_standard_env();
+    definitions.register(&mut interpreter.env);
+    interpreter.env.add_variable(
+        Expr::MapRef(Rc::from(Expr::symbol_from_str("system")), Box::from(Expr::symbol_from_str("current"))),
+        current.to_map(),
+    );
+    interpreter.eval_all();
+
+    match interpreter.env.find_variable(&Rc::from("system")).get_map_value(Expr::symbol_from_str("config")) {
+        Some(config) => Ok(System::from_map(config.clone(), definitions)),
+        None => Ok(System::from_map(Expr::Map(BTreeMap::new()), definitions)),
+    }
+}
+
+fn probe_current(options: &Options) -> Result<System, String> {
+    let package_manager = XbpsPackageManager::new(SystemCommandRunner).with_root(options.root.clone());
+    let probe = match options.root == Path::new("/") {
+        true => Probe::new(options.root.clone()).with_package_manager(&package_manager),
+        // Alternate roots may not have a usable XBPS, but they always have a pkgdb.
+        false => Probe::new(options.root.clone()),
+    };
+    probe
+        .probe()
+        .map_err(|err| format!("Unable to read the current system: {}", err))
+}
+
+/// Reads the accounts the configuration declares outside the range given to regular users,
+/// which the first probe skips so that `system.current` only has the regular ones.
+fn probe_accounts(options: &Options, current: System, config: &System) -> Result<System, String> {
+    if config.users.keys().all(|username| current.users.contains_key(username)) {
+        return Ok(current);
+    }
+
+    let users = Probe::new(options.root.clone())
+        .with_accounts(config.users.keys().cloned())
+        .users()
+        .map_err(|err| format!("Unable to read the current system: {}", err))?;
+    Ok(System { users, ..current })
+}
+
+fn load_definitions(options: &Options) -> Result<PackageDefinitions, String> {
+    PackageDefinitions::load(&options.packages_locations)
+        .map_err(|err| format!("Unable to read package files: {}", err))
+}
+
+fn plan(options: &Options, output: Option<&Path>) -> Result<(), String> {
+    let current = probe_current(options)?;
+    let definitions = load_definitions(options)?;
+    let config = load_config(&options.config_location, &current, &definitions)?;
+    let current = probe_accounts(options, current, &config)?;
+    let lockfile = Lockfile::read_from(&Lockfile::location_for(&options.config_location))
+        .map_err(|err| err.to_string())?;
+    let repodata = RepodataCache::load(&path_in_root(&options.root, Path::new(REPODATA_CACHE_LOCATION)))
+        .map_err(|err| format!("Unable to read the repository indexes: {}", err))?;
+    // vp_repos are checked out too, so the plan records the commit its builds come from.
+    let sources = config.git_sources()
+        .into_iter()
+        .map(|source| (source, lockfile.as_ref().and_then(|lockfile| lockfile.commit_for(source))));
+    let (checkouts, commits) = fetch_sources(options, sources)?;
+    // Paths in the configuration are relative to its directory, which a bare file name leaves empty.
+    let config_directory = std::path::absolute(&options.config_location)
+        .map_err(|err| format!("Unable to find {}: {}", options.config_location.display(), err))?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let planner = Planner::new(&config, &current, options.root.clone())
+        .with_definitions(&definitions)
+        .with_checkouts(&checkouts)
+        .with_commits(&commits)
+        .with_config_directory(config_directory);
+    // Without any cached indexes (e.g. before the first sync) there is nothing to check against.
+    let planner = match repodata.is_empty() {
+        true => planner,
+        false => planner.with_repodata(&repodata),
+    };
+    let planner = match &lockfile {
+        Some(lockfile) => planner.with_lockfile(lockfile),
+        None => planner,
+    };
+    let unverified = planner.unverified_versions();
+    if !unverified.is_empty() {
+        eprintln!("Warning: no repository indexes are cached, so the pinned versions {} could not be checked", unverified.join(", "));
+    }
+    let plan = planner
+        .plan()
+        .map_err(|err| format!("Unable to compute plan: {}", err))?;
+
+    print!("{}", renderer(options).render(&plan));
+    match output {
+        Some(output) => plan.write_to(output).map_err(|err| err.to_string()),
+        None => Ok(()),
+    }
+}
+
+fn show(options: &Options, plan_file: &Path) -> Result<(), String> {
+    let plan = Plan::read_from(plan_file).map_err(|err| err.to_string())?;
+    print!("{}", renderer(options).render(&plan));
+    Ok(())
+}
+
+/// Colour is only used when writing to a terminal, and never when `NO_COLOR` is set.
+fn renderer(options: &Options) -> PlanRenderer {
+    let colour = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    PlanRenderer::new(options.root.clone(), colour)
+}
+
+fn apply(options: &Options, plan_file: &Path) -> Result<(), String> {
+    let plan = Plan::read_from(plan_file).map_err(|err| err.to_string())?;
+    // Git sources are checked out at the commits the plan was computed from, not wherever the
+    // lockfile points now.
+    let sources = plan.source_commits().into_iter().map(|(source, commit)| (source, Some(commit)));
+    let (checkouts, commits) = fetch_sources(options, sources)?;
+    let current = probe_current(options)?;
+    plan.verify(&options.root, &Observed { packages: &current.packages, commits: &commits }).map_err(|err| err.to_string())?;
+
+    let runner: Rc<dyn CommandRunner> = Rc::new(SystemCommandRunner);
+    let package_manager = XbpsPackageManager::new(runner.clone()).with_root(options.root.clone());
+    Executor::new(options.root.clone(), Box::new(package_manager))
+        .with_runner(runner)
+        .with_checkouts(checkouts)
+        .apply(plan.actions())
+        .map_err(|err| format!("Unable to apply plan: {}", err))
+}
+
+/// Where each git source is checked out, and the commit each checkout is at.
+type Fetched = (BTreeMap<Source, PathBuf>, BTreeMap<Source, String>);
+
+/// Checks out each git source in the root's source cache, at the commit given for it if there
+/// is one.
+fn fetch_sources<'a>(options: &Options, sources: impl IntoIterator<Item = (&'a Source, Option<&'a str>)>) -> Result<Fetched, String> {
+    let checkouts = Checkouts::new(SystemCommandRunner, path_in_root(&options.root, Path::new(CHECKOUT_CACHE_LOCATION)));
+    let mut directories = BTreeMap::new();
+    let mut commits = BTreeMap::new();
+    for (source, commit) in sources {
+        let unavailable = |err| format!("Unable to check out {}: {}", source, err);
+        let checkout = checkouts.fetch(source, commit).map_err(unavailable)?;
+        commits.insert(source.clone(), checkouts.head(&checkout).map_err(unavailable)?);
+        directories.insert(source.clone(), checkout);
+    }
+    Ok((directories, commits))
+}
+
+/// Records the exact builds and commits the configuration currently resolves to.
+fn freeze_packages(options: &Options) -> Result<(), String> {
+    let current = probe_current(options)?;
+    let config = load_config(&options.config_location, &current, &load_definitions(options)?)?;
+
+    let package_manager = XbpsPackageManager::new(SystemCommandRunner).with_root(options.root.clone());
+    let checkouts = Checkouts::new(SystemCommandRunner, path_in_root(&options.root, Path::new(CHECKOUT_CACHE_LOCATION)));
+    let lockfile = Lockfile::freeze(&config, &package_manager, &SystemCommandRunner, &checkouts)
+        .map_err(|err| format!("Unable to freeze packages: {}", err))?;
+
+    let location = Lockfile::location_for(&options.config_location);
+    lockfile.write_to(&location).map_err(|err| err.to_string())?;
+    println!("Wrote {}", location.display());
+    Ok(())
+}
+
+/// Prints the runit status of every service in the configuration, like `sv status` does.
+fn status(options: &Options) -> Result<(), String> {
+    let current = probe_current(options)?;
+    let config = load_config(&options.config_location, &current, &load_definitions(options)?)?;
+
+    let now = std::time::SystemTime::now();
+    let mut services = config.services.keys()
+        .map(|name| (name.to_string(), Path::new(SERVICE_LOCATION).join(name.as_ref())))
+        .collect::<BTreeMap<String, PathBuf>>();
+    for (username, user) in &config.users {
+        let HomeDirectory::Path { location, .. } = &user.homedir;
+        services.extend(user.services.keys().map(|name| (
+            format!("{}/{}", username, name),
+            location.join(USER_SERVICE_LOCATION).join(name.as_ref()),
+        )));
+    }
+
+    for (name, directory) in services {
+        let status = supervise::read_status(&options.root, &directory)
+            .map_err(|err| format!("Unable to read the status of {}: {}", name, err))?;
+        println!("{}", supervise::describe(&name, status.as_ref(), now));
+    }
+    Ok(())
+}
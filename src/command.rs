@@ -0,0 +1,148 @@
+//! Running external programs.
+//!
+//! Everything in SVSM that shells out does so through a [`CommandRunner`], so that the
+//! programs can be replaced in tests or on machines where they don't exist.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::process::Command;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CommandOutput {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    Io(io::Error),
+    Failed {
+        command: String,
+        status: i32,
+        stderr: String,
+    },
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Io(err) => write!(f, "{}", err),
+            CommandError::Failed { command, status, stderr } => write!(f, "`{}` exited with status {}: {}", command, status, stderr.trim()),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<io::Error> for CommandError {
+    fn from(err: io::Error) -> Self {
+        CommandError::Io(err)
+    }
+}
+
+pub trait CommandRunner {
+    /// Runs `program` with `args`, returning its output regardless of its exit status.
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput>;
+
+    /// Runs `program` with `args`, treating a non-zero exit status as an error.
+    fn run_checked(&self, program: &str, args: &[&str]) -> Result<CommandOutput, CommandError> {
+        let output = self.run(program, args)?;
+        match output.status {
+            0 => Ok(output),
+            status => Err(CommandError::Failed {
+                command: std::iter::once(program).chain(args.iter().copied()).collect::<Vec<&str>>().join(" "),
+                status,
+                stderr: output.stderr,
+            }),
+        }
+    }
+}
+
+/// Lets a single runner be shared between several subsystems.
+impl<T: CommandRunner + ?Sized> CommandRunner for Rc<T> {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        self.as_ref().run(program, args)
+    }
+}
+
+/// Runs programs on the host.
+#[derive(Debug, Default)]
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let output = Command::new(program).args(args).output()?;
+        Ok(CommandOutput {
+            status: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use super::*;
+
+    /// Records every command it is asked to run, and answers with queued outputs.
+    ///
+    /// Commands without a queued output succeed with no output.
+    #[derive(Debug, Default)]
+    pub(crate) struct FakeCommandRunner {
+        calls: RefCell<Vec<Vec<String>>>,
+        outputs: RefCell<VecDeque<CommandOutput>>,
+    }
+
+    impl FakeCommandRunner {
+        pub(crate) fn respond(&self, status: i32, stdout: &str) {
+            self.outputs.borrow_mut().push_back(CommandOutput {
+                status,
+                stdout: stdout.to_string(),
+                stderr: String::new(),
+            });
+        }
+
+        pub(crate) fn calls(&self) -> Vec<String> {
+            self.calls.borrow().iter().map(|call| call.join(" ")).collect()
+        }
+    }
+
+    impl CommandRunner for FakeCommandRunner {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            self.calls.borrow_mut().push(std::iter::once(program).chain(args.iter().copied()).map(String::from).collect());
+            Ok(self.outputs.borrow_mut().pop_front().unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::fake::FakeCommandRunner;
+
+    #[test]
+    fn test_run_checked_failure() {
+        let runner = FakeCommandRunner::default();
+        runner.respond(2, "");
+
+        match runner.run_checked("xbps-query", &["-R", "nothing"]) {
+            Err(CommandError::Failed { command, status, .. }) => {
+                assert_eq!(command, "xbps-query -R nothing");
+                assert_eq!(status, 2);
+            }
+            other => panic!("Expected failure, got {:?}", other),
+        }
+        assert_eq!(runner.calls(), vec!["xbps-query -R nothing"]);
+    }
+
+    #[test]
+    fn test_system_runner() {
+        let output = SystemCommandRunner.run("sh", &["-c", "echo hello; exit 3"]).unwrap();
+        assert_eq!(output.status, 3);
+        assert_eq!(output.stdout, "hello\n");
+    }
+}
@@ -0,0 +1,91 @@
+//! Deploying users' dotfiles from a repository into their home directory.
+//!
+//! Every file in the repository is copied to the same path relative to the home directory, apart
+//! from git's own files and the paths the configuration excludes. The files SVSM has deployed are
+//! listed in a manifest in the home directory, so that files which disappear from the repository
+//! are removed again, and files SVSM didn't put there are never overwritten.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::system::normalise;
+
+/// Where the list of deployed dotfiles is kept, relative to the home directory.
+pub const MANIFEST_LOCATION: &str = ".local/state/svsm/dotfiles";
+
+const HEADER: &str = "# Generated by svsm, changes will be overwritten.\n";
+
+/// The files in `directory` to deploy, relative to it and sorted. Anything under one of the
+/// `exclude` paths is skipped.
+pub(crate) fn source_files(directory: &Path, exclude: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let exclude = exclude.iter().map(|path| normalise(path)).collect::<Vec<PathBuf>>();
+    let mut files = vec![];
+    collect_files(directory, Path::new(""), &exclude, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+fn collect_files(directory: &Path, relative: &Path, exclude: &[PathBuf], files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if entry.file_name() == ".git" || exclude.iter().any(|excluded| path.starts_with(excluded)) {
+            continue;
+        }
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(directory, &path, exclude, files)?;
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The deployed files a manifest lists.
+pub(crate) fn read_manifest(contents: &str) -> BTreeSet<PathBuf> {
+    contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// The manifest listing `files`.
+pub(crate) fn manifest(files: &BTreeSet<PathBuf>) -> String {
+    let mut manifest = String::from(HEADER);
+    for file in files {
+        manifest.push_str(&file.to_string_lossy());
+        manifest.push('\n');
+    }
+    manifest
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    #[test]
+    fn test_source_files() {
+        let directory = scratch_dir("dotfiles-source");
+        for file in [".git/HEAD", ".bashrc", ".config/i3/config", "README.md", "scripts/backup.sh"] {
+            fs::create_dir_all(directory.join(file).parent().unwrap()).unwrap();
+            fs::write(directory.join(file), "").unwrap();
+        }
+
+        let files = source_files(&directory, &[PathBuf::from("./README.md"), PathBuf::from("./scripts")]).unwrap();
+        assert_eq!(files, vec![PathBuf::from(".bashrc"), PathBuf::from(".config/i3/config")]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_manifest() {
+        let files = BTreeSet::from([PathBuf::from(".bashrc"), PathBuf::from(".config/i3/config")]);
+        assert_eq!(manifest(&files), format!("{}.bashrc\n.config/i3/config\n", HEADER));
+        assert_eq!(read_manifest(&manifest(&files)), files);
+    }
+}
@@ -0,0 +1,947 @@
+//! Applies actions to a system.
+//!
+//! The executor is the only part of SVSM that changes the system being managed. Everything
+//! it touches is resolved against a root directory, so that it can be pointed at a chroot
+//! or a scratch directory instead of the running system.
+//!
+//! Before a file is changed, what was there is kept, so that when an action fails the files
+//! changed by the actions before it are put back. Packages, accounts and services are not.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::{chown, symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::accounts::{AccountError, AccountManager};
+use crate::actions::{Action, FileSystemAction, ServiceUser, SystemAction};
+use crate::builder::{BuildError, XbpsSrc};
+use crate::command::{CommandError, CommandRunner, SystemCommandRunner};
+use crate::package_manager::{PackageManager, PackageManagerError};
+use crate::probe::{GROUP_LOCATION, PASSWD_LOCATION};
+use crate::service::{ServiceError, ServiceManager};
+use crate::system::{path_in_root, LocalSource, PackageRepository, PrivilegeTool, RemoteSource, Source};
+
+pub struct Executor {
+    root: PathBuf,
+    package_manager: Box<dyn PackageManager>,
+    runner: Rc<dyn CommandRunner>,
+    /// Builders for the void-packages checkouts used so far, so each is bootstrapped once.
+    builders: BTreeMap<PathBuf, XbpsSrc<Rc<dyn CommandRunner>>>,
+    /// Where git sources are checked out.
+    checkouts: BTreeMap<Source, PathBuf>,
+    services: ServiceManager,
+}
+
+#[derive(Debug)]
+pub enum ExecutorError {
+    Io(io::Error),
+    Package(PackageManagerError),
+    Build(BuildError),
+    Service(ServiceError),
+    Account(AccountError),
+    /// sudo or doas found a problem with the rules generated for them.
+    InvalidPrivileges(CommandError),
+    Unsupported(Box<Action>),
+    /// An action failed, and putting back the files changed before it failed as well.
+    Rollback { err: Box<ExecutorError>, rollback: io::Error },
+}
+
+impl Display for ExecutorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecutorError::Io(err) => write!(f, "{}", err),
+            ExecutorError::Package(err) => write!(f, "{}", err),
+            ExecutorError::Build(err) => write!(f, "{}", err),
+            ExecutorError::Service(err) => write!(f, "{}", err),
+            ExecutorError::Account(err) => write!(f, "{}", err),
+            ExecutorError::InvalidPrivileges(err) => write!(f, "Refusing to install rules that failed validation: {}", err),
+            ExecutorError::Unsupported(action) => write!(f, "Action is not supported yet: {:?}", action),
+            ExecutorError::Rollback { err, rollback } => write!(f, "{}, and the files changed before it couldn't be restored: {}", err, rollback),
+        }
+    }
+}
+
+impl std::error::Error for ExecutorError {}
+
+impl From<io::Error> for ExecutorError {
+    fn from(err: io::Error) -> Self {
+        ExecutorError::Io(err)
+    }
+}
+
+impl From<PackageManagerError> for ExecutorError {
+    fn from(err: PackageManagerError) -> Self {
+        ExecutorError::Package(err)
+    }
+}
+
+impl From<BuildError> for ExecutorError {
+    fn from(err: BuildError) -> Self {
+        ExecutorError::Build(err)
+    }
+}
+
+impl From<ServiceError> for ExecutorError {
+    fn from(err: ServiceError) -> Self {
+        ExecutorError::Service(err)
+    }
+}
+
+impl From<AccountError> for ExecutorError {
+    fn from(err: AccountError) -> Self {
+        ExecutorError::Account(err)
+    }
+}
+
+impl Executor {
+    pub fn new(root: PathBuf, package_manager: Box<dyn PackageManager>) -> Self {
+        // Only the running system has a runsvdir supervising /var/service.
+        let services = ServiceManager::new(root.clone(), root == Path::new("/"));
+        Executor { root, package_manager, runner: Rc::new(SystemCommandRunner), builders: BTreeMap::new(), checkouts: BTreeMap::new(), services }
+    }
+
+    /// Builds packages from vp_repos hosted in git in the directories they are checked out in.
+    pub fn with_checkouts(mut self, checkouts: BTreeMap<Source, PathBuf>) -> Self {
+        self.checkouts = checkouts;
+        self
+    }
+
+    /// Runs `xbps-src` and `sv` through `runner` rather than on the host directly.
+    pub fn with_runner(mut self, runner: Rc<dyn CommandRunner>) -> Self {
+        self.services = self.services.with_runner(runner.clone());
+        self.runner = runner;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn package_manager(&self) -> &dyn PackageManager {
+        self.package_manager.as_ref()
+    }
+
+    /// Applies each action in order, stopping at the first one that fails. The files changed
+    /// before then are restored to what they were. Nothing is applied if any action is not
+    /// supported.
+    pub fn apply(&mut self, actions: &[Action]) -> Result<(), ExecutorError> {
+        self.check(actions)?;
+        let mut replaced = vec![];
+        let err = match self.apply_all(actions, &mut replaced) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+
+        // Later changes are undone first, so files are put back before their directories.
+        for (location, previous) in replaced.iter().rev() {
+            if let Err(rollback) = self.restore(location, previous) {
+                return Err(ExecutorError::Rollback { err: Box::new(err), rollback });
+            }
+        }
+        Err(err)
+    }
+
+    /// Fails on the first action, including those nested in configuration, that can't be applied.
+    fn check(&self, actions: &[Action]) -> Result<(), ExecutorError> {
+        for action in actions {
+            let supported = match action {
+                Action::File(_) => true,
+                Action::System(SystemAction::AddPackage { package_repository, .. }) => install_repository(package_repository).is_some(),
+                Action::System(SystemAction::BuildPackage { package_repository, .. }) => self.checkout_for(package_repository).is_some(),
+                Action::System(SystemAction::AddRepository { .. } | SystemAction::RemoveRepository { .. }) => false,
+                Action::System(SystemAction::ConfigurePackage { configuration_actions, .. }) => {
+                    self.check(configuration_actions)?;
+                    true
+                }
+                Action::System(SystemAction::DeployDotfiles { dotfile_actions, .. }) => {
+                    self.check(dotfile_actions)?;
+                    true
+                }
+                Action::System(SystemAction::ConfigureService { configuration_actions, .. }) => {
+                    self.check(configuration_actions)?;
+                    true
+                }
+                Action::System(_) => true,
+            };
+            if !supported {
+                return Err(ExecutorError::Unsupported(Box::new(action.clone())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the vp_repo of `repository` is checked out, if it is.
+    fn checkout_for(&self, repository: &PackageRepository) -> Option<PathBuf> {
+        match &repository.location {
+            Source::Local(LocalSource::Directory(checkout)) => Some(checkout.clone()),
+            location => self.checkouts.get(location).cloned(),
+        }
+    }
+
+    fn apply_all(&mut self, actions: &[Action], replaced: &mut Vec<(PathBuf, Replaced)>) -> Result<(), ExecutorError> {
+        for action in actions {
+            self.apply_action(action, replaced)?;
+        }
+        Ok(())
+    }
+
+    fn apply_action(&mut self, action: &Action, replaced: &mut Vec<(PathBuf, Replaced)>) -> Result<(), ExecutorError> {
+        let changed = match action {
+            Action::File(action) => action.affected_paths().into_iter().map(PathBuf::as_path).collect(),
+            Action::System(SystemAction::InstallPrivileges { tool, .. }) => vec![tool.location()],
+            Action::System(_) => vec![],
+        };
+        // Directories that go away take everything inside them along.
+        let takes_contents = matches!(
+            action,
+            Action::File(FileSystemAction::RemoveFile { is_dir: true, .. } | FileSystemAction::MoveFile { is_dir: true, .. } | FileSystemAction::RenameFile { .. }),
+        );
+        for location in changed {
+            self.record(location, takes_contents, replaced)?;
+        }
+
+        match action {
+            Action::File(action) => Ok(self.apply_file_action(action)?),
+            Action::System(SystemAction::AddPackage { package_name, version, package_repository }) => {
+                let repository = match install_repository(package_repository) {
+                    Some(repository) => repository,
+                    None => return Err(ExecutorError::Unsupported(Box::new(action.clone()))),
+                };
+                let package = match version {
+                    Some(version) => format!("{}-{}", package_name, version),
+                    None => package_name.clone(),
+                };
+                self.package_manager.install(&[&package], repository.as_deref())?;
+                // These packages only add a repository, whose index is needed before anything
+                // can be installed from it.
+                if package_name.starts_with("void-repo-") {
+                    self.package_manager.sync_index()?;
+                }
+                Ok(())
+            }
+            Action::System(SystemAction::BuildPackage { package_name, version, package_repository }) => {
+                let checkout = match self.checkout_for(package_repository) {
+                    Some(checkout) => checkout,
+                    None => return Err(ExecutorError::Unsupported(Box::new(action.clone()))),
+                };
+                let runner = self.runner.clone();
+                let builder = self.builders
+                    .entry(checkout.clone())
+                    .or_insert_with(|| XbpsSrc::new(runner, checkout));
+                if package_repository.allow_restricted {
+                    builder.allow_restricted()?;
+                }
+                builder.build(package_name)?;
+
+                // Installing the exact pkgver fails if the template built a different version.
+                let package = match version {
+                    Some(version) => format!("{}-{}", package_name, version),
+                    None => package_name.clone(),
+                };
+                let binpkgs = install_repository(&builder.repository()).flatten();
+                Ok(self.package_manager.install(&[&package], binpkgs.as_deref())?)
+            }
+            Action::System(SystemAction::RemovePackage { package_name }) => {
+                Ok(self.package_manager.remove(&[package_name])?)
+            }
+            Action::System(SystemAction::HoldPackage { package_name, hold }) => {
+                Ok(self.package_manager.set_held(package_name, *hold)?)
+            }
+            Action::System(SystemAction::EnableService { service_name, user }) => Ok(self.services_for(user.as_ref()).enable(service_name)?),
+            Action::System(SystemAction::DisableService { service_name, user }) => Ok(self.services_for(user.as_ref()).disable(service_name)?),
+            Action::System(SystemAction::DownService { service_name, down, user }) => Ok(self.services_for(user.as_ref()).set_down(service_name, *down)?),
+            Action::System(SystemAction::RestartService { service_name, user }) => Ok(self.services_for(user.as_ref()).restart(service_name)?),
+            Action::System(SystemAction::ReloadService { service_name, user }) => Ok(self.services_for(user.as_ref()).reload(service_name)?),
+            Action::System(SystemAction::CreateUser { username, home, account }) => {
+                Ok(AccountManager::new(self.root.clone()).create(username, home, account)?)
+            }
+            Action::System(SystemAction::ModifyUser { username, account }) => {
+                Ok(AccountManager::new(self.root.clone()).modify(username, account)?)
+            }
+            Action::System(SystemAction::InstallPrivileges { tool, contents }) => self.install_privileges(*tool, contents.as_deref()),
+            Action::System(SystemAction::ConfigurePackage { configuration_actions, .. }) => self.apply_all(configuration_actions, replaced),
+            Action::System(SystemAction::DeployDotfiles { dotfile_actions, .. }) => self.apply_all(dotfile_actions, replaced),
+            Action::System(SystemAction::ConfigureService { configuration_actions, .. }) => self.apply_all(configuration_actions, replaced),
+            Action::System(_) => Err(ExecutorError::Unsupported(Box::new(action.clone()))),
+        }
+    }
+
+    /// The services of `user`, or the system's when there is none.
+    fn services_for(&self, user: Option<&ServiceUser>) -> Cow<'_, ServiceManager> {
+        match user {
+            Some(user) => Cow::Owned(ServiceManager::for_user(self.root.clone(), &user.home).with_runner(self.runner.clone())),
+            None => Cow::Borrowed(&self.services),
+        }
+    }
+
+    /// Writes the rules next to where they go, and only moves them into place once the tool
+    /// has checked them.
+    fn install_privileges(&self, tool: PrivilegeTool, contents: Option<&str>) -> Result<(), ExecutorError> {
+        let location = self.resolve(tool.location());
+        let contents = match contents {
+            Some(contents) => contents,
+            None if location.exists() => return Ok(fs::remove_file(location)?),
+            None => return Ok(()),
+        };
+
+        if let Some(parent) = location.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // sudo skips files in sudoers.d with a `.` in their name, so this is never read early.
+        let mut candidate = location.clone().into_os_string();
+        candidate.push(".new");
+        let candidate = PathBuf::from(candidate);
+        fs::write(&candidate, contents)?;
+        fs::set_permissions(&candidate, fs::Permissions::from_mode(tool.mode()))?;
+        if let Err(err) = tool.validate(self.runner.as_ref(), &candidate) {
+            fs::remove_file(&candidate)?;
+            return Err(ExecutorError::InvalidPrivileges(err));
+        }
+        Ok(fs::rename(candidate, location)?)
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        path_in_root(&self.root, path)
+    }
+
+    /// Keeps what is at `location` the first time the apply is about to change it, along with
+    /// everything inside it when `recursive`.
+    fn record(&self, location: &Path, recursive: bool, replaced: &mut Vec<(PathBuf, Replaced)>) -> io::Result<()> {
+        let path = self.resolve(location);
+        if !replaced.iter().any(|(recorded, _)| recorded == location) {
+            replaced.push((location.to_path_buf(), Replaced::capture(&path)?));
+        }
+        if recursive && fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+            for entry in fs::read_dir(&path)? {
+                self.record(&location.join(entry?.file_name()), true, replaced)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts back what was at `location` before the apply changed it.
+    fn restore(&self, location: &Path, previous: &Replaced) -> io::Result<()> {
+        let path = self.resolve(location);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() && matches!(previous, Replaced::Directory { .. }) => (),
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&path)?,
+            Ok(_) => fs::remove_file(&path)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        // Anything inside a removed directory is restored before the directory itself.
+        if let Some(parent) = path.parent().filter(|_| !matches!(previous, Replaced::Absent)) {
+            fs::create_dir_all(parent)?;
+        }
+
+        match previous {
+            Replaced::Absent => Ok(()),
+            Replaced::Directory { mode, uid, gid } => {
+                fs::create_dir_all(&path)?;
+                chown(&path, Some(*uid), Some(*gid))?;
+                fs::set_permissions(&path, fs::Permissions::from_mode(*mode))
+            }
+            Replaced::Symlink { target } => symlink(target, &path),
+            Replaced::File { contents, mode, uid, gid } => {
+                fs::write(&path, contents)?;
+                chown(&path, Some(*uid), Some(*gid))?;
+                fs::set_permissions(&path, fs::Permissions::from_mode(*mode))
+            }
+        }
+    }
+
+    fn apply_file_action(&self, action: &FileSystemAction) -> io::Result<()> {
+        match action {
+            FileSystemAction::MoveFile { original_location, final_location, .. } => {
+                fs::rename(self.resolve(original_location), self.resolve(final_location))
+            }
+            FileSystemAction::CopyFile { original_location, final_location, is_recursive, mode, owner } => {
+                let from = self.resolve(original_location);
+                let to = self.resolve(final_location);
+                let ownership = match owner {
+                    Some(owner) => Some(self.ownership(Some(owner), None)?),
+                    None => None,
+                };
+                // Like files created for a user, copies for one get the directories they need.
+                if let Some(parent) = to.parent().filter(|_| ownership.is_some()) {
+                    create_dirs(parent, ownership)?;
+                }
+
+                if *is_recursive && from.is_dir() {
+                    copy_dir(&from, &to)?;
+                } else {
+                    fs::copy(from, &to)?;
+                }
+
+                if let Some((uid, gid)) = ownership {
+                    chown(&to, uid, gid)?;
+                }
+                match mode {
+                    Some(mode) => fs::set_permissions(to, fs::Permissions::from_mode(*mode)),
+                    None => Ok(()),
+                }
+            }
+            FileSystemAction::RenameFile { original_name, final_name } => {
+                fs::rename(self.resolve(original_name), self.resolve(final_name))
+            }
+            FileSystemAction::AddToFile { original_file, content_to_add } => {
+                fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(self.resolve(original_file))?
+                    .write_all(content_to_add.as_bytes())
+            }
+            FileSystemAction::RemoveFile { file_location, is_dir } => {
+                match is_dir {
+                    true => fs::remove_dir_all(self.resolve(file_location)),
+                    false => fs::remove_file(self.resolve(file_location)),
+                }
+            }
+            FileSystemAction::CreateFile { file_location, contents, is_dir, mode, owner, group } => {
+                let location = self.resolve(file_location);
+                let ownership = match (owner, group) {
+                    (None, None) => None,
+                    (owner, group) => Some(self.ownership(owner.as_deref(), group.as_deref())?),
+                };
+                // Directories created along the way only belong to the owner's own group.
+                let parents = match owner {
+                    Some(owner) if group.is_some() => Some(self.ownership(Some(owner), None)?),
+                    _ => ownership,
+                };
+
+                if *is_dir {
+                    create_dirs(&location, parents)?;
+                } else {
+                    if let Some(parent) = location.parent() {
+                        create_dirs(parent, parents)?;
+                    }
+                    fs::write(&location, contents.as_deref().unwrap_or(""))?;
+                }
+
+                if let Some((uid, gid)) = ownership {
+                    chown(&location, uid, gid)?;
+                }
+                match mode {
+                    Some(mode) => fs::set_permissions(location, fs::Permissions::from_mode(*mode)),
+                    None => Ok(()),
+                }
+            }
+            FileSystemAction::Symlink { target, link_name, force } => {
+                let location = self.resolve(link_name);
+                match fs::symlink_metadata(&location) {
+                    Ok(metadata) if *force && !metadata.is_dir() => fs::remove_file(&location)?,
+                    Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", link_name.display()))),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                    Err(err) => return Err(err),
+                }
+                if let Some(parent) = location.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // The target is left as it is, since the link is followed on the managed system.
+                symlink(target, location)
+            }
+        }
+    }
+
+    /// Looks up the uid and gid files should be given, in the managed system's `/etc/passwd`
+    /// and `/etc/group`. Without a group, the owner's primary group is used.
+    fn ownership(&self, owner: Option<&str>, group: Option<&str>) -> io::Result<(Option<u32>, Option<u32>)> {
+        let entry = |file: &str, name: &str| -> io::Result<Vec<String>> {
+            fs::read_to_string(self.resolve(Path::new(file)))?
+                .lines()
+                .map(|line| line.split(':').map(String::from).collect::<Vec<String>>())
+                .find(|fields| fields.len() >= 4 && fields[0] == name)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no entry for {}", file, name)))
+        };
+        let id = |field: &str| field.parse::<u32>().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+
+        let (uid, primary_gid) = match owner {
+            Some(owner) => {
+                let fields = entry(PASSWD_LOCATION, owner)?;
+                (Some(id(&fields[2])?), Some(id(&fields[3])?))
+            }
+            None => (None, None),
+        };
+        let gid = match group {
+            Some(group) => Some(id(&entry(GROUP_LOCATION, group)?[2])?),
+            None => primary_gid,
+        };
+        Ok((uid, gid))
+    }
+}
+
+/// What was at a location before an action changed it, like a [`FileState`] that keeps the
+/// contents of files rather than their hash.
+///
+/// [`FileState`]: crate::plan::FileState
+enum Replaced {
+    Absent,
+    /// Anything inside the directory is recorded on its own.
+    Directory { mode: u32, uid: u32, gid: u32 },
+    File { contents: Vec<u8>, mode: u32, uid: u32, gid: u32 },
+    Symlink { target: PathBuf },
+}
+
+impl Replaced {
+    fn capture(path: &Path) -> io::Result<Replaced> {
+        let metadata = match fs::symlink_metadata(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Replaced::Absent),
+            metadata => metadata?,
+        };
+        Ok(match metadata.file_type() {
+            kind if kind.is_symlink() => Replaced::Symlink { target: fs::read_link(path)? },
+            kind if kind.is_dir() => Replaced::Directory {
+                mode: metadata.permissions().mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            },
+            _ => Replaced::File {
+                contents: fs::read(path)?,
+                mode: metadata.permissions().mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            },
+        })
+    }
+}
+
+/// Creates `directory` and any missing parents, giving the ones it creates to `ownership`.
+fn create_dirs(directory: &Path, ownership: Option<(Option<u32>, Option<u32>)>) -> io::Result<()> {
+    let missing = directory
+        .ancestors()
+        .take_while(|ancestor| !ancestor.exists())
+        .map(Path::to_path_buf)
+        .collect::<Vec<PathBuf>>();
+    fs::create_dir_all(directory)?;
+    if let Some((uid, gid)) = ownership {
+        for created in missing.iter().rev() {
+            chown(created, uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Determines which repository XBPS should install from.
+///
+/// `Some(None)` means the default repositories, and `None` means the package can not be
+/// installed directly from where it lives.
+fn install_repository(repository: &PackageRepository) -> Option<Option<String>> {
+    match &repository.location {
+        Source::Remote(RemoteSource::VoidRepo) => Some(None),
+        Source::Remote(RemoteSource::VoidRemote(url)) => Some(Some(url.to_string())),
+        Source::Local(LocalSource::Directory(path)) => Some(Some(path.to_string_lossy().to_string())),
+        _ => None,
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+    use std::rc::Rc;
+    use crate::command::fake::FakeCommandRunner;
+    use crate::package_manager::{FakePackageManager, XbpsPackageManager};
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    #[test]
+    fn test_create_and_append() {
+        let root = scratch_dir("executor-create");
+        let mut executor = Executor::new(root.clone(), Box::new(FakePackageManager::default()));
+        executor.apply(&[
+            Action::File(FileSystemAction::CreateFile {
+                file_location: PathBuf::from("/etc/motd"),
+                contents: Some(String::from("Hello\n")),
+                is_dir: false,
+                mode: None,
+                owner: None,
+                group: None,
+            }),
+            Action::File(FileSystemAction::AddToFile {
+                original_file: PathBuf::from("/etc/motd"),
+                content_to_add: String::from("World\n"),
+            }),
+            Action::File(FileSystemAction::CreateFile {
+                file_location: PathBuf::from("/var/empty"),
+                contents: None,
+                is_dir: true,
+                mode: None,
+                owner: None,
+                group: None,
+            }),
+            Action::File(FileSystemAction::CreateFile {
+                file_location: PathBuf::from("/etc/sv/www/run"),
+                contents: Some(String::from("#!/bin/sh\nexec darkhttpd /srv/www\n")),
+                is_dir: false,
+                mode: Some(0o755),
+                owner: None,
+                group: None,
+            }),
+        ]).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("etc/motd")).unwrap(), "Hello\nWorld\n");
+        assert!(root.join("var/empty").is_dir());
+        assert_eq!(fs::metadata(root.join("etc/sv/www/run")).unwrap().permissions().mode() & 0o7777, 0o755);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_copy_move_remove() {
+        let root = scratch_dir("executor-copy");
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::write(root.join("src/nested/file"), "data").unwrap();
+
+        let mut executor = Executor::new(root.clone(), Box::new(FakePackageManager::default()));
+        executor.apply(&[
+            Action::File(FileSystemAction::CopyFile {
+                original_location: PathBuf::from("/src"),
+                final_location: PathBuf::from("/copy"),
+                is_recursive: true,
+                mode: None,
+                owner: None,
+            }),
+            Action::File(FileSystemAction::MoveFile {
+                original_location: PathBuf::from("/copy/nested/file"),
+                final_location: PathBuf::from("/moved"),
+                is_dir: false,
+            }),
+            Action::File(FileSystemAction::RemoveFile {
+                file_location: PathBuf::from("/src"),
+                is_dir: true,
+            }),
+        ]).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("moved")).unwrap(), "data");
+        assert!(root.join("copy/nested").is_dir());
+        assert!(!root.join("src").exists());
+
+        // Copies for a user belong to them, along with the directories made for them.
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/passwd"), "sapeint:x:1000:1000::/home/sapeint:/bin/bash\n").unwrap();
+        fs::write(root.join("moved"), b"\x89PNG\xff").unwrap();
+        executor.apply(&[Action::File(FileSystemAction::CopyFile {
+            original_location: PathBuf::from("/moved"),
+            final_location: PathBuf::from("/home/sapeint/.face"),
+            is_recursive: false,
+            mode: Some(0o600),
+            owner: Some(String::from("sapeint")),
+        })]).unwrap();
+        assert_eq!(fs::read(root.join("home/sapeint/.face")).unwrap(), b"\x89PNG\xff");
+        let face = fs::metadata(root.join("home/sapeint/.face")).unwrap();
+        assert_eq!((face.uid(), face.gid(), face.permissions().mode() & 0o777), (1000, 1000, 0o600));
+        assert_eq!(fs::metadata(root.join("home/sapeint")).unwrap().uid(), 1000);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_rollback() {
+        let root = scratch_dir("executor-rollback");
+        fs::create_dir_all(root.join("etc/sv/www/log")).unwrap();
+        fs::write(root.join("etc/motd"), "Hello\n").unwrap();
+        fs::set_permissions(root.join("etc/motd"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::write(root.join("etc/sv/www/log/run"), "#!/bin/sh\nexec vlogger -t www\n").unwrap();
+        fs::create_dir_all(root.join("home/sapeint")).unwrap();
+        fs::write(root.join("home/sapeint/.vimrc"), "set number\n").unwrap();
+        symlink("/dotfiles/.bashrc", root.join("home/sapeint/.bashrc")).unwrap();
+
+        let mut executor = Executor::new(root.clone(), Box::new(FakePackageManager::default()));
+        let replace = |location: &str, contents: &str| Action::File(FileSystemAction::CreateFile {
+            file_location: PathBuf::from(location),
+            contents: Some(String::from(contents)),
+            is_dir: false,
+            mode: None,
+            owner: None,
+            group: None,
+        });
+        let failed = executor.apply(&[
+            replace("/etc/motd", "Goodbye\n"),
+            replace("/etc/issue", "Void\n"),
+            Action::System(SystemAction::DeployDotfiles {
+                username: String::from("sapeint"),
+                dotfile_actions: vec![
+                    Action::File(FileSystemAction::RemoveFile { file_location: PathBuf::from("/home/sapeint/.bashrc"), is_dir: false }),
+                    replace("/home/sapeint/.bashrc", "alias ll='ls -l'\n"),
+                    Action::File(FileSystemAction::Symlink {
+                        target: PathBuf::from("/dotfiles/.vimrc"),
+                        link_name: PathBuf::from("/home/sapeint/.vimrc"),
+                        force: true,
+                    }),
+                ],
+            }),
+            Action::File(FileSystemAction::RemoveFile { file_location: PathBuf::from("/etc/sv/www/log"), is_dir: true }),
+            Action::File(FileSystemAction::RemoveFile { file_location: PathBuf::from("/etc/missing"), is_dir: false }),
+        ]);
+        assert!(matches!(failed, Err(ExecutorError::Io(err)) if err.kind() == io::ErrorKind::NotFound));
+
+        // Everything changed before the failure is back as it was.
+        assert_eq!(fs::read_to_string(root.join("etc/motd")).unwrap(), "Hello\n");
+        assert_eq!(fs::metadata(root.join("etc/motd")).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!root.join("etc/issue").exists());
+        assert_eq!(fs::read_link(root.join("home/sapeint/.bashrc")).unwrap(), PathBuf::from("/dotfiles/.bashrc"));
+        assert!(!fs::symlink_metadata(root.join("home/sapeint/.vimrc")).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(root.join("home/sapeint/.vimrc")).unwrap(), "set number\n");
+        assert_eq!(fs::read_to_string(root.join("etc/sv/www/log/run")).unwrap(), "#!/bin/sh\nexec vlogger -t www\n");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_unsupported_actions_stop_the_plan_before_it_starts() {
+        let root = scratch_dir("executor-unsupported");
+        let runner = Rc::new(FakeCommandRunner::default());
+        let mut executor = Executor::new(root.clone(), Box::new(XbpsPackageManager::new(runner.clone())));
+        let personal = PackageRepository {
+            name: Some(Rc::from("personal")),
+            location: Source::Remote(RemoteSource::GithubRemote {
+                user: Rc::from("sapein"),
+                repository_name: Rc::from("void-packages"),
+                branch_name: None,
+            }),
+            allow_restricted: false,
+        };
+
+        let unsupported = executor.apply(&[
+            Action::System(SystemAction::AddPackage {
+                package_name: String::from("nano"),
+                version: None,
+                package_repository: PackageRepository { name: None, location: Source::Remote(RemoteSource::VoidRepo), allow_restricted: false },
+            }),
+            Action::System(SystemAction::ConfigurePackage {
+                package_name: String::from("st"),
+                configuration_actions: vec![Action::File(FileSystemAction::CreateFile {
+                    file_location: PathBuf::from("/etc/st.conf"),
+                    contents: Some(String::from("font=mono\n")),
+                    is_dir: false,
+                    mode: None,
+                    owner: None,
+                    group: None,
+                })],
+            }),
+            Action::System(SystemAction::BuildPackage { package_name: String::from("st"), version: None, package_repository: personal }),
+        ]);
+        assert!(matches!(unsupported, Err(ExecutorError::Unsupported(action)) if matches!(*action, Action::System(SystemAction::BuildPackage { .. }))));
+        assert!(runner.calls().is_empty());
+        assert!(!root.join("etc/st.conf").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_symlink() {
+        let root = scratch_dir("executor-symlink");
+        fs::create_dir_all(root.join("home/sapeint")).unwrap();
+        fs::write(root.join("home/sapeint/.vimrc"), "set number\n").unwrap();
+
+        let link = |link_name: &str, force: bool| Action::File(FileSystemAction::Symlink {
+            target: PathBuf::from("/srv/dotfiles/.vimrc"),
+            link_name: PathBuf::from(link_name),
+            force,
+        });
+        let mut executor = Executor::new(root.clone(), Box::new(FakePackageManager::default()));
+        let exists = executor.apply(&[link("/home/sapeint/.vimrc", false)]);
+        assert!(matches!(exists, Err(ExecutorError::Io(err)) if err.kind() == io::ErrorKind::AlreadyExists));
+        assert_eq!(fs::read_to_string(root.join("home/sapeint/.vimrc")).unwrap(), "set number\n");
+
+        executor.apply(&[link("/home/sapeint/.vimrc", true), link("/home/sapeint/.config/nvim/init.vim", false)]).unwrap();
+        assert_eq!(fs::read_link(root.join("home/sapeint/.vimrc")).unwrap(), PathBuf::from("/srv/dotfiles/.vimrc"));
+        assert_eq!(fs::read_link(root.join("home/sapeint/.config/nvim/init.vim")).unwrap(), PathBuf::from("/srv/dotfiles/.vimrc"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_services() {
+        let root = scratch_dir("executor-services");
+        fs::create_dir_all(root.join("etc/sv/sshd")).unwrap();
+        fs::create_dir_all(root.join("etc/sv/ntpd")).unwrap();
+        fs::create_dir_all(root.join("etc/runit/runsvdir/default")).unwrap();
+        std::os::unix::fs::symlink("/etc/sv/ntpd", root.join("etc/runit/runsvdir/default/ntpd")).unwrap();
+
+        let mut executor = Executor::new(root.clone(), Box::new(FakePackageManager::default()));
+        executor.apply(&[
+            Action::System(SystemAction::DownService { service_name: String::from("sshd"), down: true, user: None }),
+            Action::System(SystemAction::EnableService { service_name: String::from("sshd"), user: None }),
+            Action::System(SystemAction::DisableService { service_name: String::from("ntpd"), user: None }),
+        ]).unwrap();
+
+        assert_eq!(fs::read_link(root.join("etc/runit/runsvdir/default/sshd")).unwrap(), PathBuf::from("/etc/sv/sshd"));
+        assert!(root.join("etc/sv/sshd/down").exists());
+        assert!(fs::symlink_metadata(root.join("etc/runit/runsvdir/default/ntpd")).is_err());
+
+        let missing = executor.apply(&[Action::System(SystemAction::EnableService { service_name: String::from("nginx"), user: None })]);
+        assert!(matches!(missing, Err(ExecutorError::Service(ServiceError::MissingService(_)))));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_user_services() {
+        let root = scratch_dir("executor-user-services");
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/passwd"), "sapeint:x:1000:1000::/home/sapeint:/bin/bash\n").unwrap();
+        fs::write(root.join("etc/group"), "sapeint:x:1000:\naudio:x:12:sapeint\n").unwrap();
+        fs::create_dir_all(root.join("home/sapeint")).unwrap();
+
+        let user = Some(ServiceUser { username: String::from("sapeint"), home: PathBuf::from("/home/sapeint") });
+        let mut executor = Executor::new(root.clone(), Box::new(FakePackageManager::default()));
+        executor.apply(&[
+            Action::System(SystemAction::ConfigureService {
+                service_name: String::from("pipewire"),
+                configuration_actions: vec![Action::File(FileSystemAction::CreateFile {
+                    file_location: PathBuf::from("/home/sapeint/.local/sv/pipewire/run"),
+                    contents: Some(String::from("#!/bin/sh\nexec pipewire\n")),
+                    is_dir: false,
+                    mode: Some(0o755),
+                    owner: Some(String::from("sapeint")),
+                    group: Some(String::from("audio")),
+                })],
+                user: user.clone(),
+            }),
+            Action::System(SystemAction::EnableService { service_name: String::from("pipewire"), user }),
+        ]).unwrap();
+
+        let run = fs::metadata(root.join("home/sapeint/.local/sv/pipewire/run")).unwrap();
+        assert_eq!((run.uid(), run.gid()), (1000, 12));
+        let local = fs::metadata(root.join("home/sapeint/.local")).unwrap();
+        assert_eq!((local.uid(), local.gid()), (1000, 1000));
+        assert_eq!(
+            fs::read_link(root.join("home/sapeint/.local/share/service/pipewire")).unwrap(),
+            PathBuf::from("/home/sapeint/.local/sv/pipewire"),
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_packages() {
+        let package_manager = FakePackageManager::default()
+            .with_available("firefox-118.0.1_2", "current")
+            .with_available("nano-7.2_1", "current");
+        let mut executor = Executor::new(PathBuf::from("/nonexistent"), Box::new(package_manager));
+        let void = PackageRepository {
+            name: None,
+            location: Source::Remote(RemoteSource::VoidRepo),
+            allow_restricted: false,
+        };
+
+        executor.apply(&[
+            Action::System(SystemAction::AddPackage { package_name: String::from("firefox"), version: None, package_repository: void.clone() }),
+            Action::System(SystemAction::AddPackage { package_name: String::from("nano"), version: None, package_repository: void }),
+            Action::System(SystemAction::RemovePackage { package_name: String::from("nano") }),
+            Action::System(SystemAction::HoldPackage { package_name: String::from("firefox"), hold: true }),
+        ]).unwrap();
+
+        let installed = executor.package_manager().installed().unwrap();
+        assert_eq!(installed.iter().map(|p| p.pkgver.as_str()).collect::<Vec<&str>>(), vec!["firefox-118.0.1_2"]);
+        assert!(installed[0].held);
+
+        let from_source = Action::System(SystemAction::AddPackage {
+            package_name: String::from("st"),
+            version: None,
+            package_repository: PackageRepository {
+                name: Some(Rc::from("personal")),
+                location: Source::Remote(RemoteSource::GithubRemote {
+                    user: Rc::from("sapein"),
+                    repository_name: Rc::from("void-packages"),
+                    branch_name: None,
+                }),
+                allow_restricted: false,
+            },
+        });
+        assert!(matches!(executor.apply(&[from_source]), Err(ExecutorError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_repository_packages_sync_the_index() {
+        let runner = Rc::new(FakeCommandRunner::default());
+        let mut executor = Executor::new(PathBuf::from("/nonexistent"), Box::new(XbpsPackageManager::new(runner.clone())));
+        let void = PackageRepository {
+            name: None,
+            location: Source::Remote(RemoteSource::VoidRepo),
+            allow_restricted: false,
+        };
+
+        executor.apply(&[
+            Action::System(SystemAction::AddPackage { package_name: String::from("void-repo-nonfree"), version: None, package_repository: void.clone() }),
+            Action::System(SystemAction::AddPackage { package_name: String::from("steam"), version: None, package_repository: void }),
+        ]).unwrap();
+        assert_eq!(runner.calls(), vec![
+            "xbps-install -y void-repo-nonfree",
+            "xbps-install -S",
+            "xbps-install -y steam",
+        ]);
+    }
+
+    #[test]
+    fn test_install_privileges() {
+        let root = scratch_dir("executor-privileges");
+        let runner = Rc::new(FakeCommandRunner::default());
+        let mut executor = Executor::new(root.clone(), Box::new(FakePackageManager::default())).with_runner(runner.clone());
+        let sudoers = root.join("etc/sudoers.d/svsm");
+        let install = |contents: Option<&str>| Action::System(SystemAction::InstallPrivileges { tool: PrivilegeTool::Sudo, contents: contents.map(String::from) });
+
+        executor.apply(&[install(Some("sapeint ALL=(ALL:ALL) ALL\n"))]).unwrap();
+        assert_eq!(fs::read_to_string(&sudoers).unwrap(), "sapeint ALL=(ALL:ALL) ALL\n");
+        assert_eq!(fs::metadata(&sudoers).unwrap().mode() & 0o7777, 0o440);
+        assert_eq!(runner.calls(), vec![format!("visudo -c -q -f {}.new", sudoers.display())]);
+
+        // Rules the tool rejects never replace the ones in place.
+        runner.respond(1, "");
+        assert!(matches!(executor.apply(&[install(Some("sapeint ALL=\n"))]), Err(ExecutorError::InvalidPrivileges(_))));
+        assert_eq!(fs::read_to_string(&sudoers).unwrap(), "sapeint ALL=(ALL:ALL) ALL\n");
+        assert!(!root.join("etc/sudoers.d/svsm.new").exists());
+
+        executor.apply(&[install(None)]).unwrap();
+        assert!(!sudoers.exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_build_package() {
+        let checkout = scratch_dir("executor-build");
+        fs::write(checkout.join("xbps-src"), "#!/bin/sh\n").unwrap();
+        let xbps_src = checkout.join("xbps-src").to_string_lossy().to_string();
+        let personal = PackageRepository {
+            name: Some(Rc::from("personal")),
+            location: Source::Local(LocalSource::Directory(checkout.clone())),
+            allow_restricted: true,
+        };
+
+        let runner = Rc::new(FakeCommandRunner::default());
+        let mut executor = Executor::new(PathBuf::from("/nonexistent"), Box::new(XbpsPackageManager::new(runner.clone())))
+            .with_runner(runner.clone());
+        executor.apply(&[
+            Action::System(SystemAction::BuildPackage { package_name: String::from("discord"), version: None, package_repository: personal.clone() }),
+            Action::System(SystemAction::BuildPackage { package_name: String::from("st"), version: Some(String::from("0.9_1")), package_repository: personal }),
+        ]).unwrap();
+
+        let binpkgs = checkout.join("hostdir/binpkgs").to_string_lossy().to_string();
+        assert_eq!(runner.calls(), vec![
+            format!("{} binary-bootstrap", xbps_src),
+            format!("{} pkg discord", xbps_src),
+            format!("xbps-install -y -R {} discord", binpkgs),
+            format!("{} pkg st", xbps_src),
+            format!("xbps-install -y -R {} st-0.9_1", binpkgs),
+        ]);
+        assert_eq!(fs::read_to_string(checkout.join("etc/conf")).unwrap(), "XBPS_ALLOW_RESTRICTED=yes\n");
+
+        fs::remove_dir_all(checkout).unwrap();
+    }
+}
@@ -15,6 +15,7 @@ use crate::actions::Action;
 use crate::parser::{Callable, Expr, FnResultExpr};
 
 mod builtins;
+pub mod package_definitions;
 pub mod system_converter;
 
 pub struct Interpreter {
@@ -194,6 +195,21 @@ impl Interpreter {
         eval(self.get_input(), &mut self.env, self.disable_lazy)
     }
 
+    /// Evaluates every expression in the input in order, returning the result of the last one.
+    pub fn eval_all(&mut self) -> Option<Expr> {
+        let len = match &self.input {
+            InterpreterInput::VecAst(input) => input.len(),
+            InterpreterInput::ArrAst(input) => input.len(),
+        };
+
+        let mut result = None;
+        for pos in 0..len {
+            self.pos = pos;
+            result = self.eval();
+        }
+        result
+    }
+
     pub fn eval_input(&mut self, input: Expr) -> Option<Expr> {
         eval(input, &mut self.env, self.disable_lazy)
     }
@@ -4,6 +4,13 @@ use std::rc::Rc;
 use crate::interpreter::{Env, eval, Interpreter};
 use crate::parser::{Callable, Expr, NumberExpr};
 
+/// Attributes whose values `print` never shows.
+const SECRET_ATTRS: [&str; 1] = ["hashedPassword"];
+
+fn is_secret(attr: &Expr) -> bool {
+    matches!(attr, Expr::Symbol(name) if SECRET_ATTRS.contains(&name.as_ref()))
+}
+
 pub(crate) fn print(args: Vec<Expr>, _env: &mut Env) -> Option<Expr> {
     // ToDo: Maybe make print a macro, since we could (theoretically) get a macro and to resolve it
     // we must *be* a macro.
@@ -17,13 +24,28 @@ pub(crate) fn print(args: Vec<Expr>, _env: &mut Env) -> Option<Expr> {
             Expr::Boolean(bool) => print!("{}", bool),
             Expr::Number(NumberExpr { num: n})  => print!("{}", n),
             Expr::Path(path) => print!("{:?}", path.as_os_str()),
-            Expr::List(list) => print!("{:?}", list),
+            Expr::List(list) => {
+                print!("[ ");
+                for item in list {
+                    match item {
+                        // Symbols in lists are names, such as those of services, not variables.
+                        Expr::Symbol(name) => print!("{}", name),
+                        item => resolve_expr(item, env),
+                    }
+                    print!(", ");
+                }
+                print!("]");
+            },
             Expr::Map(map) => {
                 print!("{{ ");
                 for (key, attr) in map.clone() {
+                    let secret = is_secret(&key);
                     resolve_expr(key, env);
                     print!(" = ");
-                    resolve_expr(attr, env);
+                    match secret {
+                        true => print!("<redacted>"),
+                        false => resolve_expr(attr, env),
+                    }
                     print!("; ");
                 }
                 print!("}}\n");
@@ -47,6 +69,7 @@ pub(crate) fn print(args: Vec<Expr>, _env: &mut Env) -> Option<Expr> {
                 match env.find_variable_with_expr(&sym) {
                     Expr::Map(map) => match map.get_key_value(&attr) {
                         None => panic!("Attr {} not found in map {}!", attr.to_string(), sym.to_string()),
+                        Some((key, _)) if is_secret(key) => print!("<redacted>"),
                         Some((_, &ref val)) => resolve_expr(val.to_owned(), env),
                     }
                     _ => panic!("Attr not valid for non-map!")
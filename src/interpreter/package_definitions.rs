@@ -0,0 +1,421 @@
+//! Loading package definition (`.pvsm`) files.
+//!
+//! A package file defines one or more package symbols as maps, e.g.
+//!
+//! ```text
+//! discord = { name = 'Discord'; is_restricted = true; };
+//! ```
+//!
+//! See docs/Packages.md for what each key means. Definitions are read from a list of
+//! directories, with later directories overriding definitions from earlier ones, and are
+//! registered in the environment before the configuration is evaluated.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::interpreter::Env;
+use crate::interpreter::system_converter::{on_change, unquote};
+use crate::lex::Lexer;
+use crate::parser::{Expr, ExprFnCall, Parser};
+use crate::system::OnChange;
+
+/// Where package files are read from when no other directories are given.
+pub const PACKAGE_DEFINITION_LOCATIONS: &[&str] = &["/usr/share/svsm/packages", "/etc/svsm/packages"];
+
+const PACKAGE_FILE_EXTENSION: &str = "pvsm";
+const KEYS: &[&str] = &["name", "configuration", "is_nonfree", "is_restricted", "services", "on_change"];
+
+#[derive(Debug)]
+pub enum PackageFileError {
+    Io(io::Error),
+    Invalid {
+        file: PathBuf,
+        reason: String,
+    },
+}
+
+impl Display for PackageFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackageFileError::Io(err) => write!(f, "{}", err),
+            PackageFileError::Invalid { file, reason } => write!(f, "{}: {}", file.display(), reason),
+        }
+    }
+}
+
+impl std::error::Error for PackageFileError {}
+
+impl From<io::Error> for PackageFileError {
+    fn from(err: io::Error) -> Self {
+        PackageFileError::Io(err)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PackageDefinition {
+    /// The symbol the package is referred to by in configurations.
+    pub symbol: Rc<str>,
+    /// The name XBPS knows the package by.
+    pub name: Rc<str>,
+    pub configuration: Option<Expr>,
+    /// The configuration slots declared by `configuration`, by name.
+    pub slots: BTreeMap<Rc<str>, ConfigurationSlot>,
+    pub is_nonfree: bool,
+    pub is_restricted: bool,
+    /// Services that read the package's configuration files, and must pick up changes to them.
+    pub services: Vec<Rc<str>>,
+    /// How those services pick up changes, when the definition says.
+    pub on_change: Option<OnChange>,
+    /// The file the definition was read from.
+    pub file: PathBuf,
+}
+
+impl PackageDefinition {
+    fn from_map(symbol: &Rc<str>, map: &Expr, file: &Path) -> Result<PackageDefinition, PackageFileError> {
+        let invalid = |reason: String| PackageFileError::Invalid { file: file.to_path_buf(), reason };
+        let entries = match map {
+            Expr::Map(entries) => entries,
+            _ => return Err(invalid(format!("{} must be defined as a map", symbol))),
+        };
+
+        for key in entries.keys() {
+            match key {
+                Expr::Symbol(key) if KEYS.contains(&key.as_ref()) => (),
+                Expr::Symbol(key) => return Err(invalid(format!("{} has unknown key {}, expected one of {}", symbol, key, KEYS.join(", ")))),
+                key => return Err(invalid(format!("{} has invalid key {:?}", symbol, key))),
+            }
+        }
+
+        let boolean = |key: &str| match map.get_map_value(Expr::symbol_from_str(key)) {
+            Some(Expr::Boolean(value)) => Ok(*value),
+            None => Ok(false),
+            Some(_) => Err(invalid(format!("{}.{} must be a boolean", symbol, key))),
+        };
+
+        let configuration = match map.get_map_value(Expr::symbol_from_str("configuration")) {
+            Some(configuration @ Expr::Map(_)) => Some(configuration.clone()),
+            None => None,
+            Some(_) => return Err(invalid(format!("{}.configuration must be a map", symbol))),
+        };
+        let slots = match &configuration {
+            Some(configuration) => ConfigurationSlot::from_configuration(symbol, configuration, file).map_err(invalid)?,
+            None => BTreeMap::new(),
+        };
+
+        let services = match map.get_map_value(Expr::symbol_from_str("services")) {
+            Some(Expr::List(services)) => services
+                .iter()
+                .map(|service| match service {
+                    Expr::Symbol(service) => Ok(service.clone()),
+                    Expr::String(service) => Ok(unquote(service)),
+                    _ => Err(invalid(format!("{}.services must be a list of service names", symbol))),
+                })
+                .collect::<Result<Vec<Rc<str>>, PackageFileError>>()?,
+            None => vec![],
+            Some(_) => return Err(invalid(format!("{}.services must be a list of service names", symbol))),
+        };
+        let on_change = match map.get_map_value(Expr::symbol_from_str("on_change")) {
+            Some(value) => Some(on_change(value).ok_or_else(|| invalid(format!("{}.on_change must be restart or reload", symbol)))?),
+            None => None,
+        };
+
+        Ok(PackageDefinition {
+            symbol: symbol.clone(),
+            name: match map.get_map_value(Expr::symbol_from_str("name")) {
+                Some(Expr::String(name)) => unquote(name),
+                None => symbol.clone(),
+                Some(_) => return Err(invalid(format!("{}.name must be a string", symbol))),
+            },
+            configuration,
+            slots,
+            is_nonfree: boolean("is_nonfree")?,
+            is_restricted: boolean("is_restricted")?,
+            services,
+            on_change,
+            file: file.to_path_buf(),
+        })
+    }
+
+    /// The definition as a VSL map, with every key filled in.
+    pub fn to_map(&self) -> Expr {
+        let mut map = BTreeMap::from([
+            (Expr::symbol_from_str("name"), Expr::String(self.name.clone())),
+            (Expr::symbol_from_str("is_nonfree"), Expr::Boolean(self.is_nonfree)),
+            (Expr::symbol_from_str("is_restricted"), Expr::Boolean(self.is_restricted)),
+        ]);
+        if let Some(configuration) = &self.configuration {
+            map.insert(Expr::symbol_from_str("configuration"), configuration.clone());
+        }
+        if !self.services.is_empty() {
+            map.insert(Expr::symbol_from_str("services"), Expr::List(self.services.iter().cloned().map(Expr::Symbol).collect()));
+        }
+        if let Some(on_change) = self.on_change {
+            let value = match on_change {
+                OnChange::Restart => "restart",
+                OnChange::Reload => "reload",
+            };
+            map.insert(Expr::symbol_from_str("on_change"), Expr::symbol_from_str(value));
+        }
+        Expr::Map(map)
+    }
+}
+
+/// A configuration file a package expects, such as bash's `bashrc`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConfigurationSlot {
+    pub location: ConfigurationLocation,
+    /// A file copied to `location` when nothing is there yet.
+    pub template_location: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ConfigurationLocation {
+    /// Relative to the home directory of the user the package is configured for.
+    Home(PathBuf),
+    Path(PathBuf),
+}
+
+impl ConfigurationSlot {
+    /// The slot used when `configuration` declares a single file rather than named slots.
+    pub const DEFAULT_SLOT: &'static str = "config";
+
+    /// Reads the slots from a definition's `configuration` map.
+    ///
+    /// A map with a `location` declares the single slot [`Self::DEFAULT_SLOT`], otherwise every
+    /// key names a slot with its own `location` and `template_location`.
+    fn from_configuration(symbol: &str, configuration: &Expr, file: &Path) -> Result<BTreeMap<Rc<str>, ConfigurationSlot>, String> {
+        if configuration.get_map_value(Expr::symbol_from_str("location")).is_some() {
+            let slot = ConfigurationSlot::from_map(&format!("{}.configuration", symbol), configuration, file)?;
+            return Ok(BTreeMap::from([(Rc::from(Self::DEFAULT_SLOT), slot)]));
+        }
+
+        let Expr::Map(entries) = configuration else { unreachable!("configuration is checked to be a map") };
+        entries
+            .iter()
+            .map(|(key, value)| match key {
+                Expr::Symbol(name) => Ok((name.clone(), ConfigurationSlot::from_map(&format!("{}.configuration.{}", symbol, name), value, file)?)),
+                key => Err(format!("{}.configuration has invalid key {:?}", symbol, key)),
+            })
+            .collect()
+    }
+
+    fn from_map(path: &str, map: &Expr, file: &Path) -> Result<ConfigurationSlot, String> {
+        if !matches!(map, Expr::Map(_)) {
+            return Err(format!("{} must be a map with a location", path));
+        }
+
+        let location = match map.get_map_value(Expr::symbol_from_str("location")) {
+            Some(Expr::FnCall(ExprFnCall { name, args })) if name.as_ref() == "home" => match args.as_slice() {
+                [Expr::Path(relative)] if relative.is_relative() => ConfigurationLocation::Home(relative.strip_prefix(".").unwrap_or(relative).to_path_buf()),
+                _ => return Err(format!("{}.location must be given a relative path in home", path)),
+            },
+            Some(Expr::Path(location)) if location.is_absolute() => ConfigurationLocation::Path(location.clone()),
+            None => return Err(format!("{} must have a location", path)),
+            Some(_) => return Err(format!("{}.location must be an absolute path, or a path in home", path)),
+        };
+
+        // Templates ship alongside the package file, so relative paths are relative to it.
+        let template_location = match map.get_map_value(Expr::symbol_from_str("template_location")) {
+            Some(Expr::Path(template)) => Some(file.parent().unwrap_or(Path::new("")).join(template)),
+            None => None,
+            Some(_) => return Err(format!("{}.template_location must be a path", path)),
+        };
+
+        Ok(ConfigurationSlot { location, template_location })
+    }
+}
+
+impl ConfigurationLocation {
+    /// Where the file goes on the managed system, given the home directory of the user whose
+    /// package it configures, if any.
+    pub fn resolve(&self, home: Option<&Path>) -> Option<PathBuf> {
+        match self {
+            ConfigurationLocation::Home(relative) => home.map(|home| home.join(relative)),
+            ConfigurationLocation::Path(location) => Some(location.clone()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PackageDefinitions {
+    definitions: BTreeMap<Rc<str>, PackageDefinition>,
+}
+
+impl PackageDefinitions {
+    /// Reads every `*.pvsm` file in `directories`, in order.
+    ///
+    /// Directories that don't exist are skipped.
+    pub fn load(directories: &[PathBuf]) -> Result<PackageDefinitions, PackageFileError> {
+        let mut definitions = PackageDefinitions::default();
+        for directory in directories {
+            let mut files = match fs::read_dir(directory) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                entries => entries?.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<Vec<PathBuf>>>()?,
+            };
+            files.sort();
+
+            for file in files.into_iter().filter(|file| file.extension() == Some(OsStr::new(PACKAGE_FILE_EXTENSION))) {
+                definitions.load_file(&file)?;
+            }
+        }
+        Ok(definitions)
+    }
+
+    pub fn load_file(&mut self, file: &Path) -> Result<(), PackageFileError> {
+        let input = fs::read_to_string(file)?;
+        let mut lexer = Lexer::from_string(&input);
+        let mut parser = Parser::from_token_list_smart(lexer.tokenize_input_smart());
+
+        for expr in parser.parse_input().iter() {
+            let (symbol, map) = match expr {
+                Expr::VarDecl(symbol, map) => match symbol.as_ref() {
+                    Expr::Symbol(symbol) => (symbol, map),
+                    _ => return Err(PackageFileError::Invalid { file: file.to_path_buf(), reason: String::from("Only packages may be defined in a package file") }),
+                },
+                _ => return Err(PackageFileError::Invalid { file: file.to_path_buf(), reason: String::from("Package files may only contain package definitions") }),
+            };
+            let definition = PackageDefinition::from_map(symbol, map, file)?;
+            self.definitions.insert(symbol.clone(), definition);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&PackageDefinition> {
+        self.definitions.get(symbol)
+    }
+
+    /// The definition of the package XBPS knows as `name`.
+    pub fn named(&self, name: &str) -> Option<&PackageDefinition> {
+        self.definitions.values().find(|definition| definition.name.as_ref() == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PackageDefinition> {
+        self.definitions.values()
+    }
+
+    /// Makes every definition available to configurations as a variable named after its symbol.
+    pub fn register(&self, env: &mut Env) {
+        for definition in self.iter() {
+            env.add_variable(Expr::Symbol(definition.symbol.clone()), definition.to_map());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let root = scratch_dir("package-definitions");
+        let builtin = root.join("builtin");
+        let local = root.join("local");
+        fs::create_dir_all(&builtin).unwrap();
+        fs::create_dir_all(&local).unwrap();
+
+        fs::write(builtin.join("bash.pvsm"), "bash = {
+            configuration = {
+                bashrc = { location = (home ./.bashrc); };
+                bash_profile = { location = (home ./.bash_profile); };
+            };
+            name = 'bash';
+        };").unwrap();
+        fs::write(builtin.join("discord.pvsm"), "discord = { name = 'Discord'; };").unwrap();
+        fs::write(builtin.join("dunst.pvsm"), "dunst = { configuration = { location = /etc/xdg/dunst/dunstrc; template_location = ./dunstrc; }; };").unwrap();
+        fs::write(builtin.join("nginx.pvsm"), "nginx = { configuration = { location = /etc/nginx/nginx.conf; }; services = [ nginx, ]; on_change = reload; };").unwrap();
+        fs::write(builtin.join("README"), "not a package file").unwrap();
+        fs::write(local.join("discord.pvsm"), "discord = { name = 'Discord'; is_restricted = true; };\n\
+                                               steam = { is_nonfree = true; };").unwrap();
+
+        let definitions = PackageDefinitions::load(&[builtin.clone(), local.clone(), root.join("missing")]).unwrap();
+        assert_eq!(definitions.iter().map(|definition| definition.symbol.as_ref()).collect::<Vec<&str>>(), vec!["bash", "discord", "dunst", "nginx", "steam"]);
+
+        let bash = definitions.get("bash").unwrap();
+        assert_eq!(bash.name.as_ref(), "bash");
+        assert!(bash.configuration.as_ref().unwrap().get_map_value(Expr::symbol_from_str("bashrc")).is_some());
+        assert_eq!(bash.slots[&Rc::from("bashrc")], ConfigurationSlot {
+            location: ConfigurationLocation::Home(PathBuf::from(".bashrc")),
+            template_location: None,
+        });
+        assert_eq!(bash.slots.keys().map(|slot| slot.as_ref()).collect::<Vec<&str>>(), vec!["bash_profile", "bashrc"]);
+
+        // A single location declares the default slot, with its template next to the package file.
+        assert_eq!(definitions.get("dunst").unwrap().slots, BTreeMap::from([(Rc::from(ConfigurationSlot::DEFAULT_SLOT), ConfigurationSlot {
+            location: ConfigurationLocation::Path(PathBuf::from("/etc/xdg/dunst/dunstrc")),
+            template_location: Some(builtin.join("./dunstrc")),
+        })]));
+
+        assert_eq!(definitions.get("discord"), Some(&PackageDefinition {
+            symbol: Rc::from("discord"),
+            name: Rc::from("Discord"),
+            configuration: None,
+            slots: BTreeMap::new(),
+            is_nonfree: false,
+            is_restricted: true,
+            services: vec![],
+            on_change: None,
+            file: local.join("discord.pvsm"),
+        }));
+        assert_eq!(definitions.named("Discord").map(|definition| definition.symbol.as_ref()), Some("discord"));
+        assert!(definitions.named("discord").is_none());
+        let nginx = definitions.get("nginx").unwrap();
+        assert_eq!((nginx.services.clone(), nginx.on_change), (vec![Rc::from("nginx")], Some(OnChange::Reload)));
+        let round_trip = PackageDefinition::from_map(&nginx.symbol, &nginx.to_map(), &nginx.file).unwrap();
+        assert_eq!(&round_trip, nginx);
+        assert_eq!(definitions.get("steam").unwrap().name.as_ref(), "steam");
+        assert!(definitions.get("steam").unwrap().is_nonfree);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_definitions() {
+        let root = scratch_dir("package-definitions-invalid");
+        let file = root.join("bad.pvsm");
+
+        for (input, reason) in [
+            ("discord = { is_proprietary = true; };", "discord has unknown key is_proprietary, expected one of name, configuration, is_nonfree, is_restricted, services, on_change"),
+            ("nginx = { services = nginx; };", "nginx.services must be a list of service names"),
+            ("nginx = { on_change = 'reboot'; };", "nginx.on_change must be restart or reload"),
+            ("discord = { is_restricted = 'yes'; };", "discord.is_restricted must be a boolean"),
+            ("discord = { name = true; };", "discord.name must be a string"),
+            ("discord = [ 'Discord', ];", "discord must be defined as a map"),
+            ("bash = { configuration = { bashrc = { location = ./.bashrc; }; }; };", "bash.configuration.bashrc.location must be an absolute path, or a path in home"),
+            ("bash = { configuration = { bashrc = { template_location = ./bashrc; }; }; };", "bash.configuration.bashrc must have a location"),
+            ("{ name = 'Discord'; }", "Package files may only contain package definitions"),
+        ] {
+            fs::write(&file, input).unwrap();
+            match PackageDefinitions::default().load_file(&file) {
+                Err(PackageFileError::Invalid { reason: found, .. }) => assert_eq!(found, reason),
+                other => panic!("Expected {:?} to be invalid, got {:?}", input, other),
+            }
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_register() {
+        let mut definitions = PackageDefinitions::default();
+        definitions.definitions.insert(Rc::from("discord"), PackageDefinition {
+            symbol: Rc::from("discord"),
+            name: Rc::from("Discord"),
+            configuration: None,
+            slots: BTreeMap::new(),
+            is_nonfree: false,
+            is_restricted: true,
+            services: vec![],
+            on_change: None,
+            file: PathBuf::from("discord.pvsm"),
+        });
+
+        let mut env = Env::new();
+        definitions.register(&mut env);
+        assert_eq!(env.find_variable(&Rc::from("discord")).get_map_value(Expr::symbol_from_str("is_restricted")), Some(&Expr::Boolean(true)));
+    }
+}
@@ -1,28 +1,137 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
-use crate::system::{System, Service, PackageRepository, Source, User, RemoteSource, HomeDirectory, Package};
-use crate::parser::Expr;
+use crate::accounts::is_hashed;
+use crate::interpreter::package_definitions::PackageDefinitions;
+use crate::system::{Account, DotfileDeployment, Dotfiles, Password, Privilege, PrivilegeTool, System, Service, ServiceDefinition, OnChange, PackageRepository, Source, User, RemoteSource, HomeDirectory, Package, LocalSource, ConfigurationFile, ConfigurationSource};
+use crate::parser::{Expr, ExprFnCall, NumberExpr};
+use crate::pkgver::PkgVer;
+use crate::service::user_runsvdir;
 use crate::system::RemoteSource::VoidRepo;
 
 impl System {
-    pub fn from_map(map: Expr) -> Self{
+    /// Converts `system.config`, checking packages' configuration against `definitions`.
+    pub fn from_map(map: Expr, definitions: &PackageDefinitions) -> Self{
+        let repositories = HashMap::from_iter(
+            map
+                .get_map_value(Expr::symbol_from_str("vp_repos"))
+                .into_iter()
+                .flat_map(PackageRepository::from_big_map)
+                .collect::<Vec<(Rc<str>, PackageRepository)>>()
+        );
+        let context = PackageContext { definitions, repositories: &repositories, home: None };
+        let mut services = HashMap::from_iter(
+            map.get_map_value(Expr::symbol_from_str("services"))
+                .into_iter()
+                .flat_map(Service::from_list)
+                .collect::<Vec<(Rc<str>, Service)>>()
+        );
+        let users = HashMap::from_iter(
+            map
+                .get_map_value(Expr::symbol_from_str("users"))
+                .into_iter()
+                .flat_map(|users| User::from_big_map(users, &context))
+                .collect::<Vec<(Rc<str>, User)>>()
+        );
+
+        // A user's services are supervised by a runsvdir of their own, run as a system service,
+        // unless the configuration already declares one.
+        for (username, user) in users.iter().filter(|(_, user)| !user.services.is_empty()) {
+            let HomeDirectory::Path { location, .. } = &user.homedir;
+            let name = Rc::from(format!("runsvdir-{}", username));
+            services.entry(Rc::clone(&name)).or_insert_with(|| Service {
+                name,
+                enabled: true,
+                downed: false,
+                definition: Some(user_runsvdir(username, location)),
+                files: vec![],
+                on_change: None,
+            });
+        }
+
         System {
-            services: HashMap::from_iter(
-                map.get_map_value(Expr::symbol_from_str("services"))
-                    .into_iter()
-                    .flat_map(Service::from_list)
-                    .collect::<Vec<(Rc<str>, Service)>>()
-            ),
-            repositories: HashMap::from_iter(
+            services,
+            users,
+            packages: HashMap::from_iter(
                 map
-                    .get_map_value(Expr::symbol_from_str("vp_repos"))
+                    .get_map_value(Expr::symbol_from_str("packages"))
                     .into_iter()
-                    .flat_map(PackageRepository::from_big_map)
-                    .collect::<Vec<(Rc<str>, PackageRepository)>>()
+                    .flat_map(|packages| Package::from_list(packages, &context))
+                    .collect::<Vec<(Rc<str>, Package)>>()
             ),
-            users: HashMap::new(),
-            system_packages: Rc::from(""),
+            repositories,
+            auto_enable_repositories: match map.get_map_value(Expr::symbol_from_str("auto_enable_repositories")) {
+                Some(Expr::Boolean(enable)) => *enable,
+                None => true,
+                _ => panic!("system.config.auto_enable_repositories must be a boolean!"),
+            },
+        }
+    }
+
+    /// Converts the system back into a VSL map, in the same shape `from_map` reads.
+    ///
+    /// This is how the live system is exposed to configurations as `system.current`.
+    pub fn to_map(&self) -> Expr {
+        let mut services = self.services.values().collect::<Vec<&Service>>();
+        services.sort_by_key(|service| service.name.clone());
+
+        Expr::Map(BTreeMap::from([
+            (Expr::symbol_from_str("services"),
+             Expr::List(services.into_iter().map(Service::to_map).collect())),
+            (Expr::symbol_from_str("vp_repos"),
+             Expr::Map(self.repositories.iter()
+                 .map(|(name, repository)| (Expr::Symbol(name.clone()), repository.to_map()))
+                 .collect())),
+            (Expr::symbol_from_str("users"),
+             Expr::Map(self.users.iter()
+                 .map(|(name, user)| (Expr::Symbol(name.clone()), user.to_map()))
+                 .collect())),
+            (Expr::symbol_from_str("packages"), Package::to_list(&self.packages)),
+            (Expr::symbol_from_str("auto_enable_repositories"), Expr::Boolean(self.auto_enable_repositories)),
+        ]))
+    }
+}
+
+impl Source {
+    fn to_expr(&self) -> Expr {
+        match self {
+            Source::Remote(RemoteSource::GithubRemote { user, repository_name, branch_name }) => Expr::GitHubRemote {
+                user: user.clone(),
+                repo: repository_name.clone(),
+                branch: branch_name.clone(),
+            },
+            Source::Remote(RemoteSource::GitRemote { url, .. }) => Expr::String(url.clone()),
+            Source::Remote(RemoteSource::VoidRemote(url)) => Expr::String(url.clone()),
+            Source::Remote(VoidRepo) => Expr::symbol_from_str("void"),
+            Source::Local(LocalSource::Directory(path)) => Expr::Path(path.clone()),
+            Source::Local(LocalSource::File(path)) => Expr::Path(path.clone()),
+        }
+    }
+
+    /// Reads where a file given to `use_file` comes from: a git repository or a directory.
+    fn from_expr(expr: &Expr) -> Option<Source> {
+        match expr {
+            Expr::GitHubRemote { user, repo, branch } => Some(Source::Remote(RemoteSource::GithubRemote {
+                user: unquote(user),
+                repository_name: unquote(repo),
+                branch_name: branch.as_ref().map(unquote),
+            })),
+            // Package lists aren't evaluated, so calls to `gh-r` are still calls here.
+            Expr::FnCall(ExprFnCall { name, args }) if ["gh-r", "github-repo"].contains(&name.as_ref()) => match args.as_slice() {
+                [Expr::String(user), Expr::String(repo), rest @ ..] => Some(Source::Remote(RemoteSource::GithubRemote {
+                    user: unquote(user),
+                    repository_name: unquote(repo),
+                    branch_name: match rest {
+                        [Expr::String(branch)] => Some(unquote(branch)),
+                        [] => None,
+                        _ => return None,
+                    },
+                })),
+                _ => None,
+            },
+            Expr::String(url) => Some(Source::Remote(RemoteSource::GitRemote { url: unquote(url), branch_name: None })),
+            Expr::Path(path) => Some(Source::Local(LocalSource::Directory(path.clone()))),
+            _ => None,
         }
     }
 }
@@ -38,11 +147,33 @@ impl Service {
     }
     pub fn from_map(map: &Expr) -> (Rc<str>, Service) {
         let name = match map.get_map_value(Expr::symbol_from_str("name")) {
-            Some(Expr::String(str)) => str.clone(),
+            Some(Expr::String(str)) => unquote(str),
+            Some(Expr::Symbol(name)) => name.clone(),
             _ => panic!("Name must be provided!"),
         };
+        // The name becomes a directory in /etc/sv, so it can't lead anywhere else.
+        if name.is_empty() || name.contains('/') || name.as_ref() == "." || name.as_ref() == ".." {
+            panic!("Service {}'s name must not contain '/' or be '.' or '..'!", name);
+        }
+
+        let files = match map.get_map_value(Expr::symbol_from_str("files")) {
+            Some(Expr::List(files)) => files
+                .iter()
+                .map(|file| match file {
+                    Expr::Path(file) if file.is_absolute() => file.clone(),
+                    _ => panic!("Service {}'s files must be absolute paths!", name),
+                })
+                .collect(),
+            None => vec![],
+            Some(_) => panic!("Service {}'s files must be a list!", name),
+        };
+        let on_change = map.get_map_value(Expr::symbol_from_str("on_change"))
+            .map(|value| on_change(value).unwrap_or_else(|| panic!("Service {}'s on_change must be restart or reload!", name)));
 
         (name.clone(), Service {
+            definition: ServiceDefinition::from_map(map, &name),
+            files,
+            on_change,
             name,
             enabled: match map.get_map_value(Expr::symbol_from_str("enabled")) {
                 Some(Expr::Boolean(bool)) => *bool,
@@ -56,7 +187,98 @@ impl Service {
     }
 }
 
+impl ServiceDefinition {
+    /// Reads the definition of a service whose files SVSM writes, if the map has a `run` command.
+    fn from_map(map: &Expr, name: &str) -> Option<ServiceDefinition> {
+        let string = |key: &str| match map.get_map_value(Expr::symbol_from_str(key)) {
+            Some(Expr::String(string)) => Some(unquote(string)),
+            Some(Expr::Symbol(symbol)) => Some(symbol.clone()),
+            None => None,
+            Some(_) => panic!("Service {}'s {} must be a string!", name, key),
+        };
+
+        let run = match string("run") {
+            Some(run) => run,
+            None => {
+                if let Some(key) = ["finish", "env", "user", "log"].iter().find(|key| map.get_map_value(Expr::symbol_from_str(key)).is_some()) {
+                    panic!("Service {} sets {} but has no run command!", name, key);
+                }
+                return None;
+            }
+        };
+
+        let environment = match map.get_map_value(Expr::symbol_from_str("env")) {
+            Some(Expr::Map(environment)) => environment
+                .iter()
+                .map(|(key, value)| match (key, value) {
+                    (Expr::Symbol(key), Expr::String(value)) => (key.clone(), unquote(value)),
+                    (Expr::Symbol(key), Expr::Symbol(value)) => (key.clone(), value.clone()),
+                    _ => panic!("Service {}'s env must map names to strings!", name),
+                })
+                .collect(),
+            None => BTreeMap::new(),
+            Some(_) => panic!("Service {}'s env must be a map!", name),
+        };
+
+        // `log = true` sends the output to syslog, tagged with the service's name.
+        let log = match map.get_map_value(Expr::symbol_from_str("log")) {
+            Some(Expr::Boolean(true)) => Some(Rc::from(format!("vlogger -t {}", name))),
+            Some(Expr::Boolean(false)) | None => None,
+            Some(_) => string("log"),
+        };
+
+        Some(ServiceDefinition { run, finish: string("finish"), environment, user: string("user"), log })
+    }
+}
+
+impl Service {
+    fn to_map(&self) -> Expr {
+        let mut map = BTreeMap::from([
+            (Expr::symbol_from_str("name"), Expr::String(self.name.clone())),
+            (Expr::symbol_from_str("enabled"), Expr::Boolean(self.enabled)),
+            (Expr::symbol_from_str("downed"), Expr::Boolean(self.downed)),
+        ]);
+        if !self.files.is_empty() {
+            map.insert(Expr::symbol_from_str("files"), Expr::List(self.files.iter().cloned().map(Expr::Path).collect()));
+        }
+        if let Some(on_change) = self.on_change {
+            let on_change = match on_change {
+                OnChange::Restart => "restart",
+                OnChange::Reload => "reload",
+            };
+            map.insert(Expr::symbol_from_str("on_change"), Expr::symbol_from_str(on_change));
+        }
+        if let Some(definition) = &self.definition {
+            map.insert(Expr::symbol_from_str("run"), Expr::String(definition.run.clone()));
+            let optional = [("finish", &definition.finish), ("user", &definition.user), ("log", &definition.log)];
+            for (key, value) in optional {
+                if let Some(value) = value {
+                    map.insert(Expr::symbol_from_str(key), Expr::String(value.clone()));
+                }
+            }
+            if !definition.environment.is_empty() {
+                map.insert(Expr::symbol_from_str("env"), Expr::Map(definition.environment
+                    .iter()
+                    .map(|(key, value)| (Expr::Symbol(key.clone()), Expr::String(value.clone())))
+                    .collect()));
+            }
+        }
+        Expr::Map(map)
+    }
+}
+
 impl PackageRepository {
+    fn to_map(&self) -> Expr {
+        let mut map = BTreeMap::from([
+            (Expr::symbol_from_str("location"), self.location.to_expr()),
+            (Expr::symbol_from_str("allow_restricted"), Expr::Boolean(self.allow_restricted)),
+        ]);
+        if let Source::Remote(RemoteSource::GithubRemote { branch_name: Some(branch), .. }) = &self.location {
+            map.insert(Expr::symbol_from_str("branch"), Expr::String(branch.clone()));
+        }
+        Expr::Map(map)
+    }
+
     fn from_big_map(map: &Expr) -> Vec<(Rc<str>, PackageRepository)> {
         let map = match map {
             Expr::Map(map) => map,
@@ -91,6 +313,8 @@ impl PackageRepository {
                     },
                 })
             },
+            // A checkout that is already on disk.
+            Some(Expr::Path(path)) => Source::Local(LocalSource::Directory(path.clone())),
             Some(Expr::String(_)) => todo!(),
             _ => panic!("system.config.vp_repos.{repo}.location is not a valid type or was not in the map!", repo=name),
         };
@@ -107,8 +331,289 @@ impl PackageRepository {
     }
 }
 
+/// What converting a package needs from the rest of the configuration.
+#[derive(Clone, Copy)]
+pub(crate) struct PackageContext<'a> {
+    pub(crate) definitions: &'a PackageDefinitions,
+    /// The configured vp_repos, by name.
+    pub(crate) repositories: &'a HashMap<Rc<str>, PackageRepository>,
+    /// The home directory of the user the packages belong to, if any.
+    pub(crate) home: Option<&'a Path>,
+}
+
+/// Attributes every package accepts. Any other attribute configures one of the package's
+/// configuration slots.
+const PACKAGE_ATTRIBUTES: &[&str] = &["version", "hold", "repository"];
+
+impl Package {
+    /// Reads a list of packages, each keyed by the name XBPS knows it by, which its definition
+    /// may give in place of the symbol it is written as.
+    pub(crate) fn from_list(list: &Expr, context: &PackageContext) -> Vec<(Rc<str>, Package)> {
+        let xbps_name = |symbol: &Rc<str>| context.definitions.get(symbol).map_or_else(|| symbol.clone(), |definition| definition.name.clone());
+        match list {
+            Expr::List(list) => list.iter()
+                .map(|e| {
+                    match e {
+                        Expr::Symbol(name) => (xbps_name(name), Package::from_attributes(name, &Expr::Map(BTreeMap::new()), context)),
+                        Expr::FnCall(ExprFnCall { name, args }) => match args.first() {
+                            Some(attributes @ Expr::Map(_)) => (xbps_name(name), Package::from_attributes(name, attributes, context)),
+                            _ => panic!("Package {} must be followed by a map of attributes!", name),
+                        },
+                        _ => panic!("Unknown Expr when handling packages."),
+                    }
+                })
+                .collect(),
+            _ => panic!("{:?}", list),
+        }
+    }
+}
+
+impl Package {
+    /// Reads a package written with attributes, such as `firefox { version = '115.0esr_1'; }`.
+    fn from_attributes(name: &Rc<str>, attributes: &Expr, context: &PackageContext) -> Package {
+        let version = match attributes.get_map_value(Expr::symbol_from_str("version")) {
+            Some(Expr::String(version)) => {
+                let version = unquote(version);
+                if format!("{}-{}", name, version).parse::<PkgVer>().is_err() {
+                    panic!("{}.version must be a version and revision such as '1.0_1', not '{}'!", name, version);
+                }
+                Some(version)
+            }
+            None => None,
+            _ => panic!("{}.version must be a string!", name),
+        };
+
+        Package {
+            config: Package::configuration(name, attributes, context),
+            version,
+            hold: match attributes.get_map_value(Expr::symbol_from_str("hold")) {
+                Some(Expr::Boolean(hold)) => Some(*hold),
+                None => None,
+                _ => panic!("{}.hold must be a boolean!", name),
+            },
+            repository: Package::repository(name, attributes, context),
+        }
+    }
+
+    /// Where the package comes from: the vp_repo named by its `repository`, or the Void
+    /// repositories.
+    ///
+    /// Restricted packages can't be distributed as binaries, so they must name a vp_repo that
+    /// allows building them.
+    fn repository(name: &Rc<str>, attributes: &Expr, context: &PackageContext) -> Source {
+        let repository = match attributes.get_map_value(Expr::symbol_from_str("repository")) {
+            Some(Expr::String(repository)) | Some(Expr::Symbol(repository)) => {
+                let repository = unquote(repository);
+                match context.repositories.get(&repository) {
+                    Some(configured) => Some(configured),
+                    None => panic!("{}.repository is {}, which is not one of system.config.vp_repos!", name, repository),
+                }
+            }
+            None => None,
+            _ => panic!("{}.repository must be the name of a vp_repo!", name),
+        };
+
+        let restricted = context.definitions.get(name).is_some_and(|definition| definition.is_restricted);
+        match repository {
+            Some(repository) if restricted && !repository.allow_restricted => panic!(
+                "{} is restricted, but vp_repo {} does not set allow_restricted = true!",
+                name, repository.name.as_deref().unwrap_or_default(),
+            ),
+            Some(repository) => repository.location.clone(),
+            None if restricted => panic!("{} is restricted, so it must set repository to a vp_repo with allow_restricted = true!", name),
+            None => Source::Remote(VoidRepo),
+        }
+    }
+
+    /// Matches the configuration attributes of a package against the slots its definition
+    /// declares, filling in the templates of any slot that isn't given.
+    fn configuration(name: &Rc<str>, attributes: &Expr, context: &PackageContext) -> BTreeMap<Rc<str>, ConfigurationFile> {
+        let slots = context.definitions.get(name).map(|definition| &definition.slots);
+        let Expr::Map(attributes) = attributes else { unreachable!("Package attributes are always a map") };
+
+        let mut config = BTreeMap::new();
+        for (key, value) in attributes {
+            let slot_name = match key {
+                Expr::Symbol(key) if PACKAGE_ATTRIBUTES.contains(&key.as_ref()) => continue,
+                Expr::Symbol(key) => key,
+                _ => panic!("{} has an attribute that is not a symbol!", name),
+            };
+            let slot = match slots.and_then(|slots| slots.get(slot_name)) {
+                Some(slot) => slot,
+                None => {
+                    let expected = slots.into_iter().flat_map(|slots| slots.keys().map(|slot| slot.to_string())).collect::<Vec<String>>();
+                    match expected.is_empty() {
+                        true => panic!("{} has no configuration slot named {}, as its package definition declares none!", name, slot_name),
+                        false => panic!("{} has no configuration slot named {}, expected one of {}!", name, slot_name, expected.join(", ")),
+                    }
+                }
+            };
+            let location = slot.location
+                .resolve(context.home)
+                .unwrap_or_else(|| panic!("{}.{} belongs in a home directory, so it may only be configured in a user's packages!", name, slot_name));
+            config.insert(slot_name.clone(), ConfigurationFile { location, source: use_file(name, slot_name, value) });
+        }
+
+        // Templates are only used where they can be placed, so a system package never gets
+        // the templates meant for home directories.
+        for (slot_name, slot) in slots.into_iter().flatten() {
+            if let (false, Some(template), Some(location)) = (config.contains_key(slot_name), &slot.template_location, slot.location.resolve(context.home)) {
+                config.insert(slot_name.clone(), ConfigurationFile { location, source: ConfigurationSource::Template(template.clone()) });
+            }
+        }
+        config
+    }
+
+    fn to_list(packages: &HashMap<Rc<str>, Package>) -> Expr {
+        let mut packages = packages.iter().collect::<Vec<(&Rc<str>, &Package)>>();
+        packages.sort_by_key(|(name, _)| *name);
+        Expr::List(packages.into_iter().map(|(name, package)| package.to_expr(name)).collect())
+    }
+
+    fn to_expr(&self, name: &Rc<str>) -> Expr {
+        let mut attributes = BTreeMap::new();
+        if let Some(version) = &self.version {
+            attributes.insert(Expr::symbol_from_str("version"), Expr::String(version.clone()));
+        }
+        if let Some(hold) = self.hold {
+            attributes.insert(Expr::symbol_from_str("hold"), Expr::Boolean(hold));
+        }
+
+        match attributes.is_empty() {
+            true => Expr::Symbol(name.clone()),
+            false => Expr::FnCall(ExprFnCall { name: name.clone(), args: vec![Expr::Map(attributes)] }),
+        }
+    }
+}
+
+/// Reads a configuration file given as `use_file <path> (<source>)`.
+fn use_file(package: &str, slot: &str, value: &Expr) -> ConfigurationSource {
+    let args = match value {
+        Expr::FnCall(ExprFnCall { name, args }) if name.as_ref() == "use_file" => args,
+        _ => panic!("{}.{} must be given a file with use_file!", package, slot),
+    };
+
+    match args.as_slice() {
+        [Expr::Path(path)] => ConfigurationSource::File { path: path.clone(), source: None },
+        [Expr::Path(path), source] => ConfigurationSource::File {
+            path: path.clone(),
+            source: Some(Source::from_expr(source).unwrap_or_else(|| panic!("{}.{} must use a file from a git repository or a directory!", package, slot))),
+        },
+        _ => panic!("use_file for {}.{} must be given a path, and optionally where it comes from!", package, slot),
+    }
+}
+
+/// Reads how a service picks up changes, given as `restart` or `reload`.
+pub(crate) fn on_change(value: &Expr) -> Option<OnChange> {
+    let value = match value {
+        Expr::Symbol(value) => value.clone(),
+        Expr::String(value) => unquote(value),
+        _ => return None,
+    };
+    match value.as_ref() {
+        "restart" => Some(OnChange::Restart),
+        "reload" => Some(OnChange::Reload),
+        _ => None,
+    }
+}
+
+/// Strings keep the quotes they were written with, which values such as versions don't want.
+pub(crate) fn unquote(string: &Rc<str>) -> Rc<str> {
+    let unquoted = ['\'', '"']
+        .iter()
+        .find_map(|quote| string.strip_prefix(*quote).and_then(|string| string.strip_suffix(*quote)));
+    match unquoted {
+        Some(unquoted) => Rc::from(unquoted),
+        None => string.clone(),
+    }
+}
+
+impl Dotfiles {
+    /// Reads a user's `dotfiles`, which is either where they come from or a map with the
+    /// `source`, the paths to `exclude` from it and whether to `deploy` them as copies or links.
+    fn from_expr(expr: &Expr, username: &str) -> Dotfiles {
+        let source = match expr {
+            Expr::Map(_) => expr.get_map_value(Expr::symbol_from_str("source")),
+            _ => Some(expr),
+        };
+        Dotfiles {
+            source: source
+                .and_then(Source::from_expr)
+                .unwrap_or_else(|| panic!("system.config.users.{username}.dotfiles must be a git repository or a directory!", username = username)),
+            exclude: match expr.get_map_value(Expr::symbol_from_str("exclude")) {
+                Some(Expr::List(paths)) => paths
+                    .iter()
+                    .map(|path| match path {
+                        Expr::Path(path) if is_inside(path) => path.clone(),
+                        _ => panic!("system.config.users.{username}.dotfiles.exclude must be a list of paths inside the repository!", username = username),
+                    })
+                    .collect(),
+                None => vec![],
+                _ => panic!("system.config.users.{username}.dotfiles.exclude must be a list of paths inside the repository!", username = username),
+            },
+            deploy: match expr.get_map_value(Expr::symbol_from_str("deploy")) {
+                Some(Expr::Symbol(deploy)) if deploy.as_ref() == "copy" => DotfileDeployment::Copy,
+                Some(Expr::Symbol(deploy)) if deploy.as_ref() == "symlink" => DotfileDeployment::Symlink,
+                None => DotfileDeployment::default(),
+                _ => panic!("system.config.users.{username}.dotfiles.deploy must be copy or symlink!", username = username),
+            },
+        }
+    }
+
+    fn to_expr(&self) -> Expr {
+        if self.exclude.is_empty() && self.deploy == DotfileDeployment::Copy {
+            return self.source.to_expr();
+        }
+
+        let deploy = match self.deploy {
+            DotfileDeployment::Copy => "copy",
+            DotfileDeployment::Symlink => "symlink",
+        };
+        Expr::Map(BTreeMap::from([
+            (Expr::symbol_from_str("source"), self.source.to_expr()),
+            (Expr::symbol_from_str("exclude"), Expr::List(self.exclude.iter().cloned().map(Expr::Path).collect())),
+            (Expr::symbol_from_str("deploy"), Expr::symbol_from_str(deploy)),
+        ]))
+    }
+}
+
+/// Whether the relative `path` stays inside the directory it is relative to, such as a home
+/// directory or a repository.
+fn is_inside(path: &Path) -> bool {
+    path.components().all(|component| matches!(component, Component::CurDir | Component::Normal(_)))
+}
+
 impl User {
-    pub fn from_big_map(map: &Expr) -> Vec<(Rc<str>, User)>{
+    fn to_map(&self) -> Expr {
+        let HomeDirectory::Path { location, subdirs, mode, skel } = &self.homedir;
+        let mut homedir = BTreeMap::from([
+            (Expr::symbol_from_str("location"), Expr::String(Rc::from(location.to_string_lossy()))),
+            (Expr::symbol_from_str("subdirs"), Expr::List(subdirs.iter().cloned().map(Expr::Path).collect())),
+            (Expr::symbol_from_str("skel"), Expr::Boolean(*skel)),
+        ]);
+        if let Some(mode) = mode {
+            homedir.insert(Expr::symbol_from_str("mode"), Expr::String(Rc::from(format!("{:o}", mode))));
+        }
+        let mut map = BTreeMap::from([
+            (Expr::symbol_from_str("homedir"), Expr::Map(homedir)),
+            (Expr::symbol_from_str("packages"), Package::to_list(&self.packages)),
+        ]);
+        if !self.services.is_empty() {
+            let mut services = self.services.values().collect::<Vec<&Service>>();
+            services.sort_by_key(|service| service.name.clone());
+            map.insert(Expr::symbol_from_str("services"), Expr::List(services.into_iter().map(Service::to_map).collect()));
+        }
+        if let Some(dotfiles) = &self.dotfiles {
+            map.insert(Expr::symbol_from_str("dotfiles"), dotfiles.to_expr());
+        }
+        self.account.insert_into(&mut map);
+        if let Some(privilege) = &self.privilege {
+            map.insert(Expr::symbol_from_str("privilege"), privilege.to_map());
+        }
+        Expr::Map(map)
+    }
+
+    pub(crate) fn from_big_map(map: &Expr, context: &PackageContext) -> Vec<(Rc<str>, User)>{
         let map = match map {
             Expr::Map(map) => map,
             _ => panic!("Unable to convert non-map type to package repositories!"),
@@ -116,11 +621,11 @@ impl User {
         
         map
             .iter()
-            .map(|(n, m)| {(n.clone().extract_str(), User::from_map(m, n))})
+            .map(|(n, m)| {(n.clone().extract_str(), User::from_map(m, n, context))})
             .collect::<Vec<(Rc<str>, User)>>()
     }
 
-    pub fn from_map(map: &Expr, name: &Expr) -> User {
+    pub(crate) fn from_map(map: &Expr, name: &Expr, context: &PackageContext) -> User {
         let username = match name {
             Expr::Symbol(rc) => rc,
             _ => panic!("Name is not a symbol!"),
@@ -143,12 +648,29 @@ impl User {
                                     Expr::Path(path) => path.to_owned(),
                                     _ => panic!("Only Paths are allowed in subdirs!"),
                                 }
-                            }).collect(),
+                            })
+                            .inspect(|path| if !is_inside(path) {
+                                panic!("system.config.users.{username}.homedir.subdirs must stay inside the home directory, not {path}!", username = username, path = path.display());
+                            })
+                            .collect(),
                             _ => panic!("Subdirs must be a list, or be missing"),
                         }
                     } else {
                         vec![]
                     },
+                    mode: match map.get(&Expr::symbol_from_str("mode")) {
+                        Some(Expr::String(mode)) => Some(u32::from_str_radix(&unquote(mode), 8)
+                            .ok()
+                            .filter(|mode| *mode <= 0o7777)
+                            .unwrap_or_else(|| panic!("system.config.users.{username}.homedir.mode must be an octal mode, such as '0750'!", username = username))),
+                        None => None,
+                        _ => panic!("system.config.users.{username}.homedir.mode must be an octal mode, such as '0750'!", username = username),
+                    },
+                    skel: match map.get(&Expr::symbol_from_str("skel")) {
+                        Some(Expr::Boolean(skel)) => *skel,
+                        None => false,
+                        _ => panic!("system.config.users.{username}.homedir.skel must be a boolean!", username = username),
+                    },
                 }
             }
             Some(Expr::String(_)) => todo!(),
@@ -159,47 +681,167 @@ impl User {
                     path
                 },
                 subdirs: vec![],
+                mode: None,
+                skel: false,
             },
             
             _ => panic!("system.config.users.{username}.homedir is not a valid type!", username=username),
         };
         
-        let dotfiles = match map.get_map_value(Expr::symbol_from_str("dotfiles")) {
-            Some(Expr::GitHubRemote { user, repo, branch }) => {
-                Some(Source::Remote(RemoteSource::GithubRemote {
-                    user: user.to_owned(),
-                    repository_name: repo.to_owned(),
-                    branch_name: branch.to_owned(),
-                }))
-            },
-            None => None,
-            _ => panic!("system.config.users.{username}.dotfiles is not a valid type!", username = username),
-        };
+        let dotfiles = map.get_map_value(Expr::symbol_from_str("dotfiles")).map(|dotfiles| Dotfiles::from_expr(dotfiles, username));
         
         let packages = match map.get_map_value(Expr::symbol_from_str("packages")) {
-            Some(Expr::List(list)) => {
-                let map = list.iter()
-                    .map(|e| {
-                        match e {
-                            Expr::Symbol(name) => (name.to_owned(), Package {
-                                config: None,
-                                repository: Source::Remote(VoidRepo),
-                            }),
-                            _ => panic!("Unknown Expr when handling packages."),
-                        }
-                    });
-                HashMap::from_iter(map)
+            Some(list) => {
+                let HomeDirectory::Path { location, .. } = &homedir;
+                HashMap::from_iter(Package::from_list(list, &PackageContext { home: Some(location), ..*context }))
             }
             None => HashMap::new(),
-            _ => panic!("{:?}", map.get_map_value(Expr::Symbol(Rc::from("packages"))))
         };
         
+        let services = HashMap::from_iter(
+            map.get_map_value(Expr::symbol_from_str("services"))
+                .into_iter()
+                .flat_map(Service::from_list)
+        );
+
         User {
             username: Some(username.to_owned()),
             homedir,
             dotfiles,
             packages,
+            services,
+            account: Account::from_map(map, username),
+            privilege: map.get_map_value(Expr::symbol_from_str("privilege")).map(|privilege| Privilege::from_map(privilege, username)),
+        }
+    }
+}
+
+impl Privilege {
+    /// Reads a user's `privilege` block, such as `{ tool = doas; nopasswd = true; }`.
+    fn from_map(map: &Expr, username: &str) -> Privilege {
+        if !matches!(map, Expr::Map(_)) {
+            panic!("system.config.users.{username}.privilege must be a map!", username = username);
+        }
+
+        Privilege {
+            tool: match map.get_map_value(Expr::symbol_from_str("tool")) {
+                Some(Expr::Symbol(tool)) if tool.as_ref() == "sudo" => PrivilegeTool::Sudo,
+                Some(Expr::Symbol(tool)) if tool.as_ref() == "doas" => PrivilegeTool::Doas,
+                None => PrivilegeTool::default(),
+                _ => panic!("system.config.users.{username}.privilege.tool must be sudo or doas!", username = username),
+            },
+            nopasswd: match map.get_map_value(Expr::symbol_from_str("nopasswd")) {
+                Some(Expr::Boolean(nopasswd)) => *nopasswd,
+                None => false,
+                _ => panic!("system.config.users.{username}.privilege.nopasswd must be a boolean!", username = username),
+            },
+            commands: match map.get_map_value(Expr::symbol_from_str("commands")) {
+                Some(Expr::List(commands)) => commands
+                    .iter()
+                    .map(|command| match command {
+                        Expr::Path(path) => Rc::from(path.to_string_lossy()),
+                        Expr::String(command) => unquote(command),
+                        _ => panic!("system.config.users.{username}.privilege.commands must be a list of commands!", username = username),
+                    })
+                    // Both sudo and doas match commands by their full path.
+                    .inspect(|command: &Rc<str>| if !command.starts_with('/') {
+                        panic!("system.config.users.{username}.privilege.commands must start with an absolute path, not {command}!", username = username, command = command);
+                    })
+                    .collect(),
+                None => vec![],
+                _ => panic!("system.config.users.{username}.privilege.commands must be a list of commands!", username = username),
+            },
+        }
+    }
+
+    fn to_map(&self) -> Expr {
+        let tool = match self.tool {
+            PrivilegeTool::Sudo => "sudo",
+            PrivilegeTool::Doas => "doas",
+        };
+        Expr::Map(BTreeMap::from([
+            (Expr::symbol_from_str("tool"), Expr::symbol_from_str(tool)),
+            (Expr::symbol_from_str("nopasswd"), Expr::Boolean(self.nopasswd)),
+            (Expr::symbol_from_str("commands"), Expr::List(self.commands.iter().cloned().map(Expr::String).collect())),
+        ]))
+    }
+}
+
+impl Account {
+    /// Reads the account keys of the user `username`'s map.
+    fn from_map(map: &Expr, username: &str) -> Account {
+        let name = |expr: &Expr| match expr {
+            Expr::Symbol(name) => Some(name.clone()),
+            Expr::String(name) => Some(unquote(name)),
+            _ => None,
+        };
+
+        Account {
+            uid: match map.get_map_value(Expr::symbol_from_str("uid")) {
+                Some(Expr::Number(number)) if number.num.fract() == 0.0 && *number.num <= u32::MAX as f64 => Some(*number.num as u32),
+                None => None,
+                _ => panic!("system.config.users.{username}.uid must be a whole number!", username = username),
+            },
+            group: map.get_map_value(Expr::symbol_from_str("group")).map(|group| {
+                name(group).unwrap_or_else(|| panic!("system.config.users.{username}.group must be a group name!", username = username))
+            }),
+            groups: match map.get_map_value(Expr::symbol_from_str("groups")) {
+                Some(Expr::List(groups)) => groups
+                    .iter()
+                    .map(|group| name(group).unwrap_or_else(|| panic!("system.config.users.{username}.groups must be a list of group names!", username = username)))
+                    .collect(),
+                None => vec![],
+                _ => panic!("system.config.users.{username}.groups must be a list of group names!", username = username),
+            },
+            shell: match map.get_map_value(Expr::symbol_from_str("shell")) {
+                Some(Expr::Path(shell)) => Some(shell.clone()),
+                Some(Expr::String(shell)) => Some(PathBuf::from(unquote(shell).as_ref())),
+                Some(Expr::Symbol(shell)) => Some(PathBuf::from(shell.as_ref())),
+                None => None,
+                _ => panic!("system.config.users.{username}.shell is not a valid type!", username = username),
+            },
+            locked: match map.get_map_value(Expr::symbol_from_str("locked")) {
+                Some(Expr::Boolean(locked)) => *locked,
+                None => false,
+                _ => panic!("system.config.users.{username}.locked must be a boolean!", username = username),
+            },
+            password: match (map.get_map_value(Expr::symbol_from_str("hashedPassword")), map.get_map_value(Expr::symbol_from_str("hashedPasswordFile"))) {
+                (Some(_), Some(_)) => panic!("system.config.users.{username} can't have both a hashedPassword and a hashedPasswordFile!", username = username),
+                // The value is left out of the message, in case it is a password.
+                (Some(Expr::String(hash)), None) => match is_hashed(&unquote(hash)) {
+                    true => Some(Password::Hashed(unquote(hash))),
+                    false => panic!("system.config.users.{username}.hashedPassword must be a crypt(3) hash, such as one from `mkpasswd`, not a plaintext password!", username = username),
+                },
+                (Some(_), None) => panic!("system.config.users.{username}.hashedPassword must be a string!", username = username),
+                (None, Some(Expr::Path(path))) => Some(Password::File(path.clone())),
+                (None, Some(_)) => panic!("system.config.users.{username}.hashedPasswordFile must be a path!", username = username),
+                (None, None) => None,
+            },
+        }
+    }
+
+    /// Adds the account keys that are set to `map`.
+    fn insert_into(&self, map: &mut BTreeMap<Expr, Expr>) {
+        if let Some(uid) = self.uid {
+            map.insert(Expr::symbol_from_str("uid"), Expr::Number(NumberExpr::from_number(uid as f64)));
+        }
+        if let Some(group) = &self.group {
+            map.insert(Expr::symbol_from_str("group"), Expr::Symbol(group.clone()));
+        }
+        if !self.groups.is_empty() {
+            map.insert(Expr::symbol_from_str("groups"), Expr::List(self.groups.iter().cloned().map(Expr::Symbol).collect()));
+        }
+        if let Some(shell) = &self.shell {
+            map.insert(Expr::symbol_from_str("shell"), Expr::Path(shell.clone()));
         }
+        if self.locked {
+            map.insert(Expr::symbol_from_str("locked"), Expr::Boolean(true));
+        }
+        match &self.password {
+            Some(Password::Hashed(hash)) => map.insert(Expr::symbol_from_str("hashedPassword"), Expr::String(hash.clone())),
+            Some(Password::File(path)) => map.insert(Expr::symbol_from_str("hashedPasswordFile"), Expr::Path(path.clone())),
+            None => None,
+        };
     }
 }
 
@@ -209,6 +851,20 @@ mod tests {
     use std::path::PathBuf;
     use crate::system::{HomeDirectory, Source};
     use super::*;
+
+    /// What packages are converted against in a configuration without definitions or vp_repos.
+    #[derive(Default)]
+    struct EmptyContext {
+        definitions: PackageDefinitions,
+        repositories: HashMap<Rc<str>, PackageRepository>,
+    }
+
+    impl EmptyContext {
+        fn context(&self) -> PackageContext<'_> {
+            PackageContext { definitions: &self.definitions, repositories: &self.repositories, home: None }
+        }
+    }
+
     #[test]
     fn test_service_from_map() {
         let map = Expr::Map(BTreeMap::from([
@@ -217,10 +873,58 @@ mod tests {
         let expected = (Rc::from("test"), Service {
             name: Rc::from("test"),
             enabled: true,
-            downed: false,
+            ..Default::default()
         });
 
         assert_eq!(Service::from_map(&map), expected);
+
+        let quoted = Expr::Map(BTreeMap::from([(Expr::Symbol(Rc::from("name")), Expr::String(Rc::from("'test'")))]));
+        assert_eq!(Service::from_map(&quoted), expected);
+        let symbol = Expr::Map(BTreeMap::from([(Expr::Symbol(Rc::from("name")), Expr::Symbol(Rc::from("test")))]));
+        assert_eq!(Service::from_map(&symbol), expected);
+    }
+
+    #[test]
+    fn test_service_definition_from_map() {
+        let symbol = |name: &str| Expr::Symbol(Rc::from(name));
+        let string = |value: &str| Expr::String(Rc::from(value));
+        let map = Expr::Map(BTreeMap::from([
+            (symbol("name"), symbol("syncthing")),
+            (symbol("run"), string("'syncthing -no-browser'")),
+            (symbol("user"), symbol("sapeint")),
+            (symbol("env"), Expr::Map(BTreeMap::from([(symbol("STNODEFAULTFOLDER"), string("'1'"))]))),
+            (symbol("log"), Expr::Boolean(true)),
+        ]));
+
+        let (_, service) = Service::from_map(&map);
+        assert_eq!(service.definition, Some(ServiceDefinition {
+            run: Rc::from("syncthing -no-browser"),
+            finish: None,
+            environment: BTreeMap::from([(Rc::from("STNODEFAULTFOLDER"), Rc::from("1"))]),
+            user: Some(Rc::from("sapeint")),
+            log: Some(Rc::from("vlogger -t syncthing")),
+        }));
+        assert_eq!(Service::from_map(&service.to_map()).1, service);
+    }
+
+    #[test]
+    #[should_panic(expected = "Service www sets finish but has no run command!")]
+    fn test_service_definition_needs_run() {
+        let map = Expr::Map(BTreeMap::from([
+            (Expr::Symbol(Rc::from("name")), Expr::Symbol(Rc::from("www"))),
+            (Expr::Symbol(Rc::from("finish")), Expr::String(Rc::from("'true'"))),
+        ]));
+        Service::from_map(&map);
+    }
+
+    #[test]
+    #[should_panic(expected = "Service ../../root/.ssh's name must not contain '/' or be '.' or '..'!")]
+    fn test_service_name_outside_sv() {
+        let map = Expr::Map(BTreeMap::from([
+            (Expr::Symbol(Rc::from("name")), Expr::String(Rc::from("'../../root/.ssh'"))),
+            (Expr::Symbol(Rc::from("run")), Expr::String(Rc::from("'true'"))),
+        ]));
+        Service::from_map(&map);
     }
 
     #[test]
@@ -240,7 +944,7 @@ mod tests {
              Service {
                  name: Rc::from("test"),
                  enabled: true,
-                 downed: false,
+                 ..Default::default()
              }
             ),
             (Rc::from("test2"),
@@ -248,6 +952,7 @@ mod tests {
                 name: Rc::from("test2"),
                 enabled: true,
                 downed: true,
+                ..Default::default()
              }
             ),
         ];
@@ -321,7 +1026,7 @@ mod tests {
              Expr::Map(
                  BTreeMap::from([
                      (Expr::symbol_from_str("hashedPassword"),
-                      Expr::string_from_str("PASS")),
+                      Expr::string_from_str("$6$salt$hash")),
                      (Expr::symbol_from_str("homedir"),
                       Expr::Map(BTreeMap::from([
                           (Expr::symbol_from_str("subdirs"),
@@ -351,27 +1056,39 @@ mod tests {
                  homedir: HomeDirectory::Path {
                      location: PathBuf::from("/home/sapeint"),
                      subdirs: vec![PathBuf::from("./library"), PathBuf::from("./games/launchers")],
+                     mode: None,
+                     skel: false,
                  },
-                 dotfiles: Some(Source::Remote(RemoteSource::GithubRemote {
-                     user: Rc::from("sapein"),
-                     repository_name: Rc::from("dotfiles"),
-                     branch_name: None,
-                 })),
+                 dotfiles: Some(Dotfiles {
+                     source: Source::Remote(RemoteSource::GithubRemote {
+                         user: Rc::from("sapein"),
+                         repository_name: Rc::from("dotfiles"),
+                         branch_name: None,
+                     }),
+                     exclude: vec![],
+                     deploy: DotfileDeployment::Copy,
+                 }),
                  packages: HashMap::from([
                      (Rc::from("firefox"), crate::system::Package {
                          config: Default::default(),
+                         version: None,
+                         hold: None,
                          repository: Source::Remote(RemoteSource::VoidRepo)
                      }),
                      (Rc::from("dmenu"), crate::system::Package {
                          config: Default::default(),
+                         version: None,
+                         hold: None,
                          repository: Source::Remote(RemoteSource::VoidRepo)
                      }),
                  ]),
+                 account: Account { password: Some(Password::Hashed(Rc::from("$6$salt$hash"))), ..Account::default() },
+                 ..Default::default()
              }
             )
         ];
 
-        assert_eq!(User::from_big_map(&map), expected);
+        assert_eq!(User::from_big_map(&map, &EmptyContext::default().context()), expected);
     }
 
     #[test]
@@ -379,7 +1096,7 @@ mod tests {
         let map =
             Expr::Map(BTreeMap::from([
                 (Expr::symbol_from_str("hashedPassword"),
-                 Expr::string_from_str("PASS")),
+                 Expr::string_from_str("$6$salt$hash")),
                 (Expr::symbol_from_str("homedir"),
                  Expr::Map(BTreeMap::from([
                      (Expr::symbol_from_str("subdirs"),
@@ -405,25 +1122,37 @@ mod tests {
             homedir: HomeDirectory::Path {
                 location: PathBuf::from("/home/sapeint"),
                 subdirs: vec![PathBuf::from("./library")],
+                mode: None,
+                skel: false,
             },
-            dotfiles: Some(Source::Remote(RemoteSource::GithubRemote {
-                user: Rc::from("sapein"),
-                repository_name: Rc::from("dotfiles"),
-                branch_name: None,
-            })),
+            dotfiles: Some(Dotfiles {
+                source: Source::Remote(RemoteSource::GithubRemote {
+                    user: Rc::from("sapein"),
+                    repository_name: Rc::from("dotfiles"),
+                    branch_name: None,
+                }),
+                exclude: vec![],
+                deploy: DotfileDeployment::Copy,
+            }),
             packages: HashMap::from([
                 (Rc::from("firefox"), crate::system::Package {
                     config: Default::default(),
+                    version: None,
+                    hold: None,
                     repository: Source::Remote(RemoteSource::VoidRepo)
                 }),
                 (Rc::from("dmenu"), crate::system::Package {
                     config: Default::default(),
+                    version: None,
+                    hold: None,
                     repository: Source::Remote(RemoteSource::VoidRepo)
                 }),
             ]),
+            account: Account { password: Some(Password::Hashed(Rc::from("$6$salt$hash"))), ..Account::default() },
+            ..Default::default()
         };
 
-        assert_eq!(User::from_map(&map, &Expr::symbol_from_str("sapeint")), expected);
+        assert_eq!(User::from_map(&map, &Expr::symbol_from_str("sapeint"), &EmptyContext::default().context()), expected);
     }
 
     #[test]
@@ -455,7 +1184,7 @@ mod tests {
                 (Rc::from("test"), Service {
                     name: Rc::from("test"),
                     enabled: false,
-                    downed: false,
+                    ..Default::default()
                 })
             ]),
             repositories: HashMap::from([
@@ -470,10 +1199,338 @@ mod tests {
                      allow_restricted: false,
                  })
             ]),
-            users: HashMap::new(),
-            system_packages: Rc::from(""),
+            ..Default::default()
+        };
+
+        assert_eq!(System::from_map(map, &PackageDefinitions::default()), expected);
+    }
+
+    #[test]
+    fn test_system_to_map() {
+        let system = System {
+            services: HashMap::from([
+                (Rc::from("sshd"), Service { name: Rc::from("sshd"), enabled: true, ..Default::default() }),
+            ]),
+            packages: HashMap::from([
+                (Rc::from("nano"), crate::system::Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) }),
+            ]),
+            ..Default::default()
+        };
+
+        let map = system.to_map();
+        assert_eq!(map.get_map_value(Expr::symbol_from_str("packages")), Some(&Expr::List(vec![Expr::symbol_from_str("nano")])));
+        assert_eq!(System::from_map(map, &PackageDefinitions::default()), system);
+    }
+
+    #[test]
+    fn test_user_account() {
+        let symbol = |name: &str| Expr::Symbol(Rc::from(name));
+        let map = Expr::Map(BTreeMap::from([
+            (symbol("uid"), Expr::Number(NumberExpr::from_number(1001.0))),
+            (symbol("group"), Expr::String(Rc::from("'users'"))),
+            (symbol("groups"), Expr::List(vec![symbol("wheel"), symbol("audio")])),
+            (symbol("shell"), Expr::Path(PathBuf::from("/bin/zsh"))),
+            (symbol("locked"), Expr::Boolean(true)),
+            (symbol("hashedPassword"), Expr::String(Rc::from("'$y$j9T$salt$hash'"))),
+        ]));
+        let expected = Account {
+            uid: Some(1001),
+            group: Some(Rc::from("users")),
+            groups: vec![Rc::from("wheel"), Rc::from("audio")],
+            shell: Some(PathBuf::from("/bin/zsh")),
+            locked: true,
+            password: Some(Password::Hashed(Rc::from("$y$j9T$salt$hash"))),
         };
+        assert_eq!(Account::from_map(&map, "sapeint"), expected);
+        assert_eq!(Account::from_map(&Expr::Map(BTreeMap::new()), "sapeint"), Account::default());
 
-        assert_eq!(System::from_map(map), expected);
+        let mut round_trip = BTreeMap::new();
+        expected.insert_into(&mut round_trip);
+        assert_eq!(Account::from_map(&Expr::Map(round_trip), "sapeint"), expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[should_panic(expected = "system.config.users.sapeint.uid must be a whole number!")]
+    fn test_user_account_uid() {
+        let map = Expr::Map(BTreeMap::from([(Expr::symbol_from_str("uid"), Expr::Number(NumberExpr::from_number(1000.5)))]));
+        Account::from_map(&map, "sapeint");
+    }
+
+    #[test]
+    fn test_user_dotfiles() {
+        let symbol = |name: &str| Expr::Symbol(Rc::from(name));
+        let repository = Expr::GitHubRemote { user: Rc::from("sapein"), repo: Rc::from("dotfiles"), branch: None };
+        let source = Source::Remote(RemoteSource::GithubRemote { user: Rc::from("sapein"), repository_name: Rc::from("dotfiles"), branch_name: None });
+        assert_eq!(Dotfiles::from_expr(&repository, "sapeint"), Dotfiles { source: source.clone(), exclude: vec![], deploy: DotfileDeployment::Copy });
+
+        let map = Expr::Map(BTreeMap::from([
+            (symbol("source"), repository),
+            (symbol("exclude"), Expr::List(vec![Expr::Path(PathBuf::from("./README.md"))])),
+        ]));
+        let expected = Dotfiles { source, exclude: vec![PathBuf::from("./README.md")], deploy: DotfileDeployment::Copy };
+        assert_eq!(Dotfiles::from_expr(&map, "sapeint"), expected);
+        assert_eq!(Dotfiles::from_expr(&expected.to_expr(), "sapeint"), expected);
+
+        let Expr::Map(mut map) = map else { unreachable!() };
+        map.insert(symbol("deploy"), symbol("symlink"));
+        let expected = Dotfiles { deploy: DotfileDeployment::Symlink, ..expected };
+        assert_eq!(Dotfiles::from_expr(&Expr::Map(map), "sapeint"), expected);
+        assert_eq!(Dotfiles::from_expr(&expected.to_expr(), "sapeint"), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "system.config.users.sapeint.dotfiles.deploy must be copy or symlink!")]
+    fn test_user_dotfiles_deploy() {
+        let map = Expr::Map(BTreeMap::from([
+            (Expr::symbol_from_str("source"), Expr::Path(PathBuf::from("/srv/dotfiles"))),
+            (Expr::symbol_from_str("deploy"), Expr::string_from_str("'link'")),
+        ]));
+        Dotfiles::from_expr(&map, "sapeint");
+    }
+
+    #[test]
+    fn test_user_homedir() {
+        let symbol = |name: &str| Expr::Symbol(Rc::from(name));
+        let empty = EmptyContext::default();
+        let context = empty.context();
+        let map = Expr::Map(BTreeMap::from([
+            (symbol("homedir"), Expr::Map(BTreeMap::from([
+                (symbol("subdirs"), Expr::List(vec![Expr::Path(PathBuf::from("./library"))])),
+                (symbol("mode"), Expr::string_from_str("'0750'")),
+                (symbol("skel"), Expr::Boolean(true)),
+            ]))),
+        ]));
+        let user = User::from_map(&map, &symbol("sapeint"), &context);
+        let expected = HomeDirectory::Path {
+            location: PathBuf::from("/home/sapeint"),
+            subdirs: vec![PathBuf::from("./library")],
+            mode: Some(0o750),
+            skel: true,
+        };
+        assert_eq!(user.homedir, expected);
+        assert_eq!(User::from_map(&user.to_map(), &symbol("sapeint"), &context).homedir, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "system.config.users.sapeint.homedir.subdirs must stay inside the home directory, not ./../guest!")]
+    fn test_user_homedir_escape() {
+        let symbol = |name: &str| Expr::Symbol(Rc::from(name));
+        let map = Expr::Map(BTreeMap::from([
+            (symbol("homedir"), Expr::Map(BTreeMap::from([(symbol("subdirs"), Expr::List(vec![Expr::Path(PathBuf::from("./../guest"))]))]))),
+        ]));
+        User::from_map(&map, &symbol("sapeint"), &EmptyContext::default().context());
+    }
+
+    #[test]
+    #[should_panic(expected = "system.config.users.sapeint.hashedPassword must be a crypt(3) hash")]
+    fn test_user_plaintext_password() {
+        let map = Expr::Map(BTreeMap::from([(Expr::symbol_from_str("hashedPassword"), Expr::string_from_str("PASS"))]));
+        Account::from_map(&map, "sapeint");
+    }
+
+    #[test]
+    fn test_user_privilege() {
+        let symbol = |name: &str| Expr::Symbol(Rc::from(name));
+        let map = Expr::Map(BTreeMap::from([
+            (symbol("tool"), symbol("doas")),
+            (symbol("nopasswd"), Expr::Boolean(true)),
+            (symbol("commands"), Expr::List(vec![Expr::Path(PathBuf::from("/usr/bin/sv")), Expr::String(Rc::from("'/usr/bin/xbps-install -Su'"))])),
+        ]));
+        let expected = Privilege {
+            tool: PrivilegeTool::Doas,
+            nopasswd: true,
+            commands: vec![Rc::from("/usr/bin/sv"), Rc::from("/usr/bin/xbps-install -Su")],
+        };
+        assert_eq!(Privilege::from_map(&map, "sapeint"), expected);
+        assert_eq!(Privilege::from_map(&expected.to_map(), "sapeint"), expected);
+        assert_eq!(Privilege::from_map(&Expr::Map(BTreeMap::new()), "sapeint"), Privilege { tool: PrivilegeTool::Sudo, nopasswd: false, commands: vec![] });
+
+        // Shells may be named rather than given by path.
+        let shell = Expr::Map(BTreeMap::from([(symbol("shell"), symbol("bash"))]));
+        assert_eq!(Account::from_map(&shell, "sapeint").shell, Some(PathBuf::from("bash")));
+    }
+
+    #[test]
+    #[should_panic(expected = "system.config.users.sapeint.privilege.commands must start with an absolute path, not sv!")]
+    fn test_user_privilege_relative_command() {
+        let map = Expr::Map(BTreeMap::from([(Expr::symbol_from_str("commands"), Expr::List(vec![Expr::String(Rc::from("'sv'"))]))]));
+        Privilege::from_map(&map, "sapeint");
+    }
+
+    #[test]
+    fn test_user_services() {
+        let symbol = |name: &str| Expr::Symbol(Rc::from(name));
+        let map = Expr::Map(BTreeMap::from([
+            (symbol("users"), Expr::Map(BTreeMap::from([
+                (symbol("sapeint"), Expr::Map(BTreeMap::from([
+                    (symbol("services"), Expr::List(vec![
+                        Expr::Map(BTreeMap::from([(symbol("name"), symbol("pipewire"))])),
+                    ])),
+                ]))),
+                (symbol("guest"), Expr::Map(BTreeMap::new())),
+            ]))),
+        ]));
+
+        let system = System::from_map(map, &PackageDefinitions::default());
+        let sapeint = &system.users[&Rc::from("sapeint")];
+        assert_eq!(sapeint.services.keys().collect::<Vec<_>>(), vec![&Rc::from("pipewire")]);
+        assert!(system.users[&Rc::from("guest")].services.is_empty());
+
+        // Only users with services get a runsvdir of their own.
+        assert_eq!(system.services, HashMap::from([
+            (Rc::from("runsvdir-sapeint"), Service {
+                name: Rc::from("runsvdir-sapeint"),
+                enabled: true,
+                definition: Some(user_runsvdir("sapeint", Path::new("/home/sapeint"))),
+                ..Default::default()
+            }),
+        ]));
+
+        let services = sapeint.to_map();
+        assert_eq!(User::from_map(&services, &symbol("sapeint"), &EmptyContext::default().context()).services, sapeint.services);
+    }
+
+    #[test]
+    fn test_package_attributes() {
+        let list = Expr::List(vec![
+            Expr::symbol_from_str("nano"),
+            Expr::FnCall(ExprFnCall {
+                name: Rc::from("firefox-esr"),
+                args: vec![Expr::Map(BTreeMap::from([
+                    (Expr::symbol_from_str("version"), Expr::String(Rc::from("'115.3.1esr_1'"))),
+                    (Expr::symbol_from_str("hold"), Expr::Boolean(true)),
+                ]))],
+            }),
+        ]);
+
+        let packages = HashMap::from_iter(Package::from_list(&list, &EmptyContext::default().context()));
+        assert_eq!(packages[&Rc::from("firefox-esr")], crate::system::Package {
+            config: BTreeMap::new(),
+            version: Some(Rc::from("115.3.1esr_1")),
+            hold: Some(true),
+            repository: Source::Remote(RemoteSource::VoidRepo),
+        });
+        assert_eq!(packages[&Rc::from("nano")].hold, None);
+        assert_eq!(HashMap::from_iter(Package::from_list(&Package::to_list(&packages), &EmptyContext::default().context())), packages);
+    }
+
+    #[test]
+    #[should_panic(expected = "firefox.version must be a version and revision")]
+    fn test_package_version_without_revision() {
+        Package::from_list(&Expr::List(vec![Expr::FnCall(ExprFnCall {
+            name: Rc::from("firefox"),
+            args: vec![Expr::Map(BTreeMap::from([
+                (Expr::symbol_from_str("version"), Expr::String(Rc::from("'118.0'"))),
+            ]))],
+        })]), &EmptyContext::default().context());
+    }
+
+    /// Parses `packages = [ ... ];` into the package list it declares.
+    fn package_list(input: &str) -> Expr {
+        let mut lexer = crate::lex::Lexer::from_string(input);
+        let mut parser = crate::parser::Parser::from_token_list_smart(lexer.tokenize_input_smart());
+        match parser.parse_input().first() {
+            Some(Expr::VarDecl(_, list)) => list.as_ref().clone(),
+            other => panic!("Expected a package list, got {:?}", other),
+        }
+    }
+
+    fn definitions(scratch: &str, input: &str) -> PackageDefinitions {
+        let root = crate::test_util::scratch_dir(scratch);
+        let file = root.join("packages.pvsm");
+        std::fs::write(&file, input).unwrap();
+        let mut definitions = PackageDefinitions::default();
+        definitions.load_file(&file).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+        definitions
+    }
+
+    fn bash_definitions(scratch: &str) -> PackageDefinitions {
+        definitions(scratch, "bash = {
+            configuration = {
+                bashrc = { location = (home ./.bashrc); };
+                bash_profile = { location = (home ./.bash_profile); template_location = ./bash_profile; };
+            };
+        };")
+    }
+
+    #[test]
+    fn test_package_configuration_slots() {
+        let definitions = bash_definitions("converter-configuration-slots");
+        let list = package_list("packages = [ bash { bashrc = use_file ./bash/bashrc (gh-r 'sapein' 'dotfiles'); }, ];");
+
+        let packages: HashMap<Rc<str>, Package> = HashMap::from_iter(Package::from_list(&list, &PackageContext { definitions: &definitions, repositories: &HashMap::new(), home: Some(Path::new("/home/sapeint")) }));
+        let template = definitions.get("bash").unwrap().file.parent().unwrap().join("./bash_profile");
+        assert_eq!(packages[&Rc::from("bash")].config, BTreeMap::from([
+            (Rc::from("bashrc"), ConfigurationFile {
+                location: PathBuf::from("/home/sapeint/.bashrc"),
+                source: ConfigurationSource::File {
+                    path: PathBuf::from("./bash/bashrc"),
+                    source: Some(Source::Remote(RemoteSource::GithubRemote {
+                        user: Rc::from("sapein"),
+                        repository_name: Rc::from("dotfiles"),
+                        branch_name: None,
+                    })),
+                },
+            }),
+            (Rc::from("bash_profile"), ConfigurationFile {
+                location: PathBuf::from("/home/sapeint/.bash_profile"),
+                source: ConfigurationSource::Template(template),
+            }),
+        ]));
+
+        // System packages have no home directory to put the files in.
+        let packages: HashMap<Rc<str>, Package> = HashMap::from_iter(Package::from_list(&package_list("packages = [ bash, ];"), &PackageContext { definitions: &definitions, repositories: &HashMap::new(), home: None }));
+        assert!(packages[&Rc::from("bash")].config.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "bash has no configuration slot named bashprofile, expected one of bash_profile, bashrc!")]
+    fn test_package_unknown_configuration_slot() {
+        let list = package_list("packages = [ bash { bashprofile = use_file ./bash/bash_profile; }, ];");
+        let definitions = bash_definitions("converter-unknown-slot");
+        Package::from_list(&list, &PackageContext { definitions: &definitions, repositories: &HashMap::new(), home: Some(Path::new("/home/sapeint")) });
+    }
+
+    fn personal_repositories(allow_restricted: bool) -> HashMap<Rc<str>, PackageRepository> {
+        HashMap::from([(Rc::from("personal"), PackageRepository {
+            name: Some(Rc::from("personal")),
+            location: Source::Remote(RemoteSource::GithubRemote {
+                user: Rc::from("sapein"),
+                repository_name: Rc::from("void-packages"),
+                branch_name: Some(Rc::from("personal")),
+            }),
+            allow_restricted,
+        })])
+    }
+
+    #[test]
+    fn test_package_repository() {
+        let definitions = definitions("converter-package-repository", "discord = { name = 'Discord'; is_restricted = true; };");
+        let repositories = personal_repositories(true);
+        let list = package_list("packages = [ discord { repository = 'personal'; }, st { repository = 'personal'; }, dmenu, ];");
+
+        let packages: HashMap<Rc<str>, Package> = HashMap::from_iter(Package::from_list(&list, &PackageContext { definitions: &definitions, repositories: &repositories, home: None }));
+        // Packages are known by the name their definition gives XBPS.
+        assert!(!packages.contains_key("discord"));
+        assert_eq!(packages[&Rc::from("Discord")].repository, repositories[&Rc::from("personal")].location);
+        assert_eq!(packages[&Rc::from("st")].repository, repositories[&Rc::from("personal")].location);
+        assert_eq!(packages[&Rc::from("dmenu")].repository, Source::Remote(RemoteSource::VoidRepo));
+    }
+
+    #[test]
+    #[should_panic(expected = "discord is restricted, so it must set repository to a vp_repo with allow_restricted = true!")]
+    fn test_restricted_package_without_repository() {
+        let definitions = definitions("converter-restricted-without-repository", "discord = { is_restricted = true; };");
+        let list = package_list("packages = [ discord, ];");
+        Package::from_list(&list, &PackageContext { definitions: &definitions, repositories: &personal_repositories(true), home: None });
+    }
+
+    #[test]
+    #[should_panic(expected = "discord is restricted, but vp_repo personal does not set allow_restricted = true!")]
+    fn test_restricted_package_from_unrestricted_repository() {
+        let definitions = definitions("converter-restricted-unrestricted-repository", "discord = { is_restricted = true; };");
+        let list = package_list("packages = [ discord { repository = 'personal'; }, ];");
+        Package::from_list(&list, &PackageContext { definitions: &definitions, repositories: &personal_repositories(false), home: None });
+    }
+}
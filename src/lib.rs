@@ -3,7 +3,39 @@ pub mod parser;
 pub mod interpreter;
 
 pub mod system;
+pub mod cli;
+pub mod command;
+pub mod package_manager;
+pub mod pkgver;
+pub mod repodata;
+mod accounts;
 mod actions;
+mod builder;
+mod checkout;
+mod dotfiles;
+mod executor;
+mod lockfile;
+mod plan;
+mod privileges;
+mod planner;
+mod probe;
+mod render;
+mod service;
+mod supervise;
+
+#[cfg(test)]
+pub(crate) mod test_util {
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Creates an empty scratch directory to be used as the root of a test system.
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("svsm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+}
 
 #[cfg(test)]
 mod integration_tests {
@@ -121,14 +153,14 @@ mod integration_tests {
             .get_map_value(Expr::symbol_from_str("config"))
             .unwrap()
             .clone();
-        let output = System::from_map(system_config);
+        let output = System::from_map(system_config, &crate::interpreter::package_definitions::PackageDefinitions::default());
 
         let expected = System {
             services: HashMap::from([
                 (Rc::from("sshd"), Service {
                     name: Rc::from("sshd"),
                     enabled: true,
-                    downed: false
+                    ..Default::default()
                 })
             ]),
             repositories: HashMap::from( [
@@ -142,10 +174,53 @@ mod integration_tests {
                      }),
                      allow_restricted: true,
                  })]),
-            users: HashMap::new(),
-            system_packages: Rc::from(""),
+            ..Default::default()
         };
 
         assert_eq!(output, expected);
     }
+
+    #[test]
+    fn test_plan_and_apply_vp_repo() {
+        use std::fs;
+        use crate::command::fake::FakeCommandRunner;
+        use crate::executor::Executor;
+        use crate::package_manager::XbpsPackageManager;
+        use crate::planner::Planner;
+        use crate::system::{LocalSource, Package, System};
+
+        let root = crate::test_util::scratch_dir("plan-and-apply-vp-repo");
+        let checkout = root.join("void-packages");
+        fs::create_dir_all(&checkout).unwrap();
+        fs::write(checkout.join("xbps-src"), "#!/bin/sh\n").unwrap();
+        let personal = PackageRepository {
+            name: Some(Rc::from("personal")),
+            location: Source::Local(LocalSource::Directory(checkout.clone())),
+            allow_restricted: false,
+        };
+        let config = System {
+            repositories: HashMap::from([(Rc::from("personal"), personal.clone())]),
+            packages: HashMap::from([
+                (Rc::from("nano"), Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) }),
+                (Rc::from("st"), Package { config: BTreeMap::new(), version: None, hold: None, repository: personal.location.clone() }),
+            ]),
+            ..Default::default()
+        };
+
+        let plan = Planner::new(&config, &System::default(), root.clone()).plan().unwrap();
+        let runner = Rc::new(FakeCommandRunner::default());
+        let mut executor = Executor::new(root.clone(), Box::new(XbpsPackageManager::new(runner.clone())))
+            .with_runner(runner.clone());
+        executor.apply(plan.actions()).unwrap();
+
+        let xbps_src = checkout.join("xbps-src");
+        assert_eq!(runner.calls(), vec![
+            String::from("xbps-install -y nano"),
+            format!("{} binary-bootstrap", xbps_src.display()),
+            format!("{} pkg st", xbps_src.display()),
+            format!("xbps-install -y -R {} st", checkout.join("hostdir/binpkgs").display()),
+        ]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
 }
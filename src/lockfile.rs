@@ -0,0 +1,394 @@
+//! Lockfiles, which pin a configuration to exact package builds and source commits.
+//!
+//! `svsm freeze-pkgs` writes `<config>.lock` next to the configuration. While it exists,
+//! planning installs exactly the builds it records, and git sources are checked out at the
+//! recorded commits, so every machine applying the same configuration ends up the same.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::builder::{template_version, BuildError};
+use crate::checkout::{CheckoutError, Checkouts};
+use crate::command::{CommandError, CommandRunner};
+use crate::package_manager::{PackageManager, PackageManagerError};
+use crate::probe::repository_source;
+use crate::system::{LocalSource, RemoteSource, Source, System};
+
+/// Lockfiles before version 2 don't record where each build comes from.
+pub(crate) const LOCKFILE_FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub(crate) struct Lockfile {
+    pub(crate) format_version: u32,
+    pub(crate) packages: BTreeMap<String, LockedPackage>,
+    pub(crate) sources: Vec<LockedSource>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub(crate) struct LockedPackage {
+    pub(crate) pkgver: String,
+    /// Where the build comes from: the Void repository offering it, or the vp_repo it is
+    /// built from.
+    pub(crate) repository: Source,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub(crate) struct LockedSource {
+    pub(crate) source: Source,
+    pub(crate) commit: String,
+}
+
+#[derive(Debug)]
+pub enum LockfileError {
+    Io(io::Error),
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u64),
+    Package(PackageManagerError),
+    Command(CommandError),
+    Checkout(CheckoutError),
+    Build(BuildError),
+    UnknownPackage(String),
+    UnresolvedSource(String),
+}
+
+impl Display for LockfileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockfileError::Io(err) => write!(f, "{}", err),
+            LockfileError::Malformed(err) => write!(f, "Lockfile is malformed: {}", err),
+            LockfileError::UnsupportedVersion(version) => write!(f, "Lockfile format version {} is not supported (expected {})", version, LOCKFILE_FORMAT_VERSION),
+            LockfileError::Package(err) => write!(f, "{}", err),
+            LockfileError::Command(err) => write!(f, "{}", err),
+            LockfileError::Checkout(err) => write!(f, "{}", err),
+            LockfileError::Build(err) => write!(f, "{}", err),
+            LockfileError::UnknownPackage(name) => write!(f, "Package {} is neither installed nor available from any repository", name),
+            LockfileError::UnresolvedSource(source) => write!(f, "Unable to resolve a commit for {}", source),
+        }
+    }
+}
+
+impl std::error::Error for LockfileError {}
+
+impl From<io::Error> for LockfileError {
+    fn from(err: io::Error) -> Self {
+        LockfileError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for LockfileError {
+    fn from(err: serde_json::Error) -> Self {
+        LockfileError::Malformed(err)
+    }
+}
+
+impl From<PackageManagerError> for LockfileError {
+    fn from(err: PackageManagerError) -> Self {
+        LockfileError::Package(err)
+    }
+}
+
+impl From<CommandError> for LockfileError {
+    fn from(err: CommandError) -> Self {
+        LockfileError::Command(err)
+    }
+}
+
+impl From<CheckoutError> for LockfileError {
+    fn from(err: CheckoutError) -> Self {
+        LockfileError::Checkout(err)
+    }
+}
+
+impl From<BuildError> for LockfileError {
+    fn from(err: BuildError) -> Self {
+        LockfileError::Build(err)
+    }
+}
+
+impl Lockfile {
+    /// The lockfile belonging to the configuration at `config_location`.
+    pub(crate) fn location_for(config_location: &Path) -> PathBuf {
+        let mut location = config_location.as_os_str().to_owned();
+        location.push(".lock");
+        PathBuf::from(location)
+    }
+
+    /// Resolves every package and git source in `config`.
+    ///
+    /// Git sources are locked to the commit their branch currently points at. Installed
+    /// packages are locked to the installed build, packages from the Void repositories to the
+    /// newest build they offer, and packages from vp_repos to the build their template makes
+    /// at the locked commit, checked out with `checkouts`.
+    pub(crate) fn freeze<R: CommandRunner>(config: &System, package_manager: &dyn PackageManager, runner: &dyn CommandRunner, checkouts: &Checkouts<R>) -> Result<Lockfile, LockfileError> {
+        let sources = config.git_sources()
+            .into_iter()
+            .map(|source| Ok(LockedSource {
+                commit: resolve_commit(source, runner)?,
+                source: source.clone(),
+            }))
+            .collect::<Result<Vec<LockedSource>, LockfileError>>()?;
+        let mut lockfile = Lockfile { format_version: LOCKFILE_FORMAT_VERSION, packages: BTreeMap::new(), sources };
+
+        let installed = package_manager
+            .installed()?
+            .into_iter()
+            .map(|package| (package.name.clone(), package))
+            .collect::<BTreeMap<String, _>>();
+        for (name, package) in config.all_packages() {
+            let installed = installed.get(name.as_ref()).map(|installed| installed.pkgver.clone());
+            let locked = match &package.repository {
+                Source::Remote(RemoteSource::VoidRepo) => {
+                    let remote = package_manager.query_remote(name)?;
+                    match (installed, remote) {
+                        (Some(pkgver), Some(remote)) if remote.pkgver == pkgver => LockedPackage { pkgver, repository: repository_source(&remote.repository) },
+                        // The installed build isn't offered anymore, so it can only have come from the default repositories.
+                        (Some(pkgver), _) => LockedPackage { pkgver, repository: Source::Remote(RemoteSource::VoidRepo) },
+                        (None, Some(remote)) => LockedPackage { pkgver: remote.pkgver, repository: repository_source(&remote.repository) },
+                        (None, None) => return Err(LockfileError::UnknownPackage(name.to_string())),
+                    }
+                }
+                vp_repo => {
+                    let pkgver = match installed {
+                        Some(pkgver) => pkgver,
+                        None => {
+                            let checkout = match vp_repo {
+                                Source::Local(LocalSource::Directory(checkout)) => checkout.clone(),
+                                source => checkouts.fetch(source, lockfile.commit_for(source))?,
+                            };
+                            match template_version(&checkout, name)? {
+                                Some(version) => format!("{}-{}", name, version),
+                                None => return Err(LockfileError::UnknownPackage(name.to_string())),
+                            }
+                        }
+                    };
+                    LockedPackage { pkgver, repository: vp_repo.clone() }
+                }
+            };
+            lockfile.packages.insert(name.to_string(), locked);
+        }
+
+        Ok(lockfile)
+    }
+
+    /// The locked version and revision of `name`, such as `118.0.1_2`.
+    pub(crate) fn version_of(&self, name: &str) -> Option<&str> {
+        self.packages
+            .get(name)
+            .and_then(|package| package.pkgver.strip_prefix(name))
+            .and_then(|version| version.strip_prefix('-'))
+    }
+
+    /// Where the locked build of `name` comes from.
+    pub(crate) fn repository_of(&self, name: &str) -> Option<&Source> {
+        self.packages.get(name).map(|package| &package.repository)
+    }
+
+    /// The commit `source` is locked to, if it is one of the locked sources.
+    pub(crate) fn commit_for(&self, source: &Source) -> Option<&str> {
+        self.sources
+            .iter()
+            .find(|locked| &locked.source == source)
+            .map(|locked| locked.commit.as_str())
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Lockfiles are always serializable") + "\n"
+    }
+
+    pub(crate) fn from_json(json: &str) -> Result<Lockfile, LockfileError> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        match value.get("format_version").and_then(serde_json::Value::as_u64) {
+            Some(version) if version == LOCKFILE_FORMAT_VERSION as u64 => Ok(serde_json::from_value(value)?),
+            Some(version) => Err(LockfileError::UnsupportedVersion(version)),
+            None => Ok(serde_json::from_value(value)?),
+        }
+    }
+
+    pub(crate) fn write_to(&self, path: &Path) -> Result<(), LockfileError> {
+        Ok(fs::write(path, self.to_json())?)
+    }
+
+    /// Reads the lockfile at `path`, if there is one.
+    pub(crate) fn read_from(path: &Path) -> Result<Option<Lockfile>, LockfileError> {
+        match fs::read_to_string(path) {
+            Ok(json) => Ok(Some(Lockfile::from_json(&json)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Asks the remote which commit the source's branch (or `HEAD`) points at.
+fn resolve_commit(source: &Source, runner: &dyn CommandRunner) -> Result<String, LockfileError> {
+    let (url, branch) = source.git_remote().expect("Only git sources are resolved");
+    let reference = match branch {
+        Some(branch) => format!("refs/heads/{}", branch),
+        None => String::from("HEAD"),
+    };
+
+    // Lines look like `<commit>\t<reference>`.
+    runner
+        .run_checked("git", &["ls-remote", &url, &reference])?
+        .stdout
+        .lines()
+        .find_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .ok_or_else(|| LockfileError::UnresolvedSource(source.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use crate::command::fake::FakeCommandRunner;
+    use crate::package_manager::FakePackageManager;
+    use crate::system::{Dotfiles, DotfileDeployment, HomeDirectory, Package, PackageRepository, User};
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    fn config() -> System {
+        let void = Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) };
+        let vpkgs = Source::Remote(RemoteSource::GithubRemote {
+            user: Rc::from("sapein"),
+            repository_name: Rc::from("void-packages"),
+            branch_name: Some(Rc::from("personal")),
+        });
+        System {
+            repositories: HashMap::from([(Rc::from("personal"), PackageRepository {
+                name: Some(Rc::from("personal")),
+                location: vpkgs,
+                allow_restricted: false,
+            })]),
+            users: HashMap::from([(Rc::from("sapeint"), User {
+                username: Some(Rc::from("sapeint")),
+                homedir: HomeDirectory::Path { location: PathBuf::from("/home/sapeint"), subdirs: vec![], mode: None, skel: false },
+                dotfiles: Some(Dotfiles {
+                    source: Source::Remote(RemoteSource::GitRemote {
+                        url: Rc::from("file:///srv/dotfiles"),
+                        branch_name: None,
+                    }),
+                    exclude: vec![],
+                    deploy: DotfileDeployment::Copy,
+                }),
+                packages: HashMap::from([(Rc::from("firefox"), void.clone())]),
+                ..Default::default()
+            })]),
+            packages: HashMap::from([(Rc::from("nano"), void)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut package_manager = FakePackageManager::default()
+            .with_available("firefox-118.0.1_2", "https://repo-default.voidlinux.org/current")
+            .with_available("nano-7.2_1", "https://repo-default.voidlinux.org/current");
+        package_manager.install(&["nano"], None).unwrap();
+        let package_manager = package_manager
+            .with_available("nano-7.3_1", "https://repo-default.voidlinux.org/current");
+
+        let runner = FakeCommandRunner::default();
+        runner.respond(0, "fedcba9876543210fedcba9876543210fedcba98\trefs/heads/personal\n");
+        runner.respond(0, "0123456789abcdef0123456789abcdef01234567\tHEAD\n");
+
+        let checkouts = Checkouts::new(FakeCommandRunner::default(), PathBuf::from("/nonexistent"));
+        let lockfile = Lockfile::freeze(&config(), &package_manager, &runner, &checkouts).unwrap();
+        // The installed nano isn't the build the repository offers anymore.
+        assert_eq!(lockfile.packages, BTreeMap::from([
+            (String::from("firefox"), LockedPackage {
+                pkgver: String::from("firefox-118.0.1_2"),
+                repository: Source::Remote(RemoteSource::VoidRemote(Rc::from("https://repo-default.voidlinux.org/current"))),
+            }),
+            (String::from("nano"), LockedPackage {
+                pkgver: String::from("nano-7.2_1"),
+                repository: Source::Remote(RemoteSource::VoidRepo),
+            }),
+        ]));
+        assert_eq!(runner.calls(), vec![
+            "git ls-remote https://github.com/sapein/void-packages.git refs/heads/personal",
+            "git ls-remote file:///srv/dotfiles HEAD",
+        ]);
+        assert_eq!(lockfile.sources[0].commit, "fedcba9876543210fedcba9876543210fedcba98");
+        assert_eq!(lockfile.sources[1].source, config().users[&Rc::from("sapeint")].dotfiles.clone().unwrap().source);
+        assert_eq!(lockfile.sources[1].commit, "0123456789abcdef0123456789abcdef01234567");
+        assert_eq!(lockfile.version_of("firefox"), Some("118.0.1_2"));
+        assert_eq!(lockfile.version_of("dmenu"), None);
+        assert_eq!(lockfile.commit_for(&lockfile.sources[0].source.clone()), Some("fedcba9876543210fedcba9876543210fedcba98"));
+        assert_eq!(lockfile.commit_for(&Source::Remote(RemoteSource::VoidRepo)), None);
+    }
+
+    #[test]
+    fn test_freeze_unknown_package() {
+        let package_manager = FakePackageManager::default();
+        let runner = FakeCommandRunner::default();
+        runner.respond(0, "fedcba9876543210fedcba9876543210fedcba98\trefs/heads/personal\n");
+        runner.respond(0, "0123456789abcdef0123456789abcdef01234567\tHEAD\n");
+
+        let checkouts = Checkouts::new(FakeCommandRunner::default(), PathBuf::from("/nonexistent"));
+        assert!(matches!(Lockfile::freeze(&config(), &package_manager, &runner, &checkouts), Err(LockfileError::UnknownPackage(_))));
+    }
+
+    #[test]
+    fn test_freeze_vp_repo_packages() {
+        let root = scratch_dir("lockfile-vp-repo");
+        let local = PackageRepository {
+            name: Some(Rc::from("local")),
+            location: Source::Local(LocalSource::Directory(root.join("void-packages"))),
+            allow_restricted: false,
+        };
+        let mut config = config();
+        config.repositories.insert(Rc::from("local"), local.clone());
+        let personal = config.repositories[&Rc::from("personal")].location.clone();
+        config.packages = HashMap::from([
+            (Rc::from("discord"), Package { config: BTreeMap::new(), version: None, hold: None, repository: personal.clone() }),
+            (Rc::from("st"), Package { config: BTreeMap::new(), version: None, hold: None, repository: local.location.clone() }),
+        ]);
+        config.users.clear();
+
+        let runner = Rc::new(FakeCommandRunner::default());
+        let checkouts = Checkouts::new(runner.clone(), root.join("sources"));
+        let checkout = checkouts.directory_for(&personal).unwrap();
+        for (directory, package, template) in [(&checkout, "discord", "version=0.0.30\nrevision=1\n"), (&root.join("void-packages"), "st", "pkgname=st\nversion='0.9'\nrevision=2\n")] {
+            fs::create_dir_all(directory.join("srcpkgs").join(package)).unwrap();
+            fs::write(directory.join("srcpkgs").join(package).join("template"), template).unwrap();
+        }
+        runner.respond(0, "fedcba9876543210fedcba9876543210fedcba98\trefs/heads/personal\n");
+
+        // Neither is built yet, so each is locked to what its template builds at the locked commit.
+        let lockfile = Lockfile::freeze(&config, &FakePackageManager::default(), runner.as_ref(), &checkouts).unwrap();
+        assert_eq!(lockfile.packages, BTreeMap::from([
+            (String::from("discord"), LockedPackage { pkgver: String::from("discord-0.0.30_1"), repository: personal }),
+            (String::from("st"), LockedPackage { pkgver: String::from("st-0.9_2"), repository: local.location.clone() }),
+        ]));
+        assert_eq!(runner.calls().last().unwrap(), &format!("git -C {} checkout --quiet --detach fedcba9876543210fedcba9876543210fedcba98", checkout.display()));
+
+        config.packages.insert(Rc::from("slstatus"), Package { config: BTreeMap::new(), version: None, hold: None, repository: local.location });
+        runner.respond(0, "fedcba9876543210fedcba9876543210fedcba98\trefs/heads/personal\n");
+        assert!(matches!(Lockfile::freeze(&config, &FakePackageManager::default(), runner.as_ref(), &checkouts), Err(LockfileError::UnknownPackage(name)) if name == "slstatus"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let lockfile = Lockfile {
+            format_version: LOCKFILE_FORMAT_VERSION,
+            packages: BTreeMap::from([(String::from("nano"), LockedPackage {
+                pkgver: String::from("nano-7.2_1"),
+                repository: Source::Remote(RemoteSource::VoidRemote(Rc::from("https://repo-default.voidlinux.org/current"))),
+            })]),
+            sources: vec![LockedSource {
+                source: Source::Remote(RemoteSource::GitRemote { url: Rc::from("file:///srv/dotfiles"), branch_name: None }),
+                commit: String::from("0123456789abcdef0123456789abcdef01234567"),
+            }],
+        };
+
+        assert_eq!(Lockfile::from_json(&lockfile.to_json()).unwrap(), lockfile);
+        assert!(matches!(Lockfile::from_json("{\"format_version\": 99}"), Err(LockfileError::UnsupportedVersion(99))));
+        assert!(matches!(Lockfile::from_json("{\"format_version\": 1}"), Err(LockfileError::UnsupportedVersion(1))));
+        assert_eq!(Lockfile::location_for(Path::new("/etc/svsm/config.vsm")), PathBuf::from("/etc/svsm/config.vsm.lock"));
+    }
+}
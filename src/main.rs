@@ -1,2 +1,3 @@
 fn main() {
+    std::process::exit(svsm::cli::run(std::env::args().skip(1).collect()));
 }
@@ -0,0 +1,368 @@
+//! Package management for SVSM.
+//!
+//! [`PackageManager`] is the interface SVSM uses to install and query packages.
+//! [`XbpsPackageManager`] implements it with the XBPS tools, and [`FakePackageManager`]
+//! keeps everything in memory so that applying a plan can be tested without XBPS.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use crate::command::{CommandError, CommandRunner};
+
+/// A package installed on the system.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub pkgver: String,
+    pub mode: InstallMode,
+    /// Held packages are kept at their installed version by updates.
+    pub held: bool,
+}
+
+/// A package available from one of the configured repositories.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RemotePackage {
+    pub name: String,
+    pub pkgver: String,
+    pub repository: String,
+}
+
+/// Whether a package was installed explicitly, or only as a dependency of another.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InstallMode {
+    Manual,
+    Automatic,
+}
+
+#[derive(Debug)]
+pub enum PackageManagerError {
+    Command(CommandError),
+    UnknownPackage(String),
+    NotInstalled(String),
+}
+
+impl Display for PackageManagerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackageManagerError::Command(err) => write!(f, "{}", err),
+            PackageManagerError::UnknownPackage(name) => write!(f, "Package {} was not found in any repository", name),
+            PackageManagerError::NotInstalled(name) => write!(f, "Package {} is not installed", name),
+        }
+    }
+}
+
+impl std::error::Error for PackageManagerError {}
+
+impl From<CommandError> for PackageManagerError {
+    fn from(err: CommandError) -> Self {
+        PackageManagerError::Command(err)
+    }
+}
+
+pub trait PackageManager {
+    /// Installs `packages`, optionally from a specific repository.
+    fn install(&mut self, packages: &[&str], repository: Option<&str>) -> Result<(), PackageManagerError>;
+
+    fn remove(&mut self, packages: &[&str]) -> Result<(), PackageManagerError>;
+
+    fn installed(&self) -> Result<Vec<InstalledPackage>, PackageManagerError>;
+
+    /// Looks up a package in the repository index, returning `None` if it isn't available.
+    fn query_remote(&self, name: &str) -> Result<Option<RemotePackage>, PackageManagerError>;
+
+    /// Synchronizes the local copy of the repository index.
+    fn sync_index(&mut self) -> Result<(), PackageManagerError>;
+
+    fn set_mode(&mut self, package: &str, mode: InstallMode) -> Result<(), PackageManagerError>;
+
+    /// Holds an installed package at its current version, or releases the hold.
+    fn set_held(&mut self, package: &str, held: bool) -> Result<(), PackageManagerError>;
+}
+
+/// Splits a `pkgver` such as `firefox-118.0.1_2` into its name.
+pub(crate) fn pkgver_name(pkgver: &str) -> &str {
+    match pkgver.rfind('-') {
+        Some(index) => &pkgver[..index],
+        None => pkgver,
+    }
+}
+
+/// Manages packages with `xbps-install`, `xbps-remove`, `xbps-query` and `xbps-pkgdb`.
+pub struct XbpsPackageManager<R: CommandRunner> {
+    runner: R,
+    root: Option<PathBuf>,
+}
+
+impl<R: CommandRunner> XbpsPackageManager<R> {
+    pub fn new(runner: R) -> Self {
+        XbpsPackageManager { runner, root: None }
+    }
+
+    /// Operates on the system rooted at `root` rather than the running system.
+    pub fn with_root(self, root: PathBuf) -> Self {
+        XbpsPackageManager { root: Some(root), ..self }
+    }
+
+    fn run(&self, program: &str, args: &[&str]) -> Result<String, CommandError> {
+        let root = self.root.as_ref().map(|root| root.to_string_lossy().to_string());
+        let mut full_args = match &root {
+            Some(root) => vec!["-r", root.as_str()],
+            None => vec![],
+        };
+        full_args.extend_from_slice(args);
+        Ok(self.runner.run_checked(program, &full_args)?.stdout)
+    }
+}
+
+impl<R: CommandRunner> PackageManager for XbpsPackageManager<R> {
+    fn install(&mut self, packages: &[&str], repository: Option<&str>) -> Result<(), PackageManagerError> {
+        let mut args = vec!["-y"];
+        if let Some(repository) = repository {
+            args.extend_from_slice(&["-R", repository]);
+        }
+        args.extend_from_slice(packages);
+        self.run("xbps-install", &args)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, packages: &[&str]) -> Result<(), PackageManagerError> {
+        let mut args = vec!["-y"];
+        args.extend_from_slice(packages);
+        self.run("xbps-remove", &args)?;
+        Ok(())
+    }
+
+    fn installed(&self) -> Result<Vec<InstalledPackage>, PackageManagerError> {
+        let manual = self.run("xbps-query", &["-m"])?;
+        let manual = manual.lines().map(str::trim).collect::<Vec<&str>>();
+        let held = self.run("xbps-query", &["-H"])?;
+        let held = held.lines().map(str::trim).collect::<Vec<&str>>();
+
+        // Lines look like `ii firefox-118.0.1_2    Lightweight gecko-based web browser`.
+        Ok(self.run("xbps-query", &["-l"])?
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(1))
+            .map(|pkgver| InstalledPackage {
+                name: pkgver_name(pkgver).to_string(),
+                pkgver: pkgver.to_string(),
+                mode: match manual.contains(&pkgver) {
+                    true => InstallMode::Manual,
+                    false => InstallMode::Automatic,
+                },
+                held: held.contains(&pkgver),
+            })
+            .collect())
+    }
+
+    fn query_remote(&self, name: &str) -> Result<Option<RemotePackage>, PackageManagerError> {
+        match self.run("xbps-query", &["-R", "-p", "pkgver,repository", name]) {
+            Ok(output) => {
+                let mut lines = output.lines().map(str::trim);
+                match (lines.next(), lines.next()) {
+                    (Some(pkgver), Some(repository)) if !pkgver.is_empty() => Ok(Some(RemotePackage {
+                        name: name.to_string(),
+                        pkgver: pkgver.to_string(),
+                        repository: repository.to_string(),
+                    })),
+                    _ => Ok(None),
+                }
+            }
+            // xbps-query exits with ENOENT when the package doesn't exist.
+            Err(CommandError::Failed { status: 2, .. }) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn sync_index(&mut self) -> Result<(), PackageManagerError> {
+        self.run("xbps-install", &["-S"])?;
+        Ok(())
+    }
+
+    fn set_mode(&mut self, package: &str, mode: InstallMode) -> Result<(), PackageManagerError> {
+        let mode = match mode {
+            InstallMode::Manual => "manual",
+            InstallMode::Automatic => "auto",
+        };
+        self.run("xbps-pkgdb", &["-m", mode, package])?;
+        Ok(())
+    }
+
+    fn set_held(&mut self, package: &str, held: bool) -> Result<(), PackageManagerError> {
+        let mode = match held {
+            true => "hold",
+            false => "unhold",
+        };
+        self.run("xbps-pkgdb", &["-m", mode, package])?;
+        Ok(())
+    }
+}
+
+/// Keeps the installed packages and repository index in memory.
+#[derive(Debug, Default)]
+pub struct FakePackageManager {
+    pub installed: BTreeMap<String, InstalledPackage>,
+    pub available: BTreeMap<String, RemotePackage>,
+    pub syncs: usize,
+}
+
+impl FakePackageManager {
+    pub fn with_available(mut self, pkgver: &str, repository: &str) -> Self {
+        let name = pkgver_name(pkgver).to_string();
+        self.available.insert(name.clone(), RemotePackage {
+            name,
+            pkgver: pkgver.to_string(),
+            repository: repository.to_string(),
+        });
+        self
+    }
+}
+
+impl PackageManager for FakePackageManager {
+    fn install(&mut self, packages: &[&str], repository: Option<&str>) -> Result<(), PackageManagerError> {
+        for package in packages {
+            // Packages may be requested by name or, like XBPS allows, by exact pkgver.
+            let remote = self.available
+                .get(*package)
+                .or_else(|| self.available.values().find(|remote| remote.pkgver == *package))
+                .filter(|remote| repository.is_none() || repository == Some(remote.repository.as_str()))
+                .ok_or_else(|| PackageManagerError::UnknownPackage(package.to_string()))?;
+
+            self.installed.insert(remote.name.clone(), InstalledPackage {
+                name: remote.name.clone(),
+                pkgver: remote.pkgver.clone(),
+                mode: InstallMode::Manual,
+                held: false,
+            });
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, packages: &[&str]) -> Result<(), PackageManagerError> {
+        for package in packages {
+            self.installed
+                .remove(*package)
+                .ok_or_else(|| PackageManagerError::NotInstalled(package.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn installed(&self) -> Result<Vec<InstalledPackage>, PackageManagerError> {
+        Ok(self.installed.values().cloned().collect())
+    }
+
+    fn query_remote(&self, name: &str) -> Result<Option<RemotePackage>, PackageManagerError> {
+        Ok(self.available.get(name).cloned())
+    }
+
+    fn sync_index(&mut self) -> Result<(), PackageManagerError> {
+        self.syncs += 1;
+        Ok(())
+    }
+
+    fn set_mode(&mut self, package: &str, mode: InstallMode) -> Result<(), PackageManagerError> {
+        match self.installed.get_mut(package) {
+            Some(installed) => {
+                installed.mode = mode;
+                Ok(())
+            }
+            None => Err(PackageManagerError::NotInstalled(package.to_string())),
+        }
+    }
+
+    fn set_held(&mut self, package: &str, held: bool) -> Result<(), PackageManagerError> {
+        match self.installed.get_mut(package) {
+            Some(installed) => {
+                installed.held = held;
+                Ok(())
+            }
+            None => Err(PackageManagerError::NotInstalled(package.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use crate::command::fake::FakeCommandRunner;
+    use super::*;
+
+    #[test]
+    fn test_pkgver_name() {
+        assert_eq!(pkgver_name("firefox-118.0.1_2"), "firefox");
+        assert_eq!(pkgver_name("zathura-pdf-mupdf-0.4.1_1"), "zathura-pdf-mupdf");
+        assert_eq!(pkgver_name("firefox"), "firefox");
+    }
+
+    #[test]
+    fn test_xbps_install_and_remove() {
+        let runner = Rc::new(FakeCommandRunner::default());
+        let mut xbps = XbpsPackageManager::new(runner.clone()).with_root(PathBuf::from("/mnt"));
+
+        xbps.sync_index().unwrap();
+        xbps.install(&["firefox", "dmenu"], None).unwrap();
+        xbps.install(&["st"], Some("/var/cache/binpkgs")).unwrap();
+        xbps.remove(&["nano"]).unwrap();
+        xbps.set_mode("dmenu", InstallMode::Automatic).unwrap();
+        xbps.set_held("firefox", true).unwrap();
+
+        assert_eq!(runner.calls(), vec![
+            "xbps-install -r /mnt -S",
+            "xbps-install -r /mnt -y firefox dmenu",
+            "xbps-install -r /mnt -y -R /var/cache/binpkgs st",
+            "xbps-remove -r /mnt -y nano",
+            "xbps-pkgdb -r /mnt -m auto dmenu",
+            "xbps-pkgdb -r /mnt -m hold firefox",
+        ]);
+    }
+
+    #[test]
+    fn test_xbps_installed() {
+        let runner = Rc::new(FakeCommandRunner::default());
+        runner.respond(0, "firefox-118.0.1_2\n");
+        runner.respond(0, "glibc-2.36_1\n");
+        runner.respond(0, "ii firefox-118.0.1_2    Lightweight gecko-based web browser\n\
+                           ii glibc-2.36_1          GNU C library\n");
+        let xbps = XbpsPackageManager::new(runner.clone());
+
+        assert_eq!(xbps.installed().unwrap(), vec![
+            InstalledPackage { name: String::from("firefox"), pkgver: String::from("firefox-118.0.1_2"), mode: InstallMode::Manual, held: false },
+            InstalledPackage { name: String::from("glibc"), pkgver: String::from("glibc-2.36_1"), mode: InstallMode::Automatic, held: true },
+        ]);
+        assert_eq!(runner.calls(), vec!["xbps-query -m", "xbps-query -H", "xbps-query -l"]);
+    }
+
+    #[test]
+    fn test_xbps_query_remote() {
+        let runner = Rc::new(FakeCommandRunner::default());
+        runner.respond(0, "firefox-118.0.1_2\nhttps://repo-default.voidlinux.org/current\n");
+        runner.respond(2, "");
+        let xbps = XbpsPackageManager::new(runner.clone());
+
+        assert_eq!(xbps.query_remote("firefox").unwrap(), Some(RemotePackage {
+            name: String::from("firefox"),
+            pkgver: String::from("firefox-118.0.1_2"),
+            repository: String::from("https://repo-default.voidlinux.org/current"),
+        }));
+        assert_eq!(xbps.query_remote("nothing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_fake_package_manager() {
+        let mut fake = FakePackageManager::default()
+            .with_available("firefox-118.0.1_2", "current");
+
+        assert!(matches!(fake.install(&["nothing"], None), Err(PackageManagerError::UnknownPackage(_))));
+        assert!(matches!(fake.install(&["firefox"], Some("nonfree")), Err(PackageManagerError::UnknownPackage(_))));
+
+        fake.install(&["firefox"], None).unwrap();
+        fake.set_mode("firefox", InstallMode::Automatic).unwrap();
+        fake.set_held("firefox", true).unwrap();
+        assert_eq!(fake.installed().unwrap(), vec![
+            InstalledPackage { name: String::from("firefox"), pkgver: String::from("firefox-118.0.1_2"), mode: InstallMode::Automatic, held: true },
+        ]);
+
+        fake.remove(&["firefox"]).unwrap();
+        assert!(fake.installed().unwrap().is_empty());
+        assert!(matches!(fake.remove(&["firefox"]), Err(PackageManagerError::NotInstalled(_))));
+    }
+}
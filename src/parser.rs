@@ -614,7 +614,7 @@ impl Parser {
             Token::Dot if self.peek_token() == Token::Slash => Some(self.parse_path()),
             Token::OpenBracket => Some(self.parse_list()),
             Token::OpenBrace => Some(self.parse_map()),
-            Token::OpenParen => self.parse_parens().iter().map(|e| { e.to_owned() }).nth(1),
+            Token::OpenParen => self.parse_parens().iter().map(|e| { e.to_owned() }).nth(0),
             Token::Symbol(sym) => Some(self.parse_symbol(sym)),
             Token::CloseBrace => None,
             Token::CloseParen => None,
@@ -1017,4 +1017,17 @@ mod tests {
     pub fn test_discard_parse() {
         Parser::new(ParserInput::TokenList(Rc::new([Token::Discard]))).parse_token();
     }
+
+    #[test]
+    pub fn test_parenthesized_map_value() {
+        let mut lexer = crate::lex::Lexer::from_string("{ location = (home ./.bashrc); }");
+        let mut parser = Parser::from_token_list_smart(lexer.tokenize_input_smart());
+
+        assert_eq!(parser.parse_input().as_ref(), &[Expr::Map(BTreeMap::from([
+            (Expr::symbol_from_str("location"), Expr::FnCall(ExprFnCall {
+                name: Rc::from("home"),
+                args: vec![Expr::Path(PathBuf::from("./.bashrc"))],
+            })),
+        ]))]);
+    }
 }
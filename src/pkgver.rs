@@ -0,0 +1,368 @@
+//! XBPS package versions.
+//!
+//! XBPS identifies a package build by its `pkgver`, `<name>-<version>_<revision>`, e.g.
+//! `firefox-118.0.1_2`. Versions are ordered with the "dewey" rules XBPS inherited from
+//! NetBSD's pkg_install, and dependencies are written as patterns over those versions.
+
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PkgVerError {
+    MissingVersion(String),
+    MissingRevision(String),
+    InvalidPattern(String),
+}
+
+impl Display for PkgVerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PkgVerError::MissingVersion(pkgver) => write!(f, "{} has no version", pkgver),
+            PkgVerError::MissingRevision(pkgver) => write!(f, "{} has no revision", pkgver),
+            PkgVerError::InvalidPattern(pattern) => write!(f, "{} is not a valid dependency pattern", pattern),
+        }
+    }
+}
+
+impl std::error::Error for PkgVerError {}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct PkgVer {
+    pub name: String,
+    pub version: String,
+    pub revision: u32,
+}
+
+impl PkgVer {
+    /// The version and revision, as written after the name (`118.0.1_2`).
+    pub fn full_version(&self) -> String {
+        format!("{}_{}", self.version, self.revision)
+    }
+
+    /// Orders two builds by version, ignoring their names.
+    pub fn cmp_version(&self, other: &PkgVer) -> Ordering {
+        compare_versions(&self.full_version(), &other.full_version())
+    }
+}
+
+impl FromStr for PkgVer {
+    type Err = PkgVerError;
+
+    fn from_str(pkgver: &str) -> Result<Self, Self::Err> {
+        let (name, version) = pkgver
+            .rsplit_once('-')
+            .filter(|(name, version)| !name.is_empty() && !version.is_empty())
+            .ok_or_else(|| PkgVerError::MissingVersion(pkgver.to_string()))?;
+        let (version, revision) = version
+            .rsplit_once('_')
+            .filter(|(version, _)| !version.is_empty())
+            .and_then(|(version, revision)| Some((version, revision.parse::<u32>().ok()?)))
+            .ok_or_else(|| PkgVerError::MissingRevision(pkgver.to_string()))?;
+
+        Ok(PkgVer {
+            name: name.to_string(),
+            version: version.to_string(),
+            revision,
+        })
+    }
+}
+
+impl Display for PkgVer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}_{}", self.name, self.version, self.revision)
+    }
+}
+
+// Values of the modifiers that may appear in a version, relative to a plain `.`.
+const ALPHA: i64 = -3;
+const BETA: i64 = -2;
+const RC: i64 = -1;
+const DOT: i64 = 0;
+
+const MODIFIERS: &[(&str, i64)] = &[
+    ("alpha", ALPHA),
+    ("beta", BETA),
+    ("pre", RC),
+    ("rc", RC),
+    ("pl", DOT),
+    (".", DOT),
+];
+
+/// A version broken into comparable components, plus its revision.
+fn components(version: &str) -> (Vec<i64>, i64) {
+    let mut components = vec![];
+    let mut revision = 0;
+    let mut rest = version;
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            components.push(rest[..end].parse::<i64>().unwrap_or(i64::MAX));
+            rest = &rest[end..];
+        } else if let Some((modifier, value)) = MODIFIERS
+            .iter()
+            .find(|(modifier, _)| rest.get(..modifier.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(modifier)))
+        {
+            components.push(*value);
+            rest = &rest[modifier.len()..];
+        } else if c == '_' {
+            let end = rest[1..].find(|c: char| !c.is_ascii_digit()).map_or(rest.len(), |end| end + 1);
+            revision = rest[1..end].parse::<i64>().unwrap_or(0);
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() {
+            // A lone letter counts as a further component, so `1.0a` sorts after `1.0`.
+            components.push(DOT);
+            components.push((c.to_ascii_lowercase() as i64) - ('a' as i64) + 1);
+            rest = &rest[1..];
+        } else {
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    (components, revision)
+}
+
+/// Compares two versions (optionally followed by `_<revision>`) the way XBPS does.
+///
+/// Missing components count as zero, so `1.0` and `1.0.0` are equal, and `alpha`, `beta`
+/// and `rc`/`pre` sort before the release they precede while `pl` sorts after it.
+pub fn compare_versions(left: &str, right: &str) -> Ordering {
+    let (left, left_revision) = components(left);
+    let (right, right_revision) = components(right);
+
+    (0..left.len().max(right.len()))
+        .map(|i| left.get(i).unwrap_or(&0).cmp(right.get(i).unwrap_or(&0)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or_else(|| left_revision.cmp(&right_revision))
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Operator {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Operator {
+    fn allows(&self, ordering: Ordering) -> bool {
+        match self {
+            Operator::Less => ordering.is_lt(),
+            Operator::LessEqual => ordering.is_le(),
+            Operator::Greater => ordering.is_gt(),
+            Operator::GreaterEqual => ordering.is_ge(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Constraint {
+    /// Any version, e.g. `foo` or `foo-[0-9]*`.
+    Any,
+    /// Exactly one build, e.g. `foo-1.2_1`.
+    Exact(String),
+    /// Every bound must hold, e.g. `foo>=1.2_1<2.0_1`.
+    Range(Vec<(Operator, String)>),
+}
+
+/// A dependency pattern such as `glibc>=2.36_1`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Dependency {
+    pub name: String,
+    pub constraint: Constraint,
+}
+
+impl Dependency {
+    pub fn matches(&self, pkgver: &PkgVer) -> bool {
+        if pkgver.name != self.name {
+            return false;
+        }
+
+        let version = pkgver.full_version();
+        match &self.constraint {
+            Constraint::Any => true,
+            Constraint::Exact(exact) => compare_versions(&version, exact).is_eq(),
+            Constraint::Range(bounds) => bounds
+                .iter()
+                .all(|(operator, bound)| operator.allows(compare_versions(&version, bound))),
+        }
+    }
+}
+
+impl FromStr for Dependency {
+    type Err = PkgVerError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let invalid = || PkgVerError::InvalidPattern(pattern.to_string());
+
+        if let Some(start) = pattern.find(['<', '>']) {
+            let name = &pattern[..start];
+            let mut bounds = vec![];
+            let mut rest = &pattern[start..];
+            while !rest.is_empty() {
+                let (operator, length) = match rest {
+                    _ if rest.starts_with(">=") => (Operator::GreaterEqual, 2),
+                    _ if rest.starts_with("<=") => (Operator::LessEqual, 2),
+                    _ if rest.starts_with('>') => (Operator::Greater, 1),
+                    _ if rest.starts_with('<') => (Operator::Less, 1),
+                    _ => return Err(invalid()),
+                };
+                rest = &rest[length..];
+                let end = rest.find(['<', '>']).unwrap_or(rest.len());
+                if end == 0 {
+                    return Err(invalid());
+                }
+                bounds.push((operator, rest[..end].to_string()));
+                rest = &rest[end..];
+            }
+
+            if name.is_empty() || name.contains('=') {
+                return Err(invalid());
+            }
+            return Ok(Dependency { name: name.to_string(), constraint: Constraint::Range(bounds) });
+        }
+
+        if let Some(name) = pattern.strip_suffix("-[0-9]*") {
+            return Ok(Dependency { name: name.to_string(), constraint: Constraint::Any });
+        }
+        if pattern.contains(['*', '?', '[']) {
+            return Err(invalid());
+        }
+
+        match pattern.parse::<PkgVer>() {
+            Ok(pkgver) => Ok(Dependency { constraint: Constraint::Exact(pkgver.full_version()), name: pkgver.name }),
+            Err(_) if !pattern.is_empty() => Ok(Dependency { name: pattern.to_string(), constraint: Constraint::Any }),
+            Err(_) => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pkgver() {
+        assert_eq!("firefox-118.0.1_2".parse::<PkgVer>(), Ok(PkgVer {
+            name: String::from("firefox"),
+            version: String::from("118.0.1"),
+            revision: 2,
+        }));
+        assert_eq!("xorg-server-xwayland-23.2.1_1".parse::<PkgVer>().unwrap().name, "xorg-server-xwayland");
+        assert_eq!("gtk+3-3.24.38_1".parse::<PkgVer>().unwrap().version, "3.24.38");
+        assert_eq!("firefox-esr-115.3.1esr_1".parse::<PkgVer>().unwrap().to_string(), "firefox-esr-115.3.1esr_1");
+
+        assert_eq!("firefox".parse::<PkgVer>(), Err(PkgVerError::MissingVersion(String::from("firefox"))));
+        assert_eq!("-1.0_1".parse::<PkgVer>(), Err(PkgVerError::MissingVersion(String::from("-1.0_1"))));
+        assert_eq!("firefox-118.0".parse::<PkgVer>(), Err(PkgVerError::MissingRevision(String::from("firefox-118.0"))));
+        assert_eq!("firefox-118.0_a".parse::<PkgVer>(), Err(PkgVerError::MissingRevision(String::from("firefox-118.0_a"))));
+        assert_eq!("firefox-_1".parse::<PkgVer>(), Err(PkgVerError::MissingRevision(String::from("firefox-_1"))));
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        use Ordering::*;
+
+        let table = [
+            ("1.0", "1.0", Equal),
+            ("1.0", "1.0.0", Equal),
+            ("1.0_1", "1.0.0_1", Equal),
+            ("1.0", "1.1", Less),
+            ("1.10", "1.9", Greater),
+            ("118.0.1", "118.0", Greater),
+            ("2.0", "10.0", Less),
+            ("1.0_1", "1.0_2", Less),
+            ("1.0_10", "1.0_9", Greater),
+            ("1.1_1", "1.0_9", Greater),
+            ("1.0", "1.0_0", Equal),
+            ("1.0alpha1", "1.0", Less),
+            ("1.0beta1", "1.0", Less),
+            ("1.0rc1", "1.0", Less),
+            ("1.0pre1", "1.0", Less),
+            ("1.0pl1", "1.0", Greater),
+            ("1.0alpha2", "1.0beta1", Less),
+            ("1.0beta2", "1.0rc1", Less),
+            ("1.0rc1", "1.0pre1", Equal),
+            ("1.0rc1", "1.0rc2", Less),
+            ("1.0RC1", "1.0rc1", Equal),
+            ("1.0pl1", "1.0.1", Equal),
+            ("1.0a", "1.0", Greater),
+            ("1.0a", "1.0b", Less),
+            ("1.0b", "1.0.2", Equal),
+            ("1.0alpha", "0.9", Greater),
+            ("115.3.1esr", "115.3.1", Greater),
+            ("2023.10.01", "2023.9.30", Greater),
+        ];
+
+        for (left, right, ordering) in table {
+            assert_eq!(compare_versions(left, right), ordering, "{} vs {}", left, right);
+            assert_eq!(compare_versions(right, left), ordering.reverse(), "{} vs {}", right, left);
+        }
+    }
+
+    #[test]
+    fn test_pkgver_cmp_version() {
+        let old = "firefox-118.0_1".parse::<PkgVer>().unwrap();
+        let new = "firefox-118.0.1_1".parse::<PkgVer>().unwrap();
+        let rebuilt = "firefox-118.0.1_2".parse::<PkgVer>().unwrap();
+
+        assert_eq!(old.cmp_version(&new), Ordering::Less);
+        assert_eq!(rebuilt.cmp_version(&new), Ordering::Greater);
+        assert_eq!(new.cmp_version(&new), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_parse_dependency() {
+        let table = [
+            ("glibc>=2.36_1", "glibc", Constraint::Range(vec![(Operator::GreaterEqual, String::from("2.36_1"))])),
+            ("foo>1.0", "foo", Constraint::Range(vec![(Operator::Greater, String::from("1.0"))])),
+            ("foo<=1.0_3", "foo", Constraint::Range(vec![(Operator::LessEqual, String::from("1.0_3"))])),
+            ("foo>=1.0<2.0", "foo", Constraint::Range(vec![
+                (Operator::GreaterEqual, String::from("1.0")),
+                (Operator::Less, String::from("2.0")),
+            ])),
+            ("foo-1.2_1", "foo", Constraint::Exact(String::from("1.2_1"))),
+            ("gtk+3-3.24.38_1", "gtk+3", Constraint::Exact(String::from("3.24.38_1"))),
+            ("foo-[0-9]*", "foo", Constraint::Any),
+            ("foo", "foo", Constraint::Any),
+            ("xorg-server", "xorg-server", Constraint::Any),
+        ];
+
+        for (pattern, name, constraint) in table {
+            assert_eq!(pattern.parse::<Dependency>(), Ok(Dependency { name: String::from(name), constraint }), "{}", pattern);
+        }
+
+        for pattern in ["", ">=1.0", "foo>=", "foo>=1.0<", "foo=>1.0", "foo-1.*"] {
+            assert_eq!(pattern.parse::<Dependency>(), Err(PkgVerError::InvalidPattern(String::from(pattern))), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_dependency_matches() {
+        let table = [
+            ("glibc>=2.36_1", "glibc-2.36_1", true),
+            ("glibc>=2.36_1", "glibc-2.38_1", true),
+            ("glibc>=2.36_1", "glibc-2.35_4", false),
+            ("glibc>=2.36", "glibc-2.36_1", true),
+            ("foo>1.0_1", "foo-1.0_1", false),
+            ("foo>1.0_1", "foo-1.0_2", true),
+            ("foo<2.0", "foo-2.0rc1_1", true),
+            ("foo<2.0_1", "foo-2.0_1", false),
+            ("foo<=2.0_1", "foo-2.0_1", true),
+            ("foo>=1.0<2.0", "foo-1.5_1", true),
+            ("foo>=1.0<2.0", "foo-2.1_1", false),
+            ("foo-1.2_1", "foo-1.2_1", true),
+            ("foo-1.2_1", "foo-1.2_2", false),
+            ("foo-[0-9]*", "foo-0.1_1", true),
+            ("foo", "foo-99_1", true),
+            ("foo", "foobar-1.0_1", false),
+            ("foo>=1.0", "bar-2.0_1", false),
+        ];
+
+        for (pattern, pkgver, matches) in table {
+            let dependency = pattern.parse::<Dependency>().unwrap();
+            assert_eq!(dependency.matches(&pkgver.parse().unwrap()), matches, "{} matching {}", pattern, pkgver);
+        }
+    }
+}
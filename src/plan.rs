@@ -0,0 +1,375 @@
+//! Serializable plans for SVSM.
+//!
+//! A plan is the list of actions SVSM intends to perform, along with the state of the
+//! system those actions were computed against. Plans can be written out, reviewed and
+//! then applied later, at which point the recorded preconditions are checked so that
+//! a plan is never applied to a system that has changed underneath it.
+//!
+//! # Format
+//! Plans are stored as JSON with a top level `format_version`. Any change to the layout
+//! of the file must bump [`PLAN_FORMAT_VERSION`], and plans with a different version are
+//! refused rather than guessed at.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::actions::Action;
+use crate::system::{path_in_root, Package, Source};
+
+/// The version of the plan file format written by this version of SVSM.
+pub const PLAN_FORMAT_VERSION: u32 = 2;
+/// The permission bits of plan files.
+pub const PLAN_FILE_MODE: u32 = 0o600;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Plan {
+    pub(crate) format_version: u32,
+    pub(crate) preconditions: Vec<Precondition>,
+    pub(crate) actions: Vec<Action>,
+}
+
+/// Something that was true of the system when the plan was computed, and must still be
+/// true for the plan to be applied.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Precondition {
+    File {
+        path: PathBuf,
+        state: FileState,
+    },
+    /// The installed version and revision of a package the plan changes, if it is installed.
+    Package {
+        name: String,
+        version: Option<String>,
+    },
+    /// The commit a git source was checked out at. Applying checks the source out there again.
+    Source {
+        source: Source,
+        commit: String,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileState {
+    Absent,
+    Directory,
+    File {
+        sha256: String,
+    },
+    /// A symbolic link, which is never followed.
+    Symlink {
+        target: PathBuf,
+    },
+}
+
+#[derive(Debug)]
+pub enum PlanError {
+    Io(io::Error),
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u64),
+    PreconditionsFailed(Vec<PreconditionFailure>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PreconditionFailure {
+    pub expected: Precondition,
+    pub found: Precondition,
+}
+
+impl Display for PlanError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanError::Io(err) => write!(f, "Unable to access plan: {}", err),
+            PlanError::Malformed(err) => write!(f, "Plan is malformed: {}", err),
+            PlanError::UnsupportedVersion(version) => write!(f, "Plan format version {} is not supported (expected {})", version, PLAN_FORMAT_VERSION),
+            PlanError::PreconditionsFailed(failures) => {
+                writeln!(f, "The system no longer matches the plan:")?;
+                for failure in failures {
+                    writeln!(f, "  expected {}, found {}", failure.expected, failure.found)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+impl From<io::Error> for PlanError {
+    fn from(err: io::Error) -> Self {
+        PlanError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for PlanError {
+    fn from(err: serde_json::Error) -> Self {
+        PlanError::Malformed(err)
+    }
+}
+
+impl Display for Precondition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Precondition::File { path, state: FileState::Absent } => write!(f, "{} to be absent", path.display()),
+            Precondition::File { path, state: FileState::Directory } => write!(f, "{} to be a directory", path.display()),
+            Precondition::File { path, state: FileState::File { sha256 } } => write!(f, "{} to have sha256 {}", path.display(), sha256),
+            Precondition::File { path, state: FileState::Symlink { target } } => write!(f, "{} to link to {}", path.display(), target.display()),
+            Precondition::Package { name, version: Some(version) } => write!(f, "{} to be installed at {}", name, version),
+            Precondition::Package { name, version: None } => write!(f, "{} not to be installed", name),
+            Precondition::Source { source, commit } => write!(f, "{} to be at commit {}", source, commit),
+        }
+    }
+}
+
+impl FileState {
+    /// Reads the current state of `path` on the system rooted at `root`.
+    pub fn capture(root: &Path, path: &Path) -> io::Result<FileState> {
+        let location = path_in_root(root, path);
+        match fs::symlink_metadata(&location) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(FileState::Absent),
+            Err(err) => Err(err),
+            Ok(metadata) if metadata.is_dir() => Ok(FileState::Directory),
+            Ok(metadata) if metadata.is_symlink() => Ok(FileState::Symlink { target: fs::read_link(&location)? }),
+            Ok(_) => Ok(FileState::File {
+                sha256: format!("{:x}", Sha256::digest(fs::read(&location)?)),
+            }),
+        }
+    }
+}
+
+/// What preconditions are checked against besides the files of the system: its packages, and
+/// the commits its git sources are checked out at.
+pub struct Observed<'a> {
+    pub packages: &'a HashMap<Rc<str>, Package>,
+    pub commits: &'a BTreeMap<Source, String>,
+}
+
+impl Precondition {
+    /// Reads the current state of whatever this precondition describes.
+    pub fn capture_current(&self, root: &Path, observed: &Observed) -> io::Result<Precondition> {
+        match self {
+            Precondition::File { path, .. } => Ok(Precondition::File {
+                path: path.clone(),
+                state: FileState::capture(root, path)?,
+            }),
+            Precondition::Package { name, .. } => Ok(Precondition::Package {
+                name: name.clone(),
+                version: observed.packages.get(name.as_str()).and_then(|package| package.version.as_deref()).map(String::from),
+            }),
+            Precondition::Source { source, .. } => Ok(Precondition::Source {
+                source: source.clone(),
+                commit: observed.commits.get(source).cloned().unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+impl Plan {
+    pub fn new(actions: Vec<Action>, preconditions: Vec<Precondition>) -> Self {
+        Plan {
+            format_version: PLAN_FORMAT_VERSION,
+            preconditions,
+            actions,
+        }
+    }
+
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    /// The commit each git source the plan was computed against was at.
+    pub(crate) fn source_commits(&self) -> BTreeMap<&Source, &str> {
+        self.preconditions
+            .iter()
+            .filter_map(|precondition| match precondition {
+                Precondition::Source { source, commit } => Some((source, commit.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Plans are always serializable!")
+    }
+
+    /// Parses a plan, refusing any plan written in a different format version.
+    pub fn from_json(json: &str) -> Result<Plan, PlanError> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        let version = value
+            .get("format_version")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or(0);
+
+        if u32::try_from(version) != Ok(PLAN_FORMAT_VERSION) {
+            return Err(PlanError::UnsupportedVersion(version));
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Writes the plan where only its owner can read it, as it may hold password hashes and the
+    /// contents of private files.
+    pub fn write_to(&self, path: &Path) -> Result<(), PlanError> {
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(PLAN_FILE_MODE).open(path)?;
+        // An existing plan file keeps its mode when opened, as the umask may take bits away from a new one.
+        file.set_permissions(fs::Permissions::from_mode(PLAN_FILE_MODE))?;
+        file.write_all((self.to_json() + "\n").as_bytes())?;
+        Ok(())
+    }
+
+    pub fn read_from(path: &Path) -> Result<Plan, PlanError> {
+        Plan::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Checks every precondition against the system rooted at `root`.
+    pub fn verify(&self, root: &Path, observed: &Observed) -> Result<(), PlanError> {
+        let mut failures = vec![];
+        for precondition in &self.preconditions {
+            let current = precondition.capture_current(root, observed)?;
+            if &current != precondition {
+                failures.push(PreconditionFailure {
+                    expected: precondition.clone(),
+                    found: current,
+                });
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(PlanError::PreconditionsFailed(failures))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::actions::{FileSystemAction, SystemAction};
+    use crate::system::{PackageRepository, RemoteSource};
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    fn test_plan() -> Plan {
+        Plan::new(vec![
+            Action::System(SystemAction::AddRepository {
+                package_repository: PackageRepository {
+                    name: Some(Rc::from("personal")),
+                    location: Source::Remote(RemoteSource::GithubRemote {
+                        user: Rc::from("sapein"),
+                        repository_name: Rc::from("void-packages"),
+                        branch_name: Some(Rc::from("personal")),
+                    }),
+                    allow_restricted: true,
+                }
+            }),
+            Action::File(FileSystemAction::CreateFile {
+                file_location: PathBuf::from("/etc/motd"),
+                contents: Some(String::from("Hello")),
+                is_dir: false,
+                mode: None,
+                owner: None,
+                group: None,
+            }),
+        ], vec![
+            Precondition::File { path: PathBuf::from("/etc/motd"), state: FileState::Absent },
+            Precondition::Package { name: String::from("nano"), version: Some(String::from("7.2_1")) },
+            Precondition::Source { source: dotfiles(), commit: String::from("0123456789abcdef0123456789abcdef01234567") },
+        ])
+    }
+
+    fn dotfiles() -> Source {
+        Source::Remote(RemoteSource::GitRemote { url: Rc::from("file:///srv/dotfiles"), branch_name: None })
+    }
+
+    #[test]
+    fn test_plan_roundtrip() {
+        let plan = test_plan();
+        assert_eq!(Plan::from_json(&plan.to_json()).unwrap(), plan);
+    }
+
+    #[test]
+    fn test_plan_file() {
+        let root = scratch_dir("plan-file");
+        let location = root.join("plan.json");
+        fs::write(&location, "").unwrap();
+        fs::set_permissions(&location, fs::Permissions::from_mode(0o644)).unwrap();
+
+        test_plan().write_to(&location).unwrap();
+        assert_eq!(Plan::read_from(&location).unwrap(), test_plan());
+        assert_eq!(fs::metadata(&location).unwrap().permissions().mode() & 0o777, PLAN_FILE_MODE);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_format() {
+        let json: serde_json::Value = serde_json::from_str(&test_plan().to_json()).unwrap();
+
+        assert_eq!(json["format_version"], PLAN_FORMAT_VERSION);
+        assert_eq!(json["actions"][0]["system"]["add_repository"]["package_repository"]["location"]["remote"]["github_remote"]["user"], "sapein");
+        assert_eq!(json["actions"][1]["file"]["create_file"]["file_location"], "/etc/motd");
+        assert_eq!(json["preconditions"][0]["file"]["state"], "absent");
+    }
+
+    #[test]
+    fn test_plan_unsupported_version() {
+        let json = test_plan().to_json().replace("\"format_version\": 2", "\"format_version\": 1");
+
+        assert!(matches!(Plan::from_json(&json), Err(PlanError::UnsupportedVersion(1))));
+
+        // A version that only matches once truncated to 32 bits is still refused.
+        let json = test_plan().to_json().replace("\"format_version\": 2", "\"format_version\": 4294967298");
+        assert!(matches!(Plan::from_json(&json), Err(PlanError::UnsupportedVersion(4294967298))));
+    }
+
+    #[test]
+    fn test_plan_verify() {
+        let root = scratch_dir("plan-verify");
+        let plan = test_plan();
+        let nano = Package { config: BTreeMap::new(), version: Some(Rc::from("7.2_1")), hold: None, repository: Source::Remote(RemoteSource::VoidRepo) };
+        let packages = HashMap::from([(Rc::from("nano"), nano.clone())]);
+        let commits = BTreeMap::from([(dotfiles(), String::from("0123456789abcdef0123456789abcdef01234567"))]);
+        assert!(plan.verify(&root, &Observed { packages: &packages, commits: &commits }).is_ok());
+
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/motd"), "Changed").unwrap();
+        let packages = HashMap::from([(Rc::from("nano"), Package { version: Some(Rc::from("7.3_1")), ..nano })]);
+        let commits = BTreeMap::from([(dotfiles(), String::from("fedcba9876543210fedcba9876543210fedcba98"))]);
+        match plan.verify(&root, &Observed { packages: &packages, commits: &commits }) {
+            Err(PlanError::PreconditionsFailed(failures)) => {
+                assert_eq!(failures.len(), 3);
+                assert!(matches!(failures[0].found, Precondition::File { state: FileState::File { .. }, .. }));
+                assert_eq!(failures[1].found, Precondition::Package { name: String::from("nano"), version: Some(String::from("7.3_1")) });
+                assert_eq!(failures[2].found.to_string(), "file:///srv/dotfiles to be at commit fedcba9876543210fedcba9876543210fedcba98");
+            },
+            other => panic!("Expected precondition failure, got {:?}", other),
+        }
+        assert_eq!(plan.source_commits(), BTreeMap::from([(&dotfiles(), "0123456789abcdef0123456789abcdef01234567")]));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_file_state_capture() {
+        let root = scratch_dir("plan-capture");
+        fs::write(root.join("file"), "abc").unwrap();
+        std::os::unix::fs::symlink("/missing", root.join("link")).unwrap();
+
+        assert_eq!(FileState::capture(&root, Path::new("/missing")).unwrap(), FileState::Absent);
+        assert_eq!(FileState::capture(&root, Path::new("/")).unwrap(), FileState::Directory);
+        assert_eq!(FileState::capture(&root, Path::new("/file")).unwrap(), FileState::File {
+            sha256: String::from("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+        });
+        assert_eq!(FileState::capture(&root, Path::new("/link")).unwrap(), FileState::Symlink { target: PathBuf::from("/missing") });
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
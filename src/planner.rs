@@ -0,0 +1,1977 @@
+//! Computes the actions needed to bring a system to its configured state.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::actions::{Action, FileSystemAction, ServiceUser, SystemAction};
+use crate::interpreter::package_definitions::PackageDefinitions;
+use crate::lockfile::Lockfile;
+use crate::plan::{FileState, Plan, Precondition};
+use crate::repodata::RepodataCache;
+use crate::accounts::{HOME_MODE, SKEL_LOCATION, SUBDIR_MODE};
+use crate::dotfiles::{manifest, read_manifest, source_files, MANIFEST_LOCATION};
+use crate::probe::{PASSWD_LOCATION, SERVICE_LOCATION, SHELLS_LOCATION};
+use crate::service::{service_files, ServiceFile, OPTIONAL_SERVICE_FILES, USER_RUNSVDIR_LOCATION, USER_SERVICE_LOCATION};
+use crate::supervise;
+use crate::supervise::RunState;
+use crate::system::{normalise, path_in_root, Account, ConfigurationFile, ConfigurationSource, DotfileDeployment, HomeDirectory, LocalSource, OnChange, Package, PackageRepository, Password, Privilege, PrivilegeTool, RemoteSource, Service, Source, System, User};
+
+#[derive(Debug)]
+pub enum PlannerError {
+    Io(io::Error),
+    /// Packages requested from the Void repositories that none of their indexes contain.
+    UnknownPackages(Vec<String>),
+    /// Pinned builds, as pkgvers, that no configured repository provides.
+    UnavailableVersions(Vec<String>),
+    /// A file used to configure a package couldn't be read.
+    UnreadableConfiguration {
+        package: String,
+        path: PathBuf,
+        err: io::Error,
+    },
+    /// A configuration file comes from a git source that hasn't been checked out.
+    UnresolvedSource(String),
+    /// A shell given by name that neither `/etc/shells` nor the configured packages provide.
+    UnknownShell(String),
+    /// A file in a user's dotfiles couldn't be read.
+    UnreadableDotfile {
+        path: PathBuf,
+        err: io::Error,
+    },
+    /// Files that deploying dotfiles would overwrite, which SVSM didn't put there.
+    DotfileConflicts(Vec<PathBuf>),
+    /// Services to enable that have no service directory, and that neither the configuration
+    /// nor a package about to be installed provides.
+    MissingServices(Vec<String>),
+}
+
+impl Display for PlannerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlannerError::Io(err) => write!(f, "{}", err),
+            PlannerError::UnknownPackages(names) => write!(f, "No repository provides {}", names.join(", ")),
+            PlannerError::UnavailableVersions(pkgvers) => write!(f, "The pinned versions {} are not available from any configured repository", pkgvers.join(", ")),
+            PlannerError::UnreadableConfiguration { package, path, err } => write!(f, "Unable to read {} to configure {}: {}", path.display(), package, err),
+            PlannerError::UnresolvedSource(source) => write!(f, "{} has not been checked out", source),
+            PlannerError::UnknownShell(shell) => write!(f, "No shell named {} is installed or configured", shell),
+            PlannerError::UnreadableDotfile { path, err } => write!(f, "Unable to read the dotfile {}: {}", path.display(), err),
+            PlannerError::DotfileConflicts(paths) => {
+                let paths = paths.iter().map(|path| path.display().to_string()).collect::<Vec<String>>();
+                write!(f, "Deploying dotfiles would overwrite {}, which svsm didn't create; move them out of the way first", paths.join(", "))
+            }
+            PlannerError::MissingServices(names) => write!(f, "No service directory exists for {}", names.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for PlannerError {}
+
+impl From<io::Error> for PlannerError {
+    fn from(err: io::Error) -> Self {
+        PlannerError::Io(err)
+    }
+}
+
+pub struct Planner<'a> {
+    config: &'a System,
+    current: &'a System,
+    root: PathBuf,
+    repodata: Option<&'a RepodataCache>,
+    lockfile: Option<&'a Lockfile>,
+    definitions: Option<&'a PackageDefinitions>,
+    checkouts: Option<&'a BTreeMap<Source, PathBuf>>,
+    commits: Option<&'a BTreeMap<Source, String>>,
+    config_directory: PathBuf,
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(config: &'a System, current: &'a System, root: PathBuf) -> Self {
+        Planner { config, current, root, repodata: None, lockfile: None, definitions: None, checkouts: None, commits: None, config_directory: PathBuf::new() }
+    }
+
+    /// Checks requested packages against the cached repository indexes.
+    pub fn with_repodata(mut self, repodata: &'a RepodataCache) -> Self {
+        self.repodata = Some(repodata);
+        self
+    }
+
+    /// Installs the builds recorded in `lockfile` rather than the newest available.
+    pub fn with_lockfile(mut self, lockfile: &'a Lockfile) -> Self {
+        self.lockfile = Some(lockfile);
+        self
+    }
+
+    /// Uses the package definitions to tell which packages need the nonfree repository.
+    pub fn with_definitions(mut self, definitions: &'a PackageDefinitions) -> Self {
+        self.definitions = Some(definitions);
+        self
+    }
+
+    /// Reads files from git sources out of the directories they are checked out in.
+    pub fn with_checkouts(mut self, checkouts: &'a BTreeMap<Source, PathBuf>) -> Self {
+        self.checkouts = Some(checkouts);
+        self
+    }
+
+    /// Records the commit each git source is checked out at, which applying the plan checks
+    /// the source out at again.
+    pub fn with_commits(mut self, commits: &'a BTreeMap<Source, String>) -> Self {
+        self.commits = Some(commits);
+        self
+    }
+
+    /// Resolves files given to `use_file` without a source relative to `directory`, which
+    /// is the directory the configuration was read from.
+    pub fn with_config_directory(mut self, directory: PathBuf) -> Self {
+        self.config_directory = directory;
+        self
+    }
+
+    /// Computes the plan, recording the current state of everything the plan touches.
+    pub fn plan(&self) -> Result<Plan, PlannerError> {
+        self.validate_packages()?;
+        // Accounts come first, so that files and services can be given to their users.
+        let mut actions = self.account_actions()?;
+        actions.extend(self.home_actions()?);
+        actions.extend(self.privilege_actions()?);
+        actions.extend(self.actions());
+        actions.extend(self.configuration_actions()?);
+        actions.extend(self.dotfile_actions()?);
+        let mut changed = vec![];
+        collect_paths(&actions, &mut changed);
+        actions.extend(self.service_actions(&changed.into_iter().collect())?);
+        let preconditions = self.preconditions(&actions)?;
+        Ok(Plan::new(actions, preconditions))
+    }
+
+    /// Packages from the Void repositories must exist in one of their indexes, and pinned
+    /// versions that aren't installed yet must be offered by one of the cached indexes.
+    /// Packages from vp_repos are skipped, as they are built rather than downloaded.
+    fn validate_packages(&self) -> Result<(), PlannerError> {
+        let repodata = match self.repodata {
+            Some(repodata) => repodata,
+            None => return Ok(()),
+        };
+
+        let unknown = self.config.all_packages()
+            .into_iter()
+            .filter(|(_, package)| matches!(package.repository, Source::Remote(RemoteSource::VoidRepo)))
+            .filter(|(name, _)| !self.current.packages.contains_key(*name) && repodata.find(name).is_none())
+            // The nonfree index won't be cached until the repository has been enabled.
+            .filter(|(name, _)| !self.is_nonfree(name))
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<String>>();
+        if !unknown.is_empty() {
+            return Err(PlannerError::UnknownPackages(unknown));
+        }
+
+        let unavailable = self.pinned_installs()
+            .into_iter()
+            .filter(|pkgver| !repodata.indexes.iter().any(|index| index.packages.values().any(|package| &package.pkgver == pkgver)))
+            .collect::<Vec<String>>();
+        match unavailable.is_empty() {
+            true => Ok(()),
+            false => Err(PlannerError::UnavailableVersions(unavailable)),
+        }
+    }
+
+    /// The pinned versions that can't be checked because there are no repository indexes to
+    /// check them against, such as before the first sync.
+    pub fn unverified_versions(&self) -> Vec<String> {
+        match self.repodata {
+            Some(_) => vec![],
+            None => self.pinned_installs(),
+        }
+    }
+
+    /// The pinned builds, such as `nano-7.3_1`, that are about to be installed from a binary
+    /// repository.
+    fn pinned_installs(&self) -> Vec<String> {
+        self.config.all_packages()
+            .into_iter()
+            .filter(|(_, package)| !self.is_from_vp_repo(package))
+            .filter(|(name, package)| self.needs_install(name, package))
+            .filter_map(|(name, package)| self.version_for(name, package).map(|version| format!("{}-{}", name, version)))
+            .collect()
+    }
+
+    /// Creates the configured users that don't have an account yet, and changes the accounts of
+    /// those that differ from their configuration. Accounts the configuration doesn't mention
+    /// are left alone.
+    fn account_actions(&self) -> Result<Vec<Action>, PlannerError> {
+        let users = self.config.users.iter().collect::<BTreeMap<&Rc<str>, &User>>();
+        let mut actions = vec![];
+        for (username, user) in users {
+            let HomeDirectory::Path { location, .. } = &user.homedir;
+            let account = &Account {
+                shell: user.account.shell.as_deref().map(|shell| self.resolve_shell(shell)).transpose()?,
+                // Password files are read when the plan is applied, wherever that is run from.
+                password: user.account.password.as_ref().map(|password| match password {
+                    Password::File(path) => Password::File(self.config_directory.join(path)),
+                    hashed => hashed.clone(),
+                }),
+                ..user.account.clone()
+            };
+            match self.current.users.get(username) {
+                None => actions.push(Action::System(SystemAction::CreateUser {
+                    username: username.to_string(),
+                    home: location.clone(),
+                    account: account.clone(),
+                })),
+                Some(current) if account_differs(username, account, &current.account) => actions.push(Action::System(SystemAction::ModifyUser {
+                    username: username.to_string(),
+                    account: account.clone(),
+                })),
+                Some(_) => (),
+            }
+        }
+        Ok(actions)
+    }
+
+    /// The path of a shell given by name, such as `bash`: the first entry of the root's
+    /// `/etc/shells` with that name or, for a shell that is yet to be installed, the one in
+    /// `/bin` when a package of that name is configured.
+    fn resolve_shell(&self, shell: &Path) -> Result<PathBuf, PlannerError> {
+        if shell.is_absolute() {
+            return Ok(shell.to_path_buf());
+        }
+
+        let shells = fs::read_to_string(path_in_root(&self.root, Path::new(SHELLS_LOCATION))).unwrap_or_default();
+        let listed = shells
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with('/'))
+            .map(Path::new)
+            .find(|path| path.file_name() == Some(shell.as_os_str()));
+        match listed {
+            Some(path) => Ok(path.to_path_buf()),
+            None if self.config.all_packages().iter().any(|(name, _)| name.as_ref() == shell.as_os_str()) => Ok(Path::new("/bin").join(shell)),
+            None => Err(PlannerError::UnknownShell(shell.display().to_string())),
+        }
+    }
+
+    /// Creates each configured user's home directory and the subdirectories it declares, and
+    /// gives them to the user. Directories that already exist are only changed when their owner
+    /// or permissions are wrong. A new home directory is seeded from `/etc/skel` when it asks to
+    /// be.
+    fn home_actions(&self) -> Result<Vec<Action>, PlannerError> {
+        let users = self.config.users.iter().collect::<BTreeMap<&Rc<str>, &User>>();
+        let mut actions = vec![];
+        for (username, user) in users {
+            let HomeDirectory::Path { location, subdirs, mode, skel } = &user.homedir;
+            let ids = self.ids(username)?;
+            let mode = mode.unwrap_or(HOME_MODE);
+            let is_new = !path_in_root(&self.root, location).exists();
+            if !self.is_owned_directory(location, mode, ids) {
+                actions.push(home_file(username, location.clone(), None, mode));
+            }
+            if *skel && is_new {
+                self.skel_actions(username, Path::new(SKEL_LOCATION), location, &mut actions)?;
+            }
+
+            let subdirs = subdirs.iter().map(|subdir| normalise(&location.join(subdir))).collect::<BTreeSet<PathBuf>>();
+            for subdir in subdirs {
+                if !self.is_owned_directory(&subdir, SUBDIR_MODE, ids) {
+                    actions.push(home_file(username, subdir, None, SUBDIR_MODE));
+                }
+            }
+        }
+        Ok(actions)
+    }
+
+    /// Copies the contents of `source` into `destination` for `username`, keeping their
+    /// permissions. Links in the skeleton are skipped.
+    fn skel_actions(&self, username: &str, source: &Path, destination: &Path, actions: &mut Vec<Action>) -> io::Result<()> {
+        let mut entries = match fs::read_dir(path_in_root(&self.root, source)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            entries => entries?.collect::<io::Result<Vec<fs::DirEntry>>>()?,
+        };
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let metadata = entry.metadata()?;
+            let mode = metadata.permissions().mode() & 0o7777;
+            let location = destination.join(entry.file_name());
+            if metadata.is_dir() {
+                actions.push(home_file(username, location.clone(), None, mode));
+                self.skel_actions(username, &source.join(entry.file_name()), &location, actions)?;
+            } else if metadata.is_file() {
+                actions.push(self.home_copy(username, &entry.path(), location, mode)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// The uid and primary gid `/etc/passwd` gives `username`, once it has an account.
+    fn ids(&self, username: &str) -> io::Result<Option<(u32, u32)>> {
+        let passwd = match fs::read_to_string(path_in_root(&self.root, Path::new(PASSWD_LOCATION))) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            passwd => passwd?,
+        };
+        Ok(passwd
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<&str>>())
+            .find(|fields| fields.len() >= 4 && fields[0] == username)
+            .and_then(|fields| Some((fields[2].parse().ok()?, fields[3].parse().ok()?))))
+    }
+
+    /// Whether `location` is a directory with these permission bits, owned by `ids`.
+    fn is_owned_directory(&self, location: &Path, mode: u32, ids: Option<(u32, u32)>) -> bool {
+        fs::metadata(path_in_root(&self.root, location)).is_ok_and(|metadata| {
+            metadata.is_dir()
+                && metadata.permissions().mode() & 0o7777 == mode
+                && ids.is_some_and(|(uid, gid)| metadata.uid() == uid && metadata.gid() == gid)
+        })
+    }
+
+    /// Rewrites the sudo and doas rules when the users given privileges change, and removes
+    /// them once no user is.
+    fn privilege_actions(&self) -> Result<Vec<Action>, PlannerError> {
+        let users = self.config.users.iter().collect::<BTreeMap<&Rc<str>, &User>>();
+        let mut actions = vec![];
+        for tool in PrivilegeTool::ALL {
+            let rules = users
+                .iter()
+                .filter_map(|(username, user)| user.privilege.as_ref().map(|privilege| (username.as_ref(), privilege)))
+                .filter(|(_, privilege)| privilege.tool == tool)
+                .collect::<Vec<(&str, &Privilege)>>();
+            let current = match fs::read_to_string(path_in_root(&self.root, tool.location())) {
+                Ok(current) => Some(current),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            let contents = tool.contents(current.as_deref(), &rules);
+            if contents != current {
+                actions.push(Action::System(SystemAction::InstallPrivileges { tool, contents }));
+            }
+        }
+        Ok(actions)
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        let mut actions = vec![];
+
+        // Enabling a repository is done by installing its package, which has to happen before
+        // anything can be installed from it.
+        let repository_packages = self.repository_packages();
+        for name in &repository_packages {
+            actions.push(Action::System(SystemAction::AddPackage {
+                package_name: name.to_string(),
+                version: None,
+                package_repository: PackageRepository {
+                    name: None,
+                    location: Source::Remote(RemoteSource::VoidRepo),
+                    allow_restricted: false,
+                },
+            }));
+        }
+
+        for (name, package) in self.config.all_packages() {
+            let needs_install = self.needs_install(name, package) && !repository_packages.contains(name.as_ref());
+            let held = self.current.packages.get(name).is_some_and(|current| current.hold == Some(true));
+            // Holds placed by hand are kept unless the configuration says otherwise.
+            let keep_held = package.hold.unwrap_or(held);
+
+            // XBPS won't change the version of a held package, so the hold is released first.
+            if needs_install && held {
+                actions.push(hold(name, false));
+            }
+            // Packages from a vp_repo have no binary to install, so they are built locally.
+            if needs_install && self.is_from_vp_repo(package) {
+                actions.push(Action::System(SystemAction::BuildPackage {
+                    package_name: name.to_string(),
+                    version: self.version_for(name, package).map(String::from),
+                    package_repository: self.repository_for(name, package),
+                }));
+            } else if needs_install {
+                actions.push(Action::System(SystemAction::AddPackage {
+                    package_name: name.to_string(),
+                    version: self.version_for(name, package).map(String::from),
+                    package_repository: self.repository_for(name, package),
+                }));
+            }
+            if keep_held && (needs_install || !held) {
+                actions.push(hold(name, true));
+            } else if !keep_held && held && !needs_install {
+                actions.push(hold(name, false));
+            }
+        }
+
+        actions
+    }
+
+    /// The `void-repo-*` packages enabling the repositories that packages about to be installed
+    /// come from, leaving out those that are installed already.
+    fn repository_packages(&self) -> BTreeSet<&'static str> {
+        if !self.config.auto_enable_repositories {
+            return BTreeSet::new();
+        }
+
+        self.config.all_packages()
+            .into_iter()
+            .filter(|(name, package)| matches!(package.repository, Source::Remote(RemoteSource::VoidRepo)) && self.needs_install(name, package))
+            .filter_map(|(name, _)| self.required_repository(name))
+            .filter(|repository| !self.current.packages.contains_key(*repository))
+            .collect()
+    }
+
+    /// The package enabling the repository `name` comes from, if it isn't in the main repository.
+    ///
+    /// Package definitions say whether a package is nonfree, and the cached indexes say which
+    /// repository it is in.
+    fn required_repository(&self, name: &str) -> Option<&'static str> {
+        let indexes = self.repodata
+            .into_iter()
+            .flat_map(|repodata| repodata.indexes.iter())
+            .filter(|index| index.packages.contains_key(name))
+            .collect::<Vec<_>>();
+        let (nonfree, multilib) = match indexes.iter().any(|index| !index.is_nonfree() && !index.is_multilib()) {
+            true => (false, false),
+            false => indexes.first().map_or((false, false), |index| (index.is_nonfree(), index.is_multilib())),
+        };
+
+        match (nonfree || self.is_nonfree(name), multilib) {
+            (true, true) => Some("void-repo-multilib-nonfree"),
+            (true, false) => Some("void-repo-nonfree"),
+            (false, true) => Some("void-repo-multilib"),
+            (false, false) => None,
+        }
+    }
+
+    fn is_nonfree(&self, name: &str) -> bool {
+        self.definitions
+            .and_then(|definitions| definitions.named(name))
+            .is_some_and(|definition| definition.is_nonfree)
+    }
+
+    /// Enables, disables and downs the configured services, the system's and then each user's.
+    /// Services the configuration doesn't mention are left as they are, and those not on the
+    /// system yet are treated as disabled, as they are usually installed by a package earlier
+    /// in the plan.
+    ///
+    /// Services defined in the configuration have their files written first, whenever their
+    /// contents or modes differ from the definition. Running services are then restarted, or
+    /// reloaded, when their own files or any of the files they depend on are in `changed`.
+    fn service_actions(&self, changed: &BTreeSet<PathBuf>) -> Result<Vec<Action>, PlannerError> {
+        let users = self.config.users.iter().collect::<BTreeMap<&Rc<str>, &User>>();
+        let packages = users
+            .values()
+            .flat_map(|user| user.packages.iter())
+            .chain(self.config.packages.iter())
+            .collect::<Vec<(&Rc<str>, &Package)>>();
+        let mut missing = vec![];
+        let mut actions = self.scope_actions(None, &self.config.services, &self.current.services, &packages, changed, &mut missing);
+
+        // Packages may name services the configuration leaves alone, which still have to pick
+        // up changes to the package's files.
+        let package_services = self.definitions
+            .into_iter()
+            .flat_map(|definitions| definitions.iter())
+            .flat_map(|definition| definition.services.iter())
+            .filter(|name| !self.config.services.contains_key(*name))
+            .collect::<BTreeSet<&Rc<str>>>();
+        for name in package_services {
+            let directory = Path::new(SERVICE_LOCATION).join(name.as_ref());
+            let on_change = self.on_dependency_change(name, None, &packages, changed);
+            actions.extend(on_change.and_then(|on_change| self.apply_change(name, None, &directory, on_change)));
+        }
+
+        let no_services = HashMap::new();
+        for (username, user) in users {
+            let HomeDirectory::Path { location: home, .. } = &user.homedir;
+            if user.services.is_empty() {
+                continue;
+            }
+
+            // Created up front so that it belongs to the user, rather than to root when the
+            // first service is enabled.
+            let links = home.join(USER_RUNSVDIR_LOCATION);
+            if !path_in_root(&self.root, &links).is_dir() {
+                actions.push(Action::File(FileSystemAction::CreateFile {
+                    file_location: links,
+                    contents: None,
+                    is_dir: true,
+                    mode: None,
+                    owner: Some(username.to_string()),
+                    group: None,
+                }));
+            }
+
+            let scope = ServiceUser { username: username.to_string(), home: home.clone() };
+            let current = self.current.users.get(username).map_or(&no_services, |current| &current.services);
+            let packages = user.packages.iter().collect::<Vec<(&Rc<str>, &Package)>>();
+            actions.extend(self.scope_actions(Some(&scope), &user.services, current, &packages, changed, &mut missing));
+        }
+
+        match missing.is_empty() {
+            true => Ok(actions),
+            false => Err(PlannerError::MissingServices(missing)),
+        }
+    }
+
+    /// The actions for the services of one user, or of the system when `user` is `None`.
+    fn scope_actions(
+        &self,
+        user: Option<&ServiceUser>,
+        services: &HashMap<Rc<str>, Service>,
+        current: &HashMap<Rc<str>, Service>,
+        packages: &[(&Rc<str>, &Package)],
+        changed: &BTreeSet<PathBuf>,
+        missing: &mut Vec<String>,
+    ) -> Vec<Action> {
+        let services = services.iter().collect::<BTreeMap<&Rc<str>, &Service>>();
+        let service_user = || user.cloned();
+
+        let mut actions = vec![];
+        for (name, service) in services {
+            let directory = match user {
+                Some(user) => user.home.join(USER_SERVICE_LOCATION).join(name.as_ref()),
+                None => Path::new(SERVICE_LOCATION).join(name.as_ref()),
+            };
+            let files = service.definition
+                .iter()
+                .flat_map(|definition| service_files(&directory, definition))
+                .collect::<Vec<ServiceFile>>();
+            // Files a previous definition asked for, which runit would otherwise keep using.
+            let mut configuration_actions = OPTIONAL_SERVICE_FILES
+                .iter()
+                .filter(|_| service.definition.is_some())
+                .map(|file| directory.join(file))
+                .filter(|path| !files.iter().any(|file| file.location.starts_with(path)))
+                .filter_map(|path| {
+                    let metadata = path_in_root(&self.root, &path).symlink_metadata().ok()?;
+                    Some(Action::File(FileSystemAction::RemoveFile { file_location: path, is_dir: metadata.is_dir() }))
+                })
+                .collect::<Vec<Action>>();
+            configuration_actions.extend(files
+                .into_iter()
+                .filter(|file| !self.is_current(&file.location, file.contents.as_bytes(), file.mode))
+                .map(|file| Action::File(FileSystemAction::CreateFile {
+                    file_location: file.location,
+                    contents: Some(file.contents),
+                    is_dir: false,
+                    mode: Some(file.mode),
+                    owner: user.map(|user| user.username.clone()),
+                    group: None,
+                })));
+            let reconfigured = !configuration_actions.is_empty();
+            if reconfigured {
+                actions.push(Action::System(SystemAction::ConfigureService { service_name: name.to_string(), configuration_actions, user: service_user() }));
+            }
+
+            let (enabled, downed) = current
+                .get(name)
+                .map_or((false, false), |current| (current.enabled, current.downed));
+            // Downing first keeps a newly enabled service from being started.
+            if service.downed != downed {
+                actions.push(Action::System(SystemAction::DownService { service_name: name.to_string(), down: service.downed, user: service_user() }));
+            }
+            // runit can only enable a service whose directory exists by the time it gets there.
+            let provided = service.definition.is_some()
+                || path_in_root(&self.root, &directory).is_dir()
+                || packages.iter().any(|(package, _)| self.package_services(package).any(|service| service.as_ref() == name.as_ref()));
+            if service.enabled && !enabled && !provided {
+                missing.push(service_user().map_or_else(|| name.to_string(), |user| format!("{}'s {}", user.username, name)));
+                continue;
+            }
+            match (service.enabled, enabled) {
+                (true, false) => actions.push(Action::System(SystemAction::EnableService { service_name: name.to_string(), user: service_user() })),
+                (false, true) => actions.push(Action::System(SystemAction::DisableService { service_name: name.to_string(), user: service_user() })),
+                _ => (),
+            }
+
+            // A new run script only takes effect once runsv starts it again.
+            let on_change = match reconfigured {
+                true => Some(OnChange::Restart),
+                false => self.on_dependency_change(name, Some(service), packages, changed),
+            };
+            if service.enabled && !service.downed {
+                actions.extend(on_change.and_then(|on_change| self.apply_change(name, user, &directory, on_change)));
+            }
+        }
+        actions
+    }
+
+    /// The services the definition of `package` says it installs.
+    fn package_services(&self, package: &str) -> impl Iterator<Item = &Rc<str>> {
+        self.definitions
+            .and_then(|definitions| definitions.named(package))
+            .into_iter()
+            .flat_map(|definition| definition.services.iter())
+    }
+
+    /// How the service `name` picks up changes, if any file it depends on is in `changed`.
+    ///
+    /// A service depends on the files it lists itself and on the configuration files of every
+    /// package in `packages` whose definition names it. The service's own `on_change` takes
+    /// precedence over its packages'.
+    fn on_dependency_change(&self, name: &str, service: Option<&Service>, packages: &[(&Rc<str>, &Package)], changed: &BTreeSet<PathBuf>) -> Option<OnChange> {
+        let packages = packages
+            .iter()
+            .filter_map(|(package, configuration)| Some((self.definitions?.named(package)?, *configuration)))
+            .filter(|(definition, _)| definition.services.iter().any(|service| service.as_ref() == name))
+            .collect::<Vec<_>>();
+
+        let mut files = service.into_iter().flat_map(|service| service.files.iter())
+            .chain(packages.iter().flat_map(|(_, package)| package.config.values().map(|file| &file.location)));
+        if !files.any(|file| changed.contains(file)) {
+            return None;
+        }
+
+        let on_change = service.and_then(|service| service.on_change)
+            .or_else(|| packages.iter().find_map(|(definition, _)| definition.on_change))
+            .unwrap_or_default();
+        Some(on_change)
+    }
+
+    /// Restarts or reloads the service in `directory`, but only if it is running.
+    fn apply_change(&self, name: &str, user: Option<&ServiceUser>, directory: &Path, on_change: OnChange) -> Option<Action> {
+        if !self.is_running(directory) {
+            return None;
+        }
+        let (service_name, user) = (name.to_string(), user.cloned());
+        Some(Action::System(match on_change {
+            OnChange::Restart => SystemAction::RestartService { service_name, user },
+            OnChange::Reload => SystemAction::ReloadService { service_name, user },
+        }))
+    }
+
+    /// Whether runit reports the service in `directory` as running, so changes to it only
+    /// apply once it has been restarted.
+    fn is_running(&self, directory: &Path) -> bool {
+        supervise::read_status(&self.root, directory)
+            .ok()
+            .flatten()
+            .is_some_and(|status| status.state == RunState::Run)
+    }
+
+    /// Whether the file at `location` already has these contents and permission bits.
+    fn is_current(&self, location: &Path, contents: &[u8], mode: u32) -> bool {
+        let path = path_in_root(&self.root, location);
+        let mode_matches = fs::metadata(&path).is_ok_and(|metadata| metadata.permissions().mode() & 0o7777 == mode);
+        mode_matches && fs::read(path).is_ok_and(|current| current == contents)
+    }
+
+    /// Gives `username` a copy of `source` at `location`. Text is written out from the plan, so
+    /// that it can be shown, and anything else is copied from `source` when the plan is applied.
+    fn home_copy(&self, username: &str, source: &Path, location: PathBuf, mode: u32) -> io::Result<Action> {
+        Ok(match String::from_utf8(fs::read(source)?) {
+            Ok(contents) => home_file(username, location, Some(contents), mode),
+            Err(_) => Action::File(FileSystemAction::CopyFile {
+                original_location: self.system_path(source),
+                final_location: location,
+                is_recursive: false,
+                mode: Some(mode),
+                owner: Some(username.to_string()),
+            }),
+        })
+    }
+
+    /// Writes the configuration files of every package whose contents differ from the files
+    /// the configuration gives them.
+    fn configuration_actions(&self) -> Result<Vec<Action>, PlannerError> {
+        // A user's packages are configured in their home directory, so their files are theirs.
+        let mut packages = self.config.packages
+            .iter()
+            .collect::<BTreeMap<&Rc<str>, &Package>>()
+            .into_iter()
+            .map(|(name, package)| (None, name, package))
+            .collect::<Vec<_>>();
+        let users = self.config.users.iter().collect::<BTreeMap<_, _>>();
+        for (username, user) in users {
+            packages.extend(user.packages
+                .iter()
+                .collect::<BTreeMap<&Rc<str>, &Package>>()
+                .into_iter()
+                .map(|(name, package)| (Some(username.to_string()), name, package)));
+        }
+
+        let mut actions = vec![];
+        for (owner, name, package) in packages {
+            let mut configuration_actions = vec![];
+            for file in package.config.values() {
+                if let Some(contents) = self.configuration_contents(name, file)? {
+                    configuration_actions.push(Action::File(FileSystemAction::CreateFile {
+                        file_location: file.location.clone(),
+                        contents: Some(contents),
+                        is_dir: false,
+                        mode: None,
+                        owner: owner.clone(),
+                        group: None,
+                    }));
+                }
+            }
+
+            if !configuration_actions.is_empty() {
+                actions.push(Action::System(SystemAction::ConfigurePackage {
+                    package_name: name.to_string(),
+                    configuration_actions,
+                }));
+            }
+        }
+        Ok(actions)
+    }
+
+    /// Copies each user's dotfiles into their home directory where they differ, and removes the
+    /// ones that were deployed before but have since left the repository. A file the
+    /// configuration of a package writes is left to the package. Planning fails when a dotfile
+    /// would overwrite a file SVSM didn't deploy.
+    fn dotfile_actions(&self) -> Result<Vec<Action>, PlannerError> {
+        let configured = self.config.all_packages()
+            .into_values()
+            .flat_map(|package| package.config.values())
+            .map(|file| file.location.clone())
+            .collect::<BTreeSet<PathBuf>>();
+        let users = self.config.users.iter().collect::<BTreeMap<&Rc<str>, &User>>();
+
+        let mut actions = vec![];
+        let mut conflicts = vec![];
+        for (username, user) in users {
+            let HomeDirectory::Path { location: home, .. } = &user.homedir;
+            let manifest_location = home.join(MANIFEST_LOCATION);
+            let previous = fs::read_to_string(path_in_root(&self.root, &manifest_location))
+                .map(|manifest| read_manifest(&manifest))
+                .unwrap_or_default();
+            let sources = match &user.dotfiles {
+                Some(dotfiles) => {
+                    let directory = self.source_directory(&dotfiles.source)?;
+                    source_files(&directory, &dotfiles.exclude)
+                        .map_err(|err| PlannerError::UnreadableDotfile { path: directory.clone(), err })?
+                        .into_iter()
+                        .filter(|file| !configured.contains(&home.join(file)))
+                        .map(|file| (directory.join(&file), file))
+                        .collect::<Vec<(PathBuf, PathBuf)>>()
+                }
+                None => vec![],
+            };
+
+            let deploy = user.dotfiles.as_ref().map(|dotfiles| dotfiles.deploy).unwrap_or_default();
+            let mut dotfile_actions = vec![];
+            let mut deployed = BTreeSet::new();
+            let mut directories = BTreeSet::new();
+            for (source, file) in sources {
+                let location = home.join(&file);
+                let unreadable = |err| PlannerError::UnreadableDotfile { path: source.clone(), err };
+                let target = path_in_root(&self.root, &location);
+                let existing = target.symlink_metadata().ok();
+                let link_target = self.system_path(&source);
+                let is_linked = fs::read_link(&target).ok().as_ref() == Some(&link_target);
+                // A file that isn't ours is only taken over when it is already what would be deployed.
+                if !previous.contains(&file) && existing.is_some() && !is_linked && !same_contents(&source, &target).map_err(unreadable)? {
+                    conflicts.push(location);
+                    continue;
+                }
+
+                let is_link = existing.as_ref().is_some_and(|metadata| metadata.is_symlink());
+                match deploy {
+                    DotfileDeployment::Copy => {
+                        let mode = fs::metadata(&source).map_err(unreadable)?.permissions().mode() & 0o777;
+                        // Writing through a link would change the file in the repository instead.
+                        if is_link {
+                            dotfile_actions.push(Action::File(FileSystemAction::RemoveFile { file_location: location.clone(), is_dir: false }));
+                        }
+                        if is_link || !self.is_current(&location, &fs::read(&source).map_err(unreadable)?, mode) {
+                            dotfile_actions.push(self.home_copy(username, &source, location, mode).map_err(unreadable)?);
+                        }
+                    }
+                    DotfileDeployment::Symlink => {
+                        if !is_linked {
+                            let parent = location.parent().map(Path::to_path_buf).unwrap_or_default();
+                            if !path_in_root(&self.root, &parent).is_dir() && directories.insert(parent.clone()) {
+                                dotfile_actions.push(home_file(username, parent, None, SUBDIR_MODE));
+                            }
+                            dotfile_actions.push(Action::File(FileSystemAction::Symlink { target: link_target, link_name: location, force: existing.is_some() }));
+                        }
+                    }
+                }
+                deployed.insert(file);
+            }
+
+            for file in previous.difference(&deployed) {
+                let location = home.join(file);
+                if !configured.contains(&location) && path_in_root(&self.root, &location).symlink_metadata().is_ok() {
+                    dotfile_actions.push(Action::File(FileSystemAction::RemoveFile { file_location: location, is_dir: false }));
+                }
+            }
+            if deployed != previous {
+                dotfile_actions.push(match deployed.is_empty() {
+                    true => Action::File(FileSystemAction::RemoveFile { file_location: manifest_location, is_dir: false }),
+                    false => home_file(username, manifest_location, Some(manifest(&deployed)), 0o644),
+                });
+            }
+
+            if !dotfile_actions.is_empty() {
+                actions.push(Action::System(SystemAction::DeployDotfiles { username: username.to_string(), dotfile_actions }));
+            }
+        }
+
+        match conflicts.is_empty() {
+            true => Ok(actions),
+            false => Err(PlannerError::DotfileConflicts(conflicts)),
+        }
+    }
+
+    /// Where the files of `source` can be read from while planning.
+    fn source_directory(&self, source: &Source) -> Result<PathBuf, PlannerError> {
+        match source {
+            Source::Local(LocalSource::Directory(directory)) => Ok(directory.clone()),
+            source => match self.checkouts.and_then(|checkouts| checkouts.get(source)) {
+                Some(checkout) => Ok(checkout.clone()),
+                None => Err(PlannerError::UnresolvedSource(source.to_string())),
+            },
+        }
+    }
+
+    /// Where `path`, as read while planning, is found on the system being managed.
+    fn system_path(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.root) {
+            Ok(relative) => Path::new("/").join(relative),
+            Err(_) => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+        }
+    }
+
+    /// The contents `file` must be given, or `None` if it already has them.
+    ///
+    /// Templates are only ever copied to a location that is still empty.
+    fn configuration_contents(&self, package: &str, file: &ConfigurationFile) -> Result<Option<String>, PlannerError> {
+        let location = path_in_root(&self.root, &file.location);
+        let source = match &file.source {
+            ConfigurationSource::File { path, source: None } => self.config_directory.join(path),
+            ConfigurationSource::File { path, source: Some(source) } => self.source_directory(source)?.join(path),
+            ConfigurationSource::Template(_) if location.symlink_metadata().is_ok() => return Ok(None),
+            ConfigurationSource::Template(template) => template.clone(),
+        };
+
+        let contents = fs::read_to_string(&source)
+            .map_err(|err| PlannerError::UnreadableConfiguration { package: package.to_string(), path: source, err })?;
+        match fs::read_to_string(location) {
+            Ok(current) if current == contents => Ok(None),
+            _ => Ok(Some(contents)),
+        }
+    }
+
+    /// Whether the package is missing, or installed at a different version than it is pinned to.
+    fn needs_install(&self, name: &str, package: &Package) -> bool {
+        let version = self.version_for(name, package);
+        !self.current.packages
+            .get(name)
+            .is_some_and(|current| version.is_none() || current.version.as_deref() == version)
+    }
+
+    /// The version a package must be at, if the configuration or lockfile pins one.
+    fn version_for<'b>(&'b self, name: &str, package: &'b Package) -> Option<&'b str> {
+        package.version
+            .as_deref()
+            .or_else(|| self.lockfile.and_then(|lockfile| lockfile.version_of(name)))
+    }
+
+    /// Whether the package is built from one of the configured vp_repos, wherever it lives.
+    fn is_from_vp_repo(&self, package: &Package) -> bool {
+        self.config.repositories.values().any(|repository| repository.location == package.repository)
+    }
+
+    /// The vp_repo the package is built from or, for packages from the Void repositories, the
+    /// repository the lockfile recorded its locked build coming from.
+    fn repository_for(&self, name: &str, package: &Package) -> PackageRepository {
+        let configured = self.config.repositories
+            .values()
+            .find(|repository| repository.location == package.repository);
+        // A version pinned by the configuration isn't the build the lockfile found.
+        let locked = self.lockfile
+            .filter(|_| package.version.is_none())
+            .and_then(|lockfile| lockfile.repository_of(name))
+            .filter(|repository| matches!(repository, Source::Remote(RemoteSource::VoidRemote(_))));
+
+        match configured {
+            Some(repository) => repository.clone(),
+            None => PackageRepository {
+                name: None,
+                location: locked.unwrap_or(&package.repository).clone(),
+                allow_restricted: false,
+            },
+        }
+    }
+
+    /// The state of every file and package the actions change, and the commits of the git
+    /// sources they were computed from.
+    fn preconditions(&self, actions: &[Action]) -> io::Result<Vec<Precondition>> {
+        let mut paths = vec![];
+        collect_paths(actions, &mut paths);
+        paths.sort();
+        paths.dedup();
+        let mut preconditions = paths
+            .into_iter()
+            .map(|path| Ok(Precondition::File {
+                state: FileState::capture(&self.root, &path)?,
+                path,
+            }))
+            .collect::<io::Result<Vec<Precondition>>>()?;
+
+        let packages = actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::System(SystemAction::AddPackage { package_name, .. })
+                | Action::System(SystemAction::BuildPackage { package_name, .. })
+                | Action::System(SystemAction::RemovePackage { package_name })
+                | Action::System(SystemAction::HoldPackage { package_name, .. }) => Some(package_name.as_str()),
+                _ => None,
+            })
+            .collect::<BTreeSet<&str>>();
+        preconditions.extend(packages.into_iter().map(|name| Precondition::Package {
+            name: name.to_string(),
+            version: self.current.packages.get(name).and_then(|package| package.version.as_deref()).map(String::from),
+        }));
+
+        preconditions.extend(self.commits.into_iter().flatten().map(|(source, commit)| Precondition::Source {
+            source: source.clone(),
+            commit: commit.clone(),
+        }));
+        Ok(preconditions)
+    }
+}
+
+/// Whether the existing account `current` has to change to match `account`. A uid, shell or
+/// password the configuration doesn't set is left as it is, while the primary group defaults to
+/// the user's own and the supplementary groups have to match exactly.
+fn account_differs(username: &str, account: &Account, current: &Account) -> bool {
+    let sorted = |groups: &[Rc<str>]| groups.iter().cloned().collect::<BTreeSet<Rc<str>>>();
+    account.uid.is_some_and(|uid| current.uid != Some(uid))
+        || account.group.as_deref().unwrap_or(username) != current.group.as_deref().unwrap_or_default()
+        || sorted(&account.groups) != sorted(&current.groups)
+        || account.shell.as_ref().is_some_and(|shell| current.shell.as_ref() != Some(shell))
+        || account.locked != current.locked
+        || account.password.as_ref().is_some_and(|password| !has_password(password, current.password.as_ref()))
+}
+
+/// Whether an account whose current hash is `current` already has `password`. A password file
+/// that can't be read is taken to differ, so that applying the plan reports why.
+fn has_password(password: &Password, current: Option<&Password>) -> bool {
+    let current = match current {
+        Some(Password::Hashed(hash)) => hash,
+        _ => return false,
+    };
+    match password {
+        Password::Hashed(hash) => hash == current,
+        Password::File(path) => fs::read_to_string(path).is_ok_and(|hash| hash.trim() == current.as_ref()),
+    }
+}
+
+/// Whether the files at `source` and `target` hold the same bytes. A `target` that can't be read,
+/// such as a dangling link, never does.
+fn same_contents(source: &Path, target: &Path) -> io::Result<bool> {
+    let contents = fs::read(source)?;
+    Ok(fs::read(target).is_ok_and(|current| current == contents))
+}
+
+/// A file or directory in the home directory of `username`, given to them and their primary
+/// group.
+fn home_file(username: &str, location: PathBuf, contents: Option<String>, mode: u32) -> Action {
+    Action::File(FileSystemAction::CreateFile {
+        file_location: location,
+        is_dir: contents.is_none(),
+        contents,
+        mode: Some(mode),
+        owner: Some(username.to_string()),
+        group: None,
+    })
+}
+
+fn hold(name: &str, hold: bool) -> Action {
+    Action::System(SystemAction::HoldPackage { package_name: name.to_string(), hold })
+}
+
+fn collect_paths(actions: &[Action], paths: &mut Vec<PathBuf>) {
+    for action in actions {
+        match action {
+            Action::File(action) => paths.extend(action.affected_paths().into_iter().cloned()),
+            Action::System(SystemAction::ConfigurePackage { configuration_actions, .. }) => collect_paths(configuration_actions, paths),
+            Action::System(SystemAction::ConfigureService { configuration_actions, .. }) => collect_paths(configuration_actions, paths),
+            Action::System(SystemAction::DeployDotfiles { dotfile_actions, .. }) => collect_paths(dotfile_actions, paths),
+            Action::System(SystemAction::InstallPrivileges { tool, .. }) => paths.push(tool.location().to_path_buf()),
+            Action::System(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::accounts::AccountManager;
+    use crate::plan::{Observed, PlanError, PreconditionFailure};
+    use crate::probe::Probe;
+    use crate::repodata::RepositoryIndex;
+    use crate::repodata::fixtures::index_plist;
+    use crate::supervise::fixtures::{status_record, write_status};
+    use crate::system::{Dotfiles, HomeDirectory, ServiceDefinition, User};
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    #[test]
+    fn test_plan_packages_and_repositories() {
+        let personal = PackageRepository {
+            name: Some(Rc::from("personal")),
+            location: Source::Remote(RemoteSource::GithubRemote {
+                user: Rc::from("sapein"),
+                repository_name: Rc::from("void-packages"),
+                branch_name: None,
+            }),
+            allow_restricted: false,
+        };
+        // A vp_repo that is already checked out locally is built from just the same.
+        let local = PackageRepository {
+            name: Some(Rc::from("local")),
+            location: Source::Local(LocalSource::Directory(PathBuf::from("/home/sapeint/void-packages"))),
+            allow_restricted: false,
+        };
+        let config = System {
+            repositories: HashMap::from([(Rc::from("personal"), personal.clone()), (Rc::from("local"), local.clone())]),
+            users: HashMap::from([(Rc::from("sapeint"), User {
+                username: Some(Rc::from("sapeint")),
+                homedir: HomeDirectory::Path { location: PathBuf::from("/home/sapeint"), subdirs: vec![], mode: None, skel: false },
+                packages: HashMap::from([
+                    (Rc::from("st"), Package { config: BTreeMap::new(), version: None, hold: None, repository: personal.location.clone() }),
+                    (Rc::from("slstatus"), Package { config: BTreeMap::new(), version: Some(Rc::from("1.0_1")), hold: None, repository: local.location.clone() }),
+                    (Rc::from("dmenu"), Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) }),
+                ]),
+                ..Default::default()
+            })]),
+            packages: HashMap::from([
+                (Rc::from("nano"), Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) }),
+            ]),
+            ..Default::default()
+        };
+
+        let current = System {
+            packages: HashMap::from([
+                (Rc::from("nano"), Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) }),
+            ]),
+            ..Default::default()
+        };
+
+        let plan = Planner::new(&config, &current, PathBuf::from("/nonexistent")).plan().unwrap();
+        assert_eq!(plan.actions(), &[
+            Action::System(SystemAction::CreateUser {
+                username: String::from("sapeint"),
+                home: PathBuf::from("/home/sapeint"),
+                account: Account::default(),
+            }),
+            home_file("sapeint", PathBuf::from("/home/sapeint"), None, HOME_MODE),
+            Action::System(SystemAction::AddPackage {
+                package_name: String::from("dmenu"),
+                version: None,
+                package_repository: PackageRepository {
+                    name: None,
+                    location: Source::Remote(RemoteSource::VoidRepo),
+                    allow_restricted: false,
+                },
+            }),
+            Action::System(SystemAction::BuildPackage {
+                package_name: String::from("slstatus"),
+                version: Some(String::from("1.0_1")),
+                package_repository: local,
+            }),
+            Action::System(SystemAction::BuildPackage {
+                package_name: String::from("st"),
+                version: None,
+                package_repository: personal,
+            }),
+        ]);
+    }
+
+    #[test]
+    fn test_plan_skips_current_state() {
+        let void = Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) };
+        let config = System {
+            packages: HashMap::from([(Rc::from("nano"), void)]),
+            ..Default::default()
+        };
+
+        let plan = Planner::new(&config, &config, PathBuf::from("/nonexistent")).plan().unwrap();
+        assert!(plan.actions().is_empty());
+    }
+
+    #[test]
+    fn test_plan_services() {
+        let service = |name: &str, enabled: bool, downed: bool| (Rc::from(name), Service { name: Rc::from(name), enabled, downed, ..Default::default() });
+        let system = |services: Vec<(Rc<str>, Service)>| System {
+            services: HashMap::from_iter(services),
+            ..Default::default()
+        };
+        let config = system(vec![
+            service("sshd", true, false),
+            service("dhcpcd", false, false),
+            service("ntpd", true, false),
+            service("nginx", true, true),
+        ]);
+        let current = system(vec![
+            service("sshd", true, false),
+            service("dhcpcd", true, false),
+            service("ntpd", true, true),
+            service("acpid", true, false),
+        ]);
+
+        // A service can't be enabled before something puts its directory in place.
+        let root = scratch_dir("planner-services");
+        let err = Planner::new(&config, &current, root.clone()).plan().unwrap_err();
+        assert!(matches!(err, PlannerError::MissingServices(names) if names == vec![String::from("nginx")]));
+
+        fs::create_dir_all(root.join("etc/sv/nginx")).unwrap();
+        let plan = Planner::new(&config, &current, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions(), &[
+            Action::System(SystemAction::DisableService { service_name: String::from("dhcpcd"), user: None }),
+            Action::System(SystemAction::DownService { service_name: String::from("nginx"), down: true, user: None }),
+            Action::System(SystemAction::EnableService { service_name: String::from("nginx"), user: None }),
+            Action::System(SystemAction::DownService { service_name: String::from("ntpd"), down: false, user: None }),
+        ]);
+
+        // Or when the package about to be installed provides it.
+        fs::remove_dir_all(root.join("etc/sv/nginx")).unwrap();
+        fs::write(root.join("nginx.pvsm"), "nginx = { services = [ nginx, ]; };").unwrap();
+        let mut definitions = PackageDefinitions::default();
+        definitions.load_file(&root.join("nginx.pvsm")).unwrap();
+        let config = System {
+            packages: HashMap::from([(Rc::from("nginx"), Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) })]),
+            ..config
+        };
+        assert!(Planner::new(&config, &current, root.clone()).with_definitions(&definitions).plan().is_ok());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_defined_services() {
+        let root = scratch_dir("planner-defined-services");
+        let definition = ServiceDefinition {
+            run: Rc::from("darkhttpd /srv/www"),
+            finish: Some(Rc::from("true")),
+            environment: BTreeMap::new(),
+            user: None,
+            log: None,
+        };
+        let config = System {
+            services: HashMap::from([(Rc::from("www"), Service { name: Rc::from("www"), enabled: true, definition: Some(definition.clone()), ..Default::default() })]),
+            ..Default::default()
+        };
+        let current = System::default();
+
+        // The finish script is already in place, but the run script has lost its mode.
+        let files = service_files(Path::new("/etc/sv/www"), &definition);
+        fs::create_dir_all(root.join("etc/sv/www")).unwrap();
+        for file in &files {
+            fs::write(path_in_root(&root, &file.location), &file.contents).unwrap();
+        }
+        fs::set_permissions(root.join("etc/sv/www/run"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(root.join("etc/sv/www/finish"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let plan = Planner::new(&config, &current, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions(), &[
+            Action::System(SystemAction::ConfigureService {
+                service_name: String::from("www"),
+                configuration_actions: vec![Action::File(FileSystemAction::CreateFile {
+                    file_location: PathBuf::from("/etc/sv/www/run"),
+                    contents: Some(files[0].contents.clone()),
+                    is_dir: false,
+                    mode: Some(0o755),
+                    owner: None,
+                    group: None,
+                })],
+                user: None,
+            }),
+            Action::System(SystemAction::EnableService { service_name: String::from("www"), user: None }),
+        ]);
+
+        // Once it is running, changing its files restarts it.
+        write_status(&root, "www", &status_record(1, 412, 1_700_000_000, b'u'));
+        let current = System {
+            services: HashMap::from([(Rc::from("www"), Service { name: Rc::from("www"), enabled: true, ..Default::default() })]),
+            ..Default::default()
+        };
+        let plan = Planner::new(&config, &current, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions().last(), Some(&Action::System(SystemAction::RestartService { service_name: String::from("www"), user: None })));
+        fs::set_permissions(root.join("etc/sv/www/run"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(Planner::new(&config, &current, root.clone()).plan().unwrap().actions().is_empty());
+
+        // Files the definition stops asking for are removed, along with a leftover log service.
+        fs::create_dir_all(root.join("etc/sv/www/log")).unwrap();
+        fs::write(root.join("etc/sv/www/log/run"), "#!/bin/sh\nexec vlogger -t www\n").unwrap();
+        let definition = ServiceDefinition { finish: None, ..definition };
+        let config = System {
+            services: HashMap::from([(Rc::from("www"), Service { name: Rc::from("www"), enabled: true, definition: Some(definition), ..Default::default() })]),
+            ..config
+        };
+        assert_eq!(Planner::new(&config, &current, root.clone()).plan().unwrap().actions(), &[
+            Action::System(SystemAction::ConfigureService {
+                service_name: String::from("www"),
+                configuration_actions: vec![
+                    Action::File(FileSystemAction::RemoveFile { file_location: PathBuf::from("/etc/sv/www/finish"), is_dir: false }),
+                    Action::File(FileSystemAction::RemoveFile { file_location: PathBuf::from("/etc/sv/www/log"), is_dir: true }),
+                ],
+                user: None,
+            }),
+            Action::System(SystemAction::RestartService { service_name: String::from("www"), user: None }),
+        ]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_accounts() {
+        let user = |account: Account| User {
+            homedir: HomeDirectory::Path { location: PathBuf::from("/home/sapeint"), subdirs: vec![], mode: None, skel: false },
+            account,
+            ..Default::default()
+        };
+        let system = |users: Vec<(&str, Account)>| System {
+            users: users.into_iter().map(|(name, account)| (Rc::from(name), user(account))).collect(),
+            ..Default::default()
+        };
+        // Home directories are left to test_plan_home_directories.
+        let accounts = |plan: Plan| plan.actions().iter().filter(|action| matches!(action, Action::System(_))).cloned().collect::<Vec<Action>>();
+        let groups = |groups: &[&str]| groups.iter().map(|group| Rc::from(*group)).collect::<Vec<Rc<str>>>();
+        let wanted = Account { groups: groups(&["wheel", "audio"]), shell: Some(PathBuf::from("/bin/bash")), ..Account::default() };
+        let current = Account {
+            uid: Some(1000),
+            group: Some(Rc::from("sapeint")),
+            groups: groups(&["audio", "wheel"]),
+            shell: Some(PathBuf::from("/bin/bash")),
+            locked: false,
+            password: None,
+        };
+
+        // Group order doesn't matter, and an account only changes where its configuration says so.
+        let config = system(vec![("sapeint", wanted.clone()), ("guest", Account { locked: true, ..Account::default() })]);
+        let plan = Planner::new(&config, &system(vec![("sapeint", current.clone())]), PathBuf::from("/nonexistent")).plan().unwrap();
+        assert_eq!(accounts(plan), [Action::System(SystemAction::CreateUser {
+            username: String::from("guest"),
+            home: PathBuf::from("/home/sapeint"),
+            account: Account { locked: true, ..Account::default() },
+        })]);
+
+        let config = system(vec![("sapeint", Account { groups: groups(&["wheel"]), ..wanted.clone() })]);
+        let plan = Planner::new(&config, &system(vec![("sapeint", current.clone())]), PathBuf::from("/nonexistent")).plan().unwrap();
+        assert_eq!(accounts(plan), [Action::System(SystemAction::ModifyUser {
+            username: String::from("sapeint"),
+            account: Account { groups: groups(&["wheel"]), shell: Some(PathBuf::from("/bin/bash")), ..Account::default() },
+        })]);
+
+        // Passwords are compared by their hashes, and files given relative to the configuration.
+        let root = scratch_dir("planner-passwords");
+        fs::write(root.join("secret"), "$6$salt$hash\n").unwrap();
+        let hashed = Account { password: Some(Password::Hashed(Rc::from("$6$salt$hash"))), ..current };
+        let from_file = |path: &str| system(vec![("sapeint", Account { password: Some(Password::File(PathBuf::from(path))), ..wanted.clone() })]);
+        let plan = Planner::new(&from_file("./secret"), &system(vec![("sapeint", hashed.clone())]), PathBuf::from("/nonexistent"))
+            .with_config_directory(root.clone())
+            .plan()
+            .unwrap();
+        assert_eq!(accounts(plan), []);
+        let plan = Planner::new(&from_file("./missing"), &system(vec![("sapeint", hashed)]), PathBuf::from("/nonexistent"))
+            .with_config_directory(root.clone())
+            .plan()
+            .unwrap();
+        assert_eq!(accounts(plan), [Action::System(SystemAction::ModifyUser {
+            username: String::from("sapeint"),
+            account: Account { password: Some(Password::File(root.join("./missing"))), ..wanted },
+        })]);
+        fs::remove_dir_all(root).unwrap();
+
+        // System accounts are found again once created, when the probe is asked for them.
+        let root = scratch_dir("planner-system-accounts");
+        fs::create_dir_all(root.join("etc")).unwrap();
+        for table in ["passwd", "group", "shadow"] {
+            fs::write(root.join("etc").join(table), "").unwrap();
+        }
+        let config = system(vec![("_mpd", Account { uid: Some(500), ..Account::default() })]);
+        let plan = Planner::new(&config, &system(vec![]), root.clone()).plan().unwrap();
+        assert_eq!(accounts(plan), [Action::System(SystemAction::CreateUser {
+            username: String::from("_mpd"),
+            home: PathBuf::from("/home/sapeint"),
+            account: Account { uid: Some(500), ..Account::default() },
+        })]);
+        AccountManager::new(root.clone()).create("_mpd", Path::new("/home/sapeint"), &Account { uid: Some(500), ..Account::default() }).unwrap();
+        let current = Probe::new(root.clone()).with_accounts(config.users.keys().cloned()).probe().unwrap();
+        assert_eq!(accounts(Planner::new(&config, &current, root.clone()).plan().unwrap()), []);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_home_directories() {
+        let root = scratch_dir("planner-homes");
+        fs::create_dir_all(root.join("etc/skel/.config")).unwrap();
+        fs::write(root.join("etc/skel/.bashrc"), "PS1='$ '\n").unwrap();
+        fs::write(root.join("etc/skel/.face"), b"\x89PNG\r\n\x1a\n\xff").unwrap();
+        fs::set_permissions(root.join("etc/skel/.face"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(root.join("etc/skel/.bashrc"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(root.join("etc/skel/.config"), fs::Permissions::from_mode(0o755)).unwrap();
+        let homedir = |mode: Option<u32>, skel: bool| HomeDirectory::Path {
+            location: PathBuf::from("/home/sapeint"),
+            subdirs: vec![PathBuf::from("./library"), PathBuf::from("./games/launchers")],
+            mode,
+            skel,
+        };
+        let system = |homedir: HomeDirectory| System {
+            users: HashMap::from([(Rc::from("sapeint"), User {
+                username: Some(Rc::from("sapeint")),
+                homedir,
+                account: Account { uid: Some(1000), group: Some(Rc::from("sapeint")), ..Account::default() },
+                ..Default::default()
+            })]),
+            ..Default::default()
+        };
+        let current = system(homedir(None, false));
+
+        // New home directories are seeded from the skeleton before their subdirectories are made.
+        let plan = Planner::new(&system(homedir(Some(0o750), true)), &current, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions(), &[
+            home_file("sapeint", PathBuf::from("/home/sapeint"), None, 0o750),
+            home_file("sapeint", PathBuf::from("/home/sapeint/.bashrc"), Some(String::from("PS1='$ '\n")), 0o644),
+            home_file("sapeint", PathBuf::from("/home/sapeint/.config"), None, 0o755),
+            Action::File(FileSystemAction::CopyFile {
+                original_location: PathBuf::from("/etc/skel/.face"),
+                final_location: PathBuf::from("/home/sapeint/.face"),
+                is_recursive: false,
+                mode: Some(0o644),
+                owner: Some(String::from("sapeint")),
+            }),
+            home_file("sapeint", PathBuf::from("/home/sapeint/games/launchers"), None, SUBDIR_MODE),
+            home_file("sapeint", PathBuf::from("/home/sapeint/library"), None, SUBDIR_MODE),
+        ]);
+        // Joined paths compare equal with or without `./`, but keep it when written out.
+        assert!(plan.to_json().contains("\"/home/sapeint/library\""));
+
+        // Existing directories are only fixed up where their owner or permissions are wrong.
+        for (directory, mode) in [("home/sapeint", HOME_MODE), ("home/sapeint/library", SUBDIR_MODE), ("home/sapeint/games/launchers", 0o700)] {
+            fs::create_dir_all(root.join(directory)).unwrap();
+            fs::set_permissions(root.join(directory), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let owner = fs::metadata(root.join("home/sapeint")).unwrap();
+        fs::write(root.join("etc/passwd"), format!("sapeint:x:{}:{}::/home/sapeint:/bin/sh\n", owner.uid(), owner.gid())).unwrap();
+        let plan = Planner::new(&system(homedir(None, true)), &current, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions(), &[home_file("sapeint", PathBuf::from("/home/sapeint/games/launchers"), None, SUBDIR_MODE)]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_dotfiles() {
+        let root = scratch_dir("planner-dotfiles");
+        let repository = root.join("dotfiles");
+        for (file, contents) in [(".git/HEAD", "ref: refs/heads/main\n"), (".bashrc", "alias ll='ls -l'\n"), (".vimrc", "set number\n"), (".config/i3/config", "bar {}\n"), ("README.md", "My dotfiles\n")] {
+            fs::create_dir_all(repository.join(file).parent().unwrap()).unwrap();
+            fs::write(repository.join(file), contents).unwrap();
+        }
+        fs::create_dir_all(root.join("home/sapeint")).unwrap();
+        fs::write(root.join("home/sapeint/.vimrc"), "set nonumber\n").unwrap();
+
+        // The package's own bashrc wins over the one in the dotfiles.
+        let bash = Package {
+            config: BTreeMap::from([(Rc::from("bashrc"), ConfigurationFile {
+                location: PathBuf::from("/home/sapeint/.bashrc"),
+                source: ConfigurationSource::Template(repository.join(".bashrc")),
+            })]),
+            version: None,
+            hold: None,
+            repository: Source::Remote(RemoteSource::VoidRepo),
+        };
+        let config = System {
+            users: HashMap::from([(Rc::from("sapeint"), User {
+                username: Some(Rc::from("sapeint")),
+                homedir: HomeDirectory::Path { location: PathBuf::from("/home/sapeint"), subdirs: vec![], mode: None, skel: false },
+                dotfiles: Some(Dotfiles { source: Source::Local(LocalSource::Directory(repository.clone())), exclude: vec![PathBuf::from("./README.md")], deploy: DotfileDeployment::Copy }),
+                packages: HashMap::from([(Rc::from("bash"), bash)]),
+                ..Default::default()
+            })]),
+            ..Default::default()
+        };
+        let current = System::default();
+        let dotfiles = |plan: Plan| plan.actions().iter().find(|action| matches!(action, Action::System(SystemAction::DeployDotfiles { .. }))).cloned();
+        let deploy = |dotfile_actions: Vec<Action>| Some(Action::System(SystemAction::DeployDotfiles { username: String::from("sapeint"), dotfile_actions }));
+        let manifest_location = PathBuf::from("/home/sapeint").join(MANIFEST_LOCATION);
+
+        // A file svsm didn't put there is never overwritten.
+        let err = Planner::new(&config, &current, root.clone()).plan().unwrap_err();
+        assert!(matches!(err, PlannerError::DotfileConflicts(paths) if paths == vec![PathBuf::from("/home/sapeint/.vimrc")]));
+
+        // Unless it already matches, in which case it is taken over.
+        fs::write(root.join("home/sapeint/.vimrc"), "set number\n").unwrap();
+        fs::set_permissions(root.join("home/sapeint/.vimrc"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(repository.join(".vimrc"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(repository.join(".config/i3/config"), fs::Permissions::from_mode(0o644)).unwrap();
+        let files = BTreeSet::from([PathBuf::from(".config/i3/config"), PathBuf::from(".vimrc")]);
+        assert_eq!(dotfiles(Planner::new(&config, &current, root.clone()).plan().unwrap()), deploy(vec![
+            home_file("sapeint", PathBuf::from("/home/sapeint/.config/i3/config"), Some(String::from("bar {}\n")), 0o644),
+            home_file("sapeint", manifest_location.clone(), Some(manifest(&files)), 0o644),
+        ]));
+
+        // Files that leave the repository are removed along with it.
+        fs::create_dir_all(root.join("home/sapeint/.config/i3")).unwrap();
+        fs::write(root.join("home/sapeint/.config/i3/config"), "bar {}\n").unwrap();
+        fs::create_dir_all(root.join("home/sapeint/.local/state/svsm")).unwrap();
+        fs::write(path_in_root(&root, &manifest_location), manifest(&files)).unwrap();
+        fs::remove_file(repository.join(".config/i3/config")).unwrap();
+        // Files that aren't text are copied when the plan is applied, instead of being put in it.
+        fs::write(repository.join(".face"), b"\x89PNG\r\n\x1a\n\xff").unwrap();
+        fs::set_permissions(repository.join(".face"), fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(dotfiles(Planner::new(&config, &current, root.clone()).plan().unwrap()), deploy(vec![
+            Action::File(FileSystemAction::CopyFile {
+                original_location: PathBuf::from("/dotfiles/.face"),
+                final_location: PathBuf::from("/home/sapeint/.face"),
+                is_recursive: false,
+                mode: Some(0o600),
+                owner: Some(String::from("sapeint")),
+            }),
+            Action::File(FileSystemAction::RemoveFile { file_location: PathBuf::from("/home/sapeint/.config/i3/config"), is_dir: false }),
+            home_file("sapeint", manifest_location, Some(manifest(&BTreeSet::from([PathBuf::from(".face"), PathBuf::from(".vimrc")]))), 0o644),
+        ]));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_dotfile_links() {
+        let root = scratch_dir("planner-dotfile-links");
+        let repository = root.join("dotfiles");
+        for file in [".vimrc", ".config/i3/config"] {
+            fs::create_dir_all(repository.join(file).parent().unwrap()).unwrap();
+            fs::write(repository.join(file), "set number\n").unwrap();
+            fs::set_permissions(repository.join(file), fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        // A copy deployed before the user switched to links.
+        let manifest_location = PathBuf::from("/home/sapeint").join(MANIFEST_LOCATION);
+        let files = BTreeSet::from([PathBuf::from(".config/i3/config"), PathBuf::from(".vimrc")]);
+        fs::create_dir_all(root.join("home/sapeint/.local/state/svsm")).unwrap();
+        fs::write(root.join("home/sapeint/.vimrc"), "set number\n").unwrap();
+        fs::write(path_in_root(&root, &manifest_location), manifest(&BTreeSet::from([PathBuf::from(".vimrc")]))).unwrap();
+
+        let system = |deploy: DotfileDeployment| System {
+            users: HashMap::from([(Rc::from("sapeint"), User {
+                username: Some(Rc::from("sapeint")),
+                homedir: HomeDirectory::Path { location: PathBuf::from("/home/sapeint"), subdirs: vec![], mode: None, skel: false },
+                dotfiles: Some(Dotfiles { source: Source::Local(LocalSource::Directory(repository.clone())), exclude: vec![], deploy }),
+                ..Default::default()
+            })]),
+            ..Default::default()
+        };
+        let current = System::default();
+        let dotfiles = |config: &System| Planner::new(config, &current, root.clone()).plan().unwrap()
+            .actions()
+            .iter()
+            .find(|action| matches!(action, Action::System(SystemAction::DeployDotfiles { .. })))
+            .cloned();
+        let deploy = |dotfile_actions: Vec<Action>| Some(Action::System(SystemAction::DeployDotfiles { username: String::from("sapeint"), dotfile_actions }));
+        let link = |file: &str, force: bool| Action::File(FileSystemAction::Symlink {
+            target: PathBuf::from("/dotfiles").join(file),
+            link_name: PathBuf::from("/home/sapeint").join(file),
+            force,
+        });
+
+        // Links point at the repository as the managed system sees it, replacing the old copy.
+        let links = system(DotfileDeployment::Symlink);
+        assert_eq!(dotfiles(&links), deploy(vec![
+            home_file("sapeint", PathBuf::from("/home/sapeint/.config/i3"), None, SUBDIR_MODE),
+            link(".config/i3/config", false),
+            link(".vimrc", true),
+            home_file("sapeint", manifest_location.clone(), Some(manifest(&files)), 0o644),
+        ]));
+
+        // Links that are already in place are left alone.
+        fs::create_dir_all(root.join("home/sapeint/.config/i3")).unwrap();
+        fs::remove_file(root.join("home/sapeint/.vimrc")).unwrap();
+        std::os::unix::fs::symlink("/dotfiles/.vimrc", root.join("home/sapeint/.vimrc")).unwrap();
+        std::os::unix::fs::symlink("/dotfiles/.config/i3/config", root.join("home/sapeint/.config/i3/config")).unwrap();
+        fs::write(path_in_root(&root, &manifest_location), manifest(&files)).unwrap();
+        assert_eq!(dotfiles(&links), None);
+
+        // Going back to copies removes each link before writing the file in its place.
+        let vimrc = PathBuf::from("/home/sapeint/.vimrc");
+        assert_eq!(dotfiles(&system(DotfileDeployment::Copy)), deploy(vec![
+            Action::File(FileSystemAction::RemoveFile { file_location: PathBuf::from("/home/sapeint/.config/i3/config"), is_dir: false }),
+            home_file("sapeint", PathBuf::from("/home/sapeint/.config/i3/config"), Some(String::from("set number\n")), 0o644),
+            Action::File(FileSystemAction::RemoveFile { file_location: vimrc.clone(), is_dir: false }),
+            home_file("sapeint", vimrc, Some(String::from("set number\n")), 0o644),
+        ]));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_shells_and_privileges() {
+        let root = scratch_dir("planner-privileges");
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/shells"), "# /etc/shells\n/bin/sh\n/usr/bin/zsh\n").unwrap();
+        // A home directory that is already in place, owned by whoever runs the tests.
+        fs::create_dir_all(root.join("home/sapeint")).unwrap();
+        fs::set_permissions(root.join("home/sapeint"), fs::Permissions::from_mode(HOME_MODE)).unwrap();
+        let owner = fs::metadata(root.join("home/sapeint")).unwrap();
+        fs::write(root.join("etc/passwd"), format!("sapeint:x:{}:{}::/home/sapeint:/usr/bin/zsh\n", owner.uid(), owner.gid())).unwrap();
+        let user = |shell: &str, privilege: Option<Privilege>| User {
+            homedir: HomeDirectory::Path { location: PathBuf::from("/home/sapeint"), subdirs: vec![], mode: None, skel: false },
+            account: Account { shell: Some(PathBuf::from(shell)), ..Account::default() },
+            privilege,
+            ..Default::default()
+        };
+        let system = |user: User| System {
+            users: HashMap::from([(Rc::from("sapeint"), user)]),
+            ..Default::default()
+        };
+        let doas = Privilege { tool: PrivilegeTool::Doas, nopasswd: true, commands: vec![] };
+
+        let config = system(user("zsh", Some(doas.clone())));
+        let empty = System { users: HashMap::new(), ..system(user("zsh", None)) };
+        let plan = Planner::new(&config, &empty, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions(), &[
+            Action::System(SystemAction::CreateUser {
+                username: String::from("sapeint"),
+                home: PathBuf::from("/home/sapeint"),
+                account: Account { shell: Some(PathBuf::from("/usr/bin/zsh")), ..Account::default() },
+            }),
+            Action::System(SystemAction::InstallPrivileges {
+                tool: PrivilegeTool::Doas,
+                contents: PrivilegeTool::Doas.contents(None, &[("sapeint", &doas)]),
+            }),
+        ]);
+
+        // Rules that are already in place are left alone, and removed with their declaration.
+        fs::write(root.join("etc/doas.conf"), PrivilegeTool::Doas.contents(None, &[("sapeint", &doas)]).unwrap()).unwrap();
+        let mut current = user("/usr/bin/zsh", None);
+        current.account.group = Some(Rc::from("sapeint"));
+        let current = system(current);
+        let plan = Planner::new(&config, &current, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions(), &[]);
+        let plan = Planner::new(&system(user("zsh", None)), &current, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions(), &[Action::System(SystemAction::InstallPrivileges { tool: PrivilegeTool::Doas, contents: None })]);
+
+        let config = system(user("fish", None));
+        assert!(matches!(Planner::new(&config, &current, root.clone()).plan(), Err(PlannerError::UnknownShell(shell)) if shell == "fish"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_user_services() {
+        let root = scratch_dir("planner-user-services");
+        let service = |name: &str, enabled: bool, definition: Option<ServiceDefinition>| (Rc::from(name), Service { name: Rc::from(name), enabled, definition, ..Default::default() });
+        let user = |services: Vec<(Rc<str>, Service)>| User {
+            username: Some(Rc::from("sapeint")),
+            homedir: HomeDirectory::Path { location: PathBuf::from("/home/sapeint"), subdirs: vec![], mode: None, skel: false },
+            services: HashMap::from_iter(services),
+            ..Default::default()
+        };
+        let system = |user: User| System {
+            users: HashMap::from([(Rc::from("sapeint"), user)]),
+            ..Default::default()
+        };
+        let definition = ServiceDefinition { run: Rc::from("pipewire"), finish: None, environment: BTreeMap::new(), user: None, log: None };
+        let config = system(user(vec![service("pipewire", true, Some(definition)), service("mpd", false, None)]));
+        let account = Account { uid: Some(1000), group: Some(Rc::from("sapeint")), ..Account::default() };
+        let current = system(User { account, ..user(vec![service("mpd", true, None)]) });
+
+        let scope = Some(ServiceUser { username: String::from("sapeint"), home: PathBuf::from("/home/sapeint") });
+        let plan = Planner::new(&config, &current, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions(), &[
+            home_file("sapeint", PathBuf::from("/home/sapeint"), None, HOME_MODE),
+            Action::File(FileSystemAction::CreateFile {
+                file_location: PathBuf::from("/home/sapeint/.local/share/service"),
+                contents: None,
+                is_dir: true,
+                mode: None,
+                owner: Some(String::from("sapeint")),
+                group: None,
+            }),
+            Action::System(SystemAction::DisableService { service_name: String::from("mpd"), user: scope.clone() }),
+            Action::System(SystemAction::ConfigureService {
+                service_name: String::from("pipewire"),
+                configuration_actions: vec![Action::File(FileSystemAction::CreateFile {
+                    file_location: PathBuf::from("/home/sapeint/.local/sv/pipewire/run"),
+                    contents: Some(String::from("#!/bin/sh\n# Generated by svsm, changes will be overwritten.\nexec pipewire\n")),
+                    is_dir: false,
+                    mode: Some(0o755),
+                    owner: Some(String::from("sapeint")),
+                    group: None,
+                })],
+                user: scope.clone(),
+            }),
+            Action::System(SystemAction::EnableService { service_name: String::from("pipewire"), user: scope }),
+        ]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_validates_against_repodata() {
+        let void = Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) };
+        let config = System {
+            packages: HashMap::from([(Rc::from("nano"), void.clone()), (Rc::from("nanoo"), void)]),
+            ..Default::default()
+        };
+        let current = System {
+            ..Default::default()
+        };
+        let repodata = RepodataCache {
+            indexes: vec![RepositoryIndex::from_plist(index_plist(&[("nano-7.2_1", &[])]).as_bytes()).unwrap()],
+        };
+
+        match Planner::new(&config, &current, PathBuf::from("/nonexistent")).with_repodata(&repodata).plan() {
+            Err(PlannerError::UnknownPackages(names)) => assert_eq!(names, vec![String::from("nanoo")]),
+            other => panic!("Expected unknown packages, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_plan_follows_lockfile() {
+        let void = Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) };
+        let config = System {
+            packages: HashMap::from([(Rc::from("nano"), void.clone()), (Rc::from("vim"), void.clone())]),
+            ..Default::default()
+        };
+        let current = System {
+            packages: HashMap::from([
+                (Rc::from("nano"), Package { version: Some(Rc::from("7.2_1")), ..void.clone() }),
+                (Rc::from("vim"), Package { version: Some(Rc::from("9.0_1")), ..void.clone() }),
+            ]),
+            ..Default::default()
+        };
+        let lockfile = Lockfile::from_json(r#"{
+            "format_version": 2,
+            "packages": {
+                "nano": { "pkgver": "nano-7.3_1", "repository": { "remote": { "void_remote": "https://repo-default.voidlinux.org/current" } } },
+                "vim": { "pkgver": "vim-9.0_1", "repository": { "remote": "void_repo" } }
+            },
+            "sources": []
+        }"#).unwrap();
+
+        // The locked build is installed from the repository it was found in.
+        let plan = Planner::new(&config, &current, PathBuf::from("/nonexistent")).with_lockfile(&lockfile).plan().unwrap();
+        assert_eq!(plan.actions(), &[Action::System(SystemAction::AddPackage {
+            package_name: String::from("nano"),
+            version: Some(String::from("7.3_1")),
+            package_repository: PackageRepository {
+                name: None,
+                location: Source::Remote(RemoteSource::VoidRemote(Rc::from("https://repo-default.voidlinux.org/current"))),
+                allow_restricted: false,
+            },
+        })]);
+    }
+
+    #[test]
+    fn test_plan_records_packages_and_commits() {
+        let void = Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) };
+        let config = System {
+            packages: HashMap::from([(Rc::from("nano"), void.clone()), (Rc::from("vim"), Package { hold: Some(true), ..void.clone() })]),
+            ..Default::default()
+        };
+        let current = System {
+            packages: HashMap::from([(Rc::from("vim"), Package { version: Some(Rc::from("9.0_1")), ..void.clone() })]),
+            ..Default::default()
+        };
+        let personal = Source::Remote(RemoteSource::GithubRemote {
+            user: Rc::from("sapein"),
+            repository_name: Rc::from("void-packages"),
+            branch_name: None,
+        });
+        let commits = BTreeMap::from([(personal.clone(), String::from("4b825dc"))]);
+
+        let plan = Planner::new(&config, &current, PathBuf::from("/nonexistent")).with_commits(&commits).plan().unwrap();
+        assert_eq!(plan.source_commits(), BTreeMap::from([(&personal, "4b825dc")]));
+        plan.verify(Path::new("/nonexistent"), &Observed { packages: &current.packages, commits: &commits }).unwrap();
+
+        // nano was installed and the checkout moved on since the plan was made.
+        let installed = HashMap::from([
+            (Rc::from("nano"), Package { version: Some(Rc::from("7.2_1")), ..void.clone() }),
+            (Rc::from("vim"), Package { version: Some(Rc::from("9.0_1")), ..void.clone() }),
+        ]);
+        let moved = BTreeMap::from([(personal.clone(), String::from("e69de29"))]);
+        match plan.verify(Path::new("/nonexistent"), &Observed { packages: &installed, commits: &moved }) {
+            Err(PlanError::PreconditionsFailed(failures)) => assert_eq!(failures, vec![
+                PreconditionFailure {
+                    expected: Precondition::Package { name: String::from("nano"), version: None },
+                    found: Precondition::Package { name: String::from("nano"), version: Some(String::from("7.2_1")) },
+                },
+                PreconditionFailure {
+                    expected: Precondition::Source { source: personal.clone(), commit: String::from("4b825dc") },
+                    found: Precondition::Source { source: personal, commit: String::from("e69de29") },
+                },
+            ]),
+            other => panic!("Expected failed preconditions, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_plan_pins_and_holds() {
+        let void = Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) };
+        let config = System {
+            packages: HashMap::from([
+                (Rc::from("firefox-esr"), Package { version: Some(Rc::from("115.3.1esr_1")), hold: Some(true), ..void.clone() }),
+                (Rc::from("nano"), Package { hold: Some(true), ..void.clone() }),
+                (Rc::from("vim"), void.clone()),
+                (Rc::from("ed"), Package { hold: Some(false), ..void.clone() }),
+            ]),
+            ..Default::default()
+        };
+        let current = System {
+            packages: HashMap::from([
+                (Rc::from("firefox-esr"), Package { version: Some(Rc::from("115.2.0esr_1")), hold: Some(true), ..void.clone() }),
+                (Rc::from("nano"), Package { version: Some(Rc::from("7.2_1")), ..void.clone() }),
+                (Rc::from("vim"), Package { version: Some(Rc::from("9.0_1")), hold: Some(true), ..void.clone() }),
+                (Rc::from("ed"), Package { version: Some(Rc::from("1.19_1")), hold: Some(true), ..void.clone() }),
+            ]),
+            ..Default::default()
+        };
+
+        let plan = Planner::new(&config, &current, PathBuf::from("/nonexistent")).plan().unwrap();
+        // vim was held by hand and the configuration doesn't mention holding it, so it stays held.
+        assert_eq!(plan.actions(), &[
+            hold("ed", false),
+            hold("firefox-esr", false),
+            Action::System(SystemAction::AddPackage {
+                package_name: String::from("firefox-esr"),
+                version: Some(String::from("115.3.1esr_1")),
+                package_repository: PackageRepository {
+                    name: None,
+                    location: Source::Remote(RemoteSource::VoidRepo),
+                    allow_restricted: false,
+                },
+            }),
+            hold("firefox-esr", true),
+            hold("nano", true),
+        ]);
+
+        let repodata = RepodataCache {
+            indexes: vec![RepositoryIndex::from_plist(index_plist(&[("firefox-esr-115.4.0esr_1", &[])]).as_bytes()).unwrap()],
+        };
+        match Planner::new(&config, &current, PathBuf::from("/nonexistent")).with_repodata(&repodata).plan() {
+            Err(PlannerError::UnavailableVersions(pkgvers)) => assert_eq!(pkgvers, vec![String::from("firefox-esr-115.3.1esr_1")]),
+            other => panic!("Expected unavailable versions, got {:?}", other),
+        }
+        assert_eq!(Planner::new(&config, &current, PathBuf::from("/nonexistent")).unverified_versions(), vec![String::from("firefox-esr-115.3.1esr_1")]);
+        assert!(Planner::new(&config, &current, PathBuf::from("/nonexistent")).with_repodata(&repodata).unverified_versions().is_empty());
+    }
+
+    #[test]
+    fn test_plan_configuration_files() {
+        let root = scratch_dir("planner-configuration");
+        let dotfiles = root.join("dotfiles");
+        fs::create_dir_all(dotfiles.join("bash")).unwrap();
+        fs::create_dir_all(root.join("home/sapeint")).unwrap();
+        fs::write(dotfiles.join("bash/bashrc"), "alias ll='ls -l'\n").unwrap();
+        fs::write(dotfiles.join("bash/bash_profile"), ". ~/.bashrc\n").unwrap();
+        fs::write(dotfiles.join("inputrc"), "set editing-mode vi\n").unwrap();
+        fs::write(root.join("home/sapeint/.bash_profile"), ". ~/.bashrc\n").unwrap();
+        fs::write(root.join("home/sapeint/.inputrc"), "# edited by hand\n").unwrap();
+
+        let file = |location: &str, path: &str, source: Option<Source>| ConfigurationFile {
+            location: PathBuf::from(location),
+            source: ConfigurationSource::File { path: PathBuf::from(path), source },
+        };
+        let bash = Package {
+            config: BTreeMap::from([
+                (Rc::from("bashrc"), file("/home/sapeint/.bashrc", "./bash/bashrc", Some(Source::Local(LocalSource::Directory(dotfiles.clone()))))),
+                (Rc::from("bash_profile"), file("/home/sapeint/.bash_profile", "./bash/bash_profile", Some(Source::Local(LocalSource::Directory(dotfiles.clone()))))),
+                (Rc::from("inputrc"), ConfigurationFile {
+                    location: PathBuf::from("/home/sapeint/.inputrc"),
+                    source: ConfigurationSource::Template(dotfiles.join("inputrc")),
+                }),
+            ]),
+            version: None,
+            hold: None,
+            repository: Source::Remote(RemoteSource::VoidRepo),
+        };
+        let config = System {
+            users: HashMap::from([(Rc::from("sapeint"), User {
+                username: Some(Rc::from("sapeint")),
+                homedir: HomeDirectory::Path { location: PathBuf::from("/home/sapeint"), subdirs: vec![], mode: None, skel: false },
+                packages: HashMap::from([(Rc::from("bash"), bash.clone())]),
+                ..Default::default()
+            })]),
+            ..Default::default()
+        };
+        let current = System {
+            packages: HashMap::from([(Rc::from("bash"), Package { config: BTreeMap::new(), ..bash.clone() })]),
+            ..Default::default()
+        };
+
+        // The profile is already up to date, and the template isn't copied over an existing file.
+        let plan = Planner::new(&config, &current, root.clone()).plan().unwrap();
+        assert_eq!(plan.actions(), &[
+            Action::System(SystemAction::CreateUser {
+                username: String::from("sapeint"),
+                home: PathBuf::from("/home/sapeint"),
+                account: Account::default(),
+            }),
+            home_file("sapeint", PathBuf::from("/home/sapeint"), None, HOME_MODE),
+            Action::System(SystemAction::ConfigurePackage {
+                package_name: String::from("bash"),
+                configuration_actions: vec![Action::File(FileSystemAction::CreateFile {
+                    file_location: PathBuf::from("/home/sapeint/.bashrc"),
+                    contents: Some(String::from("alias ll='ls -l'\n")),
+                    is_dir: false,
+                    mode: None,
+                    owner: Some(String::from("sapeint")),
+                    group: None,
+                })],
+            }),
+        ]);
+
+        let mut config = config;
+        config.users.get_mut("sapeint").unwrap().packages.insert(Rc::from("bash"), Package {
+            config: BTreeMap::from([(Rc::from("bashrc"), file("/home/sapeint/.bashrc", "./bashrc", None))]),
+            ..bash.clone()
+        });
+        match Planner::new(&config, &current, root.clone()).with_config_directory(root.join("missing")).plan() {
+            Err(PlannerError::UnreadableConfiguration { package, path, .. }) => {
+                assert_eq!(package, "bash");
+                assert_eq!(path, root.join("missing/bashrc"));
+            }
+            other => panic!("Expected an unreadable configuration file, got {:?}", other),
+        }
+
+        // Files from git sources are read from wherever the source is checked out.
+        let dotfiles_repository = Source::Remote(RemoteSource::GitRemote { url: Rc::from("file:///srv/dotfiles"), branch_name: None });
+        config.users.get_mut("sapeint").unwrap().packages.insert(Rc::from("bash"), Package {
+            config: BTreeMap::from([(Rc::from("bashrc"), file("/home/sapeint/.bashrc", "./bash/bashrc", Some(dotfiles_repository.clone())))]),
+            ..bash.clone()
+        });
+        assert!(matches!(Planner::new(&config, &current, root.clone()).plan(), Err(PlannerError::UnresolvedSource(_))));
+        let checkouts = BTreeMap::from([(dotfiles_repository, dotfiles.clone())]);
+        let plan = Planner::new(&config, &current, root.clone()).with_checkouts(&checkouts).plan().unwrap();
+        assert_eq!(plan.actions().len(), 3);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_restarts_dependent_services() {
+        let root = scratch_dir("planner-dependent-services");
+        let files = root.join("files");
+        fs::create_dir_all(&files).unwrap();
+        fs::write(files.join("nginx.conf"), "worker_processes 2;\n").unwrap();
+        fs::write(files.join("sshd_config"), "PermitRootLogin no\n").unwrap();
+        fs::write(files.join("nginx.pvsm"), "nginx = { configuration = { location = /etc/nginx/nginx.conf; }; services = [ nginx, ]; on_change = reload; };").unwrap();
+        let mut definitions = PackageDefinitions::default();
+        definitions.load_file(&files.join("nginx.pvsm")).unwrap();
+
+        let package = |location: &str, path: &str| Package {
+            config: BTreeMap::from([(Rc::from("config"), ConfigurationFile {
+                location: PathBuf::from(location),
+                source: ConfigurationSource::File { path: PathBuf::from(path), source: Some(Source::Local(LocalSource::Directory(files.clone()))) },
+            })]),
+            version: None,
+            hold: None,
+            repository: Source::Remote(RemoteSource::VoidRepo),
+        };
+        let service = |name: &str, files: &[&str]| (Rc::from(name), Service {
+            name: Rc::from(name),
+            enabled: true,
+            files: files.iter().map(PathBuf::from).collect(),
+            ..Default::default()
+        });
+        let config = System {
+            services: HashMap::from([
+                service("sshd", &["/etc/ssh/sshd_config"]),
+                service("ntpd", &["/etc/ntpd.conf"]),
+                service("dhcpcd", &["/etc/ssh/sshd_config"]),
+            ]),
+            packages: HashMap::from([
+                (Rc::from("nginx"), package("/etc/nginx/nginx.conf", "./nginx.conf")),
+                (Rc::from("openssh"), package("/etc/ssh/sshd_config", "./sshd_config")),
+            ]),
+            ..Default::default()
+        };
+        let current = System {
+            services: HashMap::from([service("sshd", &[]), service("ntpd", &[]), service("dhcpcd", &[]), service("nginx", &[])]),
+            packages: HashMap::from([
+                (Rc::from("nginx"), Package { config: BTreeMap::new(), ..package("", "") }),
+                (Rc::from("openssh"), Package { config: BTreeMap::new(), ..package("", "") }),
+            ]),
+            ..Default::default()
+        };
+        // dhcpcd depends on a changed file too, but isn't running.
+        for name in ["sshd", "ntpd", "nginx"] {
+            write_status(&root, name, &status_record(1, 412, 1_700_000_000, b'u'));
+        }
+
+        let plan = Planner::new(&config, &current, root.clone()).with_definitions(&definitions).plan().unwrap();
+        let service_actions = plan.actions()
+            .iter()
+            .filter(|action| !matches!(action, Action::System(SystemAction::ConfigurePackage { .. })))
+            .collect::<Vec<&Action>>();
+        assert_eq!(service_actions, vec![
+            &Action::System(SystemAction::RestartService { service_name: String::from("sshd"), user: None }),
+            &Action::System(SystemAction::ReloadService { service_name: String::from("nginx"), user: None }),
+        ]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_plan_enables_repositories() {
+        let root = scratch_dir("planner-repositories");
+        fs::write(root.join("steam.pvsm"), "steam = { is_nonfree = true; };").unwrap();
+        let mut definitions = PackageDefinitions::default();
+        definitions.load_file(&root.join("steam.pvsm")).unwrap();
+
+        let index = |location: &str, pkgvers: &[(&str, &[&str])]| RepositoryIndex {
+            location: Some(PathBuf::from(location)),
+            ..RepositoryIndex::from_plist(index_plist(pkgvers).as_bytes()).unwrap()
+        };
+        let repodata = RepodataCache {
+            indexes: vec![
+                index("/var/db/xbps/https___repo-default_voidlinux_org_current/x86_64-repodata", &[
+                    ("nano-7.2_1", &[]),
+                    ("void-repo-nonfree-9_5", &[]),
+                    ("void-repo-multilib-9_5", &[]),
+                    ("void-repo-multilib-nonfree-9_5", &[]),
+                ]),
+                index("/var/db/xbps/https___repo-default_voidlinux_org_current_multilib/x86_64-repodata", &[("nano-32bit-7.2_1", &[]), ("wine-32bit-8.0_1", &[])]),
+                index("/var/db/xbps/https___repo-default_voidlinux_org_current_multilib_nonfree/x86_64-repodata", &[("nvidia-libs-32bit-535.113.01_1", &[])]),
+            ],
+        };
+
+        let void = Package { config: BTreeMap::new(), version: None, hold: None, repository: Source::Remote(RemoteSource::VoidRepo) };
+        let mut config = System {
+            packages: HashMap::from([
+                (Rc::from("nano"), void.clone()),
+                (Rc::from("nvidia-libs-32bit"), void.clone()),
+                (Rc::from("steam"), void.clone()),
+                (Rc::from("void-repo-nonfree"), void.clone()),
+                (Rc::from("wine-32bit"), void.clone()),
+            ]),
+            ..Default::default()
+        };
+        let current = System {
+            packages: HashMap::from([
+                (Rc::from("nano"), void.clone()),
+                (Rc::from("void-repo-multilib"), void.clone()),
+            ]),
+            ..Default::default()
+        };
+
+        let install = |name: &str| Action::System(SystemAction::AddPackage {
+            package_name: String::from(name),
+            version: None,
+            package_repository: PackageRepository { name: None, location: Source::Remote(RemoteSource::VoidRepo), allow_restricted: false },
+        });
+        let plan = Planner::new(&config, &current, root.clone()).with_repodata(&repodata).with_definitions(&definitions).plan().unwrap();
+        assert_eq!(plan.actions(), &[
+            install("void-repo-multilib-nonfree"),
+            install("void-repo-nonfree"),
+            install("nvidia-libs-32bit"),
+            install("steam"),
+            install("wine-32bit"),
+        ]);
+
+        config.auto_enable_repositories = false;
+        let plan = Planner::new(&config, &current, root.clone()).with_repodata(&repodata).with_definitions(&definitions).plan().unwrap();
+        assert_eq!(plan.actions(), &[
+            install("nvidia-libs-32bit"),
+            install("steam"),
+            install("void-repo-nonfree"),
+            install("wine-32bit"),
+        ]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
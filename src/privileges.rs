@@ -0,0 +1,170 @@
+//! Rules letting users run commands as root, through sudo or doas.
+//!
+//! sudo rules go in a file of their own in `/etc/sudoers.d`. doas only reads `/etc/doas.conf`,
+//! so SVSM keeps its rules between marker comments there and leaves the rest of the file alone.
+//! The rules are checked by the tool itself before they are installed, as a broken sudoers file
+//! locks everyone out of root.
+
+use std::path::Path;
+use crate::command::{CommandError, CommandRunner};
+use crate::system::{Privilege, PrivilegeTool};
+
+pub const SUDOERS_LOCATION: &str = "/etc/sudoers.d/svsm";
+pub const DOAS_LOCATION: &str = "/etc/doas.conf";
+
+const HEADER: &str = "# Generated by svsm, changes will be overwritten.\n";
+const BEGIN: &str = "# BEGIN svsm, changes will be overwritten.";
+const END: &str = "# END svsm";
+
+impl PrivilegeTool {
+    pub(crate) const ALL: [PrivilegeTool; 2] = [PrivilegeTool::Sudo, PrivilegeTool::Doas];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            PrivilegeTool::Sudo => "sudo",
+            PrivilegeTool::Doas => "doas",
+        }
+    }
+
+    /// The file the tool's rules are kept in.
+    pub(crate) fn location(&self) -> &'static Path {
+        match self {
+            PrivilegeTool::Sudo => Path::new(SUDOERS_LOCATION),
+            PrivilegeTool::Doas => Path::new(DOAS_LOCATION),
+        }
+    }
+
+    /// The permissions the tool expects its configuration to have.
+    pub(crate) fn mode(&self) -> u32 {
+        match self {
+            PrivilegeTool::Sudo => 0o440,
+            PrivilegeTool::Doas => 0o600,
+        }
+    }
+
+    /// Has the tool check the configuration file at `path`.
+    pub(crate) fn validate(&self, runner: &dyn CommandRunner, path: &Path) -> Result<(), CommandError> {
+        let path = path.to_string_lossy();
+        match self {
+            PrivilegeTool::Sudo => runner.run_checked("visudo", &["-c", "-q", "-f", &path]),
+            PrivilegeTool::Doas => runner.run_checked("doas", &["-C", &path]),
+        }?;
+        Ok(())
+    }
+
+    /// What the tool's file should contain for `rules`, given what it contains now. `None`
+    /// means the file shouldn't exist.
+    pub(crate) fn contents(&self, current: Option<&str>, rules: &[(&str, &Privilege)]) -> Option<String> {
+        match self {
+            PrivilegeTool::Sudo => sudoers(rules),
+            PrivilegeTool::Doas => doas_conf(current.unwrap_or_default(), rules),
+        }
+    }
+}
+
+/// The sudoers file for `rules`, which is only needed when there are any.
+fn sudoers(rules: &[(&str, &Privilege)]) -> Option<String> {
+    if rules.is_empty() {
+        return None;
+    }
+
+    let mut sudoers = String::from(HEADER);
+    for (username, privilege) in rules {
+        let tag = if privilege.nopasswd { "NOPASSWD: " } else { "" };
+        let commands = match privilege.commands.is_empty() {
+            true => String::from("ALL"),
+            false => privilege.commands.join(", "),
+        };
+        sudoers.push_str(&format!("{} ALL=(ALL:ALL) {}{}\n", username, tag, commands));
+    }
+    Some(sudoers)
+}
+
+/// `current` with SVSM's block of rules replaced by one for `rules`, or dropped when there are
+/// none. The file is only removed when nothing else is left in it.
+fn doas_conf(current: &str, rules: &[(&str, &Privilege)]) -> Option<String> {
+    let mut lines = vec![];
+    let mut in_block = false;
+    for line in current.lines() {
+        match line {
+            _ if line.starts_with(BEGIN) => in_block = true,
+            END => in_block = false,
+            _ if !in_block => lines.push(line.to_string()),
+            _ => (),
+        }
+    }
+
+    if !rules.is_empty() {
+        lines.push(BEGIN.to_string());
+        for (username, privilege) in rules {
+            let options = if privilege.nopasswd { "nopass " } else { "" };
+            match privilege.commands.is_empty() {
+                true => lines.push(format!("permit {}{}", options, username)),
+                // doas takes one command per rule, with its arguments given separately.
+                false => lines.extend(privilege.commands.iter().map(|command| {
+                    let mut words = command.split_whitespace();
+                    let program = words.next().unwrap_or_default();
+                    let args = words.collect::<Vec<&str>>();
+                    match args.is_empty() {
+                        true => format!("permit {}{} cmd {}", options, username, program),
+                        false => format!("permit {}{} cmd {} args {}", options, username, program, args.join(" ")),
+                    }
+                })),
+            }
+        }
+        lines.push(END.to_string());
+    }
+
+    match lines.iter().all(|line| line.trim().is_empty()) {
+        true => None,
+        false => Some(lines.join("\n") + "\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use crate::command::fake::FakeCommandRunner;
+    use super::*;
+
+    fn privilege(nopasswd: bool, commands: &[&str]) -> Privilege {
+        Privilege { tool: PrivilegeTool::Sudo, nopasswd, commands: commands.iter().map(|command| Rc::from(*command)).collect() }
+    }
+
+    #[test]
+    fn test_sudoers() {
+        let everything = privilege(false, &[]);
+        let xbps = privilege(true, &["/usr/bin/xbps-install -Su", "/usr/bin/sv"]);
+        assert_eq!(
+            sudoers(&[("guest", &xbps), ("sapeint", &everything)]).unwrap(),
+            format!("{}guest ALL=(ALL:ALL) NOPASSWD: /usr/bin/xbps-install -Su, /usr/bin/sv\nsapeint ALL=(ALL:ALL) ALL\n", HEADER),
+        );
+        assert_eq!(sudoers(&[]), None);
+    }
+
+    #[test]
+    fn test_doas_conf() {
+        let xbps = privilege(true, &["/usr/bin/xbps-install -Su", "/usr/bin/sv"]);
+        let current = "permit persist :wheel\n";
+        let conf = doas_conf(current, &[("sapeint", &xbps)]).unwrap();
+        assert_eq!(conf, format!(
+            "permit persist :wheel\n{}\npermit nopass sapeint cmd /usr/bin/xbps-install args -Su\npermit nopass sapeint cmd /usr/bin/sv\n{}\n",
+            BEGIN, END,
+        ));
+
+        // Replacing the block leaves everything else where it was.
+        let everything = privilege(false, &[]);
+        assert_eq!(doas_conf(&conf, &[("sapeint", &everything)]).unwrap(), format!("permit persist :wheel\n{}\npermit sapeint\n{}\n", BEGIN, END));
+        assert_eq!(doas_conf(&conf, &[]).unwrap(), current);
+        assert_eq!(doas_conf(&format!("{}\npermit sapeint\n{}\n", BEGIN, END), &[]), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let runner = FakeCommandRunner::default();
+        PrivilegeTool::Sudo.validate(&runner, Path::new("/etc/sudoers.d/svsm")).unwrap();
+        runner.respond(1, "");
+        assert!(PrivilegeTool::Doas.validate(&runner, Path::new("/etc/doas.conf")).is_err());
+        assert_eq!(runner.calls(), vec!["visudo -c -q -f /etc/sudoers.d/svsm", "doas -C /etc/doas.conf"]);
+    }
+}
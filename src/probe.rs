@@ -0,0 +1,440 @@
+//! Reads the live system into a [`System`].
+//!
+//! This is what SVSM exposes as `system.current`. Everything is read relative to a root
+//! directory so that a chroot, or a fixture tree in tests, can be probed the same way as
+//! the running system.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::accounts::{is_hashed, is_locked};
+use crate::package_manager::{PackageManager, PackageManagerError};
+use crate::pkgver::PkgVer;
+use crate::service::{USER_RUNSVDIR_LOCATION, USER_SERVICE_LOCATION};
+use crate::system::{path_in_root, Account, HomeDirectory, LocalSource, Package, PackageRepository, Password, RemoteSource, Service, Source, System, User};
+
+/// The package database XBPS keeps for the installed system.
+pub const PKGDB_LOCATION: &str = "/var/db/xbps/pkgdb-0.38.plist";
+pub const XBPS_CONFIG_LOCATION: &str = "/etc/xbps.d";
+pub const SERVICE_LOCATION: &str = "/etc/sv";
+pub const RUNSVDIR_LOCATION: &str = "/etc/runit/runsvdir/default";
+pub const PASSWD_LOCATION: &str = "/etc/passwd";
+pub const GROUP_LOCATION: &str = "/etc/group";
+pub const SHADOW_LOCATION: &str = "/etc/shadow";
+pub const SHELLS_LOCATION: &str = "/etc/shells";
+
+/// The range of uids given to regular users, matching the defaults in Void's `login.defs`.
+pub(crate) const UID_MIN: u32 = 1000;
+pub(crate) const UID_MAX: u32 = 60000;
+
+#[derive(Debug)]
+pub enum ProbeError {
+    Io(io::Error),
+    Pkgdb(plist::Error),
+    Package(PackageManagerError),
+}
+
+impl Display for ProbeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeError::Io(err) => write!(f, "{}", err),
+            ProbeError::Pkgdb(err) => write!(f, "Unable to read the package database: {}", err),
+            ProbeError::Package(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl From<io::Error> for ProbeError {
+    fn from(err: io::Error) -> Self {
+        ProbeError::Io(err)
+    }
+}
+
+impl From<plist::Error> for ProbeError {
+    fn from(err: plist::Error) -> Self {
+        ProbeError::Pkgdb(err)
+    }
+}
+
+impl From<PackageManagerError> for ProbeError {
+    fn from(err: PackageManagerError) -> Self {
+        ProbeError::Package(err)
+    }
+}
+
+pub struct Probe<'a> {
+    root: PathBuf,
+    package_manager: Option<&'a dyn PackageManager>,
+    /// Accounts to read even when they are outside the range given to regular users.
+    accounts: Vec<Rc<str>>,
+}
+
+impl<'a> Probe<'a> {
+    pub fn new(root: PathBuf) -> Self {
+        Probe { root, package_manager: None, accounts: vec![] }
+    }
+
+    /// Also reads the accounts named, such as system accounts a configuration declares.
+    pub fn with_accounts(self, accounts: impl IntoIterator<Item = Rc<str>>) -> Self {
+        Probe { accounts: accounts.into_iter().collect(), ..self }
+    }
+
+    /// Asks the package manager for the installed packages, instead of reading the pkgdb.
+    pub fn with_package_manager(self, package_manager: &'a dyn PackageManager) -> Self {
+        Probe { package_manager: Some(package_manager), ..self }
+    }
+
+    pub fn probe(&self) -> Result<System, ProbeError> {
+        Ok(System {
+            services: self.services()?,
+            repositories: self.repositories()?,
+            users: self.users()?,
+            packages: self.packages()?,
+            auto_enable_repositories: true,
+        })
+    }
+
+    fn resolve(&self, path: &str) -> PathBuf {
+        path_in_root(&self.root, Path::new(path))
+    }
+
+    fn packages(&self) -> Result<HashMap<Rc<str>, Package>, ProbeError> {
+        if let Some(package_manager) = self.package_manager {
+            return Ok(package_manager
+                .installed()?
+                .into_iter()
+                .map(|installed| (Rc::from(installed.name), Package {
+                    config: BTreeMap::new(),
+                    version: installed_version(&installed.pkgver),
+                    hold: Some(installed.held),
+                    repository: Source::Remote(RemoteSource::VoidRepo),
+                }))
+                .collect());
+        }
+
+        let pkgdb = self.resolve(PKGDB_LOCATION);
+        if !pkgdb.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let pkgdb = plist::Value::from_file(pkgdb)?;
+        let packages = pkgdb
+            .as_dictionary()
+            .into_iter()
+            .flat_map(|dictionary| dictionary.iter())
+            // Keys such as `_XBPS_ALTERNATIVES_` are metadata, not packages.
+            .filter(|(name, _)| !name.starts_with('_'))
+            .map(|(name, package)| {
+                let field = |key: &str| package
+                    .as_dictionary()
+                    .and_then(|package| package.get(key));
+                let repository = field("repository").and_then(plist::Value::as_string);
+                (Rc::from(name.as_str()), Package {
+                    config: BTreeMap::new(),
+                    version: field("pkgver").and_then(plist::Value::as_string).and_then(installed_version),
+                    hold: Some(field("hold").and_then(plist::Value::as_boolean).unwrap_or(false)),
+                    repository: match repository {
+                        Some(repository) => repository_source(repository),
+                        None => Source::Remote(RemoteSource::VoidRepo),
+                    },
+                })
+            })
+            .collect();
+        Ok(packages)
+    }
+
+    /// Reads every `repository=` line from the configuration files in `/etc/xbps.d`.
+    fn repositories(&self) -> io::Result<HashMap<Rc<str>, PackageRepository>> {
+        let mut repositories = HashMap::new();
+        for path in read_dir_sorted(&self.resolve(XBPS_CONFIG_LOCATION))? {
+            if path.extension() != Some(OsStr::new("conf")) {
+                continue;
+            }
+
+            let name = path.file_stem().map(|stem| Rc::from(stem.to_string_lossy()));
+            for line in fs::read_to_string(&path)?.lines() {
+                let Some(url) = line.trim().strip_prefix("repository=") else { continue };
+                repositories.insert(Rc::from(url.trim()), PackageRepository {
+                    name: name.clone(),
+                    location: repository_source(url.trim()),
+                    allow_restricted: false,
+                });
+            }
+        }
+        Ok(repositories)
+    }
+
+    /// Reads every service in `/etc/sv`, along with whether it is linked into the default
+    /// runsvdir and whether it has a `down` file.
+    fn services(&self) -> io::Result<HashMap<Rc<str>, Service>> {
+        self.services_in(Path::new(SERVICE_LOCATION), Path::new(RUNSVDIR_LOCATION))
+    }
+
+    /// Reads every service in `directory`, enabled when it is linked into `runsvdir`.
+    fn services_in(&self, directory: &Path, runsvdir: &Path) -> io::Result<HashMap<Rc<str>, Service>> {
+        let runsvdir = path_in_root(&self.root, runsvdir);
+        let mut services = HashMap::new();
+        for path in read_dir_sorted(&path_in_root(&self.root, directory))? {
+            if !path.is_dir() {
+                continue;
+            }
+
+            let Some(name) = path.file_name().map(|name| Rc::from(name.to_string_lossy())) else { continue };
+            services.insert(Rc::clone(&name), Service {
+                enabled: fs::symlink_metadata(runsvdir.join(&*name)).is_ok(),
+                downed: path.join("down").exists(),
+                name,
+                definition: None,
+                files: vec![],
+                on_change: None,
+            });
+        }
+        Ok(services)
+    }
+
+    /// Reads the regular users from `/etc/passwd`, skipping system accounts that weren't asked
+    /// for, along with their groups from `/etc/group`, their password hashes and whether
+    /// `/etc/shadow` has them locked, and the services in their home directories.
+    pub fn users(&self) -> io::Result<HashMap<Rc<str>, User>> {
+        let groups = self.read_table(GROUP_LOCATION)?;
+        let shadow = match self.read_table(SHADOW_LOCATION) {
+            // Only root can read the shadow file, and without it no account looks locked.
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => vec![],
+            shadow => shadow?,
+        };
+
+        self.read_table(PASSWD_LOCATION)?
+            .into_iter()
+            .filter(|fields| fields.len() >= 7)
+            .filter_map(|fields| fields[2].parse::<u32>().ok().map(|uid| (uid, fields)))
+            .filter(|(uid, fields)| (UID_MIN..=UID_MAX).contains(uid) || self.accounts.iter().any(|account| account.as_ref() == fields[0]))
+            .map(|(uid, fields)| {
+                let name = fields[0].as_str();
+                let home = PathBuf::from(&fields[5]);
+                let account = Account {
+                    uid: Some(uid),
+                    group: groups.iter().find(|group| group.len() >= 3 && group[2] == fields[3]).map(|group| Rc::from(group[0].as_str())),
+                    groups: groups
+                        .iter()
+                        .filter(|group| group.len() >= 4 && group[3].split(',').any(|member| member == name))
+                        .map(|group| Rc::from(group[0].as_str()))
+                        .collect(),
+                    shell: Some(PathBuf::from(&fields[6])),
+                    locked: shadow.iter().any(|entry| entry.len() >= 2 && entry[0] == name && is_locked(&entry[1])),
+                    password: shadow
+                        .iter()
+                        .find(|entry| entry.len() >= 2 && entry[0] == name)
+                        .map(|entry| entry[1].trim_start_matches('!'))
+                        .filter(|hash| is_hashed(hash))
+                        .map(|hash| Password::Hashed(Rc::from(hash))),
+                };
+                let services = self.services_in(&home.join(USER_SERVICE_LOCATION), &home.join(USER_RUNSVDIR_LOCATION))?;
+                Ok((Rc::from(name), User {
+                    username: Some(Rc::from(name)),
+                    homedir: HomeDirectory::Path {
+                        location: home,
+                        subdirs: vec![],
+                        mode: None,
+                        skel: false,
+                    },
+                    dotfiles: None,
+                    packages: HashMap::new(),
+                    services,
+                    account,
+                    // Rules in sudoers and doas.conf aren't read back.
+                    privilege: None,
+                }))
+            })
+            .collect()
+    }
+
+    /// Reads one of the colon separated account databases, which is empty when it is missing.
+    fn read_table(&self, location: &str) -> io::Result<Vec<Vec<String>>> {
+        let contents = match fs::read_to_string(self.resolve(location)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            contents => contents?,
+        };
+        Ok(contents.lines().map(|line| line.split(':').map(String::from).collect()).collect())
+    }
+}
+
+/// Repositories are either URLs or local directories of binary packages.
+pub(crate) fn repository_source(repository: &str) -> Source {
+    match repository.starts_with('/') {
+        true => Source::Local(LocalSource::Directory(PathBuf::from(repository))),
+        false => Source::Remote(RemoteSource::VoidRemote(Rc::from(repository))),
+    }
+}
+
+fn installed_version(pkgver: &str) -> Option<Rc<str>> {
+    pkgver.parse::<PkgVer>().ok().map(|pkgver| Rc::from(pkgver.full_version()))
+}
+
+fn read_dir_sorted(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = match fs::read_dir(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        entries => entries?.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<Vec<PathBuf>>>()?,
+    };
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use crate::package_manager::FakePackageManager;
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    const PKGDB: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple Computer//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>_XBPS_ALTERNATIVES_</key>
+	<dict/>
+	<key>firefox</key>
+	<dict>
+		<key>automatic-install</key>
+		<false/>
+		<key>hold</key>
+		<true/>
+		<key>pkgver</key>
+		<string>firefox-118.0.1_2</string>
+		<key>repository</key>
+		<string>https://repo-default.voidlinux.org/current</string>
+	</dict>
+	<key>st</key>
+	<dict>
+		<key>pkgver</key>
+		<string>st-0.9_1</string>
+		<key>repository</key>
+		<string>/home/sapeint/void-packages/hostdir/binpkgs</string>
+	</dict>
+</dict>
+</plist>
+"#;
+
+    fn fixture(name: &str) -> PathBuf {
+        let root = scratch_dir(name);
+        fs::create_dir_all(root.join("var/db/xbps")).unwrap();
+        fs::write(root.join("var/db/xbps/pkgdb-0.38.plist"), PKGDB).unwrap();
+
+        fs::create_dir_all(root.join("etc/xbps.d")).unwrap();
+        fs::write(root.join("etc/xbps.d/00-repository-main.conf"), "repository=https://repo-default.voidlinux.org/current\n").unwrap();
+        fs::write(root.join("etc/xbps.d/10-local.conf"), "# Local packages\nrepository=/home/sapeint/void-packages/hostdir/binpkgs\n").unwrap();
+        fs::write(root.join("etc/xbps.d/README"), "repository=https://example.com\n").unwrap();
+
+        for service in ["sshd", "dhcpcd", "ntpd"] {
+            fs::create_dir_all(root.join("etc/sv").join(service)).unwrap();
+        }
+        fs::write(root.join("etc/sv/ntpd/down"), "").unwrap();
+        fs::create_dir_all(root.join("etc/runit/runsvdir/default")).unwrap();
+        symlink("/etc/sv/sshd", root.join("etc/runit/runsvdir/default/sshd")).unwrap();
+        symlink("/etc/sv/ntpd", root.join("etc/runit/runsvdir/default/ntpd")).unwrap();
+
+        fs::create_dir_all(root.join("home/sapeint/.local/sv/pipewire")).unwrap();
+        fs::create_dir_all(root.join("home/sapeint/.local/share/service")).unwrap();
+        symlink("/home/sapeint/.local/sv/pipewire", root.join("home/sapeint/.local/share/service/pipewire")).unwrap();
+
+        fs::write(root.join("etc/passwd"), "root:x:0:0:root:/root:/bin/bash\n\
+                                            nobody:x:99:99:Unprivileged User:/dev/null:/bin/false\n\
+                                            sapeint:x:1000:1000::/home/sapeint:/bin/bash\n").unwrap();
+        fs::write(root.join("etc/group"), "root:x:0:\nwheel:x:4:sapeint\naudio:x:12:sapeint\nsapeint:x:1000:\n").unwrap();
+        fs::write(root.join("etc/shadow"), "root:!$6$salt$hash:19000::::::\nsapeint:$6$salt$hash:19000::::::\n").unwrap();
+        root
+    }
+
+    #[test]
+    fn test_probe() {
+        let root = fixture("probe");
+        let system = Probe::new(root.clone()).probe().unwrap();
+
+        assert_eq!(system.packages, HashMap::from([
+            (Rc::from("firefox"), Package {
+                config: BTreeMap::new(),
+                version: Some(Rc::from("118.0.1_2")),
+                hold: Some(true),
+                repository: Source::Remote(RemoteSource::VoidRemote(Rc::from("https://repo-default.voidlinux.org/current"))),
+            }),
+            (Rc::from("st"), Package {
+                config: BTreeMap::new(),
+                version: Some(Rc::from("0.9_1")),
+                hold: Some(false),
+                repository: Source::Local(LocalSource::Directory(PathBuf::from("/home/sapeint/void-packages/hostdir/binpkgs"))),
+            }),
+        ]));
+
+        assert_eq!(system.repositories, HashMap::from([
+            (Rc::from("https://repo-default.voidlinux.org/current"), PackageRepository {
+                name: Some(Rc::from("00-repository-main")),
+                location: Source::Remote(RemoteSource::VoidRemote(Rc::from("https://repo-default.voidlinux.org/current"))),
+                allow_restricted: false,
+            }),
+            (Rc::from("/home/sapeint/void-packages/hostdir/binpkgs"), PackageRepository {
+                name: Some(Rc::from("10-local")),
+                location: Source::Local(LocalSource::Directory(PathBuf::from("/home/sapeint/void-packages/hostdir/binpkgs"))),
+                allow_restricted: false,
+            }),
+        ]));
+
+        assert_eq!(system.services, HashMap::from([
+            (Rc::from("sshd"), Service { name: Rc::from("sshd"), enabled: true, ..Default::default() }),
+            (Rc::from("dhcpcd"), Service { name: Rc::from("dhcpcd"), enabled: false, ..Default::default() }),
+            (Rc::from("ntpd"), Service { name: Rc::from("ntpd"), enabled: true, downed: true, ..Default::default() }),
+        ]));
+
+        assert_eq!(system.users.keys().collect::<Vec<&Rc<str>>>(), vec![&Rc::from("sapeint")]);
+        let nobody = Probe::new(root.clone()).with_accounts([Rc::from("nobody")]).users().unwrap();
+        assert_eq!(nobody[&Rc::from("nobody")].account.uid, Some(99));
+        assert_eq!(system.users[&Rc::from("sapeint")].homedir, HomeDirectory::Path {
+            location: PathBuf::from("/home/sapeint"),
+            subdirs: vec![],
+            mode: None,
+            skel: false,
+        });
+        assert_eq!(system.users[&Rc::from("sapeint")].account, Account {
+            uid: Some(1000),
+            group: Some(Rc::from("sapeint")),
+            groups: vec![Rc::from("wheel"), Rc::from("audio")],
+            shell: Some(PathBuf::from("/bin/bash")),
+            locked: false,
+            password: Some(Password::Hashed(Rc::from("$6$salt$hash"))),
+        });
+        assert_eq!(system.users[&Rc::from("sapeint")].services, HashMap::from([
+            (Rc::from("pipewire"), Service { name: Rc::from("pipewire"), enabled: true, ..Default::default() }),
+        ]));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_probe_empty_root() {
+        let root = scratch_dir("probe-empty");
+        let system = Probe::new(root.clone()).probe().unwrap();
+
+        assert!(system.packages.is_empty());
+        assert!(system.repositories.is_empty());
+        assert!(system.services.is_empty());
+        assert!(system.users.is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_probe_with_package_manager() {
+        let root = scratch_dir("probe-package-manager");
+        let mut package_manager = FakePackageManager::default().with_available("dmenu-5.2_1", "current");
+        package_manager.install(&["dmenu"], None).unwrap();
+
+        let system = Probe::new(root.clone()).with_package_manager(&package_manager).probe().unwrap();
+        assert_eq!(system.packages.keys().collect::<Vec<&Rc<str>>>(), vec![&Rc::from("dmenu")]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
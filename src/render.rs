@@ -0,0 +1,540 @@
+//! Human-readable rendering of plans.
+//!
+//! Actions are grouped by what they affect, and changes to existing files are shown as
+//! unified diffs between what is currently on disk and what SVSM will write.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use similar::TextDiff;
+use crate::actions::{Action, FileSystemAction, ServiceUser, SystemAction};
+use crate::plan::Plan;
+use crate::system::{path_in_root, Account, Password};
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+enum Category {
+    Repositories,
+    Packages,
+    Services,
+    Users,
+    Files,
+}
+
+impl Category {
+    const ALL: [Category; 5] = [Category::Repositories, Category::Packages, Category::Services, Category::Users, Category::Files];
+
+    fn title(&self) -> &'static str {
+        match self {
+            Category::Repositories => "Repositories",
+            Category::Packages => "Packages",
+            Category::Services => "Services",
+            Category::Users => "Users",
+            Category::Files => "Files",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Change {
+    Add,
+    Remove,
+    Modify,
+}
+
+impl Change {
+    fn marker(&self) -> &'static str {
+        match self {
+            Change::Add => "+",
+            Change::Remove => "-",
+            Change::Modify => "~",
+        }
+    }
+
+    fn colour(&self) -> &'static str {
+        match self {
+            Change::Add => GREEN,
+            Change::Remove => RED,
+            Change::Modify => YELLOW,
+        }
+    }
+}
+
+/// A single line of the rendered plan, with an optional diff shown beneath it.
+struct Entry {
+    category: Category,
+    change: Change,
+    description: String,
+    diff: Option<String>,
+}
+
+pub struct PlanRenderer {
+    root: PathBuf,
+    colour: bool,
+}
+
+impl PlanRenderer {
+    pub fn new(root: PathBuf, colour: bool) -> Self {
+        PlanRenderer { root, colour }
+    }
+
+    pub fn render(&self, plan: &Plan) -> String {
+        let mut entries = vec![];
+        for action in plan.actions() {
+            self.collect_entries(action, &mut entries);
+        }
+
+        if entries.is_empty() {
+            return String::from("No changes.\n");
+        }
+
+        let mut output = String::new();
+        for category in Category::ALL {
+            let entries = entries.iter().filter(|e| e.category == category).collect::<Vec<&Entry>>();
+            if entries.is_empty() {
+                continue;
+            }
+
+            writeln!(output, "{}", self.paint(BOLD, category.title())).unwrap();
+            for entry in entries {
+                let line = format!("{} {}", entry.change.marker(), entry.description);
+                writeln!(output, "  {}", self.paint(entry.change.colour(), &line)).unwrap();
+                if let Some(diff) = &entry.diff {
+                    for line in diff.lines() {
+                        writeln!(output, "      {}", self.paint_diff_line(line)).unwrap();
+                    }
+                }
+            }
+        }
+        output
+    }
+
+    fn collect_entries(&self, action: &Action, entries: &mut Vec<Entry>) {
+        let (category, change, description, diff) = match action {
+            Action::System(SystemAction::AddRepository { package_repository }) => (
+                Category::Repositories, Change::Add, describe_repository(package_repository), None,
+            ),
+            Action::System(SystemAction::RemoveRepository { package_repository }) => (
+                Category::Repositories, Change::Remove, describe_repository(package_repository), None,
+            ),
+            Action::System(SystemAction::AddPackage { package_name, version: None, package_repository }) => (
+                Category::Packages, Change::Add, format!("{} (from {})", package_name, package_repository.location), None,
+            ),
+            Action::System(SystemAction::AddPackage { package_name, version: Some(version), package_repository }) => (
+                Category::Packages, Change::Add, format!("{}-{} (from {})", package_name, version, package_repository.location), None,
+            ),
+            Action::System(SystemAction::BuildPackage { package_name, version: None, package_repository }) => (
+                Category::Packages, Change::Add, format!("{} (build from {})", package_name, package_repository.location), None,
+            ),
+            Action::System(SystemAction::BuildPackage { package_name, version: Some(version), package_repository }) => (
+                Category::Packages, Change::Add, format!("{}-{} (build from {})", package_name, version, package_repository.location), None,
+            ),
+            Action::System(SystemAction::RemovePackage { package_name }) => (
+                Category::Packages, Change::Remove, package_name.clone(), None,
+            ),
+            Action::System(SystemAction::HoldPackage { package_name, hold: true }) => (
+                Category::Packages, Change::Modify, format!("{} (hold)", package_name), None,
+            ),
+            Action::System(SystemAction::HoldPackage { package_name, hold: false }) => (
+                Category::Packages, Change::Modify, format!("{} (release hold)", package_name), None,
+            ),
+            Action::System(SystemAction::EnableService { service_name, user }) => (
+                Category::Services, Change::Add, describe_service(service_name, user.as_ref()), None,
+            ),
+            Action::System(SystemAction::DisableService { service_name, user }) => (
+                Category::Services, Change::Remove, describe_service(service_name, user.as_ref()), None,
+            ),
+            Action::System(SystemAction::DownService { service_name, down: true, user }) => (
+                Category::Services, Change::Modify, format!("{} (down)", describe_service(service_name, user.as_ref())), None,
+            ),
+            Action::System(SystemAction::DownService { service_name, down: false, user }) => (
+                Category::Services, Change::Modify, format!("{} (up)", describe_service(service_name, user.as_ref())), None,
+            ),
+            Action::System(SystemAction::RestartService { service_name, user }) => (
+                Category::Services, Change::Modify, format!("{} (restart)", describe_service(service_name, user.as_ref())), None,
+            ),
+            Action::System(SystemAction::ReloadService { service_name, user }) => (
+                Category::Services, Change::Modify, format!("{} (reload)", describe_service(service_name, user.as_ref())), None,
+            ),
+            Action::System(SystemAction::CreateUser { username, account, .. }) => (
+                Category::Users, Change::Add, describe_account(username, account), None,
+            ),
+            Action::System(SystemAction::ModifyUser { username, account }) => (
+                Category::Users, Change::Modify, describe_account(username, account), None,
+            ),
+            Action::System(SystemAction::InstallPrivileges { tool, contents }) => {
+                let location = tool.location();
+                let description = format!("{} rules in {}", tool.name(), location.display());
+                match (self.current_contents(location), contents) {
+                    (Some(current), Some(new)) => (Category::Users, Change::Modify, description, diff(location, &current, new)),
+                    (None, Some(_)) => (Category::Users, Change::Add, description, None),
+                    (_, None) => (Category::Users, Change::Remove, description, None),
+                }
+            }
+            Action::System(SystemAction::ConfigurePackage { package_name, configuration_actions }) => {
+                entries.push(Entry {
+                    category: Category::Packages,
+                    change: Change::Modify,
+                    description: format!("{} (configure)", package_name),
+                    diff: None,
+                });
+                for action in configuration_actions {
+                    self.collect_entries(action, entries);
+                }
+                return;
+            }
+            Action::System(SystemAction::DeployDotfiles { username, dotfile_actions }) => {
+                entries.push(Entry {
+                    category: Category::Users,
+                    change: Change::Modify,
+                    description: format!("{}'s dotfiles", username),
+                    diff: None,
+                });
+                for action in dotfile_actions {
+                    self.collect_entries(action, entries);
+                }
+                return;
+            }
+            Action::System(SystemAction::ConfigureService { service_name, configuration_actions, user }) => {
+                entries.push(Entry {
+                    category: Category::Services,
+                    change: Change::Modify,
+                    description: format!("{} (configure)", describe_service(service_name, user.as_ref())),
+                    diff: None,
+                });
+                for action in configuration_actions {
+                    self.collect_entries(action, entries);
+                }
+                return;
+            }
+            Action::File(action) => match self.describe_file_action(action) {
+                Some(description) => description,
+                None => return,
+            },
+        };
+
+        entries.push(Entry { category, change, description, diff });
+    }
+
+    /// Describes a file action, or returns `None` when it leaves the file as it is.
+    fn describe_file_action(&self, action: &FileSystemAction) -> Option<(Category, Change, String, Option<String>)> {
+        let description = match action {
+            FileSystemAction::CreateFile { file_location, is_dir: true, mode, .. } => {
+                let location = format!("{}/", file_location.display());
+                match (path_in_root(&self.root, file_location).is_dir(), mode) {
+                    // An existing directory only has its permissions set.
+                    (true, Some(mode)) => (Category::Files, Change::Modify, format!("{} (mode {:o})", location, mode), None),
+                    (true, None) => return None,
+                    (false, _) => (Category::Files, Change::Add, location, None),
+                }
+            }
+            FileSystemAction::CreateFile { file_location, contents, is_dir: false, mode, .. } => {
+                let new = contents.clone().unwrap_or_default();
+                match (self.current_contents(file_location), mode) {
+                    // Only the permissions change, so there is nothing to diff.
+                    (Some(current), Some(mode)) if current == new => (Category::Files, Change::Modify, format!("{} (mode {:o})", file_location.display(), mode), None),
+                    (Some(current), None) if current == new => return None,
+                    (Some(current), _) => (Category::Files, Change::Modify, file_location.display().to_string(), diff(file_location, &current, &new)),
+                    // A file that isn't text is replaced without a diff.
+                    (None, _) if self.exists(file_location) => (Category::Files, Change::Modify, file_location.display().to_string(), None),
+                    (None, _) => (Category::Files, Change::Add, file_location.display().to_string(), None),
+                }
+            }
+            FileSystemAction::AddToFile { original_file, content_to_add } => {
+                match self.current_contents(original_file) {
+                    Some(current) => {
+                        let new = current.clone() + content_to_add;
+                        (Category::Files, Change::Modify, format!("{} (append)", original_file.display()), diff(original_file, &current, &new))
+                    }
+                    None if self.exists(original_file) => (Category::Files, Change::Modify, format!("{} (append)", original_file.display()), None),
+                    None => (Category::Files, Change::Add, format!("{} (append)", original_file.display()), None),
+                }
+            }
+            FileSystemAction::RemoveFile { file_location, .. } => {
+                (Category::Files, Change::Remove, file_location.display().to_string(), None)
+            }
+            FileSystemAction::MoveFile { original_location, final_location, .. } => {
+                (Category::Files, Change::Modify, format!("{} -> {} (move)", original_location.display(), final_location.display()), None)
+            }
+            FileSystemAction::CopyFile { original_location, final_location, .. } => {
+                let change = match self.exists(final_location) {
+                    true => Change::Modify,
+                    false => Change::Add,
+                };
+                (Category::Files, change, format!("{} (copied from {})", final_location.display(), original_location.display()), None)
+            }
+            FileSystemAction::RenameFile { original_name, final_name } => {
+                (Category::Files, Change::Modify, format!("{} -> {} (rename)", original_name.display(), final_name.display()), None)
+            }
+            FileSystemAction::Symlink { target, link_name, .. } => {
+                let change = match self.exists(link_name) {
+                    true => Change::Modify,
+                    false => Change::Add,
+                };
+                (Category::Files, change, format!("{} -> {} (link)", link_name.display(), target.display()), None)
+            }
+        };
+        Some(description)
+    }
+
+    /// Reads the current contents of a file, if it exists and is text.
+    fn current_contents(&self, path: &Path) -> Option<String> {
+        fs::read_to_string(path_in_root(&self.root, path)).ok()
+    }
+
+    /// Whether anything, even a dangling link, is at `path`.
+    fn exists(&self, path: &Path) -> bool {
+        path_in_root(&self.root, path).symlink_metadata().is_ok()
+    }
+
+    fn paint(&self, colour: &str, text: &str) -> String {
+        match self.colour {
+            true => format!("{}{}{}", colour, text, RESET),
+            false => text.to_string(),
+        }
+    }
+
+    fn paint_diff_line(&self, line: &str) -> String {
+        match line.chars().next() {
+            _ if line.starts_with("---") || line.starts_with("+++") => self.paint(BOLD, line),
+            Some('+') => self.paint(GREEN, line),
+            Some('-') => self.paint(RED, line),
+            Some('@') => self.paint(CYAN, line),
+            _ => line.to_string(),
+        }
+    }
+}
+
+fn describe_repository(repository: &crate::system::PackageRepository) -> String {
+    match &repository.name {
+        Some(name) => format!("{} ({})", name, repository.location),
+        None => repository.location.to_string(),
+    }
+}
+
+fn describe_service(name: &str, user: Option<&ServiceUser>) -> String {
+    match user {
+        Some(user) => format!("{}'s {}", user.username, name),
+        None => name.to_string(),
+    }
+}
+
+/// Describes an account by what the configuration sets, such as `sapeint (groups wheel, audio)`.
+fn describe_account(username: &str, account: &Account) -> String {
+    let mut details = vec![];
+    if let Some(uid) = account.uid {
+        details.push(format!("uid {}", uid));
+    }
+    if let Some(group) = &account.group {
+        details.push(format!("group {}", group));
+    }
+    if !account.groups.is_empty() {
+        details.push(format!("groups {}", account.groups.join(", ")));
+    }
+    if let Some(shell) = &account.shell {
+        details.push(format!("shell {}", shell.display()));
+    }
+    if account.locked {
+        details.push(String::from("locked"));
+    }
+    // Only where the password comes from is shown, never the hash itself.
+    match &account.password {
+        Some(Password::Hashed(_)) => details.push(String::from("password set")),
+        Some(Password::File(path)) => details.push(format!("password from {}", path.display())),
+        None => (),
+    }
+
+    match details.is_empty() {
+        true => username.to_string(),
+        false => format!("{} ({})", username, details.join("; ")),
+    }
+}
+
+fn diff(path: &Path, current: &str, new: &str) -> Option<String> {
+    if current == new {
+        return None;
+    }
+
+    let path = path.display().to_string();
+    Some(TextDiff::from_lines(current, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(&path, &format!("{} (planned)", path))
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use crate::system::{PackageRepository, RemoteSource, Source};
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    #[test]
+    fn test_render_groups_categories() {
+        let plan = Plan::new(vec![
+            Action::File(FileSystemAction::CreateFile {
+                file_location: PathBuf::from("/etc/motd"),
+                contents: Some(String::from("Hello\n")),
+                is_dir: false,
+                mode: None,
+                owner: None,
+                group: None,
+            }),
+            Action::System(SystemAction::RemovePackage { package_name: String::from("nano") }),
+            Action::System(SystemAction::AddRepository {
+                package_repository: PackageRepository {
+                    name: Some(Rc::from("personal")),
+                    location: Source::Remote(RemoteSource::GithubRemote {
+                        user: Rc::from("sapein"),
+                        repository_name: Rc::from("void-packages"),
+                        branch_name: Some(Rc::from("personal")),
+                    }),
+                    allow_restricted: false,
+                },
+            }),
+            Action::System(SystemAction::DownService { service_name: String::from("ntpd"), down: true, user: None }),
+            Action::System(SystemAction::ModifyUser {
+                username: String::from("sapeint"),
+                account: Account {
+                    groups: vec![Rc::from("wheel"), Rc::from("audio")],
+                    locked: true,
+                    password: Some(Password::Hashed(Rc::from("$6$salt$hash"))),
+                    ..Account::default()
+                },
+            }),
+        ], vec![]);
+
+        let output = PlanRenderer::new(PathBuf::from("/nonexistent"), false).render(&plan);
+        assert_eq!(output, "Repositories\n  \
+                            + personal (https://github.com/sapein/void-packages, branch personal)\n\
+                            Packages\n  \
+                            - nano\n\
+                            Services\n  \
+                            ~ ntpd (down)\n\
+                            Users\n  \
+                            ~ sapeint (groups wheel, audio; locked; password set)\n\
+                            Files\n  \
+                            + /etc/motd\n");
+    }
+
+    #[test]
+    fn test_render_diffs_existing_files() {
+        let root = scratch_dir("render-diff");
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/motd"), "Hello\n").unwrap();
+        fs::write(root.join("etc/issue"), "Void\n").unwrap();
+
+        let plan = Plan::new(vec![
+            Action::File(FileSystemAction::CreateFile {
+                file_location: PathBuf::from("/etc/motd"),
+                contents: Some(String::from("Goodbye\n")),
+                is_dir: false,
+                mode: None,
+                owner: None,
+                group: None,
+            }),
+            Action::File(FileSystemAction::AddToFile {
+                original_file: PathBuf::from("/etc/issue"),
+                content_to_add: String::from("Linux\n"),
+            }),
+        ], vec![]);
+
+        let output = PlanRenderer::new(root.clone(), false).render(&plan);
+        assert_eq!(output, "Files\n  \
+                            ~ /etc/motd\n      \
+                            --- /etc/motd\n      \
+                            +++ /etc/motd (planned)\n      \
+                            @@ -1 +1 @@\n      \
+                            -Hello\n      \
+                            +Goodbye\n  \
+                            ~ /etc/issue (append)\n      \
+                            --- /etc/issue\n      \
+                            +++ /etc/issue (planned)\n      \
+                            @@ -1 +1,2 @@\n       \
+                            Void\n      \
+                            +Linux\n");
+
+        let coloured = PlanRenderer::new(root.clone(), true).render(&plan);
+        assert!(coloured.contains("\x1b[31m-Hello\x1b[0m"));
+        assert!(coloured.contains("\x1b[32m+Goodbye\x1b[0m"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_render_unchanged_contents() {
+        let root = scratch_dir("render-unchanged");
+        fs::create_dir_all(root.join("etc/sv/www")).unwrap();
+        fs::write(root.join("etc/motd"), "Hello\n").unwrap();
+        fs::write(root.join("etc/sv/www/run"), "#!/bin/sh\n").unwrap();
+        let file = |location: &str, contents: &str, mode: Option<u32>| Action::File(FileSystemAction::CreateFile {
+            file_location: PathBuf::from(location),
+            contents: Some(String::from(contents)),
+            is_dir: false,
+            mode,
+            owner: None,
+            group: None,
+        });
+
+        let plan = Plan::new(vec![file("/etc/motd", "Hello\n", None), file("/etc/sv/www/run", "#!/bin/sh\n", Some(0o755))], vec![]);
+        assert_eq!(PlanRenderer::new(root.clone(), false).render(&plan), "Files\n  ~ /etc/sv/www/run (mode 755)\n");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_render_existing_directories_and_binary_files() {
+        let root = scratch_dir("render-existing");
+        fs::create_dir_all(root.join("home/sapeint/.config")).unwrap();
+        fs::write(root.join("home/sapeint/.face"), [0x89, 0x50, 0x4e, 0x47, 0xff]).unwrap();
+        let directory = |location: &str, mode: Option<u32>| Action::File(FileSystemAction::CreateFile {
+            file_location: PathBuf::from(location),
+            contents: None,
+            is_dir: true,
+            mode,
+            owner: Some(String::from("sapeint")),
+            group: None,
+        });
+
+        let plan = Plan::new(vec![
+            directory("/home/sapeint", Some(0o700)),
+            directory("/home/sapeint/.config", None),
+            directory("/home/sapeint/library", None),
+            Action::File(FileSystemAction::CreateFile {
+                file_location: PathBuf::from("/home/sapeint/.face"),
+                contents: Some(String::from("face\n")),
+                is_dir: false,
+                mode: None,
+                owner: None,
+                group: None,
+            }),
+            Action::File(FileSystemAction::CopyFile {
+                original_location: PathBuf::from("/srv/dotfiles/.face"),
+                final_location: PathBuf::from("/home/sapeint/.face"),
+                is_recursive: false,
+                mode: None,
+                owner: None,
+            }),
+        ], vec![]);
+        assert_eq!(PlanRenderer::new(root.clone(), false).render(&plan), "Files\n  \
+                                                                      ~ /home/sapeint/ (mode 700)\n  \
+                                                                      + /home/sapeint/library/\n  \
+                                                                      ~ /home/sapeint/.face\n  \
+                                                                      ~ /home/sapeint/.face (copied from /srv/dotfiles/.face)\n");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_render_empty_plan() {
+        let output = PlanRenderer::new(PathBuf::from("/"), true).render(&Plan::new(vec![], vec![]));
+        assert_eq!(output, "No changes.\n");
+    }
+}
@@ -0,0 +1,340 @@
+//! Offline access to XBPS repository indexes.
+//!
+//! XBPS keeps a copy of each repository's index as an `<arch>-repodata` file: a tar
+//! archive, usually compressed with zstd or xz, containing `index.plist`. Reading those
+//! lets SVSM know which packages exist, and what they depend on, without going to the
+//! network.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+/// Where XBPS caches the repodata of every configured repository.
+pub const REPODATA_CACHE_LOCATION: &str = "/var/db/xbps";
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+#[derive(Debug)]
+pub enum RepodataError {
+    Io(io::Error),
+    Plist(plist::Error),
+    MissingIndex(PathBuf),
+    Malformed(String),
+}
+
+impl Display for RepodataError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepodataError::Io(err) => write!(f, "{}", err),
+            RepodataError::Plist(err) => write!(f, "Unable to read index.plist: {}", err),
+            RepodataError::MissingIndex(path) => write!(f, "{} does not contain an index.plist", path.display()),
+            RepodataError::Malformed(reason) => write!(f, "Repodata is malformed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RepodataError {}
+
+impl From<io::Error> for RepodataError {
+    fn from(err: io::Error) -> Self {
+        RepodataError::Io(err)
+    }
+}
+
+impl From<plist::Error> for RepodataError {
+    fn from(err: plist::Error) -> Self {
+        RepodataError::Plist(err)
+    }
+}
+
+/// A single package as described by a repository index.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IndexedPackage {
+    pub name: String,
+    pub pkgver: String,
+    /// Dependency patterns, such as `glibc>=2.36_1`.
+    pub run_depends: Vec<String>,
+    pub installed_size: u64,
+    pub license: Option<String>,
+    pub restricted: bool,
+}
+
+/// The contents of one `*-repodata` file.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RepositoryIndex {
+    /// The repodata file this index was read from, if any.
+    pub location: Option<PathBuf>,
+    pub packages: BTreeMap<String, IndexedPackage>,
+}
+
+impl RepositoryIndex {
+    pub fn from_file(path: &Path) -> Result<RepositoryIndex, RepodataError> {
+        let index = RepositoryIndex::from_archive(&fs::read(path)?)
+            .map_err(|err| match err {
+                RepodataError::MissingIndex(_) => RepodataError::MissingIndex(path.to_path_buf()),
+                err => err,
+            })?;
+        Ok(RepositoryIndex { location: Some(path.to_path_buf()), ..index })
+    }
+
+    /// Reads a repodata archive, detecting its compression from its contents.
+    pub fn from_archive(archive: &[u8]) -> Result<RepositoryIndex, RepodataError> {
+        let tar = decompress(archive)?;
+        let mut tar = tar::Archive::new(Cursor::new(tar));
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            if entry.path()?.as_ref() != Path::new("index.plist") {
+                continue;
+            }
+
+            let mut plist = vec![];
+            entry.read_to_end(&mut plist)?;
+            return RepositoryIndex::from_plist(&plist);
+        }
+        Err(RepodataError::MissingIndex(PathBuf::new()))
+    }
+
+    pub fn from_plist(plist: &[u8]) -> Result<RepositoryIndex, RepodataError> {
+        let index = plist::Value::from_reader(Cursor::new(plist))?;
+        let index = index
+            .as_dictionary()
+            .ok_or_else(|| RepodataError::Malformed(String::from("index.plist is not a dictionary")))?;
+
+        let packages = index
+            .iter()
+            .map(|(name, package)| Ok((name.clone(), IndexedPackage::from_plist(name, package)?)))
+            .collect::<Result<BTreeMap<String, IndexedPackage>, RepodataError>>()?;
+
+        Ok(RepositoryIndex { location: None, packages })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&IndexedPackage> {
+        self.packages.get(name)
+    }
+
+    /// Whether this index belongs to one of the `nonfree` repositories.
+    pub fn is_nonfree(&self) -> bool {
+        self.location_contains("nonfree")
+    }
+
+    /// Whether this index belongs to one of the `multilib` repositories.
+    pub fn is_multilib(&self) -> bool {
+        self.location_contains("multilib")
+    }
+
+    fn location_contains(&self, name: &str) -> bool {
+        self.location
+            .as_ref()
+            .is_some_and(|location| location.to_string_lossy().contains(name))
+    }
+}
+
+impl IndexedPackage {
+    fn from_plist(name: &str, package: &plist::Value) -> Result<IndexedPackage, RepodataError> {
+        let package = package
+            .as_dictionary()
+            .ok_or_else(|| RepodataError::Malformed(format!("{} is not a dictionary", name)))?;
+        let string = |key: &str| package.get(key).and_then(plist::Value::as_string).map(String::from);
+
+        Ok(IndexedPackage {
+            name: name.to_string(),
+            pkgver: string("pkgver").ok_or_else(|| RepodataError::Malformed(format!("{} has no pkgver", name)))?,
+            run_depends: package
+                .get("run_depends")
+                .and_then(plist::Value::as_array)
+                .map(|depends| depends.iter().filter_map(plist::Value::as_string).map(String::from).collect())
+                .unwrap_or_default(),
+            installed_size: package
+                .get("installed_size")
+                .and_then(plist::Value::as_unsigned_integer)
+                .unwrap_or(0),
+            license: string("license"),
+            restricted: package
+                .get("restricted")
+                .and_then(plist::Value::as_boolean)
+                .unwrap_or(false),
+        })
+    }
+}
+
+fn decompress(archive: &[u8]) -> Result<Vec<u8>, RepodataError> {
+    let mut output = vec![];
+    if archive.starts_with(ZSTD_MAGIC) {
+        ruzstd::decoding::StreamingDecoder::new(archive)
+            .map_err(|err| RepodataError::Malformed(err.to_string()))?
+            .read_to_end(&mut output)?;
+    } else if archive.starts_with(XZ_MAGIC) {
+        xz2::read::XzDecoder::new(archive).read_to_end(&mut output)?;
+    } else {
+        output.extend_from_slice(archive);
+    }
+    Ok(output)
+}
+
+/// Every repository index cached on a system.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RepodataCache {
+    pub indexes: Vec<RepositoryIndex>,
+}
+
+impl RepodataCache {
+    /// Reads every `*-repodata` file in `directory` and the directories directly beneath it,
+    /// which is how XBPS lays out its cache.
+    pub fn load(directory: &Path) -> Result<RepodataCache, RepodataError> {
+        let mut files = vec![];
+        for entry in read_dir_sorted(directory)? {
+            if entry.is_dir() {
+                files.extend(read_dir_sorted(&entry)?);
+            } else {
+                files.push(entry);
+            }
+        }
+
+        let indexes = files
+            .into_iter()
+            .filter(|file| file.file_name().is_some_and(|name| name.to_string_lossy().ends_with("-repodata")))
+            .map(|file| RepositoryIndex::from_file(&file))
+            .collect::<Result<Vec<RepositoryIndex>, RepodataError>>()?;
+        Ok(RepodataCache { indexes })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Finds a package in the first index that contains it.
+    pub fn find(&self, name: &str) -> Option<(&RepositoryIndex, &IndexedPackage)> {
+        self.indexes
+            .iter()
+            .find_map(|index| index.get(name).map(|package| (index, package)))
+    }
+}
+
+fn read_dir_sorted(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = match fs::read_dir(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        entries => entries?.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<Vec<PathBuf>>>()?,
+    };
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    /// An `index.plist` with the given `(pkgver, run_depends)` entries.
+    pub(crate) fn index_plist(packages: &[(&str, &[&str])]) -> String {
+        let mut plist = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<plist version=\"1.0\">\n<dict>\n");
+        for (pkgver, depends) in packages {
+            let name = crate::package_manager::pkgver_name(pkgver);
+            plist += &format!("\t<key>{}</key>\n\t<dict>\n", name);
+            plist += "\t\t<key>installed_size</key>\n\t\t<integer>1024</integer>\n";
+            plist += "\t\t<key>license</key>\n\t\t<string>MIT</string>\n";
+            plist += &format!("\t\t<key>pkgver</key>\n\t\t<string>{}</string>\n", pkgver);
+            plist += "\t\t<key>run_depends</key>\n\t\t<array>\n";
+            for depend in *depends {
+                plist += &format!("\t\t\t<string>{}</string>\n", depend);
+            }
+            plist += "\t\t</array>\n\t</dict>\n";
+        }
+        plist + "</dict>\n</plist>\n"
+    }
+
+    /// A zstd compressed repodata archive containing `index_plist`.
+    pub(crate) fn repodata(index_plist: &str) -> Vec<u8> {
+        let mut tar = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(index_plist.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "index.plist", index_plist.as_bytes()).unwrap();
+        let tar = tar.into_inner().unwrap();
+
+        ruzstd::encoding::compress_to_vec(tar.as_slice(), ruzstd::encoding::CompressionLevel::Fastest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::test_util::scratch_dir;
+    use super::fixtures::*;
+    use super::*;
+
+    #[test]
+    fn test_index_from_plist() {
+        let index = RepositoryIndex::from_plist(index_plist(&[
+            ("firefox-118.0.1_2", &["glibc>=2.36_1", "gtk+3>=3.24_1"]),
+            ("glibc-2.36_1", &[]),
+        ]).as_bytes()).unwrap();
+
+        assert_eq!(index.get("firefox"), Some(&IndexedPackage {
+            name: String::from("firefox"),
+            pkgver: String::from("firefox-118.0.1_2"),
+            run_depends: vec![String::from("glibc>=2.36_1"), String::from("gtk+3>=3.24_1")],
+            installed_size: 1024,
+            license: Some(String::from("MIT")),
+            restricted: false,
+        }));
+        assert_eq!(index.get("glibc").unwrap().run_depends, Vec::<String>::new());
+        assert_eq!(index.get("discord"), None);
+    }
+
+    #[test]
+    fn test_index_from_zstd_archive() {
+        let archive = repodata(&index_plist(&[("nano-7.2_1", &[])]));
+        let index = RepositoryIndex::from_archive(&archive).unwrap();
+
+        assert_eq!(index.get("nano").unwrap().pkgver, "nano-7.2_1");
+    }
+
+    #[test]
+    fn test_index_from_xz_archive() {
+        let archive = repodata(&index_plist(&[("nano-7.2_1", &[])]));
+        let tar = decompress(&archive).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+        xz.write_all(&tar).unwrap();
+        let index = RepositoryIndex::from_archive(&xz.finish().unwrap()).unwrap();
+
+        assert_eq!(index.get("nano").unwrap().pkgver, "nano-7.2_1");
+    }
+
+    #[test]
+    fn test_archive_without_index() {
+        let mut tar = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_cksum();
+        tar.append_data(&mut header, "index-meta.plist", &[][..]).unwrap();
+
+        assert!(matches!(RepositoryIndex::from_archive(&tar.into_inner().unwrap()), Err(RepodataError::MissingIndex(_))));
+    }
+
+    #[test]
+    fn test_cache() {
+        let root = scratch_dir("repodata-cache");
+        let current = root.join("https___repo-default_voidlinux_org_current");
+        let nonfree = root.join("https___repo-default_voidlinux_org_current_nonfree");
+        fs::create_dir_all(&current).unwrap();
+        fs::create_dir_all(&nonfree).unwrap();
+        fs::write(current.join("x86_64-repodata"), repodata(&index_plist(&[("firefox-118.0.1_2", &[])]))).unwrap();
+        fs::write(nonfree.join("x86_64-repodata"), repodata(&index_plist(&[("steam-1.0.0.78_1", &[])]))).unwrap();
+        fs::write(root.join("pkgdb-0.38.plist"), "").unwrap();
+
+        let cache = RepodataCache::load(&root).unwrap();
+        assert_eq!(cache.indexes.len(), 2);
+
+        let (index, package) = cache.find("firefox").unwrap();
+        assert_eq!(package.pkgver, "firefox-118.0.1_2");
+        assert!(!index.is_nonfree());
+
+        let (index, _) = cache.find("steam").unwrap();
+        assert!(index.is_nonfree());
+        assert!(cache.find("discord").is_none());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
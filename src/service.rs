@@ -0,0 +1,380 @@
+//! Managing runit services.
+//!
+//! A service is enabled by linking its directory in `/etc/sv` into the directory `runsvdir`
+//! supervises, and is kept from starting automatically by a `down` file in its directory.
+//! On the running system that is `/var/service`, so `runsvdir` notices straight away; for any
+//! other root it is the default runlevel, `/etc/runit/runsvdir/default`.
+//!
+//! Services defined in the configuration get their `run`, `finish`, `conf` and `log/run`
+//! files generated from the definition by [`service_files`].
+//!
+//! Users' services work the same way inside their home directory, with service directories in
+//! `~/.local/sv` and enabled services linked into `~/.local/share/service`. A system service
+//! named `runsvdir-<user>` runs a `runsvdir` for that directory as the user.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::command::{CommandError, CommandRunner, SystemCommandRunner};
+use crate::probe::{RUNSVDIR_LOCATION, SERVICE_LOCATION};
+use crate::system::{path_in_root, ServiceDefinition};
+
+/// Where `runsvdir` looks for services on a running system.
+pub const ACTIVE_SERVICE_LOCATION: &str = "/var/service";
+/// Where a user's service directories are, relative to their home directory.
+pub const USER_SERVICE_LOCATION: &str = ".local/sv";
+/// Where a user's enabled services are linked, relative to their home directory.
+pub const USER_RUNSVDIR_LOCATION: &str = ".local/share/service";
+
+#[derive(Debug)]
+pub enum ServiceError {
+    Io(io::Error),
+    Command(CommandError),
+    /// There is no directory for the service, which is where it was looked for.
+    MissingService(PathBuf),
+    /// Something other than a link to the service is in the way.
+    NotALink(PathBuf),
+}
+
+impl Display for ServiceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceError::Io(err) => write!(f, "{}", err),
+            ServiceError::Command(err) => write!(f, "{}", err),
+            ServiceError::MissingService(directory) => write!(f, "There is no service at {}", directory.display()),
+            ServiceError::NotALink(path) => write!(f, "{} is not a link to a service", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+impl From<io::Error> for ServiceError {
+    fn from(err: io::Error) -> Self {
+        ServiceError::Io(err)
+    }
+}
+
+impl From<CommandError> for ServiceError {
+    fn from(err: CommandError) -> Self {
+        ServiceError::Command(err)
+    }
+}
+
+/// A file of a service defined in the configuration.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ServiceFile {
+    pub(crate) location: PathBuf,
+    pub(crate) contents: String,
+    pub(crate) mode: u32,
+}
+
+const HEADER: &str = "#!/bin/sh\n# Generated by svsm, changes will be overwritten.\n";
+
+/// The parts of a service directory that [`service_files`] only writes when the definition asks
+/// for them, and which have to go again when it stops asking. `log` is the log sub-service's whole
+/// directory.
+pub(crate) const OPTIONAL_SERVICE_FILES: [&str; 3] = ["finish", "conf", "log"];
+
+/// The files making up the service whose directory is `directory`.
+pub(crate) fn service_files(directory: &Path, definition: &ServiceDefinition) -> Vec<ServiceFile> {
+    let script = |location: &str, body: String| ServiceFile {
+        location: directory.join(location),
+        contents: format!("{}{}", HEADER, body),
+        mode: 0o755,
+    };
+
+    let mut run = String::new();
+    if !definition.environment.is_empty() {
+        run.push_str("[ -r ./conf ] && . ./conf\n");
+    }
+    if definition.log.is_some() {
+        run.push_str("exec 2>&1\n");
+    }
+    match &definition.user {
+        Some(user) => run.push_str(&format!("exec chpst -u {} {}\n", user, definition.run)),
+        None => run.push_str(&format!("exec {}\n", definition.run)),
+    }
+
+    let mut files = vec![script("run", run)];
+    if let Some(finish) = &definition.finish {
+        files.push(script("finish", format!("{}\n", finish)));
+    }
+    if !definition.environment.is_empty() {
+        let conf = definition.environment
+            .iter()
+            .map(|(key, value)| format!("export {}={}\n", key, shell_quote(value)))
+            .collect::<String>();
+        files.push(ServiceFile { location: directory.join("conf"), contents: conf, mode: 0o644 });
+    }
+    if let Some(log) = &definition.log {
+        files.push(script("log/run", format!("exec {}\n", log)));
+    }
+    files
+}
+
+/// The system service running `runsvdir` over `username`'s enabled services, as them.
+pub(crate) fn user_runsvdir(username: &str, home: &Path) -> ServiceDefinition {
+    ServiceDefinition {
+        run: Rc::from(format!("runsvdir {}", home.join(USER_RUNSVDIR_LOCATION).display())),
+        finish: None,
+        environment: BTreeMap::from([
+            (Rc::from("HOME"), Rc::from(home.to_string_lossy())),
+            (Rc::from("USER"), Rc::from(username)),
+        ]),
+        user: Some(Rc::from(username)),
+        log: None,
+    }
+}
+
+/// Quotes `value` so the shell reads it back unchanged.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[derive(Clone)]
+pub(crate) struct ServiceManager {
+    root: PathBuf,
+    /// Where service directories are, as a path on the managed system.
+    services: PathBuf,
+    /// Where enabled services are linked, as a path on the managed system.
+    links: PathBuf,
+    runner: Rc<dyn CommandRunner>,
+}
+
+impl ServiceManager {
+    /// `running` says whether `root` is the system runit is currently supervising.
+    pub(crate) fn new(root: PathBuf, running: bool) -> Self {
+        let links = match running {
+            true => PathBuf::from(ACTIVE_SERVICE_LOCATION),
+            false => PathBuf::from(RUNSVDIR_LOCATION),
+        };
+        ServiceManager { root, services: PathBuf::from(SERVICE_LOCATION), links, runner: Rc::new(SystemCommandRunner) }
+    }
+
+    /// Manages the services of the user whose home directory is `home`.
+    pub(crate) fn for_user(root: PathBuf, home: &Path) -> Self {
+        ServiceManager {
+            root,
+            services: home.join(USER_SERVICE_LOCATION),
+            links: home.join(USER_RUNSVDIR_LOCATION),
+            runner: Rc::new(SystemCommandRunner),
+        }
+    }
+
+    /// Controls running services through `runner` rather than on the host directly.
+    pub(crate) fn with_runner(mut self, runner: Rc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    pub(crate) fn enable(&self, name: &str) -> Result<(), ServiceError> {
+        let service = self.service_directory(name)?;
+        let link = self.link(name);
+        match fs::read_link(&link) {
+            Ok(target) if target == service => return Ok(()),
+            Ok(_) => fs::remove_file(&link)?,
+            Err(_) if fs::symlink_metadata(&link).is_ok() => return Err(ServiceError::NotALink(link)),
+            Err(_) => (),
+        }
+
+        fs::create_dir_all(path_in_root(&self.root, &self.links))?;
+        // The link has to make sense on the managed system, so it points at the service's
+        // path there rather than where it is under the root.
+        Ok(symlink(service, link)?)
+    }
+
+    pub(crate) fn disable(&self, name: &str) -> Result<(), ServiceError> {
+        let link = self.link(name);
+        match fs::symlink_metadata(&link) {
+            Ok(metadata) if metadata.file_type().is_symlink() => Ok(fs::remove_file(link)?),
+            Ok(_) => Err(ServiceError::NotALink(link)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Adds or removes the service's `down` file, which stops runit from starting it.
+    pub(crate) fn set_down(&self, name: &str, down: bool) -> Result<(), ServiceError> {
+        let down_file = path_in_root(&self.root, &self.service_directory(name)?).join("down");
+        match (down, down_file.exists()) {
+            (true, false) => Ok(fs::write(down_file, "")?),
+            (false, true) => Ok(fs::remove_file(down_file)?),
+            _ => Ok(()),
+        }
+    }
+
+    /// Restarts the service with `sv`, which talks to its `runsv`.
+    pub(crate) fn restart(&self, name: &str) -> Result<(), ServiceError> {
+        self.sv("restart", name)
+    }
+
+    /// Sends the service SIGHUP, which most daemons take as a cue to reread their configuration.
+    pub(crate) fn reload(&self, name: &str) -> Result<(), ServiceError> {
+        self.sv("hup", name)
+    }
+
+    fn sv(&self, command: &str, name: &str) -> Result<(), ServiceError> {
+        let service = path_in_root(&self.root, &self.service_directory(name)?);
+        self.runner.run_checked("sv", &[command, &service.to_string_lossy()])?;
+        Ok(())
+    }
+
+    /// The service's directory on the managed system, which must exist.
+    fn service_directory(&self, name: &str) -> Result<PathBuf, ServiceError> {
+        let service = self.services.join(name);
+        match path_in_root(&self.root, &service).is_dir() && !name.contains('/') {
+            true => Ok(service),
+            false => Err(ServiceError::MissingService(service)),
+        }
+    }
+
+    fn link(&self, name: &str) -> PathBuf {
+        path_in_root(&self.root, &self.links.join(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::fake::FakeCommandRunner;
+    use crate::test_util::scratch_dir;
+    use super::*;
+
+    #[test]
+    fn test_enable_and_disable() {
+        let root = scratch_dir("service-enable");
+        fs::create_dir_all(root.join("etc/sv/sshd")).unwrap();
+        let services = ServiceManager::new(root.clone(), false);
+        let link = root.join("etc/runit/runsvdir/default/sshd");
+
+        services.enable("sshd").unwrap();
+        services.enable("sshd").unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), PathBuf::from("/etc/sv/sshd"));
+
+        services.disable("sshd").unwrap();
+        services.disable("sshd").unwrap();
+        assert!(fs::symlink_metadata(&link).is_err());
+
+        assert!(matches!(services.enable("nginx"), Err(ServiceError::MissingService(_))));
+        assert!(matches!(services.enable("../../etc"), Err(ServiceError::MissingService(_))));
+
+        fs::create_dir_all(&link).unwrap();
+        assert!(matches!(services.enable("sshd"), Err(ServiceError::NotALink(_))));
+        assert!(matches!(services.disable("sshd"), Err(ServiceError::NotALink(_))));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_running_system() {
+        let root = scratch_dir("service-running");
+        fs::create_dir_all(root.join("etc/sv/sshd")).unwrap();
+
+        ServiceManager::new(root.clone(), true).enable("sshd").unwrap();
+        assert_eq!(fs::read_link(root.join("var/service/sshd")).unwrap(), PathBuf::from("/etc/sv/sshd"));
+        assert!(!root.join("etc/runit/runsvdir/default/sshd").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_service_files() {
+        let definition = ServiceDefinition {
+            run: Rc::from("/usr/bin/syncthing -no-browser"),
+            finish: Some(Rc::from("rm -f /tmp/syncthing.lock")),
+            environment: BTreeMap::from([(Rc::from("STNODEFAULTFOLDER"), Rc::from("1")), (Rc::from("STGUIADDRESS"), Rc::from("it's local"))]),
+            user: Some(Rc::from("sapeint")),
+            log: Some(Rc::from("vlogger -t syncthing")),
+        };
+
+        let files = service_files(Path::new("/etc/sv/syncthing"), &definition);
+        assert_eq!(files, vec![
+            ServiceFile {
+                location: PathBuf::from("/etc/sv/syncthing/run"),
+                contents: format!("{}[ -r ./conf ] && . ./conf\nexec 2>&1\nexec chpst -u sapeint /usr/bin/syncthing -no-browser\n", HEADER),
+                mode: 0o755,
+            },
+            ServiceFile {
+                location: PathBuf::from("/etc/sv/syncthing/finish"),
+                contents: format!("{}rm -f /tmp/syncthing.lock\n", HEADER),
+                mode: 0o755,
+            },
+            ServiceFile {
+                location: PathBuf::from("/etc/sv/syncthing/conf"),
+                contents: String::from("export STGUIADDRESS='it'\\''s local'\nexport STNODEFAULTFOLDER='1'\n"),
+                mode: 0o644,
+            },
+            ServiceFile {
+                location: PathBuf::from("/etc/sv/syncthing/log/run"),
+                contents: format!("{}exec vlogger -t syncthing\n", HEADER),
+                mode: 0o755,
+            },
+        ]);
+
+        let minimal = ServiceDefinition { run: Rc::from("darkhttpd /srv/www"), finish: None, environment: BTreeMap::new(), user: None, log: None };
+        assert_eq!(service_files(Path::new("/etc/sv/www"), &minimal), vec![ServiceFile {
+            location: PathBuf::from("/etc/sv/www/run"),
+            contents: format!("{}exec darkhttpd /srv/www\n", HEADER),
+            mode: 0o755,
+        }]);
+    }
+
+    #[test]
+    fn test_restart_and_reload() {
+        let root = scratch_dir("service-restart");
+        fs::create_dir_all(root.join("etc/sv/sshd")).unwrap();
+        let runner = Rc::new(FakeCommandRunner::default());
+        let services = ServiceManager::new(root.clone(), false).with_runner(runner.clone());
+
+        services.restart("sshd").unwrap();
+        services.reload("sshd").unwrap();
+        assert_eq!(runner.calls(), vec![
+            format!("sv restart {}", root.join("etc/sv/sshd").display()),
+            format!("sv hup {}", root.join("etc/sv/sshd").display()),
+        ]);
+
+        runner.respond(1, "");
+        assert!(matches!(services.restart("sshd"), Err(ServiceError::Command(_))));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_user_services() {
+        let root = scratch_dir("service-user");
+        fs::create_dir_all(root.join("home/sapeint/.local/sv/pipewire")).unwrap();
+        let services = ServiceManager::for_user(root.clone(), Path::new("/home/sapeint"));
+
+        services.enable("pipewire").unwrap();
+        services.set_down("pipewire", true).unwrap();
+        assert_eq!(fs::read_link(root.join("home/sapeint/.local/share/service/pipewire")).unwrap(), PathBuf::from("/home/sapeint/.local/sv/pipewire"));
+        assert!(root.join("home/sapeint/.local/sv/pipewire/down").exists());
+        assert!(matches!(services.enable("sshd"), Err(ServiceError::MissingService(_))));
+
+        let runsvdir = service_files(Path::new("/etc/sv/runsvdir-sapeint"), &user_runsvdir("sapeint", Path::new("/home/sapeint")));
+        assert_eq!(runsvdir[0].contents, format!("{}[ -r ./conf ] && . ./conf\nexec chpst -u sapeint runsvdir /home/sapeint/.local/share/service\n", HEADER));
+        assert_eq!(runsvdir[1].contents, "export HOME='/home/sapeint'\nexport USER='sapeint'\n");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_set_down() {
+        let root = scratch_dir("service-down");
+        fs::create_dir_all(root.join("etc/sv/ntpd")).unwrap();
+        let services = ServiceManager::new(root.clone(), false);
+
+        services.set_down("ntpd", true).unwrap();
+        assert!(root.join("etc/sv/ntpd/down").exists());
+        services.set_down("ntpd", true).unwrap();
+        services.set_down("ntpd", false).unwrap();
+        assert!(!root.join("etc/sv/ntpd/down").exists());
+        assert!(matches!(services.set_down("nginx", true), Err(ServiceError::MissingService(_))));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+//! Reading the state `runsv` records for the service it supervises.
+//!
+//! `runsv` keeps two files in a service's `supervise` directory: `status`, a 20 byte record,
+//! and `stat`, a human readable summary. `status` is laid out as
+//!
+//! ```text
+//! 0..8    TAI64 label of when the service last changed state, big endian
+//! 8..12   nanoseconds of that time, big endian
+//! 12..16  pid of the running process, little endian, 0 when there is none
+//! 16      1 if the service is paused
+//! 17      'u' if the service is wanted up, 'd' if it is wanted down
+//! 18      1 if the service has been sent TERM
+//! 19      0 when down, 1 when running, 2 while running its finish script
+//! ```
+//!
+//! `stat` is only read when `status` is missing or malformed, and gives the state alone.
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::system::path_in_root;
+
+const STATUS_LENGTH: usize = 20;
+/// The TAI64 label of the Unix epoch, as runit computes it.
+const TAI64_UNIX_EPOCH: u64 = 4611686018427387914;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RunState {
+    Down,
+    Run,
+    Finish,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Want {
+    Up,
+    Down,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SuperviseStatus {
+    pub state: RunState,
+    pub pid: Option<u32>,
+    /// When the service entered its current state, if known.
+    pub since: Option<SystemTime>,
+    pub want: Option<Want>,
+    pub paused: bool,
+    pub got_term: bool,
+}
+
+impl SuperviseStatus {
+    /// Parses the binary `supervise/status` record.
+    pub fn from_status(bytes: &[u8]) -> Option<SuperviseStatus> {
+        if bytes.len() != STATUS_LENGTH {
+            return None;
+        }
+
+        let tai = u64::from_be_bytes(bytes[0..8].try_into().ok()?);
+        let nanoseconds = u32::from_be_bytes(bytes[8..12].try_into().ok()?);
+        let since = tai
+            .checked_sub(TAI64_UNIX_EPOCH)
+            .map(|seconds| UNIX_EPOCH + Duration::new(seconds, nanoseconds));
+        let pid = u32::from_le_bytes(bytes[12..16].try_into().ok()?);
+        let state = match bytes[19] {
+            0 => RunState::Down,
+            1 => RunState::Run,
+            2 => RunState::Finish,
+            _ => return None,
+        };
+        let want = match bytes[17] {
+            b'u' => Some(Want::Up),
+            b'd' => Some(Want::Down),
+            _ => None,
+        };
+
+        Some(SuperviseStatus {
+            state,
+            pid: (pid != 0).then_some(pid),
+            since,
+            want,
+            paused: bytes[16] != 0,
+            got_term: bytes[18] != 0,
+        })
+    }
+
+    /// Parses the text of `supervise/stat`, such as `run` or `down, want up`.
+    pub fn from_stat(stat: &str) -> Option<SuperviseStatus> {
+        let mut parts = stat.trim().split(", ");
+        let state = match parts.next()? {
+            "down" => RunState::Down,
+            "run" => RunState::Run,
+            "finish" => RunState::Finish,
+            _ => return None,
+        };
+
+        let mut status = SuperviseStatus { state, pid: None, since: None, want: None, paused: false, got_term: false };
+        for part in parts {
+            match part {
+                "paused" => status.paused = true,
+                "got TERM" => status.got_term = true,
+                "want up" => status.want = Some(Want::Up),
+                "want down" => status.want = Some(Want::Down),
+                _ => (),
+            }
+        }
+        Some(status)
+    }
+
+    /// How long the service has been in its current state, as of `now`.
+    pub fn uptime(&self, now: SystemTime) -> Option<Duration> {
+        self.since.map(|since| now.duration_since(since).unwrap_or_default())
+    }
+}
+
+impl Display for RunState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunState::Down => write!(f, "down"),
+            RunState::Run => write!(f, "run"),
+            RunState::Finish => write!(f, "finish"),
+        }
+    }
+}
+
+/// Reads the status of the service in `directory`, or `None` when it isn't being supervised.
+pub fn read_status(root: &Path, directory: &Path) -> io::Result<Option<SuperviseStatus>> {
+    let supervise = path_in_root(root, &directory.join("supervise"));
+    match fs::read(supervise.join("status")) {
+        Ok(bytes) => {
+            if let Some(status) = SuperviseStatus::from_status(&bytes) {
+                return Ok(Some(status));
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+
+    match fs::read_to_string(supervise.join("stat")) {
+        Ok(stat) => Ok(SuperviseStatus::from_stat(&stat)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Describes `status` the way `sv status` does, such as `run: sshd: (pid 412) 3600s`.
+pub fn describe(name: &str, status: Option<&SuperviseStatus>, now: SystemTime) -> String {
+    let status = match status {
+        Some(status) => status,
+        None => return format!("fail: {}: runsv not running", name),
+    };
+
+    let mut description = format!("{}: {}:", status.state, name);
+    if let Some(pid) = status.pid {
+        description.push_str(&format!(" (pid {})", pid));
+    }
+    if let Some(uptime) = status.uptime(now) {
+        description.push_str(&format!(" {}s", uptime.as_secs()));
+    }
+    match (status.state, status.want) {
+        (RunState::Down, Some(Want::Up)) => description.push_str(", want up"),
+        (RunState::Run, Some(Want::Down)) => description.push_str(", want down"),
+        _ => (),
+    }
+    if status.paused {
+        description.push_str(", paused");
+    }
+    if status.got_term {
+        description.push_str(", got TERM");
+    }
+    description
+}
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use crate::probe::SERVICE_LOCATION;
+    use super::*;
+
+    /// Builds a `supervise/status` record the way `runsv` writes it.
+    pub(crate) fn status_record(state: u8, pid: u32, since: u64, want: u8) -> Vec<u8> {
+        let mut record = vec![];
+        record.extend_from_slice(&(TAI64_UNIX_EPOCH + since).to_be_bytes());
+        record.extend_from_slice(&0u32.to_be_bytes());
+        record.extend_from_slice(&pid.to_le_bytes());
+        record.extend_from_slice(&[0, want, 0, state]);
+        record
+    }
+
+    /// Writes a status record for the service `name` under `root`.
+    pub(crate) fn write_status(root: &Path, name: &str, record: &[u8]) {
+        let supervise = path_in_root(root, &Path::new(SERVICE_LOCATION).join(name).join("supervise"));
+        fs::create_dir_all(&supervise).unwrap();
+        fs::write(supervise.join("status"), record).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::probe::SERVICE_LOCATION;
+    use crate::test_util::scratch_dir;
+    use super::fixtures::*;
+    use super::*;
+
+    #[test]
+    fn test_from_status() {
+        let status = SuperviseStatus::from_status(&status_record(1, 412, 1_700_000_000, b'u')).unwrap();
+        assert_eq!(status, SuperviseStatus {
+            state: RunState::Run,
+            pid: Some(412),
+            since: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            want: Some(Want::Up),
+            paused: false,
+            got_term: false,
+        });
+        assert_eq!(status.uptime(UNIX_EPOCH + Duration::from_secs(1_700_003_600)), Some(Duration::from_secs(3600)));
+
+        let down = SuperviseStatus::from_status(&status_record(0, 0, 1_700_000_000, b'd')).unwrap();
+        assert_eq!((down.state, down.pid, down.want), (RunState::Down, None, Some(Want::Down)));
+
+        assert_eq!(SuperviseStatus::from_status(&[0; 19]), None);
+        assert_eq!(SuperviseStatus::from_status(&status_record(7, 0, 0, b'u')), None);
+    }
+
+    #[test]
+    fn test_from_stat() {
+        let status = SuperviseStatus::from_stat("down, want up, paused\n").unwrap();
+        assert_eq!(status.state, RunState::Down);
+        assert_eq!(status.want, Some(Want::Up));
+        assert!(status.paused);
+        assert_eq!(status.pid, None);
+        assert_eq!(SuperviseStatus::from_stat("unknown"), None);
+    }
+
+    #[test]
+    fn test_read_status() {
+        let root = scratch_dir("supervise-read");
+        write_status(&root, "sshd", &status_record(1, 412, 1_700_000_000, b'u'));
+        fs::create_dir_all(root.join("etc/sv/ntpd/supervise")).unwrap();
+        fs::write(root.join("etc/sv/ntpd/supervise/stat"), "finish\n").unwrap();
+
+        let service = |name: &str| Path::new(SERVICE_LOCATION).join(name);
+        assert_eq!(read_status(&root, &service("sshd")).unwrap().map(|status| status.pid), Some(Some(412)));
+        assert_eq!(read_status(&root, &service("ntpd")).unwrap().map(|status| status.state), Some(RunState::Finish));
+        assert_eq!(read_status(&root, &service("dhcpcd")).unwrap(), None);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_describe() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_003_600);
+        let run = SuperviseStatus::from_status(&status_record(1, 412, 1_700_000_000, b'u')).unwrap();
+        let down = SuperviseStatus::from_status(&status_record(0, 0, 1_700_003_590, b'u')).unwrap();
+
+        assert_eq!(describe("sshd", Some(&run), now), "run: sshd: (pid 412) 3600s");
+        assert_eq!(describe("ntpd", Some(&down), now), "down: ntpd: 10s, want up");
+        assert_eq!(describe("dhcpcd", None, now), "fail: dhcpcd: runsv not running");
+    }
+}
@@ -1,6 +1,8 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Display, Formatter};
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
+use serde::{Deserialize, Serialize};
 
 
 /// This is designed to represent the world/system in SVSM.
@@ -11,30 +13,124 @@ pub(crate) struct System {
     pub(crate) services: HashMap<Rc<str>, Service>,
     pub(crate) repositories: HashMap<Rc<str>, PackageRepository>,
     pub(crate) users: HashMap<Rc<str>, User>,
-    pub(crate) system_packages: Rc<str> //TODO: replace with actual data.
+    pub(crate) packages: HashMap<Rc<str>, Package>,
+    /// Whether the nonfree and multilib repositories are enabled for the packages that need them.
+    pub(crate) auto_enable_repositories: bool,
+}
+
+impl Default for System {
+    /// A system with nothing in it, set up the way an empty configuration is.
+    fn default() -> Self {
+        System {
+            services: HashMap::new(),
+            repositories: HashMap::new(),
+            users: HashMap::new(),
+            packages: HashMap::new(),
+            auto_enable_repositories: true,
+        }
+    }
+}
+
+impl System {
+    /// All packages requested by the system or any user, ordered by name.
+    pub(crate) fn all_packages(&self) -> BTreeMap<&Rc<str>, &Package> {
+        self.users
+            .values()
+            .flat_map(|user| user.packages.iter())
+            .chain(self.packages.iter())
+            .collect()
+    }
+
+    /// Every git repository the system uses, whether as a vp_repo, for dotfiles or for
+    /// package configuration, sorted and without duplicates.
+    pub(crate) fn git_sources(&self) -> Vec<&Source> {
+        let mut sources = self.repositories
+            .values()
+            .map(|repository| &repository.location)
+            .filter(|source| source.git_remote().is_some())
+            .chain(self.configuration_sources())
+            .collect::<Vec<&Source>>();
+        sources.sort();
+        sources.dedup();
+        sources
+    }
+
+    /// The git repositories files are deployed from: dotfiles and package configuration.
+    pub(crate) fn configuration_sources(&self) -> Vec<&Source> {
+        let configuration = self.users
+            .values()
+            .flat_map(|user| user.packages.values())
+            .chain(self.packages.values())
+            .flat_map(|package| package.config.values())
+            .filter_map(|file| match &file.source {
+                ConfigurationSource::File { source, .. } => source.as_ref(),
+                ConfigurationSource::Template(_) => None,
+            });
+
+        let mut sources = self.users
+            .values()
+            .filter_map(|user| user.dotfiles.as_ref().map(|dotfiles| &dotfiles.source))
+            .chain(configuration)
+            .filter(|source| source.git_remote().is_some())
+            .collect::<Vec<&Source>>();
+        sources.sort();
+        sources.dedup();
+        sources
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct Service {
     pub(crate) name: Rc<str>,
     pub(crate) enabled: bool,
-    pub(crate) downed: bool
+    pub(crate) downed: bool,
+    /// Services defined in the configuration have their files in `/etc/sv` written by SVSM,
+    /// rather than coming from a package.
+    pub(crate) definition: Option<ServiceDefinition>,
+    /// Managed files the service reads, whose changes it has to be restarted or reloaded for.
+    pub(crate) files: Vec<PathBuf>,
+    /// How the service picks up changes to its files, when the configuration says.
+    pub(crate) on_change: Option<OnChange>,
 }
 
-#[derive(Debug, PartialEq, Clone, Eq, Hash, Ord, PartialOrd)]
-pub(crate) struct PackageRepository {
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OnChange {
+    /// `sv restart`
+    #[default]
+    Restart,
+    /// `sv hup`, for services that reread their configuration on SIGHUP.
+    Reload,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct ServiceDefinition {
+    /// The command the service runs, which must stay in the foreground.
+    pub(crate) run: Rc<str>,
+    /// A script run each time the service stops.
+    pub(crate) finish: Option<Rc<str>>,
+    pub(crate) environment: BTreeMap<Rc<str>, Rc<str>>,
+    /// The user to run as, instead of root.
+    pub(crate) user: Option<Rc<str>>,
+    /// The command the service's output is piped to, run as its `log` sub-service.
+    pub(crate) log: Option<Rc<str>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct PackageRepository {
     pub(crate) name: Option<Rc<str>>,
     pub(crate) location: Source,
     pub(crate) allow_restricted: bool,
 }
 
-#[derive(Debug, PartialEq, Clone, Eq, Hash, Ord, PartialOrd)]
+#[derive(Debug, PartialEq, Clone, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub(crate) enum Source {
     Remote(RemoteSource),
     Local(LocalSource)
 }
 
-#[derive(Debug, PartialEq, Clone, Eq, Hash, Ord, PartialOrd)]
+#[derive(Debug, PartialEq, Clone, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub(crate) enum RemoteSource {
     GithubRemote {
         user: Rc<str>,
@@ -49,31 +145,206 @@ pub(crate) enum RemoteSource {
     VoidRepo
 }
 
-#[derive(Debug, PartialEq, Clone, Eq, Hash, Ord, PartialOrd)]
+#[derive(Debug, PartialEq, Clone, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub(crate) enum LocalSource {
     Directory(PathBuf),
     File(PathBuf),
 }
 
-#[derive(Debug, PartialEq)]
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Remote(RemoteSource::GithubRemote { user, repository_name, branch_name: Some(branch) }) => write!(f, "https://github.com/{}/{}, branch {}", user, repository_name, branch),
+            Source::Remote(RemoteSource::GithubRemote { user, repository_name, branch_name: None }) => write!(f, "https://github.com/{}/{}", user, repository_name),
+            Source::Remote(RemoteSource::GitRemote { url, branch_name: Some(branch) }) => write!(f, "{}, branch {}", url, branch),
+            Source::Remote(RemoteSource::GitRemote { url, branch_name: None }) => write!(f, "{}", url),
+            Source::Remote(RemoteSource::VoidRemote(url)) => write!(f, "{}", url),
+            Source::Remote(RemoteSource::VoidRepo) => write!(f, "void"),
+            Source::Local(LocalSource::Directory(path)) => write!(f, "{}", path.display()),
+            Source::Local(LocalSource::File(path)) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Source {
+    /// The URL and branch to clone, for sources that are git repositories.
+    pub(crate) fn git_remote(&self) -> Option<(String, Option<&str>)> {
+        match self {
+            Source::Remote(RemoteSource::GithubRemote { user, repository_name, branch_name }) => Some((
+                format!("https://github.com/{}/{}.git", user, repository_name),
+                branch_name.as_deref(),
+            )),
+            Source::Remote(RemoteSource::GitRemote { url, branch_name }) => Some((url.to_string(), branch_name.as_deref())),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Service {
+    /// An enabled service that isn't downed, the way the configuration declares one unless it
+    /// says otherwise.
+    fn default() -> Self {
+        Service { name: Rc::from(""), enabled: true, downed: false, definition: None, files: vec![], on_change: None }
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
 pub(crate) struct User {
     pub(crate) username: Option<Rc<str>>,
     pub(crate) homedir: HomeDirectory,
-    pub(crate) dotfiles: Option<Source>,
+    pub(crate) dotfiles: Option<Dotfiles>,
     pub(crate) packages: HashMap<Rc<str>, Package>,
+    /// Runit services supervised by the user's own `runsvdir`.
+    pub(crate) services: HashMap<Rc<str>, Service>,
+    pub(crate) account: Account,
+    /// What the user may run as root, if anything.
+    pub(crate) privilege: Option<Privilege>,
+}
 
+/// A repository of files to deploy into a user's home directory.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Dotfiles {
+    pub(crate) source: Source,
+    /// Paths in the repository, relative to it, that are never deployed.
+    pub(crate) exclude: Vec<PathBuf>,
+    pub(crate) deploy: DotfileDeployment,
+}
+
+/// How dotfiles are put into the home directory.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub(crate) enum DotfileDeployment {
+    /// Each file is copied, so the home directory doesn't depend on the repository.
+    #[default]
+    Copy,
+    /// Each file is a link to the file in the repository, or its checkout for git repositories.
+    Symlink,
+}
+
+/// Lets a user run commands as root, through sudo or doas.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Privilege {
+    pub(crate) tool: PrivilegeTool,
+    /// Whether sudo/doas skips asking for the user's password.
+    pub(crate) nopasswd: bool,
+    /// The only commands the user may run, as absolute paths with optional arguments. The user
+    /// may run anything when this is empty.
+    pub(crate) commands: Vec<Rc<str>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivilegeTool {
+    #[default]
+    Sudo,
+    Doas,
+}
+
+/// A user's login account, as `/etc/passwd`, `/etc/group` and `/etc/shadow` record it.
+#[derive(Debug, PartialEq, Clone, Default, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct Account {
+    /// Allocated from the range given to regular users when not set.
+    pub(crate) uid: Option<u32>,
+    /// The primary group, which is a group named after the user when not set.
+    pub(crate) group: Option<Rc<str>>,
+    /// Supplementary groups. The user is taken out of any group not listed.
+    pub(crate) groups: Vec<Rc<str>>,
+    /// The login shell, which is `/bin/sh` for new accounts when not set.
+    pub(crate) shell: Option<PathBuf>,
+    /// Locked accounts can't log in with their password.
+    pub(crate) locked: bool,
+    /// The password is left as it is when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) password: Option<Password>,
+}
+
+/// Where an account's password hash comes from. Hashes are never shown, so that they can't end
+/// up in logs or printed plans.
+#[derive(PartialEq, Clone, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Password {
+    /// A crypt(3) hash, as `/etc/shadow` keeps it.
+    Hashed(Rc<str>),
+    /// A file holding the hash, which is only read when the plan is applied.
+    File(PathBuf),
+}
+
+impl Debug for Password {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Password::Hashed(_) => write!(f, "Hashed(<redacted>)"),
+            Password::File(path) => f.debug_tuple("File").field(path).finish(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum HomeDirectory {
     Path {
         location: PathBuf,
+        /// Directories inside the home directory, relative to it.
         subdirs: Vec<PathBuf>,
+        /// Permission bits of the home directory itself, which is `0o700` when not set.
+        mode: Option<u32>,
+        /// Whether a new home directory is seeded with the contents of `/etc/skel`.
+        skel: bool,
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl Default for HomeDirectory {
+    /// A home directory with the default settings. Where it is depends on the user, so the
+    /// location is left empty.
+    fn default() -> Self {
+        HomeDirectory::Path { location: PathBuf::new(), subdirs: vec![], mode: None, skel: false }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub(crate) struct Package {
-    pub(crate) config: Option<PathBuf>,
+    /// Configuration files, keyed by the slot their package definition declares them in.
+    pub(crate) config: BTreeMap<Rc<str>, ConfigurationFile>,
+    /// The exact version and revision, such as `118.0.1_2`, when one is known or wanted.
+    pub(crate) version: Option<Rc<str>>,
+    /// Held packages are never updated by XBPS. A hold is left as it is when not set.
+    pub(crate) hold: Option<bool>,
     pub(crate) repository: Source,
-}
\ No newline at end of file
+}
+
+/// A file that configures a package, such as bash's `bashrc`.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct ConfigurationFile {
+    /// Where the file belongs on the managed system.
+    pub(crate) location: PathBuf,
+    pub(crate) source: ConfigurationSource,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum ConfigurationSource {
+    /// A file given with `use_file`, which the configured file is kept identical to.
+    ///
+    /// `path` is relative to `source` when there is one, and to the configuration file otherwise.
+    File {
+        path: PathBuf,
+        source: Option<Source>,
+    },
+    /// The template from the package definition, which is only copied when nothing is at the
+    /// location yet so that it can be modified afterwards.
+    Template(PathBuf),
+}
+
+/// `path` without any `./` in it, so that it compares equal to, and is shown like, the same path
+/// written without them.
+pub(crate) fn normalise(path: &Path) -> PathBuf {
+    path.components().filter(|component| *component != Component::CurDir).collect()
+}
+
+/// Resolves an absolute path on the managed system to its location under `root`.
+///
+/// This lets SVSM operate on a system that is not the one it is running on,
+/// such as a chroot or a test fixture.
+pub(crate) fn path_in_root(root: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix("/") {
+        Ok(relative) => root.join(relative),
+        Err(_) => root.join(path),
+    }
+}